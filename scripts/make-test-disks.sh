@@ -4,7 +4,7 @@ make_test_disks() {
     WORKLOADS_DIR="$1"
     pushd "$WORKLOADS_DIR"
 
-    rm -f fat12.img fat16.img fat32.img
+    rm -f fat12.img fat16.img fat32.img fat32-4k.img
 
     mkdosfs -F 12 -C fat12.img 8192
     file fat12.img
@@ -12,6 +12,8 @@ make_test_disks() {
     file fat16.img
     mkdosfs -F 32 -C fat32.img 1048576
     file fat32.img
+    mkdosfs -F 32 -S 4096 -C fat32-4k.img 1048576
+    file fat32-4k.img
 
     rm -rf test_data
     mkdir -p test_data/a/b/c
@@ -37,6 +39,38 @@ make_test_disks() {
     mcopy -oi fat12.img  -s test_data/* ::
     mcopy -oi fat16.img  -s test_data/* ::
     mcopy -oi fat32.img  -s test_data/* ::
+    mcopy -oi fat32-4k.img  -s test_data/* ::
+
+    # ext2 with 1KiB blocks uses double indirect blocks for the larger file,
+    # ext4 uses extents
+    rm -f ext2.img ext4.img
+    seq 1 200000 | head -c 1048576 > test_data/large
+    gzip -9 -k test_data/large
+    zstd -q -19 test_data/large -o test_data/large.zst
+    truncate -s 1M test_data/sparse
+    echo -n "end" >> test_data/sparse
+    ln -s a/b/c/512 test_data/link
+    ln -s /a/b test_data/absolute-link
+    ln -s ./a/../a/./b/../b/c/../../b/c/./../c/../../b/./c/.././c/././././././1024 test_data/long-link
+    ln -s loop test_data/loop
+    mke2fs -q -t ext2 -b 1024 -d test_data ext2.img 8M
+    file ext2.img
+    mke2fs -q -t ext4 -d test_data ext4.img 16M
+    file ext4.img
+
+    # A typical installer CD with both BIOS and EFI El Torito entries and
+    # Rock Ridge names, and one with only an EFI entry and Joliet names
+    rm -f iso9660.img joliet.img
+    rm -f test_data/link test_data/absolute-link test_data/long-link test_data/loop
+    cp fat12.img test_data/efi.img
+    head -c 2048 /dev/zero > test_data/boot.bin
+    touch test_data/$(printf 'x%.0s' `seq 200`)
+    xorriso -as mkisofs -R -J -b boot.bin -no-emul-boot \
+        -eltorito-alt-boot -e efi.img -no-emul-boot -o iso9660.img test_data
+    file iso9660.img
+    rm test_data/x*
+    xorriso -as mkisofs -J -e efi.img -no-emul-boot -o joliet.img test_data
+    file joliet.img
 
     rm -rf test_data
 
@@ -1,5 +1,18 @@
 // SPDX-License-Identifier: Apache-2.0
 // Copyright (C) 2023 Rivos Inc.
 
+use core::sync::atomic::{AtomicU64, Ordering};
+
 pub mod asm;
 pub mod layout;
+
+static BOOT_HART_ID: AtomicU64 = AtomicU64::new(0);
+
+// The hart the firmware was started on, which has to start the kernel too
+pub fn set_boot_hart_id(id: u64) {
+    BOOT_HART_ID.store(id, Ordering::Relaxed);
+}
+
+pub fn boot_hart_id() -> u64 {
+    BOOT_HART_ID.load(Ordering::Relaxed)
+}
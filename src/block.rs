@@ -3,66 +3,20 @@
 
 use core::cell::RefCell;
 
-use crate::virtio::{Error as VirtioError, VirtioTransport};
+use heapless::Vec;
 
-const QUEUE_SIZE: usize = 16;
-
-#[repr(C)]
-#[repr(align(16))]
-#[derive(Default)]
-/// A virtio qeueue entry descriptor
-struct Desc {
-    addr: u64,
-    length: u32,
-    flags: u16,
-    next: u16,
-}
-
-#[repr(C)]
-#[repr(align(2))]
-#[derive(Default)]
-/// The virtio available ring
-struct AvailRing {
-    flags: u16,
-    idx: u16,
-    ring: [u16; QUEUE_SIZE],
-}
-
-#[repr(C)]
-#[repr(align(4))]
-#[derive(Default)]
-/// The virtio used ring
-struct UsedRing {
-    flags: u16,
-    idx: u16,
-    ring: [UsedElem; QUEUE_SIZE],
-}
-
-#[repr(C)]
-#[derive(Default)]
-/// A single element in the used ring
-struct UsedElem {
-    id: u32,
-    len: u32,
-}
+use crate::virtio::{Buffer, Error as VirtioError, VirtioTransport, Virtqueue, QUEUE_SIZE};
 
 #[repr(C)]
 #[repr(align(64))]
 /// Device driver for virtio block over any transport
 pub struct VirtioBlockDevice<'a> {
     transport: &'a mut dyn VirtioTransport,
-    state: RefCell<DriverState>,
+    queue: RefCell<Virtqueue>,
     read_only: bool,
-}
-
-#[repr(C)]
-#[repr(align(64))]
-#[derive(Default)]
-struct DriverState {
-    descriptors: [Desc; QUEUE_SIZE],
-    avail: AvailRing,
-    used: UsedRing,
-    next_head: usize,
+    max_segments: usize,
+    max_segment_size: usize,
+    block_size: usize,
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -89,6 +43,13 @@ struct BlockRequestFooter {
 }
 
 const SECTOR_SIZE: usize = 512;
+/// Largest logical block size supported for a device
+pub const MAX_BLOCK_SIZE: usize = 4096;
+
+// A request needs one descriptor for the header and one for the footer
+const MAX_SEGMENTS: usize = QUEUE_SIZE - 2;
+// Size of each data descriptor when VIRTIO_BLK_F_SIZE_MAX is not offered
+const DEFAULT_SEGMENT_SIZE: usize = 1 << 20;
 
 #[repr(C)]
 pub struct SectorBuf([u8; SECTOR_SIZE]);
@@ -112,10 +73,101 @@ impl SectorBuf {
     }
 }
 
+/// Whether a device with logical blocks of `size` bytes can be used
+pub fn is_supported_block_size(size: usize) -> bool {
+    size.is_power_of_two() && (SECTOR_SIZE..=MAX_BLOCK_SIZE).contains(&size)
+}
+
+/// Read 512 byte sectors from a device that only transfers whole logical
+/// blocks of `block_size` bytes. `read` is only handed block aligned requests
+/// and blocks that `data` covers partially go through a bounce buffer.
+pub fn read_unaligned<F>(
+    block_size: usize,
+    mut sector: u64,
+    mut data: &mut [u8],
+    mut read: F,
+) -> Result<(), Error>
+where
+    F: FnMut(u64, &mut [u8]) -> Result<(), Error>,
+{
+    if data.is_empty() || data.len() % SECTOR_SIZE != 0 {
+        return Err(Error::InvalidDataBufSize);
+    }
+    let sectors_per_block = (block_size / SECTOR_SIZE) as u64;
+    while !data.is_empty() {
+        let offset = (sector % sectors_per_block) as usize * SECTOR_SIZE;
+        let length = if offset == 0 && data.len() >= block_size {
+            let length = data.len() - data.len() % block_size;
+            read(sector, &mut data[..length])?;
+            length
+        } else {
+            let mut block = [0u8; MAX_BLOCK_SIZE];
+            read(
+                sector - sector % sectors_per_block,
+                &mut block[..block_size],
+            )?;
+            let length = usize::min(block_size - offset, data.len());
+            data[..length].copy_from_slice(&block[offset..offset + length]);
+            length
+        };
+        sector += (length / SECTOR_SIZE) as u64;
+        data = &mut core::mem::take(&mut data)[length..];
+    }
+    Ok(())
+}
+
+/// Write a single 512 byte sector to a device that only transfers whole
+/// logical blocks of `block_size` bytes by updating the block holding it.
+pub fn write_unaligned<R, W>(
+    block_size: usize,
+    sector: u64,
+    data: &mut [u8],
+    mut read: R,
+    mut write: W,
+) -> Result<(), Error>
+where
+    R: FnMut(u64, &mut [u8]) -> Result<(), Error>,
+    W: FnMut(u64, &mut [u8]) -> Result<(), Error>,
+{
+    if data.len() != SECTOR_SIZE {
+        return Err(Error::InvalidDataBufSize);
+    }
+    if block_size == SECTOR_SIZE {
+        return write(sector, data);
+    }
+    let sectors_per_block = (block_size / SECTOR_SIZE) as u64;
+    let first = sector - sector % sectors_per_block;
+    let offset = (sector % sectors_per_block) as usize * SECTOR_SIZE;
+    let mut block = [0u8; MAX_BLOCK_SIZE];
+    let block = &mut block[..block_size];
+    read(first, block)?;
+    block[offset..offset + SECTOR_SIZE].copy_from_slice(data);
+    write(first, block)
+}
+
 pub trait SectorRead {
+    /// Size in bytes of the logical blocks of the underlying device. Sectors
+    /// are always 512 bytes, a device with larger blocks maps several sectors
+    /// onto each block.
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
     /// Read a single sector (512 bytes) from the block device. `data` must be
     /// exactly 512 bytes long.
     fn read(&self, sector: u64, data: &mut [u8]) -> Result<(), Error>;
+
+    /// Read contiguous sectors starting at `sector` from the block device.
+    /// `data` must be a non-zero multiple of 512 bytes long.
+    fn read_sectors(&self, sector: u64, data: &mut [u8]) -> Result<(), Error> {
+        if data.is_empty() || data.len() % SectorBuf::len() != 0 {
+            return Err(Error::InvalidDataBufSize);
+        }
+        for (i, chunk) in data.chunks_exact_mut(SectorBuf::len()).enumerate() {
+            self.read(sector + i as u64, chunk)?;
+        }
+        Ok(())
+    }
 }
 
 pub trait SectorWrite {
@@ -125,6 +177,12 @@ pub trait SectorWrite {
     fn flush(&self) -> Result<(), Error>;
 }
 
+/// A disk that can be booted from and exposed through EFI Block I/O
+pub trait BlockDevice: SectorRead + SectorWrite {
+    /// Number of 512 byte sectors that this device holds
+    fn get_capacity(&self) -> u64;
+}
+
 #[derive(PartialEq, Eq, Copy, Clone)]
 enum RequestType {
     Read = 0,
@@ -136,15 +194,21 @@ impl<'a> VirtioBlockDevice<'a> {
     pub fn new(transport: &'a mut dyn VirtioTransport) -> VirtioBlockDevice<'a> {
         VirtioBlockDevice {
             transport,
-            state: RefCell::new(DriverState::default()),
+            queue: RefCell::new(Virtqueue::default()),
             read_only: false,
+            max_segments: 1,
+            max_segment_size: SectorBuf::len(),
+            block_size: SectorBuf::len(),
         }
     }
 
     pub fn init(&mut self) -> Result<(), VirtioError> {
         const VIRTIO_SUBSYSTEM_BLOCK: u32 = 0x2;
         const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+        const VIRTIO_BLK_F_SIZE_MAX: u64 = 1 << 1;
+        const VIRTIO_BLK_F_SEG_MAX: u64 = 1 << 2;
         const VIRTIO_BLK_F_RO: u64 = 1 << 5;
+        const VIRTIO_BLK_F_BLK_SIZE: u64 = 1 << 6;
 
         const VIRTIO_STATUS_RESET: u32 = 0;
         const VIRTIO_STATUS_ACKNOWLEDGE: u32 = 1;
@@ -177,7 +241,40 @@ impl<'a> VirtioBlockDevice<'a> {
         self.read_only = (device_features & VIRTIO_BLK_F_RO) == VIRTIO_BLK_F_RO;
 
         // Don't support any advanced features for now
-        let supported_features = VIRTIO_F_VERSION_1 | VIRTIO_BLK_F_RO;
+        let supported_features = VIRTIO_F_VERSION_1
+            | VIRTIO_BLK_F_RO
+            | VIRTIO_BLK_F_SIZE_MAX
+            | VIRTIO_BLK_F_SEG_MAX
+            | VIRTIO_BLK_F_BLK_SIZE;
+
+        // Requests must cover whole logical blocks
+        self.block_size = SectorBuf::len();
+        if device_features & VIRTIO_BLK_F_BLK_SIZE == VIRTIO_BLK_F_BLK_SIZE {
+            // blk_size: 0x14
+            let blk_size = self.transport.read_device_config(20) as usize;
+            if !is_supported_block_size(blk_size) {
+                self.transport.add_status(VIRTIO_STATUS_FAILED);
+                return Err(VirtioError::UnsupportedDevice);
+            }
+            self.block_size = blk_size;
+        }
+
+        // Limits on the data descriptors of a single request
+        self.max_segment_size = DEFAULT_SEGMENT_SIZE;
+        if device_features & VIRTIO_BLK_F_SIZE_MAX == VIRTIO_BLK_F_SIZE_MAX {
+            // size_max: 0x08
+            let size_max = self.transport.read_device_config(8) as usize;
+            self.max_segment_size = usize::max(
+                size_max / self.block_size * self.block_size,
+                self.block_size,
+            );
+        }
+        self.max_segments = MAX_SEGMENTS;
+        if device_features & VIRTIO_BLK_F_SEG_MAX == VIRTIO_BLK_F_SEG_MAX {
+            // seg_max: 0x0c
+            let seg_max = self.transport.read_device_config(12) as usize;
+            self.max_segments = seg_max.clamp(1, MAX_SEGMENTS);
+        }
 
         // Report driver features
         self.transport
@@ -190,30 +287,10 @@ impl<'a> VirtioBlockDevice<'a> {
         }
 
         // Program queues
-        self.transport.set_queue(0);
-
-        let max_queue = self.transport.get_queue_max_size();
-
-        // Hardcoded queue size to QUEUE_SIZE at the moment
-        if max_queue < QUEUE_SIZE as u16 {
+        if let Err(err) = self.queue.borrow().setup(self.transport, 0) {
             self.transport.add_status(VIRTIO_STATUS_FAILED);
-            return Err(VirtioError::QueueTooSmall);
+            return Err(err);
         }
-        self.transport.set_queue_size(QUEUE_SIZE as u16);
-
-        // Update all queue parts
-        let state = self.state.borrow_mut();
-        let addr = state.descriptors.as_ptr() as u64;
-        self.transport.set_descriptors_address(addr);
-
-        let addr = (&state.avail as *const _) as u64;
-        self.transport.set_avail_ring(addr);
-
-        let addr = (&state.used as *const _) as u64;
-        self.transport.set_used_ring(addr);
-
-        // Confirm queue
-        self.transport.set_queue_enable();
 
         // Report driver ready
         self.transport.add_status(VIRTIO_STATUS_DRIVER_OK);
@@ -221,21 +298,12 @@ impl<'a> VirtioBlockDevice<'a> {
         Ok(())
     }
 
-    // Number of sectors that this device holds
-    pub fn get_capacity(&self) -> u64 {
-        u64::from(self.transport.read_device_config(0))
-            | u64::from(self.transport.read_device_config(4)) << 32
-    }
-
     fn request(
         &self,
         sector: u64,
         data: Option<&mut [u8]>,
         request: RequestType,
     ) -> Result<(), Error> {
-        const VIRTQ_DESC_F_NEXT: u16 = 1;
-        const VIRTQ_DESC_F_WRITE: u16 = 2;
-
         const VIRTIO_BLK_S_OK: u8 = 0;
         const VIRTIO_BLK_S_IOERR: u8 = 1;
         const VIRTIO_BLK_S_UNSUPP: u8 = 2;
@@ -246,66 +314,39 @@ impl<'a> VirtioBlockDevice<'a> {
             sector,
         };
 
-        let footer = BlockRequestFooter { status: 0 };
+        let mut footer = BlockRequestFooter { status: 0 };
 
-        let mut state = self.state.borrow_mut();
+        let mut buffers: Vec<Buffer, QUEUE_SIZE> = Vec::new();
+        buffers.push(Buffer::readable(&header)).unwrap();
 
-        let next_head = state.next_head;
-        let d = &mut state.descriptors[next_head];
-        let next_desc = (next_head + 1) % QUEUE_SIZE;
-        d.addr = (&header as *const _) as u64;
-        d.length = core::mem::size_of::<BlockRequestHeader>() as u32;
-        d.flags = VIRTQ_DESC_F_NEXT;
-        d.next = next_desc as u16;
-
-        let d = &mut state.descriptors[next_desc];
-        let next_desc = (next_desc + 1) % QUEUE_SIZE;
         if request != RequestType::Flush {
-            match data {
+            let data = match data {
                 None => {
                     return Err(Error::NoDataBuf);
                 }
-                Some(data) => {
-                    if data.len() != SectorBuf::len() {
-                        return Err(Error::InvalidDataBufSize);
-                    }
-                    d.addr = data.as_ptr() as u64;
-                    d.length = SectorBuf::len() as u32;
-                }
-            }
-        }
-
-        d.flags = VIRTQ_DESC_F_NEXT
-            | if request == RequestType::Read {
-                VIRTQ_DESC_F_WRITE
-            } else {
-                0
+                Some(data) => data,
             };
-        d.next = next_desc as u16;
-
-        let d = &mut state.descriptors[next_desc];
-        d.addr = (&footer as *const _) as u64;
-        d.length = core::mem::size_of::<BlockRequestFooter>() as u32;
-        d.flags = VIRTQ_DESC_F_WRITE;
-        d.next = 0;
-
-        // Update ring to point to head of chain. Fence. Then update idx
-        let avail_index = state.avail.idx;
-        state.avail.ring[(avail_index % QUEUE_SIZE as u16) as usize] = state.next_head as u16;
-        core::sync::atomic::fence(core::sync::atomic::Ordering::Acquire);
-
-        state.avail.idx = state.avail.idx.wrapping_add(1);
+            if data.is_empty()
+                || data.len() % SectorBuf::len() != 0
+                || data.len().div_ceil(self.max_segment_size) > self.max_segments
+            {
+                return Err(Error::InvalidDataBufSize);
+            }
 
-        // Next free descriptor to use
-        state.next_head = (next_desc + 1) % QUEUE_SIZE;
+            // Chain one descriptor per segment of the data buffer
+            for segment in data.chunks_mut(self.max_segment_size) {
+                let buffer = if request == RequestType::Read {
+                    Buffer::writable(segment)
+                } else {
+                    Buffer::readable(segment)
+                };
+                buffers.push(buffer).unwrap();
+            }
+        }
 
-        // Notify queue has been updated
-        self.transport.notify_queue(0);
+        buffers.push(Buffer::writable(&mut footer)).unwrap();
 
-        // Check for the completion of the request
-        while unsafe { core::ptr::read_volatile(&state.used.idx) } != state.avail.idx {
-            core::sync::atomic::fence(core::sync::atomic::Ordering::Acquire);
-        }
+        self.queue.borrow_mut().submit(self.transport, 0, &buffers);
 
         match footer.status {
             VIRTIO_BLK_S_OK => Ok(()),
@@ -317,8 +358,27 @@ impl<'a> VirtioBlockDevice<'a> {
 }
 
 impl<'a> SectorRead for VirtioBlockDevice<'a> {
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
     fn read(&self, sector: u64, data: &mut [u8]) -> Result<(), Error> {
-        self.request(sector, Some(data), RequestType::Read)
+        if data.len() != SectorBuf::len() {
+            return Err(Error::InvalidDataBufSize);
+        }
+        self.read_sectors(sector, data)
+    }
+
+    fn read_sectors(&self, sector: u64, data: &mut [u8]) -> Result<(), Error> {
+        // Split into the largest requests the device accepts
+        let request_size = self.max_segments * self.max_segment_size;
+        read_unaligned(self.block_size, sector, data, |sector, data| {
+            for (i, chunk) in data.chunks_mut(request_size).enumerate() {
+                let offset = (i * request_size / SectorBuf::len()) as u64;
+                self.request(sector + offset, Some(chunk), RequestType::Read)?;
+            }
+            Ok(())
+        })
     }
 }
 
@@ -327,7 +387,13 @@ impl<'a> SectorWrite for VirtioBlockDevice<'a> {
         if self.read_only {
             return Err(Error::BlockNotSupported);
         }
-        self.request(sector, Some(data), RequestType::Write)
+        write_unaligned(
+            self.block_size,
+            sector,
+            data,
+            |sector, data| self.request(sector, Some(data), RequestType::Read),
+            |sector, data| self.request(sector, Some(data), RequestType::Write),
+        )
     }
 
     fn flush(&self) -> Result<(), Error> {
@@ -337,3 +403,10 @@ impl<'a> SectorWrite for VirtioBlockDevice<'a> {
         self.request(0, None, RequestType::Flush)
     }
 }
+
+impl<'a> BlockDevice for VirtioBlockDevice<'a> {
+    fn get_capacity(&self) -> u64 {
+        u64::from(self.transport.read_device_config(0))
+            | u64::from(self.transport.read_device_config(4)) << 32
+    }
+}
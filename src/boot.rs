@@ -5,7 +5,7 @@ use core::mem;
 
 use crate::{
     bootinfo::{EntryType, Info, MemoryEntry},
-    fat::{Error, Read},
+    fs::{Error, Read},
     mem::MemoryRegion,
 };
 
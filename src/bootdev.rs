@@ -0,0 +1,206 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2026 The Rust Hypervisor Firmware Authors
+
+use heapless::Vec;
+use log::{info, warn};
+
+use crate::{block::BlockDevice, cache::SectorCache, common, ext4, fat, iso9660, loader, part};
+
+pub const MAX_DEVICES: usize = 16;
+
+/// A block device carrying a partition table with an EFI System Partition, or
+/// a CD with an El Torito EFI boot image
+#[derive(Clone, Copy, Debug)]
+pub struct BootDisk {
+    /// Position of the device in scan order
+    pub index: usize,
+    pub disk_guid: [u8; 16],
+    pub esp_guid: [u8; 16],
+    pub esp_start: u64,
+    pub esp_end: u64,
+    /// First and last sector of the Extended Boot Loader Partition or, if
+    /// there is none, the first Linux data partition with boot loader
+    /// entries (a separate `/boot`)
+    pub xbootldr: Option<(u64, u64)>,
+    /// Discoverable root partition for this architecture, if any
+    pub root_guid: Option<[u8; 16]>,
+    /// The device is a CD without a partition table, `esp_start` and
+    /// `esp_end` locate its El Torito EFI boot image
+    pub iso9660: bool,
+}
+
+impl BootDisk {
+    pub fn probe(index: usize, device: &dyn BlockDevice) -> Result<BootDisk, part::Error> {
+        let cache = SectorCache::new(device);
+        let r = &cache;
+        let result = match Self::probe_partitions(index, r) {
+            Ok(disk) => Ok(disk),
+            // Hybrid images carry a partition table, plain ones are only
+            // bootable through the El Torito catalog
+            Err(err) => match iso9660::find_efi_image(r) {
+                Ok((esp_start, esp_end)) => Ok(BootDisk {
+                    index,
+                    disk_guid: [0; 16],
+                    esp_guid: [0; 16],
+                    esp_start,
+                    esp_end,
+                    xbootldr: None,
+                    root_guid: None,
+                    iso9660: true,
+                }),
+                Err(_) => Err(err),
+            },
+        };
+        cache.log_stats();
+        result
+    }
+
+    fn probe_partitions(index: usize, r: &dyn BlockDevice) -> Result<BootDisk, part::Error> {
+        let partitions = part::partitions(r)?;
+        let disk_guid = partitions.disk_guid();
+        let esp = partitions.find_efi_partition()?;
+        // The other partitions are optional so errors finding them are ignored
+        let find = |partition_type| {
+            part::partitions(r)
+                .and_then(|p| p.find_partition(partition_type))
+                .ok()
+                .flatten()
+        };
+        let xbootldr = find(part::PartitionType::Xbootldr).or_else(|| {
+            part::partitions(r).ok()?.find_map(|p| {
+                p.ok().filter(|p| {
+                    p.partition_type() == part::PartitionType::LinuxData && has_loader_entries(r, p)
+                })
+            })
+        });
+        let root = find(part::PartitionType::Root(part::Arch::NATIVE));
+        Ok(BootDisk {
+            index,
+            disk_guid,
+            esp_guid: esp.guid,
+            esp_start: esp.first_lba,
+            esp_end: esp.last_lba,
+            xbootldr: xbootldr.map(|p| (p.first_lba, p.last_lba)),
+            root_guid: root.map(|p| p.guid),
+            iso9660: false,
+        })
+    }
+}
+
+// Whether the partition has a FAT or ext4 filesystem with Boot Loader
+// Specification entries
+fn has_loader_entries(r: &dyn BlockDevice, p: &part::PartitionEntry) -> bool {
+    let mut fat = fat::Filesystem::new(r, p.first_lba, p.last_lba);
+    if fat.init().is_ok() {
+        return fat.open(loader::ENTRY_DIRECTORY).is_ok();
+    }
+    let mut ext4 = ext4::Filesystem::new(r, p.first_lba, p.last_lba);
+    ext4.init().is_ok() && ext4.open(loader::ENTRY_DIRECTORY).is_ok()
+}
+
+enum Hint {
+    Index(usize),
+    Guid([u8; 16]),
+}
+
+impl Hint {
+    fn matches(&self, disk: &BootDisk) -> bool {
+        match self {
+            Hint::Index(index) => disk.index == *index,
+            Hint::Guid(guid) => disk.disk_guid == *guid || disk.esp_guid == *guid,
+        }
+    }
+}
+
+// Look for `rhf.boot=<n>` (the n-th block device in scan order, counting
+// from 0) or `rhf.boot=<guid>` (a GPT disk GUID or ESP partition GUID).
+fn cmdline_hint(cmdline: &[u8]) -> Option<Hint> {
+    let cmdline = core::str::from_utf8(cmdline).ok()?;
+    let value = cmdline
+        .split_ascii_whitespace()
+        .rev()
+        .find_map(|param| param.strip_prefix("rhf.boot="))?;
+
+    if let Ok(index) = value.parse::<usize>() {
+        return Some(Hint::Index(index));
+    }
+    if let Some(guid) = common::parse_guid(value) {
+        return Some(Hint::Guid(guid));
+    }
+    warn!("Invalid rhf.boot parameter: {value}");
+    None
+}
+
+fn add_disk(order: &mut Vec<BootDisk, MAX_DEVICES>, disk: BootDisk) {
+    if !order.iter().any(|d| d.index == disk.index) {
+        // Cannot overflow as there are never more disks than MAX_DEVICES
+        order.push(disk).unwrap();
+    }
+}
+
+/// Order the bootable disks by preference
+///
+/// An `rhf.boot=` command line hint takes precedence, all other disks follow
+/// in scan order. The UEFI `BootNext` and `BootOrder` variables are not used
+/// as there is no persistent variable store to read them from.
+pub fn boot_order(disks: &[BootDisk], cmdline: &[u8]) -> Vec<BootDisk, MAX_DEVICES> {
+    let mut order = Vec::new();
+
+    if let Some(hint) = cmdline_hint(cmdline) {
+        match disks.iter().find(|d| hint.matches(d)) {
+            Some(disk) => add_disk(&mut order, *disk),
+            None => warn!("No bootable disk matches the rhf.boot parameter"),
+        }
+    }
+
+    for disk in disks {
+        add_disk(&mut order, *disk);
+    }
+
+    for disk in order.iter() {
+        info!("Boot candidate: block device {}", disk.index);
+    }
+
+    order
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BootDisk;
+
+    fn disk(index: usize, byte: u8) -> BootDisk {
+        BootDisk {
+            index,
+            disk_guid: [byte; 16],
+            esp_guid: [byte + 0x80; 16],
+            esp_start: 2048,
+            esp_end: 4095,
+            xbootldr: None,
+            root_guid: None,
+            iso9660: false,
+        }
+    }
+
+    #[test]
+    fn test_boot_order() {
+        let disks = [disk(0, 1), disk(1, 2), disk(3, 3)];
+        let indices = |cmdline: &[u8]| -> std::vec::Vec<usize> {
+            super::boot_order(&disks, cmdline)
+                .iter()
+                .map(|d| d.index)
+                .collect()
+        };
+
+        assert_eq!(indices(b""), [0, 1, 3]);
+        assert_eq!(indices(b"console=ttyS0 rhf.boot=3"), [3, 0, 1]);
+        assert_eq!(indices(b"rhf.boot=2"), [0, 1, 3]);
+        assert_eq!(
+            indices(b"rhf.boot=02020202-0202-0202-0202-020202020202"),
+            [1, 0, 3]
+        );
+        assert_eq!(
+            indices(b"rhf.boot=83838383-8383-8383-8383-838383838383"),
+            [3, 0, 1]
+        );
+    }
+}
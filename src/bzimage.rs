@@ -7,21 +7,21 @@ use crate::{
     block::SectorBuf,
     boot::{Header, Params},
     bootinfo::{EntryType, Info},
-    fat::{self, Read},
+    fs::{self, Read},
     mem::MemoryRegion,
 };
 
 #[derive(Debug)]
 pub enum Error {
     #[allow(dead_code)]
-    File(fat::Error),
+    File(fs::Error),
     NoInitrdMemory,
     MagicMissing,
     NotRelocatable,
 }
 
-impl From<fat::Error> for Error {
-    fn from(e: fat::Error) -> Error {
+impl From<fs::Error> for Error {
+    fn from(e: fs::Error) -> Error {
         Error::File(e)
     }
 }
@@ -119,16 +119,28 @@ impl Kernel {
         current_addr
     }
 
-    pub fn load_initrd(&mut self, f: &mut dyn Read) -> Result<(), Error> {
-        let size = f.get_size() as u64;
+    // Load the initrds back-to-back as one, each starting 4 byte aligned as
+    // the kernel unpacks them as one stream of cpio archives
+    pub fn load_initrd(&mut self, files: &mut [&mut dyn Read]) -> Result<(), Error> {
+        let size = files.iter().fold(0u64, |size, f| {
+            size.next_multiple_of(4) + f.get_size() as u64
+        });
         let addr = match self.initrd_addr(size) {
             Some(addr) => addr,
             None => return Err(Error::NoInitrdMemory),
         };
 
-        let mut region = MemoryRegion::new(addr, size);
-        f.seek(0)?;
-        f.load_file(&mut region)?;
+        let mut offset: u64 = 0;
+        for f in files.iter_mut() {
+            let start = offset.next_multiple_of(4);
+            MemoryRegion::new(addr + offset, start - offset)
+                .as_bytes()
+                .fill(0);
+            let mut region = MemoryRegion::new(addr + start, f.get_size() as u64);
+            f.seek(0)?;
+            f.load_file(&mut region)?;
+            offset = start + f.get_size() as u64;
+        }
 
         // initrd pointer/size
         self.0.hdr.ramdisk_image = addr as u32;
@@ -186,3 +198,94 @@ impl CmdLine {
         bytes[self.length] = 0;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use core::ffi::c_void;
+
+    use crate::{
+        bootinfo::{EntryType, Info, MemoryEntry},
+        layout::MemoryDescriptor,
+    };
+
+    extern "C" {
+        fn mmap(
+            addr: *mut c_void,
+            len: usize,
+            prot: i32,
+            flags: i32,
+            fd: i32,
+            off: i64,
+        ) -> *mut c_void;
+        fn munmap(addr: *mut c_void, len: usize) -> i32;
+    }
+
+    // PROT_READ | PROT_WRITE and MAP_PRIVATE | MAP_ANONYMOUS | MAP_32BIT, as
+    // initrds are placed below 4GiB
+    const PROT: i32 = 0x3;
+    const FLAGS: i32 = 0x2 | 0x20 | 0x40;
+
+    struct TestInfo {
+        ram: MemoryEntry,
+    }
+
+    impl Info for TestInfo {
+        fn name(&self) -> &str {
+            "test"
+        }
+
+        fn cmdline(&self) -> &[u8] {
+            b""
+        }
+
+        fn num_entries(&self) -> usize {
+            1
+        }
+
+        fn entry(&self, _: usize) -> MemoryEntry {
+            self.ram
+        }
+
+        fn kernel_load_addr(&self) -> u64 {
+            0
+        }
+
+        fn memory_layout(&self) -> &'static [MemoryDescriptor] {
+            &[]
+        }
+    }
+
+    #[test]
+    fn test_load_initrd() {
+        let size = 8 << 20;
+        let ram = unsafe { mmap(core::ptr::null_mut(), size, PROT, FLAGS, -1, 0) };
+        assert!(!ram.is_null() && ram as isize != -1);
+        let memory = unsafe { core::slice::from_raw_parts_mut(ram as *mut u8, size) };
+        memory.fill(0xff);
+
+        let info = TestInfo {
+            ram: MemoryEntry {
+                addr: ram as u64,
+                size: size as u64,
+                entry_type: EntryType::Ram,
+            },
+        };
+        let mut kernel = super::Kernel::new(&info);
+        kernel.0.hdr.initrd_addr_max = u32::MAX;
+
+        let fs = crate::part::tests::ext4_filesystem();
+        let mut first = crate::fs::Filesystem::open(&fs, "/a/b/c/511").unwrap();
+        let mut second = crate::fs::Filesystem::open(&fs, "/a/b/c/1024").unwrap();
+        kernel.load_initrd(&mut [&mut first, &mut second]).unwrap();
+
+        // The second initrd starts 4 byte aligned after zero padding
+        assert_eq!({ kernel.0.hdr.ramdisk_size }, 512 + 1024);
+        let start = kernel.0.hdr.ramdisk_image as usize - ram as usize;
+        let initrd = &memory[start..start + 512 + 1024];
+        assert_eq!(initrd[..511], [b'a'; 511]);
+        assert_eq!(initrd[511], 0);
+        assert_eq!(initrd[512..], [b'a'; 1024]);
+
+        assert_eq!(unsafe { munmap(ram, size) }, 0);
+    }
+}
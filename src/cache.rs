@@ -0,0 +1,253 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2026 The Rust Hypervisor Firmware Authors
+
+use core::cell::{Cell, RefCell};
+
+use log::debug;
+
+use crate::block::{BlockDevice, Error, SectorBuf, SectorRead, SectorWrite};
+
+/// Number of sectors held by a `SectorCache`
+const CACHE_SECTORS: usize = 32;
+
+#[derive(Clone, Copy)]
+struct Entry {
+    sector: Option<u64>,
+    last_used: u64,
+    data: [u8; SectorBuf::len()],
+}
+
+/// A least recently used cache of single sector reads from a device
+///
+/// Filesystem metadata (FAT sectors, directories, partition tables) is read
+/// one sector at a time and often repeatedly, every read being a round trip
+/// to the device. Multi-sector reads are assumed to be file data and go
+/// straight to the device. Writes are passed through immediately and update
+/// the cached copy so the device never holds stale data.
+pub struct SectorCache<'a> {
+    device: &'a dyn BlockDevice,
+    entries: RefCell<[Entry; CACHE_SECTORS]>,
+    clock: Cell<u64>,
+    hits: Cell<u32>,
+    misses: Cell<u32>,
+}
+
+impl<'a> SectorCache<'a> {
+    pub fn new(device: &'a dyn BlockDevice) -> SectorCache<'a> {
+        SectorCache {
+            device,
+            entries: RefCell::new(
+                [Entry {
+                    sector: None,
+                    last_used: 0,
+                    data: [0; SectorBuf::len()],
+                }; CACHE_SECTORS],
+            ),
+            clock: Cell::new(0),
+            hits: Cell::new(0),
+            misses: Cell::new(0),
+        }
+    }
+
+    pub fn log_stats(&self) {
+        debug!(
+            "Sector cache: {} hits, {} misses",
+            self.hits.get(),
+            self.misses.get()
+        );
+    }
+
+    fn tick(&self) -> u64 {
+        let clock = self.clock.get() + 1;
+        self.clock.set(clock);
+        clock
+    }
+}
+
+impl<'a> SectorRead for SectorCache<'a> {
+    fn block_size(&self) -> usize {
+        self.device.block_size()
+    }
+
+    fn read(&self, sector: u64, data: &mut [u8]) -> Result<(), Error> {
+        if data.len() != SectorBuf::len() {
+            return Err(Error::InvalidDataBufSize);
+        }
+
+        let now = self.tick();
+        let mut entries = self.entries.borrow_mut();
+        if let Some(entry) = entries.iter_mut().find(|e| e.sector == Some(sector)) {
+            self.hits.set(self.hits.get() + 1);
+            entry.last_used = now;
+            data.copy_from_slice(&entry.data);
+            return Ok(());
+        }
+
+        self.misses.set(self.misses.get() + 1);
+        // Unused entries have never been used so they are picked first
+        let entry = entries.iter_mut().min_by_key(|e| e.last_used).unwrap();
+        entry.sector = None;
+        self.device.read(sector, &mut entry.data)?;
+        entry.sector = Some(sector);
+        entry.last_used = now;
+        data.copy_from_slice(&entry.data);
+        Ok(())
+    }
+
+    fn read_sectors(&self, sector: u64, data: &mut [u8]) -> Result<(), Error> {
+        if data.len() == SectorBuf::len() {
+            self.read(sector, data)
+        } else {
+            self.device.read_sectors(sector, data)
+        }
+    }
+}
+
+impl<'a> SectorWrite for SectorCache<'a> {
+    fn write(&self, sector: u64, data: &mut [u8]) -> Result<(), Error> {
+        let mut entries = self.entries.borrow_mut();
+        let entry = entries.iter_mut().find(|e| e.sector == Some(sector));
+        let result = self.device.write(sector, data);
+        if let Some(entry) = entry {
+            // The sector may be partially written after an error
+            match result {
+                Ok(()) => entry.data.copy_from_slice(data),
+                Err(_) => entry.sector = None,
+            }
+        }
+        result
+    }
+
+    fn flush(&self) -> Result<(), Error> {
+        self.device.flush()
+    }
+}
+
+impl<'a> BlockDevice for SectorCache<'a> {
+    fn get_capacity(&self) -> u64 {
+        self.device.get_capacity()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::{Cell, RefCell};
+
+    use super::{SectorCache, CACHE_SECTORS};
+    use crate::block::{BlockDevice, Error, SectorBuf, SectorRead, SectorWrite};
+
+    // Each sector is filled with its number, reads are counted
+    struct CountingDisk {
+        data: RefCell<Vec<u8>>,
+        reads: Cell<u32>,
+    }
+
+    impl CountingDisk {
+        fn new(sectors: usize) -> CountingDisk {
+            let mut data = vec![0u8; sectors * SectorBuf::len()];
+            for (i, sector) in data.chunks_exact_mut(SectorBuf::len()).enumerate() {
+                sector.fill(i as u8);
+            }
+            CountingDisk {
+                data: RefCell::new(data),
+                reads: Cell::new(0),
+            }
+        }
+    }
+
+    impl SectorRead for CountingDisk {
+        fn read(&self, sector: u64, data: &mut [u8]) -> Result<(), Error> {
+            self.read_sectors(sector, data)
+        }
+
+        fn read_sectors(&self, sector: u64, data: &mut [u8]) -> Result<(), Error> {
+            self.reads.set(self.reads.get() + 1);
+            let offset = sector as usize * SectorBuf::len();
+            data.copy_from_slice(&self.data.borrow()[offset..offset + data.len()]);
+            Ok(())
+        }
+    }
+
+    impl SectorWrite for CountingDisk {
+        fn write(&self, sector: u64, data: &mut [u8]) -> Result<(), Error> {
+            let offset = sector as usize * SectorBuf::len();
+            self.data.borrow_mut()[offset..offset + data.len()].copy_from_slice(data);
+            Ok(())
+        }
+
+        fn flush(&self) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
+    impl BlockDevice for CountingDisk {
+        fn get_capacity(&self) -> u64 {
+            (self.data.borrow().len() / SectorBuf::len()) as u64
+        }
+    }
+
+    fn read(cache: &SectorCache, sector: u64) -> u8 {
+        let mut data = SectorBuf::new();
+        cache.read(sector, data.as_mut_bytes()).unwrap();
+        assert!(data.as_bytes().iter().all(|b| *b == data.as_bytes()[0]));
+        data.as_bytes()[0]
+    }
+
+    #[test]
+    fn test_cache_hits() {
+        let disk = CountingDisk::new(64);
+        let cache = SectorCache::new(&disk);
+
+        for _ in 0..3 {
+            for sector in 0..4 {
+                assert_eq!(read(&cache, sector), sector as u8);
+            }
+        }
+        assert_eq!(disk.reads.get(), 4);
+        assert_eq!((cache.hits.get(), cache.misses.get()), (8, 4));
+
+        // Larger reads bypass the cache
+        let mut data = vec![0u8; 2 * SectorBuf::len()];
+        cache.read_sectors(2, &mut data).unwrap();
+        assert_eq!(disk.reads.get(), 5);
+        assert_eq!(data[SectorBuf::len()], 3);
+        assert_eq!(cache.get_capacity(), 64);
+    }
+
+    #[test]
+    fn test_cache_eviction() {
+        let disk = CountingDisk::new(64);
+        let cache = SectorCache::new(&disk);
+
+        for sector in 0..CACHE_SECTORS as u64 {
+            read(&cache, sector);
+        }
+        // Sector 0 becomes the most recently used so 1 is evicted
+        read(&cache, 0);
+        read(&cache, CACHE_SECTORS as u64);
+        let reads = disk.reads.get();
+        read(&cache, 0);
+        assert_eq!(disk.reads.get(), reads);
+        read(&cache, 1);
+        assert_eq!(disk.reads.get(), reads + 1);
+    }
+
+    #[test]
+    fn test_cache_write_through() {
+        let disk = CountingDisk::new(64);
+        let cache = SectorCache::new(&disk);
+
+        assert_eq!(read(&cache, 5), 5);
+        let mut data = SectorBuf::new();
+        data.as_mut_bytes().fill(0xaa);
+        cache.write(5, data.as_mut_bytes()).unwrap();
+        cache.write(6, data.as_mut_bytes()).unwrap();
+        assert_eq!(disk.data.borrow()[5 * SectorBuf::len()], 0xaa);
+        assert_eq!(disk.data.borrow()[6 * SectorBuf::len()], 0xaa);
+
+        let reads = disk.reads.get();
+        assert_eq!(read(&cache, 5), 0xaa);
+        assert_eq!(disk.reads.get(), reads);
+        assert_eq!(read(&cache, 6), 0xaa);
+    }
+}
@@ -79,3 +79,156 @@ pub fn ascii_to_ucs2(input: &str, output: &mut [u16]) {
         output[i] = u16::from(c);
     }
 }
+
+const fn crc32_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+static CRC32_TABLE: [u32; 256] = crc32_table();
+
+/// Incremental CRC-32 (IEEE 802.3), as used by GPT and EFI tables
+#[derive(Clone, Copy)]
+pub struct Crc32(u32);
+
+impl Crc32 {
+    pub const fn new() -> Self {
+        Crc32(!0)
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        for b in data {
+            self.0 = CRC32_TABLE[((self.0 ^ u32::from(*b)) & 0xff) as usize] ^ (self.0 >> 8);
+        }
+    }
+
+    pub fn finish(&self) -> u32 {
+        !self.0
+    }
+}
+
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(data);
+    crc.finish()
+}
+
+// Position of each byte of a textual GUID in the mixed-endian form used on
+// disk: the first three fields are little endian, the remaining eight bytes
+// are stored as written.
+const GUID_BYTE_ORDER: [usize; 16] = [3, 2, 1, 0, 5, 4, 7, 6, 8, 9, 10, 11, 12, 13, 14, 15];
+
+/// Parse a textual GUID (e.g. "C12A7328-F81F-11D2-BA4B-00A0C93EC93B") into
+/// its on-disk form
+pub const fn parse_guid(s: &str) -> Option<[u8; 16]> {
+    const fn hex(c: u8) -> Option<u8> {
+        match c {
+            b'0'..=b'9' => Some(c - b'0'),
+            b'a'..=b'f' => Some(c - b'a' + 10),
+            b'A'..=b'F' => Some(c - b'A' + 10),
+            _ => None,
+        }
+    }
+
+    let s = s.as_bytes();
+    if s.len() != 36 {
+        return None;
+    }
+
+    let mut bytes = [0u8; 16];
+    let mut i = 0;
+    let mut n = 0;
+    while i < s.len() {
+        if i == 8 || i == 13 || i == 18 || i == 23 {
+            if s[i] != b'-' {
+                return None;
+            }
+            i += 1;
+            continue;
+        }
+        let (Some(hi), Some(lo)) = (hex(s[i]), hex(s[i + 1])) else {
+            return None;
+        };
+        bytes[GUID_BYTE_ORDER[n]] = hi << 4 | lo;
+        i += 2;
+        n += 1;
+    }
+    Some(bytes)
+}
+
+/// Format an on-disk GUID in lowercase textual form
+pub fn format_guid<'a>(guid: &[u8; 16], out: &'a mut [u8; 36]) -> &'a str {
+    const DIGITS: &[u8; 16] = b"0123456789abcdef";
+    let mut i = 0;
+    for (n, &b) in GUID_BYTE_ORDER.iter().enumerate() {
+        if matches!(n, 4 | 6 | 8 | 10) {
+            out[i] = b'-';
+            i += 1;
+        }
+        out[i] = DIGITS[(guid[b] >> 4) as usize];
+        out[i + 1] = DIGITS[(guid[b] & 0xf) as usize];
+        i += 2;
+    }
+    // Only ASCII hex digits and dashes have been written
+    core::str::from_utf8(out).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_crc32() {
+        assert_eq!(super::crc32(b""), 0);
+        assert_eq!(super::crc32(b"123456789"), 0xcbf4_3926);
+
+        let mut crc = super::Crc32::new();
+        crc.update(b"1234");
+        crc.update(b"56789");
+        assert_eq!(crc.finish(), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn test_guid() {
+        let esp = [
+            0x28, 0x73, 0x2a, 0xc1, 0x1f, 0xf8, 0xd2, 0x11, 0xba, 0x4b, 0x00, 0xa0, 0xc9, 0x3e,
+            0xc9, 0x3b,
+        ];
+        assert_eq!(
+            super::parse_guid("C12A7328-F81F-11D2-BA4B-00A0C93EC93B"),
+            Some(esp)
+        );
+        assert_eq!(
+            super::parse_guid("c12a7328-f81f-11d2-ba4b-00a0c93ec93b"),
+            Some(esp)
+        );
+        assert_eq!(super::parse_guid("C12A7328F81F11D2BA4B00A0C93EC93B"), None);
+        assert_eq!(
+            super::parse_guid("C12A7328-F81F-11D2-BA4B-00A0C93EC93G"),
+            None
+        );
+        assert_eq!(
+            super::parse_guid("C12A7328-F81F-11D2-BA4B00-A0C93EC93B"),
+            None
+        );
+
+        let mut buf = [0u8; 36];
+        assert_eq!(
+            super::format_guid(&esp, &mut buf),
+            "c12a7328-f81f-11d2-ba4b-00a0c93ec93b"
+        );
+    }
+}
@@ -0,0 +1,458 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2026 The Rust Hypervisor Firmware Authors
+
+// gzip (RFC 1952) and DEFLATE (RFC 1951) decoder
+
+use super::{copy_match, Error, Input};
+use crate::common::crc32;
+
+pub const MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+const METHOD_DEFLATE: u8 = 8;
+
+const FLAG_HEADER_CRC: u8 = 1 << 1;
+const FLAG_EXTRA: u8 = 1 << 2;
+const FLAG_NAME: u8 = 1 << 3;
+const FLAG_COMMENT: u8 = 1 << 4;
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u32; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u32; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+
+// Order in which the code length code lengths are stored
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+// The bits of DEFLATE data are packed starting from the least significant bit
+// of each byte
+struct Bits<'a, 'b> {
+    input: &'a mut Input<'b>,
+    value: u64,
+    count: u32,
+}
+
+impl<'a, 'b> Bits<'a, 'b> {
+    fn new(input: &'a mut Input<'b>) -> Self {
+        Bits {
+            input,
+            value: 0,
+            count: 0,
+        }
+    }
+
+    fn refill(&mut self) -> Result<(), Error> {
+        while self.count <= 56 {
+            match self.input.next()? {
+                Some(byte) => {
+                    self.value |= u64::from(byte) << self.count;
+                    self.count += 8;
+                }
+                None => break,
+            }
+        }
+        Ok(())
+    }
+
+    fn consume(&mut self, n: u32) -> Result<(), Error> {
+        if n > self.count {
+            return Err(Error::Truncated);
+        }
+        self.value >>= n;
+        self.count -= n;
+        Ok(())
+    }
+
+    fn bits(&mut self, n: u32) -> Result<u32, Error> {
+        if self.count < n {
+            self.refill()?;
+        }
+        let value = (self.value & ((1 << n) - 1)) as u32;
+        self.consume(n)?;
+        Ok(value)
+    }
+
+    // Skip to the next byte boundary
+    fn align(&mut self) {
+        let n = self.count % 8;
+        self.value >>= n;
+        self.count -= n;
+    }
+
+    // Whole bytes, only valid when aligned
+    fn next_byte(&mut self) -> Result<Option<u8>, Error> {
+        if self.count >= 8 {
+            let byte = self.value as u8;
+            self.value >>= 8;
+            self.count -= 8;
+            return Ok(Some(byte));
+        }
+        self.input.next()
+    }
+
+    fn byte(&mut self) -> Result<u8, Error> {
+        self.next_byte()?.ok_or(Error::Truncated)
+    }
+
+    fn le(&mut self, size: usize) -> Result<u32, Error> {
+        let mut value = 0;
+        for i in 0..size {
+            value |= u32::from(self.byte()?) << (8 * i);
+        }
+        Ok(value)
+    }
+}
+
+// Codes of up to this many bits are decoded with a single table lookup
+const FAST_BITS: u32 = 9;
+const MAX_SYMBOLS: usize = 288;
+
+// Canonical Huffman code as stored by DEFLATE. Codes that are too long for the
+// lookup table are found from the first code of each length.
+struct Huffman {
+    // Code length in the top bits and symbol in the bottom 9, 0 if the code
+    // is longer
+    fast: [u16; 1 << FAST_BITS],
+    first_code: [u16; 16],
+    // One past the last code of each length, shifted to 16 bits
+    max_code: [u32; 17],
+    first_symbol: [u16; 16],
+    // Lengths and symbols in code order
+    size: [u8; MAX_SYMBOLS],
+    value: [u16; MAX_SYMBOLS],
+}
+
+fn reverse(code: u32, len: u32) -> u32 {
+    code.reverse_bits() >> (32 - len)
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Result<Self, Error> {
+        let mut huffman = Huffman {
+            fast: [0; 1 << FAST_BITS],
+            first_code: [0; 16],
+            max_code: [0; 17],
+            first_symbol: [0; 16],
+            size: [0; MAX_SYMBOLS],
+            value: [0; MAX_SYMBOLS],
+        };
+
+        let mut counts = [0u32; 16];
+        for &len in lengths {
+            counts[len as usize] += 1;
+        }
+        counts[0] = 0;
+
+        let mut next_code = [0u32; 16];
+        let mut code = 0;
+        let mut symbol = 0;
+        for len in 1..16 {
+            next_code[len] = code;
+            huffman.first_code[len] = code as u16;
+            huffman.first_symbol[len] = symbol as u16;
+            code += counts[len];
+            // Over-subscribed
+            if counts[len] != 0 && code > 1 << len {
+                return Err(Error::Corrupt);
+            }
+            huffman.max_code[len] = code << (16 - len);
+            code <<= 1;
+            symbol += counts[len];
+        }
+        huffman.max_code[16] = 1 << 16;
+
+        for (symbol, &len) in lengths.iter().enumerate() {
+            let len = len as usize;
+            if len == 0 {
+                continue;
+            }
+            let code = next_code[len];
+            let index = (code - huffman.first_code[len] as u32) as usize
+                + huffman.first_symbol[len] as usize;
+            huffman.size[index] = len as u8;
+            huffman.value[index] = symbol as u16;
+            if len as u32 <= FAST_BITS {
+                let mut i = reverse(code, len as u32) as usize;
+                while i < 1 << FAST_BITS {
+                    huffman.fast[i] = ((len << 9) | symbol) as u16;
+                    i += 1 << len;
+                }
+            }
+            next_code[len] += 1;
+        }
+        Ok(huffman)
+    }
+
+    fn decode(&self, bits: &mut Bits) -> Result<u16, Error> {
+        if bits.count < 16 {
+            bits.refill()?;
+        }
+        let fast = self.fast[(bits.value & ((1 << FAST_BITS) - 1)) as usize];
+        if fast != 0 {
+            bits.consume(u32::from(fast >> 9))?;
+            return Ok(fast & 0x1ff);
+        }
+
+        let code = reverse(bits.value as u32 & 0xffff, 16);
+        let mut len = FAST_BITS as usize + 1;
+        while code >= self.max_code[len] {
+            len += 1;
+        }
+        if len >= 16 {
+            return Err(Error::Corrupt);
+        }
+        let index = ((code >> (16 - len)) + self.first_symbol[len] as u32)
+            .checked_sub(self.first_code[len] as u32)
+            .ok_or(Error::Corrupt)? as usize;
+        if index >= MAX_SYMBOLS || self.size[index] as usize != len {
+            return Err(Error::Corrupt);
+        }
+        bits.consume(len as u32)?;
+        Ok(self.value[index])
+    }
+}
+
+fn fixed_codes() -> Result<(Huffman, Huffman), Error> {
+    let mut lengths = [0; MAX_SYMBOLS];
+    lengths[..144].fill(8);
+    lengths[144..256].fill(9);
+    lengths[256..280].fill(7);
+    lengths[280..].fill(8);
+    Ok((Huffman::new(&lengths)?, Huffman::new(&[5; 30])?))
+}
+
+fn dynamic_codes(bits: &mut Bits) -> Result<(Huffman, Huffman), Error> {
+    let literals = bits.bits(5)? as usize + 257;
+    let distances = bits.bits(5)? as usize + 1;
+    let code_lengths = bits.bits(4)? as usize + 4;
+    if literals > 286 || distances > 30 {
+        return Err(Error::Corrupt);
+    }
+
+    let mut lengths = [0; 19];
+    for &i in &CODE_LENGTH_ORDER[..code_lengths] {
+        lengths[i] = bits.bits(3)? as u8;
+    }
+    let code_lengths = Huffman::new(&lengths)?;
+
+    let mut lengths = [0; 286 + 30];
+    let total = literals + distances;
+    let mut i = 0;
+    while i < total {
+        let (len, repeat) = match code_lengths.decode(bits)? {
+            len @ 0..=15 => (len as u8, 1),
+            16 if i > 0 => (lengths[i - 1], 3 + bits.bits(2)? as usize),
+            17 => (0, 3 + bits.bits(3)? as usize),
+            18 => (0, 11 + bits.bits(7)? as usize),
+            _ => return Err(Error::Corrupt),
+        };
+        if i + repeat > total {
+            return Err(Error::Corrupt);
+        }
+        lengths[i..i + repeat].fill(len);
+        i += repeat;
+    }
+    // Without an end of block code the block never ends
+    if lengths[256] == 0 {
+        return Err(Error::Corrupt);
+    }
+
+    Ok((
+        Huffman::new(&lengths[..literals])?,
+        Huffman::new(&lengths[literals..total])?,
+    ))
+}
+
+fn stored_block(bits: &mut Bits, out: &mut [u8], len: usize) -> Result<usize, Error> {
+    bits.align();
+    let size = bits.le(2)?;
+    if size != !bits.le(2)? & 0xffff {
+        return Err(Error::Corrupt);
+    }
+    let end = len + size as usize;
+    if end > out.len() {
+        return Err(Error::OutputTooLarge);
+    }
+    for byte in &mut out[len..end] {
+        *byte = bits.byte()?;
+    }
+    Ok(end)
+}
+
+fn compressed_block(
+    bits: &mut Bits,
+    out: &mut [u8],
+    mut len: usize,
+    literals: &Huffman,
+    distances: &Huffman,
+) -> Result<usize, Error> {
+    loop {
+        let symbol = literals.decode(bits)? as usize;
+        if symbol < 256 {
+            if len >= out.len() {
+                return Err(Error::OutputTooLarge);
+            }
+            out[len] = symbol as u8;
+            len += 1;
+            continue;
+        }
+        if symbol == 256 {
+            return Ok(len);
+        }
+
+        let i = symbol - 257;
+        if i >= LENGTH_BASE.len() {
+            return Err(Error::Corrupt);
+        }
+        let length = LENGTH_BASE[i] as usize + bits.bits(LENGTH_EXTRA[i])? as usize;
+        let i = distances.decode(bits)? as usize;
+        if i >= DISTANCE_BASE.len() {
+            return Err(Error::Corrupt);
+        }
+        let distance = DISTANCE_BASE[i] as usize + bits.bits(DISTANCE_EXTRA[i])? as usize;
+        if distance > len {
+            return Err(Error::Corrupt);
+        }
+        if len + length > out.len() {
+            return Err(Error::OutputTooLarge);
+        }
+        copy_match(out, len, distance, length);
+        len += length;
+    }
+}
+
+fn inflate(bits: &mut Bits, out: &mut [u8], mut len: usize) -> Result<usize, Error> {
+    loop {
+        let last = bits.bits(1)? == 1;
+        len = match bits.bits(2)? {
+            0 => stored_block(bits, out, len)?,
+            1 => {
+                let (literals, distances) = fixed_codes()?;
+                compressed_block(bits, out, len, &literals, &distances)?
+            }
+            2 => {
+                let (literals, distances) = dynamic_codes(bits)?;
+                compressed_block(bits, out, len, &literals, &distances)?
+            }
+            _ => return Err(Error::Corrupt),
+        };
+        if last {
+            return Ok(len);
+        }
+    }
+}
+
+// A gzip member after its magic, appended to out at start
+fn member(bits: &mut Bits, out: &mut [u8], start: usize) -> Result<usize, Error> {
+    if bits.byte()? != METHOD_DEFLATE {
+        return Err(Error::Unsupported);
+    }
+    let flags = bits.byte()?;
+    // Modification time, extra flags and operating system
+    bits.le(4)?;
+    bits.le(2)?;
+    if flags & FLAG_EXTRA != 0 {
+        let size = bits.le(2)?;
+        for _ in 0..size {
+            bits.byte()?;
+        }
+    }
+    for flag in [FLAG_NAME, FLAG_COMMENT] {
+        if flags & flag != 0 {
+            while bits.byte()? != 0 {}
+        }
+    }
+    if flags & FLAG_HEADER_CRC != 0 {
+        bits.le(2)?;
+    }
+
+    let len = inflate(bits, out, start)?;
+
+    bits.align();
+    let crc = bits.le(4)?;
+    let size = bits.le(4)?;
+    if crc != crc32(&out[start..len]) || size != (len - start) as u32 {
+        return Err(Error::ChecksumMismatch);
+    }
+    Ok(len)
+}
+
+// Members are decompressed one after the other, anything after the last one
+// such as padding is ignored like gzip does
+pub fn decompress(input: &mut Input, out: &mut [u8]) -> Result<usize, Error> {
+    let mut bits = Bits::new(input);
+    let mut len = 0;
+    let mut first = true;
+    loop {
+        let magic = [bits.next_byte()?, bits.next_byte()?];
+        if magic != MAGIC.map(Some) {
+            return if first { Err(Error::Corrupt) } else { Ok(len) };
+        }
+        len = member(&mut bits, out, len)?;
+        first = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{decompress, tests::*, Error, Format};
+
+    // "hello hello hello hello\n" with fixed codes
+    const FIXED: [u8; 29] = [
+        0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x03, 0xcb, 0x48, 0xcd, 0xc9, 0xc9,
+        0x57, 0xc8, 0x40, 0x27, 0xb9, 0x00, 0x00, 0x88, 0x59, 0x0b, 0x18, 0x00, 0x00, 0x00,
+    ];
+    // "stored block\n" in a stored block
+    const STORED: [u8; 36] = [
+        0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x04, 0x03, 0x01, 0x0d, 0x00, 0xf2, 0xff,
+        0x73, 0x74, 0x6f, 0x72, 0x65, 0x64, 0x20, 0x62, 0x6c, 0x6f, 0x63, 0x6b, 0x0a, 0x6d, 0x75,
+        0x88, 0xc5, 0x0d, 0x00, 0x00, 0x00,
+    ];
+
+    fn gunzip(data: &[u8], size: usize) -> Result<Vec<u8>, Error> {
+        let mut out = vec![0; size];
+        let len = decompress(Format::Gzip, &mut Bytes::new(data), &mut out)?;
+        out.truncate(len);
+        Ok(out)
+    }
+
+    #[test]
+    fn test_gzip() {
+        assert_eq!(gunzip(&FIXED, 4096).unwrap(), b"hello hello hello hello\n");
+        assert_eq!(gunzip(&STORED, 4096).unwrap(), b"stored block\n");
+
+        // Members are concatenated and trailing padding is ignored
+        let mut data = FIXED.to_vec();
+        data.extend_from_slice(&STORED);
+        data.extend_from_slice(&[0; 100]);
+        assert_eq!(
+            gunzip(&data, 4096).unwrap(),
+            b"hello hello hello hello\nstored block\n"
+        );
+
+        assert!(matches!(gunzip(&FIXED, 23), Err(Error::OutputTooLarge)));
+        assert!(matches!(gunzip(&FIXED[..20], 4096), Err(Error::Truncated)));
+        let mut data = FIXED;
+        data[23] ^= 1;
+        assert!(matches!(gunzip(&data, 4096), Err(Error::ChecksumMismatch)));
+        let mut data = STORED;
+        data[13] ^= 1;
+        assert!(matches!(gunzip(&data, 4096), Err(Error::Corrupt)));
+
+        let expected = test_file("/large");
+        let data = test_file("/large.gz");
+        assert_eq!(gunzip(&data, expected.len()).unwrap(), expected);
+    }
+}
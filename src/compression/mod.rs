@@ -0,0 +1,223 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2026 The Rust Hypervisor Firmware Authors
+
+// Decompression of gzip and zstd compressed files straight into memory. The
+// output buffer doubles as the history window, so neither format needs memory
+// of its own beyond what a single block takes.
+
+use crate::{
+    block::SectorBuf,
+    fs::{self, Read},
+};
+
+mod gzip;
+mod zstd;
+
+#[derive(Debug)]
+pub enum Error {
+    #[allow(dead_code)]
+    File(fs::Error),
+    Truncated,
+    Corrupt,
+    Unsupported,
+    OutputTooLarge,
+    ChecksumMismatch,
+}
+
+impl From<fs::Error> for Error {
+    fn from(e: fs::Error) -> Error {
+        Error::File(e)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Gzip,
+    Zstd,
+}
+
+impl Format {
+    /// Recognises a compressed file from its first bytes
+    pub fn detect(data: &[u8]) -> Option<Self> {
+        if data.starts_with(&gzip::MAGIC) {
+            Some(Format::Gzip)
+        } else if data.starts_with(&zstd::MAGIC.to_le_bytes()) {
+            Some(Format::Zstd)
+        } else {
+            None
+        }
+    }
+}
+
+/// Decompresses the whole of f into out, returning the decompressed size
+pub fn decompress(format: Format, f: &mut dyn Read, out: &mut [u8]) -> Result<usize, Error> {
+    f.seek(0)?;
+    let mut input = Input::new(f);
+    match format {
+        Format::Gzip => gzip::decompress(&mut input, out),
+        Format::Zstd => zstd::decompress(&mut input, out),
+    }
+}
+
+// Byte stream over a file that is read a sector at a time
+struct Input<'a> {
+    file: &'a mut dyn Read,
+    data: SectorBuf,
+    position: usize,
+    len: usize,
+}
+
+impl<'a> Input<'a> {
+    fn new(file: &'a mut dyn Read) -> Self {
+        Input {
+            file,
+            data: SectorBuf::new(),
+            position: 0,
+            len: 0,
+        }
+    }
+
+    // Make sure there is something to read, false at the end of the file
+    fn fill(&mut self) -> Result<bool, Error> {
+        if self.position < self.len {
+            return Ok(true);
+        }
+        match self.file.read(self.data.as_mut_bytes()) {
+            Ok(len) => {
+                self.position = 0;
+                self.len = len as usize;
+                Ok(self.len > 0)
+            }
+            Err(fs::Error::EndOfFile) => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn is_empty(&mut self) -> Result<bool, Error> {
+        Ok(!self.fill()?)
+    }
+
+    fn next(&mut self) -> Result<Option<u8>, Error> {
+        if !self.fill()? {
+            return Ok(None);
+        }
+        let byte = self.data.as_bytes()[self.position];
+        self.position += 1;
+        Ok(Some(byte))
+    }
+
+    fn byte(&mut self) -> Result<u8, Error> {
+        self.next()?.ok_or(Error::Truncated)
+    }
+
+    fn read_exact(&mut self, out: &mut [u8]) -> Result<(), Error> {
+        let mut done = 0;
+        while done < out.len() {
+            if !self.fill()? {
+                return Err(Error::Truncated);
+            }
+            let n = usize::min(out.len() - done, self.len - self.position);
+            out[done..done + n].copy_from_slice(&self.data.as_bytes()[self.position..][..n]);
+            self.position += n;
+            done += n;
+        }
+        Ok(())
+    }
+
+    fn skip(&mut self, mut n: usize) -> Result<(), Error> {
+        while n > 0 {
+            if !self.fill()? {
+                return Err(Error::Truncated);
+            }
+            let skipped = usize::min(n, self.len - self.position);
+            self.position += skipped;
+            n -= skipped;
+        }
+        Ok(())
+    }
+
+    // Little endian integer of the given size
+    fn le(&mut self, size: usize) -> Result<u64, Error> {
+        let mut value = 0;
+        for i in 0..size {
+            value |= u64::from(self.byte()?) << (8 * i);
+        }
+        Ok(value)
+    }
+}
+
+// Copy length bytes from distance bytes before position to position, the
+// source may overlap what is being written to repeat a shorter pattern
+fn copy_match(out: &mut [u8], position: usize, distance: usize, length: usize) {
+    let source = position - distance;
+    if distance >= length {
+        out.copy_within(source..source + length, position);
+    } else {
+        for i in 0..length {
+            out[position + i] = out[source + i];
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use crate::block::SectorBuf;
+    use crate::fs::{Error, Read};
+
+    // A file in memory
+    pub struct Bytes<'a> {
+        data: &'a [u8],
+        position: usize,
+    }
+
+    impl<'a> Bytes<'a> {
+        pub fn new(data: &'a [u8]) -> Self {
+            Bytes { data, position: 0 }
+        }
+    }
+
+    impl Read for Bytes<'_> {
+        fn read(&mut self, data: &mut [u8]) -> Result<u32, Error> {
+            assert_eq!(data.len(), SectorBuf::len());
+            let rest = &self.data[self.position.min(self.data.len())..];
+            if rest.is_empty() {
+                return Err(Error::EndOfFile);
+            }
+            let n = rest.len().min(data.len());
+            data[..n].copy_from_slice(&rest[..n]);
+            self.position += data.len();
+            Ok(n as u32)
+        }
+
+        fn seek(&mut self, position: u32) -> Result<(), Error> {
+            self.position = position as usize;
+            Ok(())
+        }
+
+        fn get_size(&self) -> u32 {
+            self.data.len() as u32
+        }
+    }
+
+    // Read a file from the ext4 test image
+    pub fn test_file(path: &str) -> Vec<u8> {
+        let fs = crate::part::tests::ext4_filesystem();
+        let mut f = crate::fs::Filesystem::open(&fs, path).unwrap();
+        let mut data = vec![0; f.get_size() as usize];
+        let mut region = crate::mem::MemoryRegion::new(data.as_mut_ptr() as u64, data.len() as u64);
+        f.load_file(&mut region).unwrap();
+        data
+    }
+
+    #[test]
+    fn test_detect() {
+        use super::Format;
+        assert_eq!(Format::detect(&[0x1f, 0x8b, 0x08, 0]), Some(Format::Gzip));
+        assert_eq!(
+            Format::detect(&[0x28, 0xb5, 0x2f, 0xfd]),
+            Some(Format::Zstd)
+        );
+        assert_eq!(Format::detect(b"MZ\0\0"), None);
+        assert_eq!(Format::detect(&[0x1f]), None);
+    }
+}
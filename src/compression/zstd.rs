@@ -0,0 +1,904 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2026 The Rust Hypervisor Firmware Authors
+
+// Zstandard (RFC 8878) decoder without dictionary support
+
+use atomic_refcell::AtomicRefCell;
+
+use super::{copy_match, Error, Input};
+
+pub const MAGIC: u32 = 0xfd2f_b528;
+// The low four bits are free for the user
+const SKIPPABLE_MAGIC: u32 = 0x184d_2a50;
+
+const MAX_BLOCK_SIZE: usize = 128 << 10;
+
+// Compressed blocks are decoded from memory as their bit streams are read
+// backwards
+static BLOCK: AtomicRefCell<[u8; MAX_BLOCK_SIZE]> = AtomicRefCell::new([0; MAX_BLOCK_SIZE]);
+
+const LITERAL_LENGTH_BASE: [u32; 36] = [
+    0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 18, 20, 22, 24, 28, 32, 40, 48, 64,
+    128, 256, 512, 1024, 2048, 4096, 8192, 16384, 32768, 65536,
+];
+const LITERAL_LENGTH_BITS: [u32; 36] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 3, 3, 4, 6, 7, 8, 9, 10, 11,
+    12, 13, 14, 15, 16,
+];
+const LITERAL_LENGTH_DEFAULT: [i16; 36] = [
+    4, 3, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 1, 1, 1, 2, 2, 2, 2, 2, 2, 2, 2, 2, 3, 2, 1, 1, 1, 1, 1,
+    -1, -1, -1, -1,
+];
+
+const MATCH_LENGTH_BASE: [u32; 53] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27,
+    28, 29, 30, 31, 32, 33, 34, 35, 37, 39, 41, 43, 47, 51, 59, 67, 83, 99, 131, 259, 515, 1027,
+    2051, 4099, 8195, 16387, 32771, 65539,
+];
+const MATCH_LENGTH_BITS: [u32; 53] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    1, 1, 1, 1, 2, 2, 3, 3, 4, 4, 5, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16,
+];
+const MATCH_LENGTH_DEFAULT: [i16; 53] = [
+    1, 4, 3, 2, 2, 2, 2, 2, 2, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, -1, -1, -1, -1, -1, -1, -1,
+];
+
+const MAX_OFFSET_CODE: usize = 31;
+const OFFSET_DEFAULT: [i16; 29] = [
+    1, 1, 1, 1, 1, 1, 2, 2, 2, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, -1, -1, -1, -1, -1,
+];
+
+fn read_le(data: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0; 8];
+    if let Some(data) = data.get(offset..) {
+        let n = data.len().min(8);
+        bytes[..n].copy_from_slice(&data[..n]);
+    }
+    u64::from_le_bytes(bytes)
+}
+
+// Bit stream read from the start, least significant bits first
+struct ForwardBits<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> ForwardBits<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        ForwardBits { data, position: 0 }
+    }
+
+    // Reading past the end gives zeros, bytes() catches it once done
+    fn read(&mut self, n: u32) -> u32 {
+        let value = read_le(self.data, self.position / 8) >> (self.position % 8);
+        self.position += n as usize;
+        (value & ((1 << n) - 1)) as u32
+    }
+
+    fn rewind(&mut self, n: usize) {
+        self.position -= n;
+    }
+
+    // Bytes used, rounded up to whole bytes
+    fn bytes(&self) -> Result<usize, Error> {
+        let bytes = self.position.div_ceil(8);
+        if bytes > self.data.len() {
+            return Err(Error::Corrupt);
+        }
+        Ok(bytes)
+    }
+}
+
+// Bit stream read from the end, starting below the highest set bit of the last
+// byte. Reading past the start gives zeros.
+struct BackwardBits<'a> {
+    data: &'a [u8],
+    // Bits left to read
+    position: isize,
+}
+
+impl<'a> BackwardBits<'a> {
+    fn new(data: &'a [u8]) -> Result<Self, Error> {
+        let last = *data.last().ok_or(Error::Corrupt)?;
+        if last == 0 {
+            return Err(Error::Corrupt);
+        }
+        let position = (data.len() * 8 - 1 - last.leading_zeros() as usize) as isize;
+        Ok(BackwardBits { data, position })
+    }
+
+    fn read(&mut self, n: u32) -> u64 {
+        if n == 0 {
+            return 0;
+        }
+        self.position -= n as isize;
+        let (start, n, shift) = if self.position >= 0 {
+            (self.position as usize, n, 0)
+        } else if self.position > -(n as isize) {
+            (
+                0,
+                (n as isize + self.position) as u32,
+                (-self.position) as u32,
+            )
+        } else {
+            return 0;
+        };
+        let value = read_le(self.data, start / 8) >> (start % 8);
+        (value & ((1 << n) - 1)) << shift
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+struct FseEntry {
+    symbol: u8,
+    bits: u8,
+    base: u16,
+}
+
+// Finite State Entropy decoding table
+#[derive(Clone, Copy)]
+struct Fse {
+    log: u32,
+    entries: [FseEntry; 1 << 9],
+}
+
+impl Fse {
+    fn new(counts: &[i16], log: u32) -> Result<Self, Error> {
+        let mut fse = Fse {
+            log,
+            entries: [FseEntry::default(); 1 << 9],
+        };
+        let size = 1 << log;
+        let mut next = [0u16; 256];
+
+        // Symbols with a "less than 1" probability take one state each from
+        // the end of the table
+        let mut high = size;
+        for (symbol, &count) in counts.iter().enumerate() {
+            if count == -1 {
+                high -= 1;
+                fse.entries[high].symbol = symbol as u8;
+                next[symbol] = 1;
+            }
+        }
+
+        // The others are spread over the remaining states
+        let step = (size >> 1) + (size >> 3) + 3;
+        let mut position = 0;
+        for (symbol, &count) in counts.iter().enumerate() {
+            if count <= 0 {
+                continue;
+            }
+            next[symbol] = count as u16;
+            for _ in 0..count {
+                fse.entries[position].symbol = symbol as u8;
+                loop {
+                    position = (position + step) & (size - 1);
+                    if position < high {
+                        break;
+                    }
+                }
+            }
+        }
+        if position != 0 {
+            return Err(Error::Corrupt);
+        }
+
+        for entry in &mut fse.entries[..size] {
+            let state = next[entry.symbol as usize];
+            next[entry.symbol as usize] += 1;
+            let bits = log - (15 - state.leading_zeros());
+            entry.bits = bits as u8;
+            entry.base = ((u32::from(state) << bits) - size as u32) as u16;
+        }
+        Ok(fse)
+    }
+
+    // Every state decodes the same symbol
+    fn rle(symbol: u8) -> Self {
+        let mut fse = Fse {
+            log: 0,
+            entries: [FseEntry::default(); 1 << 9],
+        };
+        fse.entries[0].symbol = symbol;
+        fse
+    }
+
+    // Table description of normalised symbol counts
+    fn read(bits: &mut ForwardBits, max_log: u32, max_symbol: usize) -> Result<Self, Error> {
+        let log = bits.read(4) + 5;
+        if log > max_log {
+            return Err(Error::Corrupt);
+        }
+
+        let mut counts = [0i16; 256];
+        let mut symbols = 0;
+        let mut remaining = 1i32 << log;
+        while remaining > 0 && symbols < counts.len() {
+            let n = 32 - (remaining + 1).leading_zeros();
+            let mut value = bits.read(n);
+            let low_mask = (1 << (n - 1)) - 1;
+            let threshold = (1 << n) - 1 - (remaining as u32 + 1);
+            if value & low_mask < threshold {
+                bits.rewind(1);
+                value &= low_mask;
+            } else if value > low_mask {
+                value -= threshold;
+            }
+            let count = value as i32 - 1;
+            remaining -= count.abs();
+            counts[symbols] = count as i16;
+            symbols += 1;
+
+            // Zero counts are followed by how many more zeros there are
+            if count == 0 {
+                loop {
+                    let repeat = bits.read(2);
+                    symbols = usize::min(symbols + repeat as usize, counts.len());
+                    if repeat != 3 {
+                        break;
+                    }
+                }
+            }
+        }
+        bits.position = bits.position.next_multiple_of(8);
+        if remaining != 0 || symbols > max_symbol + 1 {
+            return Err(Error::Corrupt);
+        }
+        Fse::new(&counts[..symbols], log)
+    }
+
+    fn entry(&self, state: usize) -> FseEntry {
+        self.entries[state]
+    }
+}
+
+// Table of one of the sequence symbols for a block
+struct SequenceTable {
+    default: &'static [i16],
+    default_log: u32,
+    max_log: u32,
+    max_symbol: usize,
+}
+
+const LITERAL_LENGTHS: SequenceTable = SequenceTable {
+    default: &LITERAL_LENGTH_DEFAULT,
+    default_log: 6,
+    max_log: 9,
+    max_symbol: LITERAL_LENGTH_BASE.len() - 1,
+};
+const OFFSETS: SequenceTable = SequenceTable {
+    default: &OFFSET_DEFAULT,
+    default_log: 5,
+    max_log: 8,
+    max_symbol: MAX_OFFSET_CODE,
+};
+const MATCH_LENGTHS: SequenceTable = SequenceTable {
+    default: &MATCH_LENGTH_DEFAULT,
+    default_log: 6,
+    max_log: 9,
+    max_symbol: MATCH_LENGTH_BASE.len() - 1,
+};
+
+impl SequenceTable {
+    fn read(&self, mode: u8, bits: &mut ForwardBits, previous: Option<Fse>) -> Result<Fse, Error> {
+        match mode {
+            0 => Fse::new(self.default, self.default_log),
+            1 => match bits.read(8) as usize {
+                symbol if symbol <= self.max_symbol => Ok(Fse::rle(symbol as u8)),
+                _ => Err(Error::Corrupt),
+            },
+            2 => Fse::read(bits, self.max_log, self.max_symbol),
+            // Repeat the table of the previous block
+            _ => previous.ok_or(Error::Corrupt),
+        }
+    }
+}
+
+const HUFFMAN_MAX_BITS: u32 = 11;
+
+// Literals are coded with a prefix code described by symbol weights
+#[derive(Clone, Copy)]
+struct Huffman {
+    max_bits: u32,
+    symbols: [u8; 1 << HUFFMAN_MAX_BITS],
+    bits: [u8; 1 << HUFFMAN_MAX_BITS],
+}
+
+impl Huffman {
+    // The weights of all but the last symbol, whose weight makes the total a
+    // power of two
+    fn from_weights(weights: &mut [u8; 256], count: usize) -> Result<Self, Error> {
+        if count >= weights.len() {
+            return Err(Error::Corrupt);
+        }
+        let mut total = 0u32;
+        for &weight in &weights[..count] {
+            if weight > HUFFMAN_MAX_BITS as u8 {
+                return Err(Error::Corrupt);
+            }
+            if weight > 0 {
+                total += 1 << (weight - 1);
+            }
+        }
+        if total == 0 {
+            return Err(Error::Corrupt);
+        }
+        let max_bits = 32 - total.leading_zeros();
+        let rest = (1 << max_bits) - total;
+        if max_bits > HUFFMAN_MAX_BITS || !rest.is_power_of_two() {
+            return Err(Error::Corrupt);
+        }
+        weights[count] = (rest.trailing_zeros() + 1) as u8;
+        let weights = &weights[..count + 1];
+
+        let mut huffman = Huffman {
+            max_bits,
+            symbols: [0; 1 << HUFFMAN_MAX_BITS],
+            bits: [0; 1 << HUFFMAN_MAX_BITS],
+        };
+        let bits = |weight: u8| max_bits + 1 - u32::from(weight);
+
+        let mut counts = [0usize; HUFFMAN_MAX_BITS as usize + 1];
+        for &weight in weights.iter().filter(|&&w| w > 0) {
+            counts[bits(weight) as usize] += 1;
+        }
+
+        // Longer codes come first, each taking a range of the table for all
+        // the values of the bits that follow it
+        let size = 1 << max_bits;
+        let mut next = [0usize; HUFFMAN_MAX_BITS as usize + 1];
+        let mut start = 0;
+        for len in (1..=max_bits as usize).rev() {
+            next[len] = start;
+            let end = start + (counts[len] << (max_bits as usize - len));
+            if end > size {
+                return Err(Error::Corrupt);
+            }
+            huffman.bits[start..end].fill(len as u8);
+            start = end;
+        }
+        if start != size {
+            return Err(Error::Corrupt);
+        }
+        for (symbol, &weight) in weights.iter().enumerate() {
+            if weight == 0 {
+                continue;
+            }
+            let len = bits(weight) as usize;
+            let range = next[len]..next[len] + (1 << (max_bits as usize - len));
+            huffman.symbols[range.clone()].fill(symbol as u8);
+            next[len] = range.end;
+        }
+        Ok(huffman)
+    }
+
+    // Tree description, returns the table and the bytes it took
+    fn read(data: &[u8]) -> Result<(Self, usize), Error> {
+        let header = *data.first().ok_or(Error::Corrupt)? as usize;
+        let mut weights = [0; 256];
+        if header >= 128 {
+            // Four bits per weight
+            let count = header - 127;
+            let size = count.div_ceil(2);
+            let bytes = data.get(1..1 + size).ok_or(Error::Corrupt)?;
+            for (i, weight) in weights[..count].iter_mut().enumerate() {
+                let byte = bytes[i / 2];
+                *weight = if i % 2 == 0 { byte >> 4 } else { byte & 0xf };
+            }
+            return Ok((Self::from_weights(&mut weights, count)?, 1 + size));
+        }
+
+        // FSE compressed weights, decoded with two interleaved states
+        let data = data.get(1..1 + header).ok_or(Error::Corrupt)?;
+        let mut forward = ForwardBits::new(data);
+        let fse = Fse::read(&mut forward, 6, 255)?;
+        let mut bits = BackwardBits::new(&data[forward.bytes()?..])?;
+        let mut states = [bits.read(fse.log) as usize, bits.read(fse.log) as usize];
+        let mut count = 0;
+        'decode: loop {
+            for i in 0..2 {
+                if count >= 255 {
+                    return Err(Error::Corrupt);
+                }
+                let entry = fse.entry(states[i]);
+                weights[count] = entry.symbol;
+                count += 1;
+                states[i] = entry.base as usize + bits.read(u32::from(entry.bits)) as usize;
+                // Once the stream is exhausted the other state holds the
+                // last weight
+                if bits.position < 0 {
+                    if count >= 255 {
+                        return Err(Error::Corrupt);
+                    }
+                    weights[count] = fse.entry(states[1 - i]).symbol;
+                    count += 1;
+                    break 'decode;
+                }
+            }
+        }
+        Ok((Self::from_weights(&mut weights, count)?, 1 + header))
+    }
+
+    fn decode(&self, data: &[u8], out: &mut [u8]) -> Result<(), Error> {
+        let mut bits = BackwardBits::new(data)?;
+        let mask = (1 << self.max_bits) - 1;
+        let mut state = bits.read(self.max_bits) as usize;
+        for byte in out {
+            *byte = self.symbols[state];
+            let n = u32::from(self.bits[state]);
+            state = ((state << n) | bits.read(n) as usize) & mask;
+        }
+        // The state always reads max_bits ahead
+        if bits.position != -(self.max_bits as isize) {
+            return Err(Error::Corrupt);
+        }
+        Ok(())
+    }
+
+    // Literals in one stream, or four that each hold a quarter of them
+    fn decode_streams(&self, data: &[u8], streams: usize, out: &mut [u8]) -> Result<(), Error> {
+        if streams == 1 {
+            return self.decode(data, out);
+        }
+        let mut sizes = [0; 4];
+        let mut total = 6;
+        for (i, size) in sizes[..3].iter_mut().enumerate() {
+            *size = read_le(data, i * 2) as u16 as usize;
+            total += *size;
+        }
+        sizes[3] = data.len().checked_sub(total).ok_or(Error::Corrupt)?;
+        let quarter = out.len().div_ceil(4);
+        if quarter * 3 > out.len() {
+            return Err(Error::Corrupt);
+        }
+        let mut data = &data[6..];
+        let mut out = out;
+        for size in sizes {
+            let (stream, rest) = data.split_at(size);
+            let (literals, remaining) = out.split_at_mut(quarter.min(out.len()));
+            self.decode(stream, literals)?;
+            data = rest;
+            out = remaining;
+        }
+        Ok(())
+    }
+}
+
+// Decoding state carried from one block of a frame to the next
+struct Frame {
+    huffman: Option<Huffman>,
+    literal_lengths: Option<Fse>,
+    offsets: Option<Fse>,
+    match_lengths: Option<Fse>,
+    repeat_offsets: [usize; 3],
+}
+
+impl Frame {
+    fn new() -> Self {
+        Frame {
+            huffman: None,
+            literal_lengths: None,
+            offsets: None,
+            match_lengths: None,
+            repeat_offsets: [1, 4, 8],
+        }
+    }
+
+    // Decode the literals to the end of the space the block may take in out,
+    // so that the sequences write their output before them and never catch
+    // up with the literals yet to be copied. Returns where the literals start
+    // and end and the size of the literals section.
+    fn literals(
+        &mut self,
+        data: &[u8],
+        out: &mut [u8],
+        start: usize,
+    ) -> Result<(usize, usize, usize), Error> {
+        let byte = |i: usize| data.get(i).copied().map(usize::from).ok_or(Error::Corrupt);
+        let first = byte(0)?;
+        let kind = first & 3;
+        let (header_size, size, compressed_size, streams) = match (kind, (first >> 2) & 3) {
+            (0 | 1, 0 | 2) => (1, first >> 3, 0, 0),
+            (0 | 1, 1) => (2, (first >> 4) + (byte(1)? << 4), 0, 0),
+            (0 | 1, _) => (3, (first >> 4) + (byte(1)? << 4) + (byte(2)? << 12), 0, 0),
+            (_, format) => {
+                let (header_size, bits, streams) = match format {
+                    0 => (3, 10, 1),
+                    1 => (3, 10, 4),
+                    2 => (4, 14, 4),
+                    _ => (5, 18, 4),
+                };
+                let mut header = 0;
+                for i in 0..header_size {
+                    header |= byte(i)? << (8 * i);
+                }
+                let mask = (1 << bits) - 1;
+                let size = (header >> 4) & mask;
+                let compressed_size = (header >> (4 + bits)) & mask;
+                (header_size, size, compressed_size, streams)
+            }
+        };
+        if size > MAX_BLOCK_SIZE {
+            return Err(Error::Corrupt);
+        }
+
+        let end = usize::min(out.len(), start + MAX_BLOCK_SIZE);
+        if end - start < size {
+            return Err(Error::OutputTooLarge);
+        }
+        let literals = &mut out[end - size..end];
+        let data = &data[header_size..];
+        let used = match kind {
+            0 => {
+                literals.copy_from_slice(data.get(..size).ok_or(Error::Corrupt)?);
+                size
+            }
+            1 => {
+                literals.fill(*data.first().ok_or(Error::Corrupt)?);
+                1
+            }
+            _ => {
+                let mut data = data.get(..compressed_size).ok_or(Error::Corrupt)?;
+                // Otherwise the table of the previous block is used again
+                if kind == 2 {
+                    let (huffman, used) = Huffman::read(data)?;
+                    self.huffman = Some(huffman);
+                    data = &data[used..];
+                }
+                let huffman = self.huffman.as_ref().ok_or(Error::Corrupt)?;
+                huffman.decode_streams(data, streams, literals)?;
+                compressed_size
+            }
+        };
+        Ok((end - size, end, header_size + used))
+    }
+
+    fn offset(&mut self, value: usize, literal_length: usize) -> usize {
+        if value > 3 {
+            let offset = value - 3;
+            self.repeat_offsets = [offset, self.repeat_offsets[0], self.repeat_offsets[1]];
+            return offset;
+        }
+        // Without literals the repeated offsets shift by one, with the
+        // most recent one less one byte in place of the third
+        let index = value - 1 + usize::from(literal_length == 0);
+        let [first, second, third] = self.repeat_offsets;
+        match index {
+            0 => first,
+            1 => {
+                self.repeat_offsets = [second, first, third];
+                second
+            }
+            2 => {
+                self.repeat_offsets = [third, first, second];
+                third
+            }
+            _ => {
+                let offset = first.wrapping_sub(1);
+                self.repeat_offsets = [offset, first, second];
+                offset
+            }
+        }
+    }
+
+    fn block(&mut self, data: &[u8], out: &mut [u8], start: usize) -> Result<usize, Error> {
+        let (mut literal, literals_end, used) = self.literals(data, out, start)?;
+        let data = &data[used..];
+        // Running out of room between output and literals means the block is
+        // larger than allowed or than out
+        let overrun = if literals_end == out.len() {
+            Error::OutputTooLarge
+        } else {
+            Error::Corrupt
+        };
+
+        let byte = |i: usize| data.get(i).copied().map(usize::from).ok_or(Error::Corrupt);
+        let (count, header_size) = match byte(0)? {
+            count @ 0..=127 => (count, 1),
+            high @ 128..=254 => (((high - 128) << 8) + byte(1)?, 2),
+            _ => (byte(1)? + (byte(2)? << 8) + 0x7f00, 3),
+        };
+
+        let mut len = start;
+        if count > 0 {
+            let modes = byte(header_size)? as u8;
+            if modes & 3 != 0 {
+                return Err(Error::Corrupt);
+            }
+            let data = &data[header_size + 1..];
+            let mut forward = ForwardBits::new(data);
+            let literal_lengths =
+                LITERAL_LENGTHS.read(modes >> 6, &mut forward, self.literal_lengths)?;
+            let offsets = OFFSETS.read((modes >> 4) & 3, &mut forward, self.offsets)?;
+            let match_lengths =
+                MATCH_LENGTHS.read((modes >> 2) & 3, &mut forward, self.match_lengths)?;
+            self.literal_lengths = Some(literal_lengths);
+            self.offsets = Some(offsets);
+            self.match_lengths = Some(match_lengths);
+
+            let mut bits = BackwardBits::new(&data[forward.bytes()?..])?;
+            let mut literal_length_state = bits.read(literal_lengths.log) as usize;
+            let mut offset_state = bits.read(offsets.log) as usize;
+            let mut match_length_state = bits.read(match_lengths.log) as usize;
+
+            for i in 0..count {
+                let literal_length_code = literal_lengths.entry(literal_length_state);
+                let offset_code = offsets.entry(offset_state);
+                let match_length_code = match_lengths.entry(match_length_state);
+
+                let code = offset_code.symbol as u32;
+                let offset_value = (1usize << code) + bits.read(code) as usize;
+                let code = match_length_code.symbol as usize;
+                let match_length =
+                    (MATCH_LENGTH_BASE[code] + bits.read(MATCH_LENGTH_BITS[code]) as u32) as usize;
+                let code = literal_length_code.symbol as usize;
+                let literal_length = (LITERAL_LENGTH_BASE[code]
+                    + bits.read(LITERAL_LENGTH_BITS[code]) as u32)
+                    as usize;
+
+                if i + 1 < count {
+                    for (state, code) in [
+                        (&mut literal_length_state, literal_length_code),
+                        (&mut match_length_state, match_length_code),
+                        (&mut offset_state, offset_code),
+                    ] {
+                        *state = code.base as usize + bits.read(u32::from(code.bits)) as usize;
+                    }
+                }
+
+                if literal + literal_length > literals_end {
+                    return Err(Error::Corrupt);
+                }
+                if len + match_length > literal {
+                    return Err(overrun);
+                }
+                out.copy_within(literal..literal + literal_length, len);
+                len += literal_length;
+                literal += literal_length;
+
+                let offset = self.offset(offset_value, literal_length);
+                if offset == 0 || offset > len {
+                    return Err(Error::Corrupt);
+                }
+                copy_match(out, len, offset, match_length);
+                len += match_length;
+            }
+            if bits.position != 0 {
+                return Err(Error::Corrupt);
+            }
+        }
+
+        out.copy_within(literal..literals_end, len);
+        Ok(len + literals_end - literal)
+    }
+}
+
+const PRIME1: u64 = 0x9e37_79b1_85eb_ca87;
+const PRIME2: u64 = 0xc2b2_ae3d_27d4_eb4f;
+const PRIME3: u64 = 0x1656_67b1_9e37_79f9;
+const PRIME4: u64 = 0x85eb_ca77_c2b2_ae63;
+const PRIME5: u64 = 0x27d4_eb2f_1656_67c5;
+
+fn xxh64_round(accumulator: u64, input: u64) -> u64 {
+    accumulator
+        .wrapping_add(input.wrapping_mul(PRIME2))
+        .rotate_left(31)
+        .wrapping_mul(PRIME1)
+}
+
+// XXH64 with a seed of 0, of which the frame checksum is the low 32 bits
+fn xxh64(data: &[u8]) -> u64 {
+    let mut stripes = data.chunks_exact(32);
+    let mut hash = if data.len() >= 32 {
+        let mut v = [
+            PRIME1.wrapping_add(PRIME2),
+            PRIME2,
+            0,
+            0u64.wrapping_sub(PRIME1),
+        ];
+        for stripe in &mut stripes {
+            for (i, v) in v.iter_mut().enumerate() {
+                *v = xxh64_round(*v, read_le(stripe, i * 8));
+            }
+        }
+        let mut hash = v[0]
+            .rotate_left(1)
+            .wrapping_add(v[1].rotate_left(7))
+            .wrapping_add(v[2].rotate_left(12))
+            .wrapping_add(v[3].rotate_left(18));
+        for v in v {
+            hash = (hash ^ xxh64_round(0, v))
+                .wrapping_mul(PRIME1)
+                .wrapping_add(PRIME4);
+        }
+        hash
+    } else {
+        PRIME5
+    };
+    hash = hash.wrapping_add(data.len() as u64);
+
+    let mut words = stripes.remainder().chunks_exact(8);
+    for word in &mut words {
+        hash ^= xxh64_round(0, read_le(word, 0));
+        hash = hash
+            .rotate_left(27)
+            .wrapping_mul(PRIME1)
+            .wrapping_add(PRIME4);
+    }
+    let mut words = words.remainder().chunks_exact(4);
+    for word in &mut words {
+        hash ^= (read_le(word, 0) as u32 as u64).wrapping_mul(PRIME1);
+        hash = hash
+            .rotate_left(23)
+            .wrapping_mul(PRIME2)
+            .wrapping_add(PRIME3);
+    }
+    for &byte in words.remainder() {
+        hash ^= u64::from(byte).wrapping_mul(PRIME5);
+        hash = hash.rotate_left(11).wrapping_mul(PRIME1);
+    }
+
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(PRIME2);
+    hash ^= hash >> 29;
+    hash = hash.wrapping_mul(PRIME3);
+    hash ^ (hash >> 32)
+}
+
+// A frame after its magic, appended to out at start
+fn frame(
+    input: &mut Input,
+    out: &mut [u8],
+    start: usize,
+    block: &mut [u8; MAX_BLOCK_SIZE],
+) -> Result<usize, Error> {
+    let descriptor = input.byte()?;
+    let single_segment = descriptor & (1 << 5) != 0;
+    let has_checksum = descriptor & (1 << 2) != 0;
+    if descriptor & (1 << 3) != 0 {
+        return Err(Error::Corrupt);
+    }
+    // The window is all of out anyway
+    if !single_segment {
+        input.byte()?;
+    }
+    let dictionary = input.le([0, 1, 2, 4][(descriptor & 3) as usize])?;
+    if dictionary != 0 {
+        return Err(Error::Unsupported);
+    }
+    let content_size = match descriptor >> 6 {
+        0 if !single_segment => None,
+        0 => Some(input.le(1)?),
+        1 => Some(input.le(2)? + 256),
+        2 => Some(input.le(4)?),
+        _ => Some(input.le(8)?),
+    };
+    if content_size.is_some_and(|size| size > (out.len() - start) as u64) {
+        return Err(Error::OutputTooLarge);
+    }
+
+    let mut frame = Frame::new();
+    let mut len = start;
+    loop {
+        let header = input.le(3)? as usize;
+        let size = header >> 3;
+        if size > MAX_BLOCK_SIZE {
+            return Err(Error::Corrupt);
+        }
+        let end = len + size;
+        match (header >> 1) & 3 {
+            // Raw
+            0 => {
+                input.read_exact(out.get_mut(len..end).ok_or(Error::OutputTooLarge)?)?;
+                len = end;
+            }
+            // One byte repeated
+            1 => {
+                let byte = input.byte()?;
+                out.get_mut(len..end)
+                    .ok_or(Error::OutputTooLarge)?
+                    .fill(byte);
+                len = end;
+            }
+            2 => {
+                input.read_exact(&mut block[..size])?;
+                len = frame.block(&block[..size], out, len)?;
+            }
+            _ => return Err(Error::Corrupt),
+        }
+        if header & 1 != 0 {
+            break;
+        }
+    }
+
+    if content_size.is_some_and(|size| size != (len - start) as u64) {
+        return Err(Error::Corrupt);
+    }
+    if has_checksum && input.le(4)? != xxh64(&out[start..len]) & 0xffff_ffff {
+        return Err(Error::ChecksumMismatch);
+    }
+    Ok(len)
+}
+
+pub fn decompress(input: &mut Input, out: &mut [u8]) -> Result<usize, Error> {
+    let mut block = BLOCK.borrow_mut();
+    let mut len = 0;
+    while !input.is_empty()? {
+        let magic = input.le(4)? as u32;
+        if magic & !0xf == SKIPPABLE_MAGIC {
+            let size = input.le(4)?;
+            input.skip(size as usize)?;
+        } else if magic == MAGIC {
+            len = frame(input, out, len, &mut block)?;
+        } else {
+            return Err(Error::Corrupt);
+        }
+    }
+    Ok(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{decompress, tests::*, Error, Format};
+
+    // "zstd zstd zstd zstd zstd\n"
+    const SEQUENCES: [u8; 25] = [
+        0x28, 0xb5, 0x2f, 0xfd, 0x24, 0x19, 0x65, 0x00, 0x00, 0x30, 0x7a, 0x73, 0x74, 0x64, 0x20,
+        0x0a, 0x01, 0x00, 0x48, 0x8a, 0x16, 0x3f, 0xdd, 0x3b, 0xa8,
+    ];
+    // 3000 times 'a'
+    const REPEAT: [u8; 23] = [
+        0x28, 0xb5, 0x2f, 0xfd, 0x64, 0xb8, 0x0a, 0x4d, 0x00, 0x00, 0x10, 0x61, 0x61, 0x01, 0x00,
+        0xb3, 0xf3, 0x01, 0x16, 0xe1, 0x0e, 0x09, 0x96,
+    ];
+
+    fn unzstd(data: &[u8], size: usize) -> Result<Vec<u8>, Error> {
+        let mut out = vec![0; size];
+        let len = decompress(Format::Zstd, &mut Bytes::new(data), &mut out)?;
+        out.truncate(len);
+        Ok(out)
+    }
+
+    #[test]
+    fn test_xxh64() {
+        assert_eq!(super::xxh64(b""), 0xef46_db37_51d8_e999);
+        assert_eq!(super::xxh64(b"a"), 0xd24e_c4f1_a98c_6e5b);
+        assert_eq!(super::xxh64(b"abc"), 0x44bc_2cf5_ad77_0999);
+    }
+
+    // All in one test as the decoder has a single block buffer
+    #[test]
+    fn test_zstd() {
+        assert_eq!(
+            unzstd(&SEQUENCES, 4096).unwrap(),
+            b"zstd zstd zstd zstd zstd\n"
+        );
+        assert_eq!(unzstd(&REPEAT, 4096).unwrap(), [b'a'; 3000]);
+
+        // Frames are concatenated and skippable frames skipped
+        let mut data = SEQUENCES.to_vec();
+        data.extend_from_slice(&[0x5e, 0x2a, 0x4d, 0x18, 3, 0, 0, 0, 1, 2, 3]);
+        data.extend_from_slice(&SEQUENCES);
+        assert_eq!(
+            unzstd(&data, 4096).unwrap(),
+            b"zstd zstd zstd zstd zstd\nzstd zstd zstd zstd zstd\n"
+        );
+        data.push(0);
+        assert!(matches!(unzstd(&data, 4096), Err(Error::Truncated)));
+
+        assert!(matches!(unzstd(&REPEAT, 2999), Err(Error::OutputTooLarge)));
+        assert!(matches!(
+            unzstd(&SEQUENCES[..20], 4096),
+            Err(Error::Truncated)
+        ));
+        let mut data = SEQUENCES;
+        data[24] ^= 1;
+        assert!(matches!(unzstd(&data, 4096), Err(Error::ChecksumMismatch)));
+
+        let expected = test_file("/large");
+        let data = test_file("/large.zst");
+        assert_eq!(unzstd(&data, expected.len()).unwrap(), expected);
+    }
+}
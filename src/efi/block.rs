@@ -3,6 +3,7 @@
 
 use core::ffi::c_void;
 
+use log::{error, warn};
 use r_efi::{
     efi::{self, Status},
     protocols::{
@@ -12,8 +13,8 @@ use r_efi::{
 };
 
 use crate::{
-    block::{SectorBuf, VirtioBlockDevice},
-    part::{get_partitions, PartitionEntry},
+    block::{BlockDevice, SectorBuf},
+    part::{partitions, PartitionEntry},
 };
 
 #[allow(dead_code)]
@@ -26,19 +27,72 @@ pub struct ControllerDevicePathProtocol {
 #[repr(C)]
 pub struct BlockWrapper<'a> {
     hw: super::HandleWrapper,
-    block: &'a VirtioBlockDevice<'a>,
+    block: &'a dyn BlockDevice,
     media: Media,
     pub proto: BlockIoProtocol,
     // The ordering of these paths are very important, along with the C
     // representation as the device path "flows" from the first.
     pub controller_path: ControllerDevicePathProtocol,
     pub disk_paths: [HardDriveMedia; 2],
-    start_lba: u64,
+    start_sector: u64,
 }
 
 pub struct BlockWrappers<'a> {
-    pub wrappers: [*mut BlockWrapper<'a>; 16],
-    pub count: usize,
+    wrappers: *mut *mut BlockWrapper<'a>,
+    count: usize,
+    capacity: usize,
+}
+
+impl<'a> BlockWrappers<'a> {
+    pub const fn new() -> Self {
+        BlockWrappers {
+            wrappers: core::ptr::null_mut(),
+            count: 0,
+            capacity: 0,
+        }
+    }
+
+    pub fn handles(&self) -> &[*mut BlockWrapper<'a>] {
+        if self.wrappers.is_null() {
+            return &[];
+        }
+        unsafe { core::slice::from_raw_parts(self.wrappers, self.count) }
+    }
+
+    // Free the handles of a previously wrapped disk
+    fn clear(&mut self) {
+        let mut allocator = super::ALLOCATOR.borrow_mut();
+        for wrapper in self.handles() {
+            allocator.free_pool(*wrapper as u64);
+        }
+        if !self.wrappers.is_null() {
+            allocator.free_pool(self.wrappers as u64);
+        }
+        *self = Self::new();
+    }
+
+    // Add a handle, moving them all to a larger allocation when full
+    fn push(&mut self, wrapper: *mut BlockWrapper<'a>) {
+        if self.count == self.capacity {
+            // Allocations are in pages so start with a page worth
+            let capacity = usize::max(512, self.capacity * 2);
+            let mut allocator = super::ALLOCATOR.borrow_mut();
+            let (status, new_address) = allocator.allocate_pool(
+                efi::LOADER_DATA,
+                capacity * core::mem::size_of::<*mut BlockWrapper>(),
+            );
+            assert!(status == Status::SUCCESS);
+            let wrappers = new_address as *mut *mut BlockWrapper<'a>;
+            if !self.wrappers.is_null() {
+                unsafe { core::ptr::copy_nonoverlapping(self.wrappers, wrappers, self.count) };
+                allocator.free_pool(self.wrappers as u64);
+            }
+            self.wrappers = wrappers;
+            self.capacity = capacity;
+        }
+        unsafe { *self.wrappers.add(self.count) = wrapper };
+        self.count += 1;
+    }
 }
 
 pub extern "efiapi" fn reset(_: *mut BlockIoProtocol, _: efi::Boolean) -> Status {
@@ -56,22 +110,20 @@ pub extern "efiapi" fn read_blocks(
     let wrapper = unsafe { &*wrapper };
 
     let block_size = wrapper.media.block_size as usize;
-    let blocks = size / block_size;
+    if size % block_size != 0 {
+        return Status::BAD_BUFFER_SIZE;
+    }
+    if size == 0 {
+        return Status::SUCCESS;
+    }
     let mut region = crate::mem::MemoryRegion::new(buffer as u64, size as u64);
 
-    for i in 0..blocks {
-        use crate::block::SectorRead;
-        let data = region.as_mut_slice((i * block_size) as u64, block_size as u64);
-        let block = wrapper.block;
-        match block.read(wrapper.start_lba + start + i as u64, data) {
-            Ok(()) => continue,
-            Err(_) => {
-                return Status::DEVICE_ERROR;
-            }
-        };
+    let block = wrapper.block;
+    let sector = wrapper.start_sector + start * (block_size / SectorBuf::len()) as u64;
+    match block.read_sectors(sector, region.as_bytes()) {
+        Ok(()) => Status::SUCCESS,
+        Err(_) => Status::DEVICE_ERROR,
     }
-
-    Status::SUCCESS
 }
 
 pub extern "efiapi" fn write_blocks(
@@ -85,14 +137,17 @@ pub extern "efiapi" fn write_blocks(
     let wrapper = unsafe { &*wrapper };
 
     let block_size = wrapper.media.block_size as usize;
-    let blocks = size / block_size;
+    if size % block_size != 0 {
+        return Status::BAD_BUFFER_SIZE;
+    }
+    let sectors = size / SectorBuf::len();
     let mut region = crate::mem::MemoryRegion::new(buffer as u64, size as u64);
 
-    for i in 0..blocks {
-        use crate::block::SectorWrite;
-        let data = region.as_mut_slice((i * block_size) as u64, block_size as u64);
+    let sector = wrapper.start_sector + start * (block_size / SectorBuf::len()) as u64;
+    for i in 0..sectors {
+        let data = region.as_mut_slice((i * SectorBuf::len()) as u64, SectorBuf::len() as u64);
         let block = wrapper.block;
-        match block.write(wrapper.start_lba + start + i as u64, data) {
+        match block.write(sector + i as u64, data) {
             Ok(()) => continue,
             Err(_) => {
                 return Status::DEVICE_ERROR;
@@ -106,7 +161,6 @@ pub extern "efiapi" fn write_blocks(
 pub extern "efiapi" fn flush_blocks(proto: *mut BlockIoProtocol) -> Status {
     let wrapper = container_of!(proto, BlockWrapper, proto);
     let wrapper = unsafe { &*wrapper };
-    use crate::block::SectorWrite;
     let block = wrapper.block;
     match block.flush() {
         Ok(()) => Status::SUCCESS,
@@ -116,13 +170,15 @@ pub extern "efiapi" fn flush_blocks(proto: *mut BlockIoProtocol) -> Status {
 
 impl<'a> BlockWrapper<'a> {
     pub fn new(
-        block: &'a VirtioBlockDevice<'a>,
-        partition_number: u32,
-        start_lba: u64,
-        last_lba: u64,
-        uuid: [u8; 16],
+        block: &'a dyn BlockDevice,
+        partition: Option<PartitionEntry>,
     ) -> *mut BlockWrapper<'a> {
-        let last_block = (*block).get_capacity() - 1;
+        let start_sector = partition.map_or(0, |p| p.first_lba);
+
+        // The media is presented in logical blocks of the device
+        let block_size = block.block_size();
+        let sectors_per_block = (block_size / SectorBuf::len()) as u64;
+        let last_block = (*block).get_capacity() / sectors_per_block - 1;
 
         let (status, new_address) = super::ALLOCATOR
             .borrow_mut()
@@ -144,7 +200,7 @@ impl<'a> BlockWrapper<'a> {
                     logical_partition: false,
                     read_only: true,
                     write_caching: false,
-                    block_size: SectorBuf::len() as u32,
+                    block_size: block_size as u32,
                     io_align: 0,
                     last_block,
                     lowest_aligned_lba: 0,
@@ -159,7 +215,7 @@ impl<'a> BlockWrapper<'a> {
                     write_blocks,
                     flush_blocks,
                 },
-                start_lba,
+                start_sector,
                 controller_path: ControllerDevicePathProtocol {
                     device_path: DevicePathProtocol {
                         r#type: 1,
@@ -169,8 +225,8 @@ impl<'a> BlockWrapper<'a> {
                     controller: 0,
                 },
                 // full disk vs partition
-                disk_paths: if partition_number == 0 {
-                    [
+                disk_paths: match partition {
+                    None => [
                         HardDriveMedia {
                             header: DevicePathProtocol {
                                 r#type: r_efi::protocols::device_path::TYPE_END,
@@ -197,21 +253,21 @@ impl<'a> BlockWrapper<'a> {
                             partition_signature: [0; 16],
                             signature_type: 0,
                         },
-                    ]
-                } else {
-                    [
+                    ],
+                    Some(p) => [
                         HardDriveMedia {
                             header: DevicePathProtocol {
                                 r#type: r_efi::protocols::device_path::TYPE_MEDIA,
                                 sub_type: 1,
                                 length: [42, 0],
                             },
-                            partition_number,
-                            partition_format: 0x02, // GPT
-                            partition_start: start_lba,
-                            partition_size: last_lba - start_lba + 1,
-                            partition_signature: uuid,
-                            signature_type: 0x02,
+                            partition_number: p.number,
+                            // MBR or GPT, with the matching signature type
+                            partition_format: if p.is_mbr() { 0x01 } else { 0x02 },
+                            partition_start: p.first_lba / sectors_per_block,
+                            partition_size: (p.last_lba - p.first_lba + 1) / sectors_per_block,
+                            partition_signature: p.guid,
+                            signature_type: if p.is_mbr() { 0x01 } else { 0x02 },
                         },
                         HardDriveMedia {
                             header: DevicePathProtocol {
@@ -226,7 +282,7 @@ impl<'a> BlockWrapper<'a> {
                             partition_signature: [0; 16],
                             signature_type: 0,
                         },
-                    ]
+                    ],
                 },
             };
 
@@ -236,35 +292,52 @@ impl<'a> BlockWrapper<'a> {
     }
 }
 
+/// Create the handles of the disk and its partitions, returning the id of the
+/// one starting at `partition_start` or, without one, of the ESP
 pub fn populate_block_wrappers(
-    wrappers: &mut BlockWrappers,
-    block: *const VirtioBlockDevice,
+    wrappers: &mut BlockWrappers<'static>,
+    block: &dyn BlockDevice,
+    partition_start: Option<u64>,
 ) -> Option<u32> {
-    let mut parts = [PartitionEntry::default(); 16];
+    // SAFETY: The device outlives the EFI payload that uses the wrappers
+    let block =
+        unsafe { core::mem::transmute::<&dyn BlockDevice, &'static dyn BlockDevice>(block) };
 
-    wrappers.wrappers[0] = BlockWrapper::new(
-        unsafe { &*block.cast::<crate::block::VirtioBlockDevice<'_>>() },
-        0,
-        0,
-        0,
-        [0; 16],
-    );
+    wrappers.clear();
 
+    // One handle for the whole disk followed by one per partition. An active
+    // FAT partition is only reported if there is no explicit ESP.
+    wrappers.push(BlockWrapper::new(block, None));
+    let mut start_part_id = None;
     let mut efi_part_id = None;
-    let part_count = get_partitions(unsafe { &*block }, &mut parts).unwrap();
-    for i in 0..part_count {
-        let p = parts[i as usize];
-        wrappers.wrappers[i as usize + 1] = BlockWrapper::new(
-            unsafe { &*block.cast::<crate::block::VirtioBlockDevice<'_>>() },
-            i + 1,
-            p.first_lba,
-            p.last_lba,
-            p.guid,
-        );
-        if p.is_efi_partition() {
-            efi_part_id = Some(i + 1);
+    let mut fallback_part_id = None;
+    match partitions(block) {
+        Ok(parts) => {
+            for p in parts {
+                let p = match p {
+                    Ok(p) => p,
+                    Err(e) => {
+                        error!("Error reading partition entry: {e:?}");
+                        break;
+                    }
+                };
+                wrappers.push(BlockWrapper::new(block, Some(p)));
+                let id = Some(wrappers.count as u32 - 1);
+                if partition_start == Some(p.first_lba) && start_part_id.is_none() {
+                    start_part_id = id;
+                }
+                if p.is_esp_type() && efi_part_id.is_none() {
+                    efi_part_id = id;
+                } else if p.is_efi_partition() && fallback_part_id.is_none() {
+                    fallback_part_id = id;
+                }
+            }
         }
+        Err(e) => warn!("Error reading partition table: {e:?}"),
+    }
+
+    match partition_start {
+        Some(_) => start_part_id,
+        None => efi_part_id.or(fallback_part_id),
     }
-    wrappers.count = part_count as usize + 1;
-    efi_part_id
 }
@@ -15,11 +15,11 @@ use r_efi::{
 #[cfg(target_arch = "riscv64")]
 use r_efi::{eficall, eficall_abi};
 
-use crate::fat;
+use crate::fs;
 
 use super::{
-    block, device_path::DevicePath, file, mem_file, new_image_handle, HandleType, HandleWrapper,
-    LoadedImageWrapper, ALLOCATOR, BLOCK_WRAPPERS, ST,
+    block, device_path::DevicePath, file, initrd, mem_file, new_image_handle, HandleType,
+    HandleWrapper, LoadedImageWrapper, ALLOCATOR, BLOCK_WRAPPERS, HANDLE_REGISTRY, ST,
 };
 
 pub static mut BS: SyncUnsafeCell<efi::BootServices> = SyncUnsafeCell::new(efi::BootServices {
@@ -274,41 +274,68 @@ pub extern "efiapi" fn locate_handle(
     size: *mut usize,
     handles: *mut Handle,
 ) -> Status {
-    if unsafe { *guid } == r_efi::protocols::block_io::PROTOCOL_GUID {
-        #[allow(static_mut_refs)]
-        let count = unsafe { BLOCK_WRAPPERS.get_mut().count };
-        if unsafe { *size } < size_of::<Handle>() * count {
-            unsafe { *size = size_of::<Handle>() * count };
-            return Status::BUFFER_TOO_SMALL;
-        }
-
-        let handles =
-            unsafe { core::slice::from_raw_parts_mut(handles, *size / size_of::<Handle>()) };
-
-        let wrappers_as_handles: &[Handle] = unsafe {
-            core::slice::from_raw_parts_mut(
-                (#[allow(static_mut_refs)]
-                BLOCK_WRAPPERS.get_mut().wrappers.as_mut_ptr()) as *mut Handle,
-                count,
-            )
-        };
+    if guid.is_null() || size.is_null() {
+        return Status::INVALID_PARAMETER;
+    }
+    let guid = unsafe { *guid };
 
-        handles[0..count].copy_from_slice(wrappers_as_handles);
+    #[allow(static_mut_refs)]
+    let wrappers = unsafe { BLOCK_WRAPPERS.get_mut().handles() };
+    let block_handles: &[Handle] = if guid == r_efi::protocols::block_io::PROTOCOL_GUID {
+        unsafe { core::slice::from_raw_parts(wrappers.as_ptr() as *const Handle, wrappers.len()) }
+    } else {
+        &[]
+    };
+    #[allow(static_mut_refs)]
+    let registry = unsafe { HANDLE_REGISTRY.get_mut() };
 
+    let count = block_handles.len() + registry.handles(guid).count();
+    if count == 0 {
+        return Status::NOT_FOUND;
+    }
+    if unsafe { *size } < size_of::<Handle>() * count {
         unsafe { *size = size_of::<Handle>() * count };
+        return Status::BUFFER_TOO_SMALL;
+    }
+    if handles.is_null() {
+        return Status::INVALID_PARAMETER;
+    }
 
-        return Status::SUCCESS;
+    let handles = unsafe { core::slice::from_raw_parts_mut(handles, count) };
+    let found = block_handles.iter().copied().chain(registry.handles(guid));
+    for (dst, handle) in handles.iter_mut().zip(found) {
+        *dst = handle;
     }
 
-    Status::UNSUPPORTED
+    unsafe { *size = size_of::<Handle>() * count };
+
+    Status::SUCCESS
 }
 
 pub extern "efiapi" fn locate_device_path(
-    _: *mut Guid,
-    _: *mut *mut DevicePathProtocol,
-    _: *mut *mut c_void,
+    guid: *mut Guid,
+    device_path: *mut *mut DevicePathProtocol,
+    device: *mut *mut c_void,
 ) -> Status {
-    Status::NOT_FOUND
+    if guid.is_null()
+        || device_path.is_null()
+        || unsafe { *device_path }.is_null()
+        || device.is_null()
+    {
+        return Status::INVALID_PARAMETER;
+    }
+    #[allow(static_mut_refs)]
+    let registry = unsafe { HANDLE_REGISTRY.get_mut() };
+    match registry.locate_device_path(unsafe { *guid }, unsafe { *device_path }) {
+        Some((handle, length)) => {
+            unsafe {
+                *device_path = (*device_path as *mut u8).add(length) as *mut DevicePathProtocol;
+                *device = handle;
+            }
+            Status::SUCCESS
+        }
+        None => Status::NOT_FOUND,
+    }
 }
 
 pub extern "efiapi" fn install_configuration_table(guid: *mut Guid, table: *mut c_void) -> Status {
@@ -387,7 +414,7 @@ pub extern "efiapi" fn load_image(
 }
 
 fn load_from_file(
-    file: &mut dyn fat::Read,
+    file: &mut dyn fs::Read,
     dp: &DevicePath,
     parent_image_handle: *mut c_void,
     device_handle: *mut c_void,
@@ -527,7 +554,7 @@ pub extern "efiapi" fn open_protocol(
         unsafe {
             if let Some(block_part_id) = (*(handle as *mut file::FileSystemWrapper)).block_part_id {
                 *out = (&mut (*(#[allow(static_mut_refs)]
-                BLOCK_WRAPPERS.get_mut().wrappers[block_part_id as usize]))
+                BLOCK_WRAPPERS.get_mut().handles()[block_part_id as usize]))
                     .controller_path) as *mut _ as *mut c_void;
 
                 return Status::SUCCESS;
@@ -535,6 +562,25 @@ pub extern "efiapi" fn open_protocol(
         }
     }
 
+    if unsafe { *guid } == r_efi::protocols::load_file2::PROTOCOL_GUID
+        && handle_type == HandleType::Initrd
+    {
+        unsafe {
+            *out = &mut (*(handle as *mut initrd::InitrdWrapper)).proto as *mut _ as *mut c_void;
+        }
+        return Status::SUCCESS;
+    }
+
+    if unsafe { *guid } == r_efi::protocols::device_path::PROTOCOL_GUID
+        && handle_type == HandleType::Initrd
+    {
+        unsafe {
+            *out =
+                &mut (*(handle as *mut initrd::InitrdWrapper)).device_path as *mut _ as *mut c_void;
+        }
+        return Status::SUCCESS;
+    }
+
     if unsafe { *guid } == r_efi::protocols::block_io::PROTOCOL_GUID
         && handle_type == HandleType::Block
     {
@@ -570,13 +616,43 @@ pub extern "efiapi" fn protocols_per_handle(
 }
 
 pub extern "efiapi" fn locate_handle_buffer(
-    _: LocateSearchType,
-    _: *mut Guid,
-    _: *mut c_void,
-    _: *mut usize,
-    _: *mut *mut Handle,
+    search_type: LocateSearchType,
+    guid: *mut Guid,
+    search_key: *mut c_void,
+    count: *mut usize,
+    buffer: *mut *mut Handle,
 ) -> Status {
-    Status::UNSUPPORTED
+    if count.is_null() || buffer.is_null() {
+        return Status::INVALID_PARAMETER;
+    }
+    let mut size = 0;
+    let status = locate_handle(search_type, guid, search_key, &mut size, null_mut());
+    if status != Status::BUFFER_TOO_SMALL {
+        return status;
+    }
+
+    let mut handles = null_mut();
+    let status = allocate_pool(efi::BOOT_SERVICES_DATA, size, &mut handles);
+    if status != Status::SUCCESS {
+        return status;
+    }
+    let status = locate_handle(
+        search_type,
+        guid,
+        search_key,
+        &mut size,
+        handles as *mut Handle,
+    );
+    if status != Status::SUCCESS {
+        free_pool(handles);
+        return status;
+    }
+
+    unsafe {
+        *count = size / size_of::<Handle>();
+        *buffer = handles as *mut Handle;
+    }
+    Status::SUCCESS
 }
 
 #[cfg(target_arch = "riscv64")]
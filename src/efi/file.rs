@@ -11,7 +11,39 @@ use r_efi::{
     },
 };
 
-use crate::block::SectorBuf;
+use crate::{
+    block::SectorBuf,
+    fat,
+    fs::{self, Filesystem, Node, Read},
+};
+
+fn status(error: fs::Error) -> Status {
+    use fs::Error;
+    match error {
+        Error::NotFound => Status::NOT_FOUND,
+        Error::NoSpace => Status::VOLUME_FULL,
+        Error::AlreadyExists => Status::ACCESS_DENIED,
+        Error::InvalidName => Status::INVALID_PARAMETER,
+        Error::Unsupported | Error::NodeTypeMismatch => Status::UNSUPPORTED,
+        Error::WriteProtected | Error::Block(crate::block::Error::BlockNotSupported) => {
+            Status::WRITE_PROTECTED
+        }
+        Error::Corrupt => Status::VOLUME_CORRUPTED,
+        _ => Status::DEVICE_ERROR,
+    }
+}
+
+fn attribute(fs: &dyn Filesystem, node: &Node) -> u64 {
+    let mut attribute = if node.is_directory() {
+        r_efi::protocols::file::DIRECTORY
+    } else {
+        r_efi::protocols::file::ARCHIVE
+    };
+    if !fs.is_writable() {
+        attribute |= r_efi::protocols::file::READ_ONLY;
+    }
+    attribute
+}
 
 pub extern "efiapi" fn filesystem_open_volume(
     fs_proto: *mut SimpleFileSystemProtocol,
@@ -21,7 +53,7 @@ pub extern "efiapi" fn filesystem_open_volume(
     let wrapper = unsafe { &*wrapper };
     let root = wrapper.fs.root().unwrap();
 
-    if let Some(fw) = wrapper.create_file(root.into()) {
+    if let Some(fw) = wrapper.create_file(root) {
         unsafe {
             *file = &mut (*fw).proto;
         }
@@ -35,30 +67,38 @@ pub extern "efiapi" fn open(
     file_in: *mut FileProtocol,
     file_out: *mut *mut FileProtocol,
     path_in: *mut Char16,
-    _: u64,
-    _: u64,
+    mode: u64,
+    attributes: u64,
 ) -> Status {
     let wrapper = container_of!(file_in, FileWrapper, proto);
     let wrapper = unsafe { &*wrapper };
 
     let mut path = [0; 256];
     crate::common::ucs2_to_ascii(path_in, &mut path[0..255]);
-    let path = unsafe { core::str::from_utf8_unchecked(&path) };
+    let len = path.iter().position(|c| *c == 0).unwrap_or(path.len());
+    let path = unsafe { core::str::from_utf8_unchecked(&path[..len]) };
+
+    if !wrapper.fs.is_writable() && mode & r_efi::protocols::file::MODE_WRITE != 0 {
+        return Status::WRITE_PROTECTED;
+    }
 
     let root = wrapper.fs.root().unwrap();
-    let dir = if crate::fat::is_absolute_path(path) {
+    let dir = if fat::is_absolute_path(path) {
         &root
+    } else if wrapper.node.is_directory() {
+        &wrapper.node
     } else {
-        match &wrapper.node {
-            crate::fat::Node::Directory(d) => d,
-            _ => {
-                error!("Attempt to open from non-directory is unsupported");
-                return Status::UNSUPPORTED;
-            }
-        }
+        error!("Attempt to open from non-directory is unsupported");
+        return Status::UNSUPPORTED;
     };
 
-    match dir.open(path) {
+    let result = match dir.open(path) {
+        Err(fs::Error::NotFound) if mode & r_efi::protocols::file::MODE_CREATE != 0 => {
+            dir.create(path, attributes & r_efi::protocols::file::DIRECTORY != 0)
+        }
+        result => result,
+    };
+    match result {
         Ok(f) => {
             let fs_wrapper = unsafe { &(*wrapper.fs_wrapper) };
             if let Some(file_out_wrapper) = fs_wrapper.create_file(f) {
@@ -70,8 +110,7 @@ pub extern "efiapi" fn open(
                 Status::DEVICE_ERROR
             }
         }
-        Err(crate::fat::Error::NotFound) => Status::NOT_FOUND,
-        Err(_) => Status::DEVICE_ERROR,
+        Err(e) => status(e),
     }
 }
 
@@ -82,14 +121,21 @@ pub extern "efiapi" fn close(proto: *mut FileProtocol) -> Status {
         .free_pages(&wrapper as *const _ as u64)
 }
 
-pub extern "efiapi" fn delete(_: *mut FileProtocol) -> Status {
-    Status::UNSUPPORTED
+pub extern "efiapi" fn delete(file: *mut FileProtocol) -> Status {
+    let wrapper = container_of!(file, FileWrapper, proto);
+    let result = unsafe { (*wrapper).node.delete() };
+    // The handle is closed even if the file could not be deleted
+    close(file);
+    match result {
+        Ok(()) => Status::SUCCESS,
+        Err(_) => Status::WARN_DELETE_FAILURE,
+    }
 }
 
 pub extern "efiapi" fn read(file: *mut FileProtocol, size: *mut usize, buf: *mut c_void) -> Status {
-    use crate::fat::Read;
     let wrapper = container_of_mut!(file, FileWrapper, proto);
-    if let crate::fat::Node::Directory(d) = unsafe { &mut (*wrapper).node } {
+    let d = unsafe { &mut (*wrapper).node };
+    if d.is_directory() {
         match d.has_next() {
             Ok(has_next) => {
                 if has_next && unsafe { *size } < core::mem::size_of::<FileInfo>() {
@@ -102,18 +148,13 @@ pub extern "efiapi" fn read(file: *mut FileProtocol, size: *mut usize, buf: *mut
 
         let (node, name) = match d.next_node() {
             Ok(node) => node,
-            Err(crate::fat::Error::EndOfFile) => {
+            Err(fs::Error::EndOfFile) => {
                 unsafe { *size = 0 };
                 return Status::SUCCESS;
             }
             Err(_) => return Status::DEVICE_ERROR,
         };
 
-        let attribute = match &node {
-            crate::fat::Node::Directory(_) => r_efi::protocols::file::DIRECTORY,
-            crate::fat::Node::File(_) => r_efi::protocols::file::ARCHIVE,
-        };
-
         let info = buf as *mut FileInfo;
 
         let name = crate::common::ascii_strip(&name);
@@ -121,7 +162,7 @@ pub extern "efiapi" fn read(file: *mut FileProtocol, size: *mut usize, buf: *mut
             (*info).size = core::mem::size_of::<FileInfo>() as u64;
             (*info).file_size = node.get_size().into();
             (*info).physical_size = node.get_size().into();
-            (*info).attribute = attribute;
+            (*info).attribute = attribute((*wrapper).fs, &node);
             crate::common::ascii_to_ucs2(name, &mut (*info).file_name);
         }
 
@@ -161,24 +202,50 @@ pub extern "efiapi" fn read(file: *mut FileProtocol, size: *mut usize, buf: *mut
     }
 }
 
-pub extern "efiapi" fn write(_: *mut FileProtocol, _: *mut usize, _: *mut c_void) -> Status {
-    Status::UNSUPPORTED
+pub extern "efiapi" fn write(
+    file: *mut FileProtocol,
+    size: *mut usize,
+    buf: *mut c_void,
+) -> Status {
+    let wrapper = container_of_mut!(file, FileWrapper, proto);
+    let data = unsafe { core::slice::from_raw_parts(buf as *const u8, *size) };
+    match unsafe { (*wrapper).node.write(data) } {
+        Ok(written) => {
+            unsafe { *size = written as usize };
+            Status::SUCCESS
+        }
+        Err(e) => {
+            unsafe { *size = 0 };
+            status(e)
+        }
+    }
 }
 
-pub extern "efiapi" fn get_position(_: *mut FileProtocol, _: *mut u64) -> Status {
-    Status::UNSUPPORTED
+pub extern "efiapi" fn get_position(file: *mut FileProtocol, position: *mut u64) -> Status {
+    let wrapper = container_of!(file, FileWrapper, proto);
+    match unsafe { (*wrapper).node.position() } {
+        Ok(current) => {
+            unsafe { *position = current.into() };
+            Status::SUCCESS
+        }
+        Err(e) => status(e),
+    }
 }
 
 pub extern "efiapi" fn set_position(file: *mut FileProtocol, position: u64) -> Status {
-    // Seeking to end of file is not supported
-    if position == 0xFFFFFFFFFFFFFFFF {
-        return Status::UNSUPPORTED;
-    }
-    use crate::fat::Read;
     let wrapper = container_of_mut!(file, FileWrapper, proto);
-    match unsafe { (*wrapper).node.seek(position as u32) } {
-        Err(crate::fat::Error::Unsupported) => Status::UNSUPPORTED,
-        Err(_) => Status::DEVICE_ERROR,
+    let node = unsafe { &mut (*wrapper).node };
+    // Seeking to the end of the file is used to append
+    let position = match position {
+        0xFFFFFFFFFFFFFFFF if node.is_directory() => return Status::UNSUPPORTED,
+        0xFFFFFFFFFFFFFFFF => node.get_size(),
+        _ => match u32::try_from(position) {
+            Ok(position) => position,
+            Err(_) => return Status::UNSUPPORTED,
+        },
+    };
+    match node.seek(position) {
+        Err(e) => status(e),
         Ok(()) => Status::SUCCESS,
     }
 }
@@ -209,16 +276,11 @@ pub extern "efiapi" fn get_info(
             let info = info as *mut FileInfo;
 
             let wrapper = container_of!(file, FileWrapper, proto);
-            let attribute = match unsafe { &(*wrapper).node } {
-                crate::fat::Node::Directory(_) => r_efi::protocols::file::DIRECTORY,
-                crate::fat::Node::File(_) => r_efi::protocols::file::ARCHIVE,
-            };
-            use crate::fat::Read;
             unsafe {
                 (*info).size = core::mem::size_of::<FileInfo>() as u64;
                 (*info).file_size = (*wrapper).node.get_size().into();
                 (*info).physical_size = (*wrapper).node.get_size().into();
-                (*info).attribute = attribute;
+                (*info).attribute = attribute((*wrapper).fs, &(*wrapper).node);
             }
 
             Status::SUCCESS
@@ -228,36 +290,91 @@ pub extern "efiapi" fn get_info(
     }
 }
 
+// Supports changing the size of files and renaming or moving files and
+// directories. Requested changes to the timestamps and to attributes other
+// than the directory bit are ignored.
 pub extern "efiapi" fn set_info(
-    _: *mut FileProtocol,
-    _: *mut Guid,
-    _: usize,
-    _: *mut c_void,
+    file: *mut FileProtocol,
+    guid: *mut Guid,
+    info_size: usize,
+    info: *mut c_void,
 ) -> Status {
-    Status::UNSUPPORTED
+    if unsafe { *guid } != r_efi::protocols::file::INFO_ID {
+        return Status::UNSUPPORTED;
+    }
+    let name_offset = core::mem::offset_of!(FileInfo, file_name);
+    if info_size < name_offset + core::mem::size_of::<Char16>() {
+        return Status::BAD_BUFFER_SIZE;
+    }
+
+    let info = info as *const FileInfo;
+    let wrapper = container_of_mut!(file, FileWrapper, proto);
+    if !unsafe { (*wrapper).fs.is_writable() } {
+        return Status::WRITE_PROTECTED;
+    }
+    let node = unsafe { &mut (*wrapper).node };
+
+    let attribute = unsafe { (*info).attribute };
+    if (attribute & r_efi::protocols::file::DIRECTORY != 0) != node.is_directory() {
+        return Status::ACCESS_DENIED;
+    }
+
+    // Check the size and apply the rename before resizing, so that a bad
+    // request leaves the file unchanged
+    let file_size = unsafe { (*info).file_size };
+    let file_size = match u32::try_from(file_size) {
+        _ if node.is_directory() => None,
+        Ok(file_size) => Some(file_size),
+        Err(_) => return Status::VOLUME_FULL,
+    };
+
+    let mut name = [0; 256];
+    let name_ptr = unsafe { core::ptr::addr_of!((*info).file_name) } as *const Char16;
+    if crate::common::ucs2_as_ascii_length(name_ptr) >= name.len() {
+        return Status::INVALID_PARAMETER;
+    }
+    crate::common::ucs2_to_ascii(name_ptr, &mut name);
+    let name = crate::common::ascii_strip(&name);
+    if !name.is_empty() {
+        if let Err(e) = node.rename(name) {
+            return status(e);
+        }
+    }
+
+    if let Some(file_size) = file_size.filter(|size| *size != node.get_size()) {
+        if let Err(e) = node.set_size(file_size) {
+            return status(e);
+        }
+    }
+
+    Status::SUCCESS
 }
 
-pub extern "efiapi" fn flush(_: *mut FileProtocol) -> Status {
-    Status::UNSUPPORTED
+pub extern "efiapi" fn flush(file: *mut FileProtocol) -> Status {
+    let wrapper = container_of!(file, FileWrapper, proto);
+    match unsafe { (*wrapper).fs.flush() } {
+        Ok(()) => Status::SUCCESS,
+        Err(e) => status(e),
+    }
 }
 
 struct FileWrapper<'a> {
-    fs: &'a crate::fat::Filesystem<'a>,
+    fs: &'a dyn Filesystem,
     proto: FileProtocol,
-    node: crate::fat::Node<'a>,
+    node: Node<'a>,
     fs_wrapper: *const FileSystemWrapper<'a>,
 }
 
 #[repr(C)]
 pub struct FileSystemWrapper<'a> {
     hw: super::HandleWrapper,
-    pub fs: &'a crate::fat::Filesystem<'a>,
+    pub fs: &'a dyn Filesystem,
     pub proto: SimpleFileSystemProtocol,
     pub block_part_id: Option<u32>,
 }
 
 impl<'a> FileSystemWrapper<'a> {
-    fn create_file(&self, node: crate::fat::Node<'a>) -> Option<*mut FileWrapper> {
+    fn create_file(&self, node: Node<'a>) -> Option<*mut FileWrapper> {
         let (status, new_address) = super::ALLOCATOR
             .borrow_mut()
             .allocate_pool(efi::LOADER_DATA, core::mem::size_of::<FileWrapper>());
@@ -288,10 +405,7 @@ impl<'a> FileSystemWrapper<'a> {
         }
     }
 
-    pub fn new(
-        fs: &'a crate::fat::Filesystem,
-        block_part_id: Option<u32>,
-    ) -> FileSystemWrapper<'a> {
+    pub fn new(fs: &'a dyn Filesystem, block_part_id: Option<u32>) -> FileSystemWrapper<'a> {
         FileSystemWrapper {
             hw: super::HandleWrapper {
                 handle_type: super::HandleType::FileSystem,
@@ -0,0 +1,220 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2026 The Rust Hypervisor Firmware Authors
+
+// The Linux EFI stub asks for its initrd through the Load File 2 protocol on
+// the handle with a vendor media device path of LINUX_EFI_INITRD_MEDIA_GUID.
+// The initrds of the boot entry are read from its filesystem when the stub
+// asks and handed over back-to-back, each starting 4 byte aligned as the
+// kernel expects of concatenated cpio archives.
+
+use core::{ffi::c_void, mem::size_of};
+
+use r_efi::{
+    efi::{Boolean, Guid, Handle, Status},
+    protocols::{device_path::Protocol as DevicePathProtocol, load_file2::Protocol as LoadFile2},
+};
+
+use crate::{
+    fs::{Filesystem, Read},
+    mem::MemoryRegion,
+};
+
+use super::{HandleType, HandleWrapper, HANDLE_REGISTRY};
+
+pub const LINUX_EFI_INITRD_MEDIA_GUID: Guid = Guid::from_fields(
+    0x5568_e427,
+    0x68fc,
+    0x4f3d,
+    0xac,
+    0x74,
+    &[0xca, 0x55, 0x52, 0x31, 0xcc, 0x68],
+);
+
+const PROTOCOLS: [Guid; 2] = [
+    r_efi::protocols::load_file2::PROTOCOL_GUID,
+    r_efi::protocols::device_path::PROTOCOL_GUID,
+];
+
+#[repr(C)]
+pub struct InitrdDevicePath {
+    vendor: DevicePathProtocol,
+    guid: Guid,
+    end: DevicePathProtocol,
+}
+
+#[repr(C)]
+pub struct InitrdWrapper<'a> {
+    hw: HandleWrapper,
+    pub proto: LoadFile2,
+    pub device_path: InitrdDevicePath,
+    fs: &'a dyn Filesystem,
+    paths: &'a [[u8; 260]],
+}
+
+impl<'a> InitrdWrapper<'a> {
+    pub fn new(fs: &'a dyn Filesystem, paths: &'a [[u8; 260]]) -> InitrdWrapper<'a> {
+        InitrdWrapper {
+            hw: HandleWrapper {
+                handle_type: HandleType::Initrd,
+            },
+            proto: LoadFile2 { load_file },
+            device_path: InitrdDevicePath {
+                vendor: DevicePathProtocol {
+                    r#type: r_efi::protocols::device_path::TYPE_MEDIA,
+                    sub_type: r_efi::protocols::device_path::Media::SUBTYPE_VENDOR,
+                    length: ((size_of::<DevicePathProtocol>() + size_of::<Guid>()) as u16)
+                        .to_le_bytes(),
+                },
+                guid: LINUX_EFI_INITRD_MEDIA_GUID,
+                end: DevicePathProtocol {
+                    r#type: r_efi::protocols::device_path::TYPE_END,
+                    sub_type: r_efi::protocols::device_path::End::SUBTYPE_ENTIRE,
+                    length: (size_of::<DevicePathProtocol>() as u16).to_le_bytes(),
+                },
+            },
+            fs,
+            paths,
+        }
+    }
+
+    /// Install the initrd handle for `locate_device_path` and `locate_handle`
+    /// to find, the wrapper must not move afterwards
+    pub fn install(&self) -> Status {
+        #[allow(static_mut_refs)]
+        let registry = unsafe { HANDLE_REGISTRY.get_mut() };
+        registry.install(self.handle(), &PROTOCOLS, &self.device_path.vendor)
+    }
+
+    fn handle(&self) -> Handle {
+        self as *const _ as Handle
+    }
+
+    // Size of the initrds one after the other
+    fn size(&self) -> Result<u32, Status> {
+        let mut size: u32 = 0;
+        for path in self.paths {
+            let file = self
+                .fs
+                .open(crate::common::ascii_strip(path))
+                .map_err(|_| Status::NOT_FOUND)?;
+            size = size.next_multiple_of(4) + file.get_size();
+        }
+        Ok(size)
+    }
+}
+
+// The program may return, leaving the wrapper behind
+impl Drop for InitrdWrapper<'_> {
+    fn drop(&mut self) {
+        #[allow(static_mut_refs)]
+        let registry = unsafe { HANDLE_REGISTRY.get_mut() };
+        registry.uninstall(self.handle());
+    }
+}
+
+pub extern "efiapi" fn load_file(
+    proto: *mut LoadFile2,
+    _: *mut DevicePathProtocol,
+    boot_policy: Boolean,
+    buffer_size: *mut usize,
+    buffer: *mut c_void,
+) -> Status {
+    // Load File 2 is never for boot options
+    if boot_policy.into() {
+        return Status::UNSUPPORTED;
+    }
+    if buffer_size.is_null() {
+        return Status::INVALID_PARAMETER;
+    }
+    let wrapper = container_of!(proto, InitrdWrapper, proto);
+    let wrapper = unsafe { &*wrapper };
+
+    let size = match wrapper.size() {
+        Ok(size) => size,
+        Err(status) => return status,
+    };
+    if buffer.is_null() || unsafe { *buffer_size } < size as usize {
+        unsafe { *buffer_size = size as usize };
+        return Status::BUFFER_TOO_SMALL;
+    }
+
+    let mut region = MemoryRegion::new(buffer as u64, u64::from(size));
+    region.as_bytes().fill(0);
+    let mut offset: u32 = 0;
+    for path in wrapper.paths {
+        let mut file = match wrapper.fs.open(crate::common::ascii_strip(path)) {
+            Ok(file) => file,
+            Err(_) => return Status::NOT_FOUND,
+        };
+        offset = offset.next_multiple_of(4);
+        let mut region = MemoryRegion::new(
+            buffer as u64 + u64::from(offset),
+            u64::from(file.get_size()),
+        );
+        if file.load_file(&mut region).is_err() {
+            return Status::DEVICE_ERROR;
+        }
+        offset += file.get_size();
+    }
+    unsafe { *buffer_size = size as usize };
+    Status::SUCCESS
+}
+
+#[cfg(test)]
+mod tests {
+    use core::ptr::null_mut;
+
+    use r_efi::efi::Status;
+
+    use super::InitrdWrapper;
+    use crate::part::tests::ext4_filesystem;
+
+    fn path(path: &str) -> [u8; 260] {
+        let mut result = [0; 260];
+        result[..path.len()].copy_from_slice(path.as_bytes());
+        result
+    }
+
+    #[test]
+    fn test_load_file() {
+        let fs = ext4_filesystem();
+
+        let paths = [path("/a/b/c/511"), path("/a/b/c/1024")];
+        let mut wrapper = InitrdWrapper::new(&fs, &paths);
+        let proto = &mut wrapper.proto;
+        let load_file = proto.load_file;
+
+        let mut size = 0;
+        let status = load_file(proto, null_mut(), false.into(), &mut size, null_mut());
+        assert_eq!(status, Status::BUFFER_TOO_SMALL);
+        assert_eq!(size, 512 + 1024);
+
+        // The second initrd starts aligned, after zero padding
+        let mut data = vec![0xff; size];
+        let status = load_file(
+            proto,
+            null_mut(),
+            false.into(),
+            &mut size,
+            data.as_mut_ptr() as *mut _,
+        );
+        assert_eq!(status, Status::SUCCESS);
+        assert_eq!(data[..511], [b'a'; 511]);
+        assert_eq!(data[511], 0);
+        assert_eq!(data[512..], [b'a'; 1024]);
+
+        let status = load_file(proto, null_mut(), true.into(), &mut size, null_mut());
+        assert_eq!(status, Status::UNSUPPORTED);
+
+        let paths = [path("/missing")];
+        let mut wrapper = InitrdWrapper::new(&fs, &paths);
+        let status = load_file(
+            &mut wrapper.proto,
+            null_mut(),
+            false.into(),
+            &mut size,
+            null_mut(),
+        );
+        assert_eq!(status, Status::NOT_FOUND);
+    }
+}
@@ -3,7 +3,7 @@
 
 use core::slice::from_raw_parts;
 
-use crate::{block::SectorBuf, fat};
+use crate::{block::SectorBuf, fs};
 
 pub struct MemoryFile {
     address: u64,
@@ -21,12 +21,12 @@ impl MemoryFile {
     }
 }
 
-impl fat::Read for MemoryFile {
+impl fs::Read for MemoryFile {
     fn get_size(&self) -> u32 {
         self.size
     }
 
-    fn read(&mut self, data: &mut [u8]) -> Result<u32, fat::Error> {
+    fn read(&mut self, data: &mut [u8]) -> Result<u32, fs::Error> {
         let sector_size = SectorBuf::len() as u32;
         assert_eq!(data.len(), SectorBuf::len());
 
@@ -54,14 +54,14 @@ impl fat::Read for MemoryFile {
         }
     }
 
-    fn seek(&mut self, position: u32) -> Result<(), fat::Error> {
+    fn seek(&mut self, position: u32) -> Result<(), fs::Error> {
         let sector_size = SectorBuf::len() as u32;
         if position % sector_size != 0 {
-            return Err(fat::Error::InvalidOffset);
+            return Err(fs::Error::InvalidOffset);
         }
 
         if position >= self.size {
-            return Err(fat::Error::EndOfFile);
+            return Err(fs::Error::EndOfFile);
         }
 
         self.position = position;
@@ -6,7 +6,9 @@ use core::{cell::SyncUnsafeCell, ffi::c_void, mem::size_of, ptr::null_mut};
 use atomic_refcell::AtomicRefCell;
 use r_efi::{
     efi::{self, Guid, Handle, Status},
-    protocols::loaded_image::Protocol as LoadedImageProtocol,
+    protocols::{
+        device_path::Protocol as DevicePathProtocol, loaded_image::Protocol as LoadedImageProtocol,
+    },
 };
 
 use crate::{bootinfo, layout};
@@ -17,6 +19,7 @@ mod boot_services;
 mod console;
 mod device_path;
 mod file;
+mod initrd;
 mod mem_file;
 mod runtime_services;
 mod var;
@@ -40,6 +43,7 @@ enum HandleType {
     Block,
     FileSystem,
     LoadedImage,
+    Initrd,
 }
 
 #[repr(C)]
@@ -80,10 +84,115 @@ static mut ST: SyncUnsafeCell<efi::SystemTable> = SyncUnsafeCell::new(efi::Syste
 });
 
 static mut BLOCK_WRAPPERS: SyncUnsafeCell<block::BlockWrappers> =
-    SyncUnsafeCell::new(block::BlockWrappers {
-        wrappers: [null_mut(); 16],
-        count: 0,
-    });
+    SyncUnsafeCell::new(block::BlockWrappers::new());
+
+static mut HANDLE_REGISTRY: SyncUnsafeCell<HandleRegistry> =
+    SyncUnsafeCell::new(HandleRegistry::new());
+
+const MAX_REGISTERED_HANDLES: usize = 8;
+
+#[derive(Copy, Clone)]
+struct Registration {
+    handle: Handle,
+    protocols: &'static [Guid],
+    device_path: *const DevicePathProtocol,
+}
+
+// Handles installed while a program runs, beyond the block devices, with the
+// protocols they carry and the device path they are found by
+pub struct HandleRegistry {
+    registrations: heapless::Vec<Registration, MAX_REGISTERED_HANDLES>,
+}
+
+impl HandleRegistry {
+    pub const fn new() -> HandleRegistry {
+        HandleRegistry {
+            registrations: heapless::Vec::new(),
+        }
+    }
+
+    /// Register `handle`, or replace its registration. `device_path` must
+    /// stay valid until the handle is uninstalled.
+    pub fn install(
+        &mut self,
+        handle: Handle,
+        protocols: &'static [Guid],
+        device_path: *const DevicePathProtocol,
+    ) -> Status {
+        self.uninstall(handle);
+        let registration = Registration {
+            handle,
+            protocols,
+            device_path,
+        };
+        match self.registrations.push(registration) {
+            Ok(()) => Status::SUCCESS,
+            Err(_) => Status::OUT_OF_RESOURCES,
+        }
+    }
+
+    pub fn uninstall(&mut self, handle: Handle) {
+        self.registrations.retain(|r| r.handle != handle);
+    }
+
+    /// The handles carrying `protocol`
+    pub fn handles(&self, protocol: Guid) -> impl Iterator<Item = Handle> + '_ {
+        self.registrations
+            .iter()
+            .filter(move |r| r.protocols.contains(&protocol))
+            .map(|r| r.handle)
+    }
+
+    /// The handle carrying `protocol` whose device path is the longest one
+    /// starting `device_path`, with the length of its device path in bytes
+    pub fn locate_device_path(
+        &self,
+        protocol: Guid,
+        device_path: *const DevicePathProtocol,
+    ) -> Option<(Handle, usize)> {
+        self.registrations
+            .iter()
+            .filter(|r| r.protocols.contains(&protocol))
+            .filter_map(|r| Some((r.handle, device_path_prefix(r.device_path, device_path)?)))
+            .max_by_key(|(_, length)| *length)
+    }
+}
+
+// The length of `prefix` without its end node if `path` starts with its nodes
+fn device_path_prefix(
+    prefix: *const DevicePathProtocol,
+    path: *const DevicePathProtocol,
+) -> Option<usize> {
+    let mut offset = 0;
+    loop {
+        let (node, other) = unsafe {
+            (
+                &*((prefix as *const u8).add(offset) as *const DevicePathProtocol),
+                &*((path as *const u8).add(offset) as *const DevicePathProtocol),
+            )
+        };
+        if node.r#type == r_efi::protocols::device_path::TYPE_END {
+            return Some(offset);
+        }
+        let length = u16::from_le_bytes(node.length) as usize;
+        if other.r#type == r_efi::protocols::device_path::TYPE_END
+            || other.length != node.length
+            || length < size_of::<DevicePathProtocol>()
+        {
+            return None;
+        }
+        let (node, other) = unsafe {
+            (
+                core::slice::from_raw_parts(node as *const _ as *const u8, length),
+                core::slice::from_raw_parts(other as *const _ as *const u8, length),
+            )
+        };
+        if node != other {
+            return None;
+        }
+        offset += length;
+    }
+}
 
 // Populate allocator from E820, fixed ranges for the firmware and the loaded binary.
 fn populate_allocator(info: &dyn bootinfo::Info, image_address: u64, image_size: u64) {
@@ -186,13 +295,39 @@ fn new_image_handle(
     image
 }
 
+// Pass `options` to the image as a NUL terminated UTF-16 string
+fn set_load_options(image: *mut LoadedImageWrapper, options: &str) {
+    let size = (options.encode_utf16().count() + 1) * size_of::<u16>();
+    let mut buffer = null_mut();
+    let status = boot_services::allocate_pool(efi::LOADER_DATA, size, &mut buffer);
+    assert!(status == Status::SUCCESS);
+    let utf16 =
+        unsafe { core::slice::from_raw_parts_mut(buffer as *mut u16, size / size_of::<u16>()) };
+    encode_load_options(options, utf16);
+    let image = unsafe { &mut *image };
+    image.proto.load_options = buffer;
+    image.proto.load_options_size = size as u32;
+}
+
+// Fill `utf16`, sized for it, with `options` and the terminator
+fn encode_load_options(options: &str, utf16: &mut [u16]) {
+    for (dst, c) in utf16.iter_mut().zip(options.encode_utf16().chain([0])) {
+        *dst = c;
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 pub fn efi_exec(
     address: u64,
     loaded_address: u64,
     loaded_size: u64,
     info: &dyn bootinfo::Info,
-    fs: &crate::fat::Filesystem,
-    block: &crate::block::VirtioBlockDevice,
+    fs: &dyn crate::fs::Filesystem,
+    block: &dyn crate::block::BlockDevice,
+    path: &str,
+    load_options: &str,
+    initrds: &[[u8; 260]],
+    partition_start: Option<u64>,
 ) {
     let vendor_data = 0u32;
 
@@ -271,13 +406,23 @@ pub fn efi_exec(
     populate_allocator(info, loaded_address, loaded_size);
 
     #[allow(static_mut_refs)]
-    let efi_part_id = unsafe { block::populate_block_wrappers(BLOCK_WRAPPERS.get_mut(), block) };
+    let efi_part_id =
+        unsafe { block::populate_block_wrappers(BLOCK_WRAPPERS.get_mut(), block, partition_start) };
 
     let wrapped_fs = file::FileSystemWrapper::new(fs, efi_part_id);
 
-    let mut path = [0u8; 256];
-    path[0..crate::efi::EFI_BOOT_PATH.len()].copy_from_slice(crate::efi::EFI_BOOT_PATH.as_bytes());
-    let device_path = DevicePath::File(path);
+    let initrd = initrd::InitrdWrapper::new(fs, initrds);
+    if !initrds.is_empty() {
+        let status = initrd.install();
+        assert!(status == Status::SUCCESS);
+    }
+
+    // Boot entries name files with '/' separators
+    let mut file_path = [0u8; 256];
+    for (dst, c) in file_path[..255].iter_mut().zip(path.bytes()) {
+        *dst = if c == b'/' { b'\\' } else { c };
+    }
+    let device_path = DevicePath::File(file_path);
     let image = new_image_handle(
         device_path.generate(),
         0 as Handle,
@@ -286,9 +431,75 @@ pub fn efi_exec(
         loaded_size,
         address,
     );
+    if !load_options.is_empty() {
+        set_load_options(image, load_options);
+    }
 
     let ptr = address as *const ();
     let code: extern "efiapi" fn(Handle, *mut efi::SystemTable) -> Status =
         unsafe { core::mem::transmute(ptr) };
     (code)((image as *const _) as Handle, &mut *st);
 }
+
+#[cfg(test)]
+mod tests {
+    use r_efi::efi::{Guid, Handle};
+
+    use super::{encode_load_options, HandleRegistry};
+
+    // A vendor media node with `guid`'s first byte as its GUID
+    fn vendor(guid: u8) -> [u8; 20] {
+        let mut node = [0; 20];
+        node[..4].copy_from_slice(&[4, 3, 20, 0]);
+        node[4] = guid;
+        node
+    }
+
+    const END: [u8; 4] = [0x7f, 0xff, 4, 0];
+    const LOAD_FILE2: Guid = r_efi::protocols::load_file2::PROTOCOL_GUID;
+    const BLOCK_IO: Guid = r_efi::protocols::block_io::PROTOCOL_GUID;
+
+    #[test]
+    fn test_handle_registry() {
+        let first = [vendor(1).as_slice(), &END].concat();
+        let nested = [vendor(1).as_slice(), &vendor(2), &END].concat();
+        let other = [vendor(3).as_slice(), &END].concat();
+        let (a, b, c) = (1 as Handle, 2 as Handle, 3 as Handle);
+
+        let mut registry = HandleRegistry::new();
+        registry.install(a, &[LOAD_FILE2], first.as_ptr() as *const _);
+        registry.install(b, &[LOAD_FILE2], nested.as_ptr() as *const _);
+        registry.install(c, &[BLOCK_IO], other.as_ptr() as *const _);
+        assert_eq!(registry.handles(LOAD_FILE2).collect::<Vec<_>>(), [a, b]);
+        assert_eq!(registry.handles(BLOCK_IO).collect::<Vec<_>>(), [c]);
+
+        // The longest matching device path wins, the rest is left over
+        let path = [vendor(1).as_slice(), &vendor(2), &vendor(4), &END].concat();
+        let path = path.as_ptr() as *const _;
+        assert_eq!(registry.locate_device_path(LOAD_FILE2, path), Some((b, 40)));
+        let path = [vendor(1).as_slice(), &vendor(4), &END].concat();
+        let path = path.as_ptr() as *const _;
+        assert_eq!(registry.locate_device_path(LOAD_FILE2, path), Some((a, 20)));
+        assert_eq!(registry.locate_device_path(BLOCK_IO, path), None);
+        let path = other.as_ptr() as *const _;
+        assert_eq!(registry.locate_device_path(LOAD_FILE2, path), None);
+        assert_eq!(registry.locate_device_path(BLOCK_IO, path), Some((c, 20)));
+
+        registry.uninstall(b);
+        assert_eq!(registry.handles(LOAD_FILE2).collect::<Vec<_>>(), [a]);
+        let path = nested.as_ptr() as *const _;
+        assert_eq!(registry.locate_device_path(LOAD_FILE2, path), Some((a, 20)));
+    }
+
+    #[test]
+    fn test_encode_load_options() {
+        let options = "root=/dev/vda1 é☃ 𝄞";
+        let mut utf16 = vec![0xffff; options.encode_utf16().count() + 1];
+        encode_load_options(options, &mut utf16);
+        assert_eq!(
+            utf16[..15],
+            *"root=/dev/vda1 ".encode_utf16().collect::<Vec<_>>()
+        );
+        assert_eq!(utf16[15..], [0xe9, 0x2603, 0x20, 0xd834, 0xdd1e, 0]);
+    }
+}
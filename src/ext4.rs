@@ -0,0 +1,1126 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2026 The Rust Hypervisor Firmware Authors
+
+//! Read-only ext2/3/4 support
+//!
+//! Directories are scanned linearly (hashed directory indexes are stored in
+//! a form that linear readers skip over) and the journal is ignored, so
+//! changes that have not been checkpointed yet are not seen.
+
+use crate::{
+    block::{Error as BlockError, SectorBuf, SectorRead},
+    fat, fs,
+    mem::MemoryRegion,
+};
+
+const SUPERBLOCK_OFFSET: u64 = 1024;
+const SUPERBLOCK_MAGIC: u16 = 0xef53;
+const ROOT_INODE: u32 = 2;
+
+const INCOMPAT_FILETYPE: u32 = 0x2;
+const INCOMPAT_RECOVER: u32 = 0x4;
+const INCOMPAT_META_BG: u32 = 0x10;
+const INCOMPAT_EXTENTS: u32 = 0x40;
+const INCOMPAT_64BIT: u32 = 0x80;
+const INCOMPAT_MMP: u32 = 0x100;
+const INCOMPAT_FLEX_BG: u32 = 0x200;
+const INCOMPAT_EA_INODE: u32 = 0x400;
+const INCOMPAT_CSUM_SEED: u32 = 0x2000;
+const INCOMPAT_LARGEDIR: u32 = 0x4000;
+const INCOMPAT_CASEFOLD: u32 = 0x20000;
+// Features that do not change how files are found and read
+const INCOMPAT_SUPPORTED: u32 = INCOMPAT_FILETYPE
+    | INCOMPAT_RECOVER
+    | INCOMPAT_META_BG
+    | INCOMPAT_EXTENTS
+    | INCOMPAT_64BIT
+    | INCOMPAT_MMP
+    | INCOMPAT_FLEX_BG
+    | INCOMPAT_EA_INODE
+    | INCOMPAT_CSUM_SEED
+    | INCOMPAT_LARGEDIR
+    | INCOMPAT_CASEFOLD;
+
+const RO_COMPAT_SPARSE_SUPER: u32 = 0x1;
+
+// Group descriptors of 64-bit filesystems may be larger, up to this size
+const MAX_DESC_SIZE: u64 = 1024;
+
+const INODE_FLAG_EXTENTS: u32 = 0x80000;
+const INODE_FLAG_INLINE_DATA: u32 = 0x1000_0000;
+
+const MODE_TYPE_MASK: u16 = 0xf000;
+const MODE_DIRECTORY: u16 = 0x4000;
+const MODE_FILE: u16 = 0x8000;
+const MODE_SYMLINK: u16 = 0xa000;
+
+const DIRENT_TYPE_FILE: u8 = 1;
+const DIRENT_TYPE_DIRECTORY: u8 = 2;
+const DIRENT_TYPE_SYMLINK: u8 = 7;
+
+const EXTENT_MAGIC: u16 = 0xf30a;
+// Extent trees are at most five levels deep
+const EXTENT_MAX_DEPTH: u16 = 5;
+// Longer extents are preallocated but not yet written
+const EXTENT_MAX_INITIALIZED: u16 = 32768;
+
+// i_block holds 12 direct block numbers followed by the single, double and
+// triple indirect blocks
+const DIRECT_BLOCKS: u64 = 12;
+// Symlinks shorter than this are stored in i_block
+const FAST_SYMLINK_SIZE: u64 = 60;
+
+const MAX_PATH_LENGTH: usize = 1024;
+// Same limit as Linux
+const MAX_SYMLINK_FOLLOWS: u32 = 40;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Error {
+    Block(BlockError),
+    NotExt4,
+    Unsupported,
+    Corrupt,
+    NotFound,
+    EndOfFile,
+    NodeTypeMismatch,
+    SymlinkLoop,
+    PathTooLong,
+}
+
+impl From<Error> for fs::Error {
+    fn from(e: Error) -> fs::Error {
+        match e {
+            Error::Block(e) => fs::Error::Block(e),
+            Error::NotFound | Error::SymlinkLoop => fs::Error::NotFound,
+            Error::EndOfFile => fs::Error::EndOfFile,
+            Error::NodeTypeMismatch => fs::Error::NodeTypeMismatch,
+            Error::PathTooLong => fs::Error::InvalidName,
+            Error::Corrupt => fs::Error::Corrupt,
+            Error::NotExt4 | Error::Unsupported => fs::Error::Unsupported,
+        }
+    }
+}
+
+fn le16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap())
+}
+
+fn le32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+#[derive(Clone, Copy)]
+struct Inode {
+    mode: u16,
+    size: u64,
+    flags: u32,
+    block: [u8; 60],
+}
+
+impl Inode {
+    fn file_type(&self) -> u16 {
+        self.mode & MODE_TYPE_MASK
+    }
+}
+
+// A range of logical blocks that are contiguous on disk, or a hole if
+// `physical` is zero
+#[derive(Clone, Copy, Default)]
+struct Run {
+    logical: u64,
+    physical: u64,
+    count: u64,
+}
+
+impl Run {
+    fn contains(&self, logical: u64) -> bool {
+        logical >= self.logical && logical - self.logical < self.count
+    }
+}
+
+pub struct Filesystem<'a> {
+    device: &'a dyn SectorRead,
+    start: u64,
+    last: u64,
+    block_size: u64,
+    inode_size: u64,
+    blocks_per_group: u64,
+    inodes_per_group: u32,
+    first_data_block: u64,
+    desc_size: u64,
+    incompat: u32,
+    ro_compat: u32,
+    first_meta_bg: u64,
+}
+
+pub enum Node<'a> {
+    File(File<'a>),
+    Directory(Directory<'a>),
+}
+
+impl<'a> TryFrom<Node<'a>> for File<'a> {
+    type Error = Error;
+
+    fn try_from(from: Node<'a>) -> Result<Self, Self::Error> {
+        match from {
+            Node::File(f) => Ok(f),
+            _ => Err(Self::Error::NodeTypeMismatch),
+        }
+    }
+}
+
+impl<'a> TryFrom<Node<'a>> for Directory<'a> {
+    type Error = Error;
+
+    fn try_from(from: Node<'a>) -> Result<Self, Self::Error> {
+        match from {
+            Node::Directory(d) => Ok(d),
+            _ => Err(Self::Error::NodeTypeMismatch),
+        }
+    }
+}
+
+impl<'a> fs::Read for Node<'a> {
+    fn read(&mut self, data: &mut [u8]) -> Result<u32, fs::Error> {
+        match self {
+            Self::File(file) => file.read(data),
+            Self::Directory(_) => Err(fs::Error::Unsupported),
+        }
+    }
+
+    fn seek(&mut self, position: u32) -> Result<(), fs::Error> {
+        match self {
+            Self::File(file) => file.seek(position),
+            Self::Directory(directory) if position == 0 => {
+                directory.offset = 0;
+                Ok(())
+            }
+            Self::Directory(_) => Err(fs::Error::Unsupported),
+        }
+    }
+
+    fn get_size(&self) -> u32 {
+        match self {
+            Self::File(file) => file.get_size(),
+            Self::Directory(_) => 0,
+        }
+    }
+
+    fn load_file(&mut self, mem: &mut MemoryRegion) -> Result<(), fs::Error> {
+        match self {
+            Self::File(file) => file.load_file(mem),
+            Self::Directory(_) => Err(fs::Error::Unsupported),
+        }
+    }
+}
+
+pub struct File<'a> {
+    filesystem: &'a Filesystem<'a>,
+    inode: Inode,
+    size: u32,
+    position: u32,
+    // Most recently used part of the block map
+    run: Run,
+}
+
+pub struct Directory<'a> {
+    data: File<'a>,
+    number: u32,
+    offset: u32,
+}
+
+pub struct DirectoryEntry {
+    name: [u8; 255],
+    inode: u32,
+    file_type: u8,
+}
+
+impl<'a> File<'a> {
+    pub fn position(&self) -> u32 {
+        self.position
+    }
+
+    // Find the sector holding the byte at `position`, None if it is in a hole
+    fn sector(&mut self, position: u32) -> Result<Option<u64>, Error> {
+        let block_size = self.filesystem.block_size;
+        let logical = u64::from(position) / block_size;
+        if !self.run.contains(logical) {
+            self.run = self.filesystem.map_block(&self.inode, logical)?;
+        }
+        if self.run.physical == 0 {
+            return Ok(None);
+        }
+        let block = self.run.physical + (logical - self.run.logical);
+        Ok(Some(
+            block * (block_size / SectorBuf::len() as u64)
+                + (u64::from(position) % block_size) / SectorBuf::len() as u64,
+        ))
+    }
+
+    // Read `data.len()` bytes at `offset` into `data`
+    fn read_at(&mut self, offset: u32, data: &mut [u8]) -> Result<(), Error> {
+        let end = offset
+            .checked_add(data.len() as u32)
+            .filter(|end| *end <= self.size)
+            .ok_or(Error::Corrupt)?;
+        let mut position = offset;
+        let mut buf = SectorBuf::new();
+        while position < end {
+            let start = position as usize % SectorBuf::len();
+            let count = usize::min(SectorBuf::len() - start, (end - position) as usize);
+            match self.sector(position)? {
+                Some(sector) => self.filesystem.read_sector(sector, &mut buf)?,
+                None => buf = SectorBuf::new(),
+            }
+            let done = (position - offset) as usize;
+            data[done..done + count].copy_from_slice(&buf.as_bytes()[start..start + count]);
+            position += count as u32;
+        }
+        Ok(())
+    }
+}
+
+impl<'a> fs::Read for File<'a> {
+    fn read(&mut self, data: &mut [u8]) -> Result<u32, fs::Error> {
+        assert_eq!(data.len(), SectorBuf::len());
+
+        if self.position >= self.size {
+            return Err(fs::Error::EndOfFile);
+        }
+
+        match self.sector(self.position)? {
+            Some(sector) => self
+                .filesystem
+                .read(sector, data)
+                .map_err(fs::Error::Block)?,
+            None => data.fill(0),
+        }
+
+        // After a seek the position may be part way into the sector
+        let skip = self.position % SectorBuf::len() as u32;
+        data.copy_within(skip as usize.., 0);
+        let bytes_read = u32::min(SectorBuf::len() as u32 - skip, self.size - self.position);
+        self.position += bytes_read;
+        Ok(bytes_read)
+    }
+
+    fn seek(&mut self, position: u32) -> Result<(), fs::Error> {
+        if position > self.size {
+            return Err(fs::Error::EndOfFile);
+        }
+        self.position = position;
+        Ok(())
+    }
+
+    fn get_size(&self) -> u32 {
+        self.size
+    }
+
+    // Read contiguous blocks with as few requests as possible
+    fn load_file(&mut self, mem: &mut MemoryRegion) -> Result<(), fs::Error> {
+        let sector_size = SectorBuf::len() as u64;
+        let sectors_per_block = self.filesystem.block_size / sector_size;
+        let mut data = mem.as_bytes();
+        while data.len() >= SectorBuf::len() {
+            if self.position >= self.size {
+                return Err(fs::Error::EndOfFile);
+            }
+            if self.position as usize % SectorBuf::len() != 0 {
+                let bytes = self.read(&mut data[..SectorBuf::len()])? as usize;
+                data = &mut data[bytes..];
+                continue;
+            }
+
+            let sector = self.sector(self.position)?;
+            let logical = u64::from(self.position) / self.filesystem.block_size;
+            let sector_in_run = (logical - self.run.logical) * sectors_per_block
+                + (u64::from(self.position) % self.filesystem.block_size) / sector_size;
+            let sectors = u64::min(
+                self.run.count * sectors_per_block - sector_in_run,
+                (data.len() / SectorBuf::len()) as u64,
+            );
+            let (chunk, rest) = data.split_at_mut(sectors as usize * SectorBuf::len());
+            match sector {
+                Some(sector) => self
+                    .filesystem
+                    .read_sectors(sector, chunk)
+                    .map_err(fs::Error::Block)?,
+                None => chunk.fill(0),
+            }
+            self.position = u32::try_from(u64::from(self.position) + chunk.len() as u64)
+                .unwrap_or(u32::MAX)
+                .min(self.size);
+            data = rest;
+        }
+
+        if !data.is_empty() {
+            let mut last = SectorBuf::new();
+            let bytes = self.read(last.as_mut_bytes())? as usize;
+            assert!(bytes >= data.len());
+            data.copy_from_slice(&last.as_bytes()[..data.len()]);
+        }
+        Ok(())
+    }
+}
+
+impl<'a> Directory<'a> {
+    // Returns the next entry, or EndOfFile after the last one
+    pub fn next_entry(&mut self) -> Result<DirectoryEntry, Error> {
+        loop {
+            if self.offset >= self.data.size {
+                return Err(Error::EndOfFile);
+            }
+
+            // inode, record length, name length and type
+            let mut header = [0u8; 8];
+            self.data.read_at(self.offset, &mut header)?;
+            let inode = le32(&header, 0);
+            let record_length = u32::from(le16(&header, 4));
+            let name_length = usize::from(header[6]);
+
+            // Entries never cross a block boundary
+            let block_size = self.data.filesystem.block_size as u32;
+            if record_length < 8
+                || record_length % 4 != 0
+                || self.offset % block_size + record_length > block_size
+                || name_length + 8 > record_length as usize
+            {
+                return Err(Error::Corrupt);
+            }
+
+            let offset = self.offset;
+            self.offset += record_length;
+            // Unused space, including checksums and hashed index nodes
+            if inode == 0 {
+                continue;
+            }
+
+            let mut name = [0u8; 255];
+            self.data.read_at(offset + 8, &mut name[..name_length])?;
+            let file_type = if self.data.filesystem.incompat & INCOMPAT_FILETYPE != 0 {
+                header[7]
+            } else {
+                // Without the filetype feature this is the high byte of the
+                // name length so the inode has to be checked
+                match self.data.filesystem.inode(inode)?.file_type() {
+                    MODE_FILE => DIRENT_TYPE_FILE,
+                    MODE_DIRECTORY => DIRENT_TYPE_DIRECTORY,
+                    MODE_SYMLINK => DIRENT_TYPE_SYMLINK,
+                    _ => 0,
+                }
+            };
+
+            return Ok(DirectoryEntry {
+                name,
+                inode,
+                file_type,
+            });
+        }
+    }
+
+    pub fn has_next(&mut self) -> Result<bool, Error> {
+        let offset = self.offset;
+        let result = match self.next_entry() {
+            Ok(_) => Ok(true),
+            Err(Error::EndOfFile) => Ok(false),
+            Err(e) => Err(e),
+        };
+        self.offset = offset;
+        result
+    }
+
+    // Like next_entry but skipping symlinks and special files
+    pub fn next_node(&mut self) -> Result<(Node<'a>, [u8; 255]), Error> {
+        let filesystem = self.data.filesystem;
+        loop {
+            let de = self.next_entry()?;
+            match de.file_type {
+                DIRENT_TYPE_FILE => {
+                    let inode = filesystem.inode(de.inode)?;
+                    return Ok((Node::File(filesystem.file(inode)?), de.name));
+                }
+                DIRENT_TYPE_DIRECTORY => {
+                    let inode = filesystem.inode(de.inode)?;
+                    let directory = filesystem.directory(de.inode, inode)?;
+                    return Ok((Node::Directory(directory), de.name));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Open the file or directory at `path`, relative to this directory
+    /// unless it is absolute
+    pub fn open(&self, path: &str) -> Result<Node<'a>, Error> {
+        let start = if fat::is_absolute_path(path) {
+            ROOT_INODE
+        } else {
+            self.number
+        };
+        self.data.filesystem.open_from(start, path)
+    }
+
+    fn find(&mut self, name: &[u8]) -> Result<DirectoryEntry, Error> {
+        loop {
+            match self.next_entry() {
+                Ok(de) if de.name.split(|c| *c == 0).next() == Some(name) => return Ok(de),
+                Ok(_) => {}
+                Err(Error::EndOfFile) => return Err(Error::NotFound),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+impl<'a> SectorRead for Filesystem<'a> {
+    fn read(&self, sector: u64, data: &mut [u8]) -> Result<(), BlockError> {
+        if self.start + sector > self.last {
+            Err(BlockError::BlockIO)
+        } else {
+            self.device.read(self.start + sector, data)
+        }
+    }
+
+    fn read_sectors(&self, sector: u64, data: &mut [u8]) -> Result<(), BlockError> {
+        let count = (data.len() / SectorBuf::len()) as u64;
+        if count == 0 || self.start + sector + count - 1 > self.last {
+            Err(BlockError::BlockIO)
+        } else {
+            self.device.read_sectors(self.start + sector, data)
+        }
+    }
+}
+
+impl<'a> Filesystem<'a> {
+    pub fn new(device: &'a dyn SectorRead, start: u64, last: u64) -> Filesystem<'a> {
+        Filesystem {
+            device,
+            start,
+            last,
+            block_size: 0,
+            inode_size: 0,
+            blocks_per_group: 0,
+            inodes_per_group: 0,
+            first_data_block: 0,
+            desc_size: 0,
+            incompat: 0,
+            ro_compat: 0,
+            first_meta_bg: 0,
+        }
+    }
+
+    pub fn init(&mut self) -> Result<(), Error> {
+        let mut sb = [0u8; 1024];
+        self.read_bytes(SUPERBLOCK_OFFSET, &mut sb)?;
+
+        if le16(&sb, 56) != SUPERBLOCK_MAGIC {
+            return Err(Error::NotExt4);
+        }
+
+        let log_block_size = le32(&sb, 24);
+        if log_block_size > 6 {
+            return Err(Error::Unsupported);
+        }
+        self.block_size = 1024 << log_block_size;
+        self.first_data_block = u64::from(le32(&sb, 20));
+        self.blocks_per_group = u64::from(le32(&sb, 32));
+        self.inodes_per_group = le32(&sb, 40);
+
+        // Revision 0 filesystems have fixed size inodes and no features
+        if le32(&sb, 76) == 0 {
+            self.inode_size = 128;
+        } else {
+            self.inode_size = u64::from(le16(&sb, 88));
+            self.incompat = le32(&sb, 96);
+            self.ro_compat = le32(&sb, 100);
+            self.first_meta_bg = u64::from(le32(&sb, 260));
+        }
+
+        if self.incompat & !INCOMPAT_SUPPORTED != 0 {
+            return Err(Error::Unsupported);
+        }
+        if self.inodes_per_group == 0 || self.blocks_per_group == 0 || self.inode_size < 128 {
+            return Err(Error::Corrupt);
+        }
+
+        self.desc_size = if self.incompat & INCOMPAT_64BIT != 0 {
+            u64::from(le16(&sb, 254))
+        } else {
+            32
+        };
+        // As checked by the kernel, so descriptors never straddle blocks
+        if self.desc_size < 32
+            || self.desc_size > MAX_DESC_SIZE
+            || self.desc_size > self.block_size
+            || !self.desc_size.is_power_of_two()
+        {
+            return Err(Error::Corrupt);
+        }
+
+        Ok(())
+    }
+
+    fn read_sector(&self, sector: u64, buf: &mut SectorBuf) -> Result<(), Error> {
+        self.read(sector, buf.as_mut_bytes()).map_err(Error::Block)
+    }
+
+    // Read `data.len()` bytes at byte `offset` into the filesystem
+    fn read_bytes(&self, offset: u64, data: &mut [u8]) -> Result<(), Error> {
+        let sector_size = SectorBuf::len() as u64;
+        let mut buf = SectorBuf::new();
+        let mut done = 0;
+        while done < data.len() {
+            let position = offset + done as u64;
+            let start = (position % sector_size) as usize;
+            let count = usize::min(SectorBuf::len() - start, data.len() - done);
+            self.read_sector(position / sector_size, &mut buf)?;
+            data[done..done + count].copy_from_slice(&buf.as_bytes()[start..start + count]);
+            done += count;
+        }
+        Ok(())
+    }
+
+    fn read_block_u32(&self, block: u64, index: u64) -> Result<u32, Error> {
+        let mut data = [0u8; 4];
+        self.read_bytes(block * self.block_size + index * 4, &mut data)?;
+        Ok(u32::from_le_bytes(data))
+    }
+
+    // Whether block group `group` starts with a backup superblock
+    fn has_superblock(&self, group: u64) -> bool {
+        if self.ro_compat & RO_COMPAT_SPARSE_SUPER == 0 || group <= 1 {
+            return true;
+        }
+        [3, 5, 7].iter().any(|base| {
+            let mut power = *base;
+            while power < group {
+                power *= base;
+            }
+            power == group
+        })
+    }
+
+    // Byte offset of the descriptor for block group `group`
+    fn group_descriptor_offset(&self, group: u64) -> u64 {
+        let per_block = self.block_size / self.desc_size;
+        let meta_group = group / per_block;
+        let block = if self.incompat & INCOMPAT_META_BG != 0 && meta_group >= self.first_meta_bg {
+            // Each meta group keeps its descriptors in its first group
+            let first_group = meta_group * per_block;
+            self.first_data_block
+                + first_group * self.blocks_per_group
+                + u64::from(self.has_superblock(first_group))
+        } else {
+            self.first_data_block + 1 + meta_group
+        };
+        block * self.block_size + (group % per_block) * self.desc_size
+    }
+
+    fn inode(&self, number: u32) -> Result<Inode, Error> {
+        if number == 0 {
+            return Err(Error::Corrupt);
+        }
+        let group = u64::from((number - 1) / self.inodes_per_group);
+        let index = u64::from((number - 1) % self.inodes_per_group);
+
+        let mut desc = [0u8; 64];
+        let desc = &mut desc[..usize::min(self.desc_size as usize, 64)];
+        self.read_bytes(self.group_descriptor_offset(group), desc)?;
+        let mut inode_table = u64::from(le32(desc, 8));
+        if desc.len() >= 64 {
+            inode_table |= u64::from(le32(desc, 40)) << 32;
+        }
+
+        let mut data = [0u8; 128];
+        self.read_bytes(
+            inode_table * self.block_size + index * self.inode_size,
+            &mut data,
+        )?;
+        Ok(Inode {
+            mode: le16(&data, 0),
+            size: u64::from(le32(&data, 4)) | u64::from(le32(&data, 108)) << 32,
+            flags: le32(&data, 32),
+            block: data[40..100].try_into().unwrap(),
+        })
+    }
+
+    // Map logical block `logical` of the file to a run of blocks on disk
+    fn map_block(&self, inode: &Inode, logical: u64) -> Result<Run, Error> {
+        if inode.flags & INODE_FLAG_INLINE_DATA != 0 {
+            return Err(Error::Unsupported);
+        }
+        if inode.flags & INODE_FLAG_EXTENTS != 0 {
+            self.map_extent(inode, logical)
+        } else {
+            self.map_indirect(inode, logical)
+        }
+    }
+
+    fn map_extent(&self, inode: &Inode, logical: u64) -> Result<Run, Error> {
+        // Extent tree nodes are a header followed by 12 byte entries, the
+        // root is in i_block and the rest take a block each
+        let mut node = None;
+        let read = |node: Option<u64>, offset: u64, data: &mut [u8; 12]| match node {
+            None => {
+                let entry = inode.block.get(offset as usize..offset as usize + 12);
+                data.copy_from_slice(entry.ok_or(Error::Corrupt)?);
+                Ok(())
+            }
+            Some(block) => {
+                if offset + 12 > self.block_size {
+                    return Err(Error::Corrupt);
+                }
+                self.read_bytes(block * self.block_size + offset, data)
+            }
+        };
+
+        for _ in 0..=EXTENT_MAX_DEPTH {
+            let mut header = [0u8; 12];
+            read(node, 0, &mut header)?;
+            if le16(&header, 0) != EXTENT_MAGIC || le16(&header, 6) > EXTENT_MAX_DEPTH {
+                return Err(Error::Corrupt);
+            }
+            let entries = u64::from(le16(&header, 2));
+
+            let mut entry = [0u8; 12];
+            if le16(&header, 6) == 0 {
+                for i in 0..entries {
+                    read(node, 12 + i * 12, &mut entry)?;
+                    let first = u64::from(le32(&entry, 0));
+                    let length = le16(&entry, 4);
+                    let (length, initialized) = if length > EXTENT_MAX_INITIALIZED {
+                        (u64::from(length - EXTENT_MAX_INITIALIZED), false)
+                    } else {
+                        (u64::from(length), true)
+                    };
+                    if logical < first {
+                        break;
+                    }
+                    if logical < first + length {
+                        let start = u64::from(le16(&entry, 6)) << 32 | u64::from(le32(&entry, 8));
+                        return Ok(Run {
+                            logical,
+                            // Preallocated blocks read as zeroes
+                            physical: if initialized {
+                                start + (logical - first)
+                            } else {
+                                0
+                            },
+                            count: first + length - logical,
+                        });
+                    }
+                }
+                return Ok(Run {
+                    logical,
+                    physical: 0,
+                    count: 1,
+                });
+            }
+
+            // Descend into the last index covering the block
+            let mut child = None;
+            for i in 0..entries {
+                read(node, 12 + i * 12, &mut entry)?;
+                if u64::from(le32(&entry, 0)) > logical {
+                    break;
+                }
+                child = Some(u64::from(le16(&entry, 8)) << 32 | u64::from(le32(&entry, 4)));
+            }
+            match child {
+                Some(child) => node = Some(child),
+                None => {
+                    return Ok(Run {
+                        logical,
+                        physical: 0,
+                        count: 1,
+                    })
+                }
+            }
+        }
+        Err(Error::Corrupt)
+    }
+
+    fn map_indirect(&self, inode: &Inode, logical: u64) -> Result<Run, Error> {
+        let per_block = self.block_size / 4;
+        let direct = |i: u64| u64::from(le32(&inode.block, i as usize * 4));
+
+        if logical < DIRECT_BLOCKS {
+            let mut pointers = [0u64; DIRECT_BLOCKS as usize];
+            for (i, p) in pointers.iter_mut().enumerate() {
+                *p = direct(i as u64);
+            }
+            return Ok(run_from_pointers(logical, &pointers[logical as usize..]));
+        }
+
+        // Find which indirect tree holds the block and its index within it
+        let mut index = logical - DIRECT_BLOCKS;
+        let mut levels = 1;
+        let mut span = per_block;
+        while index >= span {
+            index -= span;
+            levels += 1;
+            span *= per_block;
+            if levels > 3 {
+                return Err(Error::Corrupt);
+            }
+        }
+
+        let mut block = direct(DIRECT_BLOCKS + levels - 1);
+        for level in (0..levels).rev() {
+            if block == 0 {
+                return Ok(Run {
+                    logical,
+                    physical: 0,
+                    count: 1,
+                });
+            }
+            let divisor = per_block.pow(level as u32);
+            let entry = index / divisor;
+            index %= divisor;
+            if level > 0 {
+                block = u64::from(self.read_block_u32(block, entry)?);
+                continue;
+            }
+
+            // Only look as far as the end of the sector holding the entry
+            let mut pointers = [0u64; 128];
+            let in_sector = (SectorBuf::len() as u64 - (entry * 4) % SectorBuf::len() as u64) / 4;
+            let count = u64::min(in_sector, per_block - entry) as usize;
+            let mut data = [0u8; 512];
+            self.read_bytes(block * self.block_size + entry * 4, &mut data[..count * 4])?;
+            for (i, p) in pointers[..count].iter_mut().enumerate() {
+                *p = u64::from(le32(&data, i * 4));
+            }
+            return Ok(run_from_pointers(logical, &pointers[..count]));
+        }
+        Err(Error::Corrupt)
+    }
+
+    fn file(&self, inode: Inode) -> Result<File, Error> {
+        Ok(File {
+            filesystem: self,
+            inode,
+            size: u32::try_from(inode.size).map_err(|_| Error::Unsupported)?,
+            position: 0,
+            run: Run::default(),
+        })
+    }
+
+    fn directory(&self, number: u32, inode: Inode) -> Result<Directory, Error> {
+        Ok(Directory {
+            data: self.file(inode)?,
+            number,
+            offset: 0,
+        })
+    }
+
+    pub fn root(&self) -> Result<Directory, Error> {
+        self.directory(ROOT_INODE, self.inode(ROOT_INODE)?)
+    }
+
+    // Read the target of a symlink into `target`, returning its length
+    fn read_link(&self, inode: Inode, target: &mut [u8]) -> Result<usize, Error> {
+        let len = usize::try_from(inode.size).map_err(|_| Error::PathTooLong)?;
+        if len > target.len() {
+            return Err(Error::PathTooLong);
+        }
+        if inode.size < FAST_SYMLINK_SIZE && inode.flags & INODE_FLAG_EXTENTS == 0 {
+            target[..len].copy_from_slice(&inode.block[..len]);
+        } else {
+            self.file(inode)?.read_at(0, &mut target[..len])?;
+        }
+        Ok(len)
+    }
+
+    /// Open the file or directory at `path`, following symlinks
+    pub fn open(&self, path: &str) -> Result<Node, Error> {
+        self.open_from(ROOT_INODE, path)
+    }
+
+    // Resolve `path` starting from the directory `start`
+    fn open_from(&self, start: u32, path: &str) -> Result<Node, Error> {
+        let mut path_buf = [0u8; MAX_PATH_LENGTH];
+        let mut len = path.len();
+        if len > path_buf.len() {
+            return Err(Error::PathTooLong);
+        }
+        path_buf[..len].copy_from_slice(path.as_bytes());
+
+        let mut current = start;
+        let mut follows = 0;
+        let mut position = 0;
+        loop {
+            let rest = &path_buf[position..len];
+            let rest = rest
+                .iter()
+                .position(|c| *c != b'/' && *c != b'\\')
+                .map_or(&rest[rest.len()..], |start| &rest[start..]);
+            if rest.is_empty() {
+                break;
+            }
+            let name_length = rest
+                .iter()
+                .position(|c| *c == b'/' || *c == b'\\')
+                .unwrap_or(rest.len());
+            position = len - rest.len() + name_length;
+            let name = &rest[..name_length];
+            if name == b"." {
+                continue;
+            }
+
+            let inode = self.inode(current)?;
+            if inode.file_type() != MODE_DIRECTORY {
+                return Err(Error::NotFound);
+            }
+            let de = self.directory(current, inode)?.find(name)?;
+            let inode = self.inode(de.inode)?;
+            if inode.file_type() != MODE_SYMLINK {
+                current = de.inode;
+                continue;
+            }
+
+            // Replace the link in the path with its target, which is
+            // resolved relative to the directory holding the link (still
+            // `current`) unless it is absolute
+            follows += 1;
+            if follows > MAX_SYMLINK_FOLLOWS {
+                return Err(Error::SymlinkLoop);
+            }
+            let mut target = [0u8; MAX_PATH_LENGTH];
+            let target_length = self.read_link(inode, &mut target)?;
+            let remaining = len - position;
+            if target_length + 1 + remaining > path_buf.len() {
+                return Err(Error::PathTooLong);
+            }
+            path_buf.copy_within(position..len, target_length + 1);
+            path_buf[..target_length].copy_from_slice(&target[..target_length]);
+            path_buf[target_length] = b'/';
+            len = target_length + 1 + remaining;
+            position = 0;
+            if target.first() == Some(&b'/') {
+                current = ROOT_INODE;
+            }
+        }
+
+        let inode = self.inode(current)?;
+        match inode.file_type() {
+            MODE_DIRECTORY => Ok(Node::Directory(self.directory(current, inode)?)),
+            MODE_FILE => Ok(Node::File(self.file(inode)?)),
+            _ => Err(Error::Unsupported),
+        }
+    }
+}
+
+impl<'a> fs::Filesystem for Filesystem<'a> {
+    fn root(&self) -> Result<fs::Node<'_>, fs::Error> {
+        Ok(fs::Node::Ext4(Node::Directory(self.root()?)))
+    }
+
+    fn open(&self, path: &str) -> Result<fs::Node<'_>, fs::Error> {
+        Ok(fs::Node::Ext4(self.open(path)?))
+    }
+}
+
+// Build a run from a list of block numbers starting at `logical`
+fn run_from_pointers(logical: u64, pointers: &[u64]) -> Run {
+    let first = pointers[0];
+    let count = pointers
+        .iter()
+        .enumerate()
+        .take_while(|(i, p)| {
+            if first == 0 {
+                **p == 0
+            } else {
+                **p == first + *i as u64
+            }
+        })
+        .count();
+    Run {
+        logical,
+        physical: first,
+        count: count as u64,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use crate::block::{SectorBuf, SectorRead, SectorWrite};
+    use crate::fs::Read;
+    use crate::part::tests::*;
+
+    fn ext_test_image_paths() -> Vec<PathBuf> {
+        let mut workload_path = dirs::home_dir().unwrap();
+        workload_path.push("workloads");
+        ["ext2.img", "ext4.img"]
+            .iter()
+            .map(|image| workload_path.join(image))
+            .collect()
+    }
+
+    fn read_all(f: &mut super::File) -> Vec<u8> {
+        let mut contents = Vec::new();
+        loop {
+            let mut data = SectorBuf::new();
+            match f.read(data.as_mut_bytes()) {
+                Ok(bytes) => contents.extend_from_slice(&data.as_bytes()[..bytes as usize]),
+                Err(crate::fs::Error::EndOfFile) => break,
+                Err(e) => panic!("{e:?}"),
+            }
+        }
+        contents
+    }
+
+    fn load_all(f: &mut super::File) -> Vec<u8> {
+        let data = vec![0u8; f.get_size() as usize];
+        let mut region = crate::mem::MemoryRegion::from_bytes(&data);
+        f.load_file(&mut region).expect("expect load to work");
+        data
+    }
+
+    #[test]
+    fn test_ext4_file_reads() {
+        for image in &ext_test_image_paths() {
+            let d = FakeDisk::new(image);
+            let mut fs = super::Filesystem::new(&d, 0, d.len() / SectorBuf::len() as u64);
+            fs.init().expect("Error initialising filesystem");
+
+            for n in 9..16 {
+                for o in 0..2 {
+                    let v = 2u32.pow(n) - o;
+                    let mut f: super::File = fs
+                        .open(&format!("/a/b/c/{v}"))
+                        .expect("Error opening file")
+                        .try_into()
+                        .unwrap();
+                    assert_eq!(f.get_size(), v);
+                    let contents = read_all(&mut f);
+                    assert_eq!(contents.len(), v as usize);
+                    assert!(contents.iter().all(|c| *c == b'a'));
+                }
+            }
+
+            let expected: Vec<u8> = (1..=200000)
+                .flat_map(|n| format!("{n}\n").into_bytes())
+                .take(1048576)
+                .collect();
+            let mut f: super::File = fs.open("/large").unwrap().try_into().unwrap();
+            assert_eq!(read_all(&mut f), expected);
+            f.seek(0).unwrap();
+            assert_eq!(load_all(&mut f), expected);
+
+            // Unaligned seeks
+            f.seek(1000).unwrap();
+            let mut data = SectorBuf::new();
+            assert_eq!(f.read(data.as_mut_bytes()).unwrap(), 24);
+            assert_eq!(data.as_bytes()[..24], expected[1000..1024]);
+            f.seek(1048570).unwrap();
+            assert_eq!(f.read(data.as_mut_bytes()).unwrap(), 6);
+
+            // Holes read as zeroes
+            let mut f: super::File = fs.open("/sparse").unwrap().try_into().unwrap();
+            let mut expected = vec![0u8; 1048576];
+            expected.extend_from_slice(b"end");
+            assert_eq!(read_all(&mut f), expected);
+            f.seek(0).unwrap();
+            assert_eq!(load_all(&mut f), expected);
+        }
+    }
+
+    #[test]
+    fn test_ext4_open() {
+        for image in &ext_test_image_paths() {
+            let d = FakeDisk::new(image);
+            let mut fs = super::Filesystem::new(&d, 0, d.len() / SectorBuf::len() as u64);
+            fs.init().expect("Error initialising filesystem");
+
+            let size = |path| match fs.open(path) {
+                Ok(super::Node::File(f)) => Ok(f.get_size()),
+                Ok(super::Node::Directory(_)) => Ok(0),
+                Err(e) => Err(e),
+            };
+            assert_eq!(size("/a/b/c/512"), Ok(512));
+            assert_eq!(size("a\\b\\c\\1024"), Ok(1024));
+            assert_eq!(size("/a/./b/../b//c/2048"), Ok(2048));
+            assert_eq!(size("/link"), Ok(512));
+            assert_eq!(size("/long-link"), Ok(1024));
+            assert_eq!(size("/absolute-link/c/4096"), Ok(4096));
+            assert_eq!(size("/a"), Ok(0));
+            assert_eq!(size("/"), Ok(0));
+            assert_eq!(size("/A/b/c/512"), Err(super::Error::NotFound));
+            assert_eq!(size("/a/b/c/512/x"), Err(super::Error::NotFound));
+            assert_eq!(size("/loop"), Err(super::Error::SymlinkLoop));
+        }
+    }
+
+    #[test]
+    fn test_ext4_directory() {
+        for image in &ext_test_image_paths() {
+            let d = FakeDisk::new(image);
+            let mut fs = super::Filesystem::new(&d, 0, d.len() / SectorBuf::len() as u64);
+            fs.init().expect("Error initialising filesystem");
+
+            let mut dir: super::Directory = fs.open("/largedir").unwrap().try_into().unwrap();
+            let mut names = Vec::new();
+            loop {
+                match dir.next_entry() {
+                    Ok(de) => {
+                        let name = crate::common::ascii_strip(&de.name).to_string();
+                        assert_eq!(
+                            de.file_type == super::DIRENT_TYPE_FILE,
+                            name != "." && name != ".."
+                        );
+                        names.push(name);
+                    }
+                    Err(super::Error::EndOfFile) => break,
+                    Err(e) => panic!("{e:?}"),
+                }
+            }
+            names.sort();
+            let mut expected: Vec<String> = (0..=100).map(|n| n.to_string()).collect();
+            expected.extend([".".to_string(), "..".to_string()]);
+            expected.sort();
+            assert_eq!(names, expected);
+        }
+    }
+
+    #[test]
+    fn test_ext4_bad_superblock() {
+        let mut workload_path = dirs::home_dir().unwrap();
+        workload_path.push("workloads");
+        // Superblock fields as (offset, bytes) patched into a good image
+        let patches: [&[(usize, &[u8])]; 4] = [
+            &[(32, &[0; 4])],
+            &[(96, &[0x80]), (254, &[16, 0])],
+            &[(96, &[0x80]), (254, &[48, 0])],
+            &[(96, &[0x80]), (254, &[0, 8])],
+        ];
+        for patch in patches {
+            let d = RamDisk::new(&workload_path.join("ext4.img"));
+            let mut sb = SectorBuf::new();
+            d.read(2, sb.as_mut_bytes()).unwrap();
+            for (offset, bytes) in patch {
+                let field = &mut sb.as_mut_bytes()[*offset..*offset + bytes.len()];
+                // Keep the other feature flags
+                if *offset == 96 {
+                    field[0] |= bytes[0];
+                } else {
+                    field.copy_from_slice(bytes);
+                }
+            }
+            d.write(2, sb.as_mut_bytes()).unwrap();
+            let mut fs = super::Filesystem::new(&d, 0, d.len() / SectorBuf::len() as u64);
+            assert_eq!(fs.init(), Err(super::Error::Corrupt));
+        }
+    }
+
+    #[test]
+    fn test_ext4_not_ext4() {
+        let mut workload_path = dirs::home_dir().unwrap();
+        workload_path.push("workloads");
+        let d = FakeDisk::new(&workload_path.join("fat16.img"));
+        let mut fs = super::Filesystem::new(&d, 0, d.len() / SectorBuf::len() as u64);
+        assert_eq!(fs.init(), Err(super::Error::NotExt4));
+    }
+}
@@ -1,8 +1,11 @@
 // SPDX-License-Identifier: Apache-2.0
 // Copyright © 2019 Intel Corporation
 
+use core::cell::Cell;
+
 use crate::{
-    block::{Error as BlockError, SectorBuf, SectorRead},
+    block::{BlockDevice, SectorBuf, SectorRead, SectorWrite},
+    fs::{self, Error, Read},
     mem::MemoryRegion,
 };
 
@@ -31,7 +34,7 @@ struct Fat32Header {
     _flags: u16,
     _version: u16,
     root_cluster: u32,
-    _fsinfo_sector: u16,
+    fsinfo_sector: u16,
     _backup_boot_sector: u16,
     _reserved: [u8; 12],
     _drive_no: u8,
@@ -43,12 +46,18 @@ struct Fat32Header {
 }
 
 #[repr(C, packed)]
+#[derive(Clone, Copy)]
 struct FatDirectory {
     name: [u8; 11],
     flags: u8,
-    _unused1: [u8; 8],
+    _nt_flags: u8,
+    _create_time_tenths: u8,
+    create_time: u16,
+    create_date: u16,
+    access_date: u16,
     cluster_high: u16,
-    _unused2: [u8; 4],
+    modify_time: u16,
+    modify_date: u16,
     cluster_low: u16,
     size: u32,
 }
@@ -65,12 +74,50 @@ struct FatLongNameEntry {
     name3: [u16; 2],
 }
 
+const ATTR_LONG_NAME: u8 = 0x0f;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+
+// First byte of the name of unused directory entries
+const ENTRY_FREE: u8 = 0xe5;
+const ENTRY_END: u8 = 0x00;
+
+const ENTRIES_PER_SECTOR: usize = SectorBuf::len() / 32;
+
+// 1980-01-01, the earliest date a directory entry can hold
+const DOS_EPOCH_DATE: u16 = (1 << 5) | 1;
+
+// Long name entries hold 13 characters each, the last one (which comes first
+// on disk) has this bit set in its sequence number
+const LFN_CHARS: usize = 13;
+const LFN_LAST: u8 = 0x40;
+const MAX_NAME_LENGTH: usize = 255;
+
+// Position of a 32 byte directory entry slot
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct Slot {
+    cluster: Option<u32>,
+    sector: u32,
+    offset: usize,
+}
+
+// Where a directory entry lives: the first cluster of its parent directory
+// (None for the FAT12/16 root directory), the first slot it uses (its first
+// long name entry, if any) and the slot of the entry itself
+#[derive(Clone, Copy, Debug, Default)]
+struct EntryLocation {
+    parent: Option<u32>,
+    first: Slot,
+    entry: Slot,
+}
+
 pub struct DirectoryEntry {
     name: [u8; 11],
     long_name: [u8; 255],
     file_type: FileType,
     size: u32,
     cluster: u32,
+    location: EntryLocation,
 }
 
 impl Default for DirectoryEntry {
@@ -81,20 +128,11 @@ impl Default for DirectoryEntry {
             file_type: FileType::File,
             size: 0,
             cluster: 0,
+            location: EntryLocation::default(),
         }
     }
 }
 
-impl DirectoryEntry {
-    pub fn long_name(&self) -> [u8; 255] {
-        self.long_name
-    }
-
-    pub fn is_file(&self) -> bool {
-        matches!(self.file_type, FileType::File)
-    }
-}
-
 #[derive(Debug, PartialEq, Eq)]
 enum FatType {
     Unknown,
@@ -104,7 +142,7 @@ enum FatType {
 }
 
 pub struct Filesystem<'a> {
-    device: &'a dyn SectorRead,
+    device: &'a dyn BlockDevice,
     start: u64,
     last: u64,
     bytes_per_sector: u32,
@@ -120,17 +158,10 @@ pub struct Filesystem<'a> {
     data_sector_count: u32,
     #[allow(unused)]
     data_cluster_count: u32,
-    root_cluster: u32, // FAT32 only
-}
-
-#[derive(Debug, PartialEq, Eq)]
-pub enum Error {
-    Block(BlockError),
-    Unsupported,
-    NotFound,
-    EndOfFile,
-    InvalidOffset,
-    NodeTypeMismatch,
+    root_cluster: u32,    // FAT32 only
+    fsinfo_sector: u32,   // FAT32 only
+    next_free: Cell<u32>, // Where to start looking for a free cluster
+    free_count_invalidated: Cell<bool>,
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -178,6 +209,54 @@ impl<'a> TryFrom<Node<'a>> for Directory<'a> {
     }
 }
 
+impl<'a> Node<'a> {
+    fn entry(&self) -> Option<EntryLocation> {
+        match self {
+            Self::File(file) => file.entry,
+            Self::Directory(directory) => directory.entry,
+        }
+    }
+
+    /// Remove the file or empty directory and free its clusters
+    pub fn delete(&self) -> Result<(), Error> {
+        let location = self.entry().ok_or(Error::Unsupported)?;
+        let (filesystem, cluster) = match self {
+            Self::File(file) => (file.filesystem, file.start_cluster),
+            Self::Directory(directory) => {
+                let mut dir = *directory;
+                dir.seek(0)?;
+                loop {
+                    match dir.next_entry() {
+                        Ok(de) if de.name[0] == b'.' => {}
+                        Ok(_) => return Err(Error::DirectoryNotEmpty),
+                        Err(Error::EndOfFile) => break,
+                        Err(e) => return Err(e),
+                    }
+                }
+                (directory.filesystem, directory.first_cluster.unwrap_or(0))
+            }
+        };
+
+        filesystem.remove_entry(&location)?;
+        filesystem.free_chain(cluster)
+    }
+
+    /// Rename or move the file or directory to `path`, relative to its
+    /// current directory unless it is absolute
+    pub fn rename(&mut self, path: &str) -> Result<(), Error> {
+        let location = self.entry().ok_or(Error::Unsupported)?;
+        match self {
+            Self::File(file) => {
+                file.entry = Some(file.filesystem.rename_entry(&location, path)?);
+            }
+            Self::Directory(directory) => {
+                directory.entry = Some(directory.filesystem.rename_entry(&location, path)?);
+            }
+        }
+        Ok(())
+    }
+}
+
 pub struct File<'a> {
     filesystem: &'a Filesystem<'a>,
     start_cluster: u32,
@@ -185,15 +264,18 @@ pub struct File<'a> {
     sector_offset: u64,
     size: u32,
     position: u32,
+    entry: Option<EntryLocation>,
 }
 
 #[derive(Copy, Clone)]
 pub struct Directory<'a> {
     filesystem: &'a Filesystem<'a>,
+    first_cluster: Option<u32>,
     cluster: Option<u32>,
     first_sector: u32,
     sector: u32,
     offset: usize,
+    entry: Option<EntryLocation>, // None for the root directory
 }
 
 fn ucs2_to_ascii(input: &[u16]) -> [u8; 255] {
@@ -278,30 +360,40 @@ impl<'a> Read for Node<'a> {
 }
 
 impl<'a> Directory<'a> {
-    fn read_next(&mut self, data: &mut [u8]) -> Result<(), Error> {
-        assert_eq!(data.len(), SectorBuf::len());
-
-        let sector = if self.cluster.is_some() {
-            if self.sector >= self.filesystem.sectors_per_cluster {
-                match self.filesystem.next_cluster(self.cluster.unwrap()) {
-                    Ok(new_cluster) => {
-                        self.cluster = Some(new_cluster);
-                        self.sector = 0;
-                        self.offset = 0;
-                    }
-                    Err(e) => {
-                        return Err(e);
-                    }
+    // Follow the cluster chain once the end of the current cluster has been
+    // reached, growing the directory if `extend` is set, and return the
+    // sector holding the current slot.
+    fn current_sector(&mut self, extend: bool) -> Result<u32, Error> {
+        let filesystem = self.filesystem;
+        match self.cluster {
+            Some(mut cluster) => {
+                if self.sector >= filesystem.sectors_per_cluster {
+                    cluster = match filesystem.next_cluster(cluster) {
+                        Err(Error::EndOfFile) if extend => {
+                            filesystem.allocate_cluster(Some(cluster), true)?
+                        }
+                        result => result?,
+                    };
+                    self.cluster = Some(cluster);
+                    self.sector = 0;
+                    self.offset = 0;
                 }
+                Ok(self.sector + filesystem.first_sector_of_cluster(cluster))
             }
-            self.sector
-                + self
-                    .filesystem
-                    .first_sector_of_cluster(self.cluster.unwrap())
-        } else {
-            self.sector
-        };
+            // The FAT12/16 root directory has a fixed size
+            None if self.sector >= filesystem.first_data_sector => Err(if extend {
+                Error::NoSpace
+            } else {
+                Error::EndOfFile
+            }),
+            None => Ok(self.sector),
+        }
+    }
 
+    fn read_next(&mut self, data: &mut [u8]) -> Result<(), Error> {
+        assert_eq!(data.len(), SectorBuf::len());
+
+        let sector = self.current_sector(false)?;
         match self.filesystem.read(u64::from(sector), data) {
             Ok(_) => {}
             Err(e) => return Err(Error::Block(e)),
@@ -310,12 +402,29 @@ impl<'a> Directory<'a> {
         Ok(())
     }
 
+    // Returns the slot at the current position and then moves to the next one
+    fn next_slot(&mut self, extend: bool) -> Result<Slot, Error> {
+        self.current_sector(extend)?;
+        let slot = Slot {
+            cluster: self.cluster,
+            sector: self.sector,
+            offset: self.offset,
+        };
+        self.offset += 1;
+        if self.offset == ENTRIES_PER_SECTOR {
+            self.sector += 1;
+            self.offset = 0;
+        }
+        Ok(slot)
+    }
+
     // Checks if there are any other entries.
     pub fn has_next(&mut self) -> Result<bool, Error> {
         let mut data = SectorBuf::new();
 
         match self.read_next(data.as_mut_bytes()) {
             Ok(_) => {}
+            Err(Error::EndOfFile) => return Ok(false),
             Err(e) => {
                 return Err(e);
             }
@@ -331,7 +440,7 @@ impl<'a> Directory<'a> {
         let d = &dirs[self.offset];
 
         // Last entry
-        if d.name[0] == 0x0 {
+        if d.name[0] == ENTRY_END {
             return Ok(false);
         }
 
@@ -342,6 +451,7 @@ impl<'a> Directory<'a> {
     // this is the last entry
     pub fn next_entry(&mut self) -> Result<DirectoryEntry, Error> {
         let mut long_entry = [0u16; 260];
+        let mut first_slot = None;
         loop {
             let mut data = SectorBuf::new();
 
@@ -370,16 +480,26 @@ impl<'a> Directory<'a> {
 
             for i in self.offset..dirs.len() {
                 let d = &dirs[i];
+                let slot = Slot {
+                    cluster: self.cluster,
+                    sector: self.sector,
+                    offset: i,
+                };
                 // Last entry
-                if d.name[0] == 0x0 {
+                if d.name[0] == ENTRY_END {
                     return Err(Error::EndOfFile);
                 }
                 // Directory unused
-                if d.name[0] == 0xe5 {
+                if d.name[0] == ENTRY_FREE {
+                    first_slot = None;
                     continue;
                 }
                 // LFN entry
-                if d.flags == 0x0f {
+                if d.flags == ATTR_LONG_NAME {
+                    if lfns[i].seq & LFN_LAST != 0 {
+                        first_slot = Some(slot);
+                    }
+
                     // DOS starts sequences as 1. LFN entries come in reverse order before
                     // actual entry so populate the slice using the sequence.
                     let lfn_seq = ((lfns[i].seq & 0x1f) as usize) - 1;
@@ -403,7 +523,7 @@ impl<'a> Directory<'a> {
 
                 let entry = DirectoryEntry {
                     name: d.name,
-                    file_type: if d.flags & 0x10 == 0x10 {
+                    file_type: if d.flags & ATTR_DIRECTORY == ATTR_DIRECTORY {
                         FileType::Directory
                     } else {
                         FileType::File
@@ -411,6 +531,11 @@ impl<'a> Directory<'a> {
                     cluster: (u32::from(d.cluster_high)) << 16 | u32::from(d.cluster_low),
                     size: d.size,
                     long_name: ucs2_to_ascii(&long_entry[..]),
+                    location: EntryLocation {
+                        parent: self.first_cluster,
+                        first: first_slot.unwrap_or(slot),
+                        entry: slot,
+                    },
                 };
 
                 self.offset = i + 1;
@@ -425,27 +550,19 @@ impl<'a> Directory<'a> {
         }
     }
 
-    pub fn next_node(&mut self) -> Result<(Node, [u8; 12]), Error> {
+    // Returns the next node and its long name, or its short name if it has
+    // no long name
+    pub fn next_node(&mut self) -> Result<(Node<'a>, [u8; 255]), Error> {
         let de = self.next_entry()?;
-        let mut name = [0_u8; 12];
-        name_to_str(core::str::from_utf8(&de.name).unwrap(), &mut name);
-
-        match de.file_type {
-            FileType::Directory => Ok((
-                self.filesystem.get_directory(de.cluster).unwrap().into(),
-                name,
-            )),
-            FileType::File => Ok((
-                self.filesystem
-                    .get_file(de.cluster, de.size)
-                    .unwrap()
-                    .into(),
-                name,
-            )),
+        let mut name = de.long_name;
+        if name[0] == 0 {
+            name_to_str(core::str::from_utf8(&de.name).unwrap(), &mut name[..12]);
         }
+
+        Ok((self.filesystem.get_node(&de)?, name))
     }
 
-    pub fn open(&self, path: &str) -> Result<Node, Error> {
+    pub fn open(&self, path: &str) -> Result<Node<'a>, Error> {
         let root = self.filesystem.root().unwrap();
         let dir = if is_absolute_path(path) { &root } else { self };
         self.filesystem.open_from(dir, path)
@@ -455,90 +572,386 @@ impl<'a> Directory<'a> {
         if offset != 0 {
             return Err(Error::Unsupported);
         }
+        self.cluster = self.first_cluster;
         self.sector = self.first_sector;
         self.offset = 0;
         Ok(())
     }
+
+    // Look up `name` in this directory, ignoring case
+    fn find(&self, name: &str) -> Result<Option<DirectoryEntry>, Error> {
+        let mut dir = *self;
+        dir.seek(0)?;
+        loop {
+            match dir.next_entry() {
+                Ok(de) if compare_name(name, &de) => return Ok(Some(de)),
+                Ok(_) => {}
+                Err(Error::EndOfFile) => return Ok(None),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn contains_short_name(&self, short_name: &[u8; 11]) -> Result<bool, Error> {
+        let mut dir = *self;
+        dir.seek(0)?;
+        loop {
+            match dir.next_entry() {
+                Ok(de) if de.name == *short_name => return Ok(true),
+                Ok(_) => {}
+                Err(Error::EndOfFile) => return Ok(false),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    // Split `path` into the directory holding its last component and the
+    // name of that component
+    fn parent_of<'b>(&self, path: &'b str) -> Result<(Directory<'a>, &'b str), Error> {
+        match path.rfind(['/', '\\']) {
+            None => Ok((*self, path)),
+            Some(0) => Ok((self.filesystem.root()?, &path[1..])),
+            Some(i) => Ok((self.open(&path[..i])?.try_into()?, &path[i + 1..])),
+        }
+    }
+
+    // Find `slots.len()` consecutive free slots, growing the directory if
+    // there are not enough
+    fn find_free_slots(&self, slots: &mut [Slot]) -> Result<(), Error> {
+        let mut dir = *self;
+        dir.seek(0)?;
+        let mut cache = SlotCache::new(self.filesystem);
+        let mut end = false;
+        let mut found = 0;
+        while found < slots.len() {
+            let slot = dir.next_slot(true)?;
+            // Everything after the end marker is unused
+            if !end {
+                match cache.entry(slot)?.name[0] {
+                    ENTRY_END => end = true,
+                    ENTRY_FREE => {}
+                    _ => {
+                        found = 0;
+                        continue;
+                    }
+                }
+            }
+            slots[found] = slot;
+            found += 1;
+        }
+        Ok(())
+    }
+
+    // Add an entry called `name` (with a long name if it is not a valid 8.3
+    // name) copying everything but the name from `template`
+    fn add_entry(&self, name: &str, template: &FatDirectory) -> Result<EntryLocation, Error> {
+        if !is_valid_name(name) {
+            return Err(Error::InvalidName);
+        }
+        if self.find(name)?.is_some() {
+            return Err(Error::AlreadyExists);
+        }
+        self.write_entry(name, template)
+    }
+
+    // Write the entries for `name` to free slots, without checking whether
+    // the name is already used
+    fn write_entry(&self, name: &str, template: &FatDirectory) -> Result<EntryLocation, Error> {
+        let (short_name, lfn_count) = match short_name(name) {
+            Some(short_name) => (short_name, 0),
+            None => {
+                let mut n = 1;
+                let short_name = loop {
+                    let alias = short_name_alias(name, n);
+                    if !self.contains_short_name(&alias)? {
+                        break alias;
+                    }
+                    n += 1;
+                    if n > 999_999 {
+                        return Err(Error::NoSpace);
+                    }
+                };
+                (short_name, name.len().div_ceil(LFN_CHARS))
+            }
+        };
+
+        let mut slots = [Slot::default(); MAX_NAME_LENGTH.div_ceil(LFN_CHARS) + 1];
+        let slots = &mut slots[..=lfn_count];
+        self.find_free_slots(slots)?;
+
+        let checksum = lfn_checksum(&short_name);
+        let mut cache = SlotCache::new(self.filesystem);
+        for (i, slot) in slots[..lfn_count].iter().enumerate() {
+            // Long name entries are stored last part first
+            let seq = lfn_count - i;
+            let mut chars = [0xffffu16; LFN_CHARS];
+            for (j, c) in chars.iter_mut().enumerate() {
+                let k = (seq - 1) * LFN_CHARS + j;
+                *c = match k.cmp(&name.len()) {
+                    core::cmp::Ordering::Less => u16::from(name.as_bytes()[k]),
+                    core::cmp::Ordering::Equal => 0,
+                    core::cmp::Ordering::Greater => 0xffff,
+                };
+            }
+            *cache.long_name_entry_mut(*slot)? = FatLongNameEntry {
+                seq: seq as u8 | if i == 0 { LFN_LAST } else { 0 },
+                name: chars[0..5].try_into().unwrap(),
+                _attr: ATTR_LONG_NAME,
+                r#_type: 0,
+                _checksum: checksum,
+                name2: chars[5..11].try_into().unwrap(),
+                _cluster: 0,
+                name3: chars[11..13].try_into().unwrap(),
+            };
+        }
+        let entry = cache.entry_mut(slots[lfn_count])?;
+        *entry = *template;
+        entry.name = short_name;
+        cache.flush()?;
+
+        Ok(EntryLocation {
+            parent: self.first_cluster,
+            first: slots[0],
+            entry: slots[lfn_count],
+        })
+    }
+
+    /// Create an empty file or directory at `path`, relative to this
+    /// directory unless it is absolute. The parent directory must exist.
+    pub fn create(&self, path: &str, directory: bool) -> Result<Node<'a>, Error> {
+        let filesystem = self.filesystem;
+        let (parent, name) = self.parent_of(path)?;
+
+        let cluster = if directory {
+            filesystem.allocate_cluster(None, true)?
+        } else {
+            0
+        };
+        let (date, time) = dos_timestamp();
+        let template = FatDirectory {
+            name: [b' '; 11],
+            flags: if directory {
+                ATTR_DIRECTORY
+            } else {
+                ATTR_ARCHIVE
+            },
+            _nt_flags: 0,
+            _create_time_tenths: 0,
+            create_time: time,
+            create_date: date,
+            access_date: date,
+            cluster_high: (cluster >> 16) as u16,
+            modify_time: time,
+            modify_date: date,
+            cluster_low: cluster as u16,
+            size: 0,
+        };
+        let location = match parent.add_entry(name, &template) {
+            Ok(location) => location,
+            Err(e) => {
+                if directory {
+                    filesystem.free_chain(cluster)?;
+                }
+                return Err(e);
+            }
+        };
+
+        if !directory {
+            return Ok(File {
+                entry: Some(location),
+                ..filesystem.get_file(0, 0)?
+            }
+            .into());
+        }
+
+        let mut cache = SlotCache::new(filesystem);
+        for (offset, name, target) in [
+            (0, b".          ", cluster),
+            (
+                1,
+                b"..         ",
+                filesystem.dot_dot_cluster(parent.first_cluster),
+            ),
+        ] {
+            let slot = Slot {
+                cluster: Some(cluster),
+                sector: 0,
+                offset,
+            };
+            *cache.entry_mut(slot)? = FatDirectory {
+                name: *name,
+                cluster_high: (target >> 16) as u16,
+                cluster_low: target as u16,
+                ..template
+            };
+        }
+        cache.flush()?;
+
+        Ok(Directory {
+            entry: Some(location),
+            ..filesystem.get_directory(cluster)?
+        }
+        .into())
+    }
+}
+
+// DOS date and time of the RTC reading, whose year counts from 2000, or None
+// if it is not a valid date
+fn dos_date_time(date: (u8, u8, u8), time: (u8, u8, u8)) -> Option<(u16, u16)> {
+    let ((year, month, day), (hour, minute, second)) = (date, time);
+    if year > 99 || !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+    if hour > 23 || minute > 59 || second > 59 {
+        return None;
+    }
+    let date = ((u16::from(year) + 20) << 9) | (u16::from(month) << 5) | u16::from(day);
+    let time = (u16::from(hour) << 11) | (u16::from(minute) << 5) | u16::from(second / 2);
+    Some((date, time))
 }
 
-pub trait Read {
-    fn read(&mut self, data: &mut [u8]) -> Result<u32, Error>;
-    fn seek(&mut self, offset: u32) -> Result<(), Error>;
-    fn get_size(&self) -> u32;
+// The current DOS date and time, or the start of 1980 without a clock
+fn dos_timestamp() -> (u16, u16) {
+    #[cfg(not(test))]
+    if let (Ok(date), Ok(time)) = (crate::rtc::read_date(), crate::rtc::read_time()) {
+        if let Some(timestamp) = dos_date_time(date, time) {
+            return timestamp;
+        }
+    }
+    (DOS_EPOCH_DATE, 0)
+}
 
-    // Loads the remainder of the file into the specified memory region
-    fn load_file(&mut self, mem: &mut MemoryRegion) -> Result<(), Error> {
-        let mut chunks = mem.as_bytes().chunks_exact_mut(SectorBuf::len());
-        for chunk in chunks.by_ref() {
-            self.read(chunk)?;
+// Caches the directory sector being worked on, writing it back if it was
+// modified before moving on to another sector
+struct SlotCache<'a> {
+    filesystem: &'a Filesystem<'a>,
+    sector: Option<u32>,
+    dirty: bool,
+    data: SectorBuf,
+}
+
+impl<'a> SlotCache<'a> {
+    fn new(filesystem: &'a Filesystem<'a>) -> Self {
+        SlotCache {
+            filesystem,
+            sector: None,
+            dirty: false,
+            data: SectorBuf::new(),
         }
-        let last = chunks.into_remainder();
-        if last.is_empty() {
-            return Ok(());
+    }
+
+    fn load(&mut self, slot: Slot) -> Result<*mut u8, Error> {
+        let sector = self.filesystem.slot_sector(slot);
+        if self.sector != Some(sector) {
+            self.flush()?;
+            self.sector = None;
+            self.filesystem
+                .read(u64::from(sector), self.data.as_mut_bytes())
+                .map_err(Error::Block)?;
+            self.sector = Some(sector);
+        }
+        Ok(self.data.as_mut_bytes()[slot.offset * 32..].as_mut_ptr())
+    }
+
+    fn entry(&mut self, slot: Slot) -> Result<&FatDirectory, Error> {
+        // Safe as the packed entries have no alignment requirement
+        Ok(unsafe { &*(self.load(slot)? as *const FatDirectory) })
+    }
+
+    fn entry_mut(&mut self, slot: Slot) -> Result<&mut FatDirectory, Error> {
+        let entry = self.load(slot)? as *mut FatDirectory;
+        self.dirty = true;
+        Ok(unsafe { &mut *entry })
+    }
+
+    fn long_name_entry_mut(&mut self, slot: Slot) -> Result<&mut FatLongNameEntry, Error> {
+        let entry = self.load(slot)? as *mut FatLongNameEntry;
+        self.dirty = true;
+        Ok(unsafe { &mut *entry })
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        if let (Some(sector), true) = (self.sector, self.dirty) {
+            self.filesystem
+                .write(u64::from(sector), self.data.as_mut_bytes())
+                .map_err(Error::Block)?;
+            self.dirty = false;
         }
-        // Use tmp buffer for last, partial sector
-        let mut dst = SectorBuf::new();
-        let bytes = self.read(dst.as_mut_bytes())? as usize;
-        assert_eq!(bytes, last.len());
-        last.copy_from_slice(&dst.as_bytes()[..bytes]);
         Ok(())
     }
 }
 
-impl<'a> Read for File<'a> {
-    fn read(&mut self, data: &mut [u8]) -> Result<u32, Error> {
-        assert_eq!(data.len(), SectorBuf::len());
+impl<'a> File<'a> {
+    // Read whole sectors into `data` from a sector aligned position, following
+    // the cluster chain for as long as the clusters are contiguous on disk so
+    // they can be read in one go. Returns the number of bytes of the file that
+    // were read.
+    fn read_contiguous(&mut self, data: &mut [u8]) -> Result<u32, Error> {
+        let sector_size = SectorBuf::len() as u32;
+        assert!(!data.is_empty() && data.len() % SectorBuf::len() == 0);
+        assert_eq!(self.position % sector_size, 0);
 
         if self.position >= self.size {
             return Err(Error::EndOfFile);
         }
 
         if self.sector_offset == u64::from(self.filesystem.sectors_per_cluster) {
+            self.active_cluster = self.filesystem.next_cluster(self.active_cluster)?;
+            self.sector_offset = 0;
+        }
+
+        let remaining = self.size - self.position;
+        let max_sectors = u64::min(
+            (data.len() / SectorBuf::len()) as u64,
+            u64::from(remaining.div_ceil(sector_size)),
+        );
+        let sectors_per_cluster = u64::from(self.filesystem.sectors_per_cluster);
+
+        let first_sector = u64::from(self.filesystem.first_sector_of_cluster(self.active_cluster))
+            + self.sector_offset;
+        let mut sectors = u64::min(sectors_per_cluster - self.sector_offset, max_sectors);
+        self.sector_offset += sectors;
+
+        while sectors < max_sectors {
             match self.filesystem.next_cluster(self.active_cluster) {
-                Err(e) => {
-                    return Err(e);
-                }
-                Ok(cluster) => {
+                Ok(cluster) if cluster == self.active_cluster + 1 => {
+                    let count = u64::min(sectors_per_cluster, max_sectors - sectors);
                     self.active_cluster = cluster;
-                    self.sector_offset = 0;
+                    self.sector_offset = count;
+                    sectors += count;
                 }
+                // Fragmented or end of chain, the next read picks it up
+                Ok(_) | Err(Error::EndOfFile) => break,
+                Err(e) => return Err(e),
             }
         }
 
-        let cluster_start = self.filesystem.first_sector_of_cluster(self.active_cluster);
-
+        let bytes = sectors as usize * SectorBuf::len();
         match self
             .filesystem
-            .read(u64::from(cluster_start) + self.sector_offset, data)
+            .read_sectors(first_sector, &mut data[..bytes])
         {
             Err(e) => Err(Error::Block(e)),
             Ok(()) => {
-                self.sector_offset += 1;
-                let sector_size = SectorBuf::len() as u32;
-                if (self.position + sector_size) > self.size {
-                    let bytes_read = self.size - self.position;
-                    self.position = self.size;
-                    Ok(bytes_read)
-                } else {
-                    self.position += sector_size;
-                    Ok(sector_size)
-                }
+                let bytes_read = u32::min(bytes as u32, remaining);
+                self.position += bytes_read;
+                Ok(bytes_read)
             }
         }
     }
 
-    fn seek(&mut self, position: u32) -> Result<(), Error> {
-        let sector_size = SectorBuf::len() as u32;
-        if position % sector_size != 0 {
-            return Err(Error::InvalidOffset);
-        }
-
-        if position >= self.size {
-            return Err(Error::EndOfFile);
-        }
+    pub fn position(&self) -> u32 {
+        self.position
+    }
 
-        // Beyond, reset to zero and come back
-        if position < self.position {
+    // Move to `position`, following the cluster chain from the current
+    // position when moving forwards from a sector boundary or else from the
+    // start of the file. Afterwards `sector_offset` is the sector within
+    // `active_cluster` that holds `position`, which may be one past the end
+    // of the cluster.
+    fn locate(&mut self, position: u32) -> Result<(), Error> {
+        let sector_size = SectorBuf::len() as u32;
+        if position < self.position || self.position % sector_size != 0 {
             self.position = 0;
             self.sector_offset = 0;
             self.active_cluster = self.start_cluster;
@@ -546,28 +959,255 @@ impl<'a> Read for File<'a> {
 
         // Like read but without reading, follow cluster chain if we reach end of
         // cluster
-        while self.position != position {
+        let target = position - position % sector_size;
+        while self.position != target {
             if self.sector_offset == u64::from(self.filesystem.sectors_per_cluster) {
-                match self.filesystem.next_cluster(self.active_cluster) {
-                    Err(e) => {
-                        return Err(e);
-                    }
+                self.active_cluster = self.filesystem.next_cluster(self.active_cluster)?;
+                self.sector_offset = 0;
+            }
+
+            self.sector_offset += 1;
+            self.position += sector_size;
+        }
+        self.position = position;
+
+        Ok(())
+    }
+
+    // Grow the cluster chain so that it can hold `size` bytes
+    fn allocate_clusters(&mut self, size: u32) -> Result<(), Error> {
+        let filesystem = self.filesystem;
+        let cluster_size = filesystem.sectors_per_cluster * SectorBuf::len() as u32;
+        let needed = size.div_ceil(cluster_size);
+        if needed <= self.size.div_ceil(cluster_size) {
+            return Ok(());
+        }
+
+        let mut last = self.start_cluster;
+        let mut count = 1;
+        if last == 0 {
+            last = filesystem.allocate_cluster(None, false)?;
+            self.start_cluster = last;
+            self.active_cluster = last;
+        } else {
+            // The chain may be longer than the file needs
+            loop {
+                match filesystem.next_cluster(last) {
                     Ok(cluster) => {
-                        self.active_cluster = cluster;
-                        self.sector_offset = 0;
+                        last = cluster;
+                        count += 1;
                     }
+                    Err(Error::EndOfFile) => break,
+                    Err(e) => return Err(e),
                 }
             }
+        }
+
+        while count < needed {
+            last = filesystem.allocate_cluster(Some(last), false)?;
+            count += 1;
+        }
+
+        Ok(())
+    }
+
+    // Store the size and first cluster in the directory entry, along with
+    // the time of the change
+    fn update_entry(&self) -> Result<(), Error> {
+        let Some(location) = self.entry else {
+            return Ok(());
+        };
+
+        let (date, time) = dos_timestamp();
+        let mut cache = SlotCache::new(self.filesystem);
+        let entry = cache.entry_mut(location.entry)?;
+        entry.size = self.size;
+        entry.cluster_high = (self.start_cluster >> 16) as u16;
+        entry.cluster_low = self.start_cluster as u16;
+        entry.modify_time = time;
+        entry.modify_date = date;
+        entry.access_date = date;
+        cache.flush()
+    }
+
+    /// Write `data` at the current position, growing the file as needed.
+    /// Returns the number of bytes written.
+    pub fn write(&mut self, data: &[u8]) -> Result<u32, Error> {
+        let sector_size = SectorBuf::len();
+        let end = u32::try_from(data.len())
+            .ok()
+            .and_then(|len| self.position.checked_add(len))
+            .ok_or(Error::NoSpace)?;
+        if data.is_empty() {
+            return Ok(0);
+        }
+
+        self.allocate_clusters(end)?;
+        self.locate(self.position)?;
+
+        let mut written = 0;
+        while written < data.len() {
+            if self.sector_offset == u64::from(self.filesystem.sectors_per_cluster) {
+                self.active_cluster = self.filesystem.next_cluster(self.active_cluster)?;
+                self.sector_offset = 0;
+            }
+
+            let sector = u64::from(self.filesystem.first_sector_of_cluster(self.active_cluster))
+                + self.sector_offset;
+            let start = self.position as usize % sector_size;
+            let count = usize::min(sector_size - start, data.len() - written);
+
+            let mut buf = SectorBuf::new();
+            // Keep the rest of a partially written sector
+            if count != sector_size {
+                self.filesystem
+                    .read(sector, buf.as_mut_bytes())
+                    .map_err(Error::Block)?;
+            }
+            buf.as_mut_bytes()[start..start + count]
+                .copy_from_slice(&data[written..written + count]);
+            self.filesystem
+                .write(sector, buf.as_mut_bytes())
+                .map_err(Error::Block)?;
+
+            written += count;
+            self.position += count as u32;
+            if start + count == sector_size {
+                self.sector_offset += 1;
+            }
+        }
+
+        self.size = u32::max(self.size, self.position);
+        self.update_entry()?;
+
+        Ok(written as u32)
+    }
+
+    /// Truncate the file to `size` bytes, or extend it with zeroes
+    pub fn set_size(&mut self, size: u32) -> Result<(), Error> {
+        let position = self.position;
+
+        if size > self.size {
+            let zeroes = SectorBuf::new();
+            self.locate(self.size)?;
+            while self.position < size {
+                let count = usize::min(SectorBuf::len(), (size - self.position) as usize);
+                self.write(&zeroes.as_bytes()[..count])?;
+            }
+            return self.locate(position);
+        }
+
+        let filesystem = self.filesystem;
+        let cluster_size = filesystem.sectors_per_cluster * SectorBuf::len() as u32;
+        let keep = size.div_ceil(cluster_size);
+        if keep == 0 {
+            filesystem.free_chain(self.start_cluster)?;
+            self.start_cluster = 0;
+        } else {
+            let mut last = self.start_cluster;
+            for _ in 1..keep {
+                last = filesystem.next_cluster(last)?;
+            }
+            match filesystem.next_cluster(last) {
+                Ok(next) => {
+                    filesystem.set_fat_entry(last, filesystem.end_of_chain())?;
+                    filesystem.free_chain(next)?;
+                }
+                Err(Error::EndOfFile) => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        self.size = size;
+        self.update_entry()?;
+
+        // The current cluster may have been freed so start from the beginning
+        self.position = 0;
+        self.sector_offset = 0;
+        self.active_cluster = self.start_cluster;
+        self.locate(u32::min(position, size))
+    }
+}
+
+impl<'a> Read for File<'a> {
+    fn read(&mut self, data: &mut [u8]) -> Result<u32, Error> {
+        assert_eq!(data.len(), SectorBuf::len());
+
+        if self.position >= self.size {
+            return Err(Error::EndOfFile);
+        }
+
+        if self.sector_offset == u64::from(self.filesystem.sectors_per_cluster) {
+            match self.filesystem.next_cluster(self.active_cluster) {
+                Err(e) => {
+                    return Err(e);
+                }
+                Ok(cluster) => {
+                    self.active_cluster = cluster;
+                    self.sector_offset = 0;
+                }
+            }
+        }
+
+        let cluster_start = self.filesystem.first_sector_of_cluster(self.active_cluster);
+
+        match self
+            .filesystem
+            .read(u64::from(cluster_start) + self.sector_offset, data)
+        {
+            Err(e) => Err(Error::Block(e)),
+            Ok(()) => {
+                self.sector_offset += 1;
+                // After an unaligned seek or write the position is part way
+                // into the sector
+                let skip = self.position % SectorBuf::len() as u32;
+                data.copy_within(skip as usize.., 0);
+                let bytes_read =
+                    u32::min(SectorBuf::len() as u32 - skip, self.size - self.position);
+                self.position += bytes_read;
+                Ok(bytes_read)
+            }
+        }
+    }
 
-            self.sector_offset += 1;
-            self.position += sector_size;
+    fn seek(&mut self, position: u32) -> Result<(), Error> {
+        if position > self.size {
+            return Err(Error::EndOfFile);
         }
 
-        Ok(())
+        self.locate(position)
     }
+
     fn get_size(&self) -> u32 {
         self.size
     }
+
+    fn load_file(&mut self, mem: &mut MemoryRegion) -> Result<(), Error> {
+        let mut data = mem.as_bytes();
+        while data.len() >= SectorBuf::len() {
+            // After an unaligned seek or write the rest of the current sector
+            // comes first
+            if self.position as usize % SectorBuf::len() != 0 {
+                let mut sector = SectorBuf::new();
+                let bytes = self.read(sector.as_mut_bytes())? as usize;
+                data[..bytes].copy_from_slice(&sector.as_bytes()[..bytes]);
+                data = &mut data[bytes..];
+                continue;
+            }
+
+            let whole_sectors = data.len() / SectorBuf::len() * SectorBuf::len();
+            let bytes_read = self.read_contiguous(&mut data[..whole_sectors])? as usize;
+            data = &mut data[bytes_read..];
+        }
+
+        while !data.is_empty() {
+            let mut sector = SectorBuf::new();
+            let bytes = usize::min(self.read(sector.as_mut_bytes())? as usize, data.len());
+            data[..bytes].copy_from_slice(&sector.as_bytes()[..bytes]);
+            data = &mut data[bytes..];
+        }
+        Ok(())
+    }
 }
 
 impl<'a> SectorRead for Filesystem<'a> {
@@ -578,6 +1218,47 @@ impl<'a> SectorRead for Filesystem<'a> {
             self.device.read(self.start + sector, data)
         }
     }
+
+    fn read_sectors(&self, sector: u64, data: &mut [u8]) -> Result<(), crate::block::Error> {
+        let count = (data.len() / SectorBuf::len()) as u64;
+        if count == 0 || self.start + sector + count - 1 > self.last {
+            Err(crate::block::Error::BlockIO)
+        } else {
+            self.device.read_sectors(self.start + sector, data)
+        }
+    }
+}
+
+impl<'a> SectorWrite for Filesystem<'a> {
+    fn write(&self, sector: u64, data: &mut [u8]) -> Result<(), crate::block::Error> {
+        if self.start + sector > self.last {
+            Err(crate::block::Error::BlockIO)
+        } else {
+            self.device.write(self.start + sector, data)
+        }
+    }
+
+    fn flush(&self) -> Result<(), crate::block::Error> {
+        self.device.flush()
+    }
+}
+
+impl<'a> fs::Filesystem for Filesystem<'a> {
+    fn root(&self) -> Result<fs::Node<'_>, Error> {
+        Ok(fs::Node::Fat(self.root()?.into()))
+    }
+
+    fn open(&self, path: &str) -> Result<fs::Node<'_>, Error> {
+        Ok(fs::Node::Fat(self.open(path)?))
+    }
+
+    fn is_writable(&self) -> bool {
+        true
+    }
+
+    fn flush(&self) -> Result<(), Error> {
+        SectorWrite::flush(self).map_err(Error::Block)
+    }
 }
 
 // Do a case-insensitive match on the name with the 8.3 format that you get from
@@ -590,6 +1271,12 @@ fn compare_short_name(name: &str, de: &DirectoryEntry) -> bool {
         return false;
     }
 
+    // The dot entries are stored as is
+    if name == "." || name == ".." {
+        let (dots, padding) = de.name.split_at(name.len());
+        return dots == name.as_bytes() && padding.iter().all(|c| *c == b' ');
+    }
+
     let mut i = 0;
     for a in name.as_bytes().iter() {
         // Handle cases which are 11 long but not 8.3 (e.g "loader.conf")
@@ -601,8 +1288,11 @@ fn compare_short_name(name: &str, de: &DirectoryEntry) -> bool {
             break;
         }
 
-        // Jump to the extension
+        // Jump to the extension, the rest of the base name must be padding
         if *a == b'.' {
+            if i > 8 || de.name[i..8].iter().any(|c| *c != b' ') {
+                return false;
+            }
             i = 8;
             continue;
         }
@@ -623,13 +1313,97 @@ fn compare_short_name(name: &str, de: &DirectoryEntry) -> bool {
     true
 }
 
+// Long names are also matched ignoring case as FAT is case-insensitive
 fn compare_name(name: &str, de: &DirectoryEntry) -> bool {
-    compare_short_name(name, de)
-        || (&de.long_name[0..name.len()] == name.as_bytes() && de.long_name[name.len()] == 0)
+    let name = name.trim_matches(char::from(0));
+    let long_name = de.long_name.split(|c| *c == 0).next().unwrap();
+    compare_short_name(name, de) || long_name.eq_ignore_ascii_case(name.as_bytes())
+}
+
+// Characters allowed in 8.3 names besides upper case letters and digits
+const SHORT_NAME_SPECIAL_CHARS: &[u8] = b"!#$%&'()-@^_`{}~";
+// Characters not allowed in any name
+const INVALID_NAME_CHARS: &[u8] = b"\"*/:<>?\\|";
+
+fn is_short_name_char(c: u8) -> bool {
+    c.is_ascii_uppercase() || c.is_ascii_digit() || SHORT_NAME_SPECIAL_CHARS.contains(&c)
+}
+
+// Only printable ASCII names are supported for new entries
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_NAME_LENGTH
+        && !name.ends_with(['.', ' '])
+        && name
+            .bytes()
+            .all(|c| (0x20..0x7f).contains(&c) && !INVALID_NAME_CHARS.contains(&c))
+}
+
+// Convert a name that fits the 8.3 format (and is all upper case) into the
+// form stored in directory entries
+fn short_name(name: &str) -> Option<[u8; 11]> {
+    let (base, extension) = name.split_once('.').unwrap_or((name, ""));
+    if base.is_empty()
+        || base.len() > 8
+        || extension.len() > 3
+        || !base
+            .bytes()
+            .chain(extension.bytes())
+            .all(is_short_name_char)
+    {
+        return None;
+    }
+
+    let mut short_name = [b' '; 11];
+    short_name[..base.len()].copy_from_slice(base.as_bytes());
+    short_name[8..8 + extension.len()].copy_from_slice(extension.as_bytes());
+    Some(short_name)
+}
+
+// Generate the "BASE~N.EXT" 8.3 alias for a long name
+fn short_name_alias(name: &str, n: u32) -> [u8; 11] {
+    let (base, extension) = match name.rfind('.') {
+        Some(i) if i > 0 => (&name[..i], &name[i + 1..]),
+        _ => (name, ""),
+    };
+    let convert = |c: u8| match c.to_ascii_uppercase() {
+        b' ' | b'.' => None,
+        c if is_short_name_char(c) => Some(c),
+        _ => Some(b'_'),
+    };
+
+    let mut tail = [0u8; 8];
+    let mut tail_len = 0;
+    let mut n = n;
+    while n > 0 {
+        tail[7 - tail_len] = b'0' + (n % 10) as u8;
+        tail_len += 1;
+        n /= 10;
+    }
+    tail[7 - tail_len] = b'~';
+    tail_len += 1;
+
+    let mut short_name = [b' '; 11];
+    let mut len = 0;
+    for c in base.bytes().filter_map(convert).take(8 - tail_len) {
+        short_name[len] = c;
+        len += 1;
+    }
+    short_name[len..len + tail_len].copy_from_slice(&tail[8 - tail_len..]);
+    for (i, c) in extension.bytes().filter_map(convert).take(3).enumerate() {
+        short_name[8 + i] = c;
+    }
+    short_name
+}
+
+fn lfn_checksum(short_name: &[u8; 11]) -> u8 {
+    short_name
+        .iter()
+        .fold(0u8, |sum, c| sum.rotate_right(1).wrapping_add(*c))
 }
 
 impl<'a> Filesystem<'a> {
-    pub fn new(device: &'a dyn SectorRead, start: u64, last: u64) -> Filesystem<'a> {
+    pub fn new(device: &'a dyn BlockDevice, start: u64, last: u64) -> Filesystem<'a> {
         Filesystem {
             device,
             start,
@@ -647,6 +1421,9 @@ impl<'a> Filesystem<'a> {
             data_sector_count: 0,
             data_cluster_count: 0,
             root_cluster: 0,
+            fsinfo_sector: 0,
+            next_free: Cell::new(2),
+            free_count_invalidated: Cell::new(false),
         }
     }
 
@@ -663,30 +1440,40 @@ impl<'a> Filesystem<'a> {
         let h = unsafe { &*(data.as_bytes().as_ptr() as *const Header) };
 
         self.bytes_per_sector = u32::from(h.bytes_per_sector);
+        if self.bytes_per_sector == 0 || self.bytes_per_sector % SectorBuf::len() as u32 != 0 {
+            return Err(Error::Unsupported);
+        }
+
+        // The header counts in sectors of bytes_per_sector (matching the
+        // logical block size of the disk) whereas all reads are done in 512
+        // byte sectors, so scale everything accordingly.
+        let scale = self.bytes_per_sector / SectorBuf::len() as u32;
+
         self.fat_count = u32::from(h.fat_count);
-        self.sectors_per_cluster = u32::from(h.sectors_per_cluster);
-        self.root_dir_sectors = (u32::from(h.root_dir_count * 32)).div_ceil(self.bytes_per_sector);
+        self.sectors_per_cluster = u32::from(h.sectors_per_cluster) * scale;
+        self.root_dir_sectors =
+            (u32::from(h.root_dir_count * 32)).div_ceil(self.bytes_per_sector) * scale;
 
         self.sectors_per_fat = if h.legacy_sectors_per_fat == 0 {
             let h32 = unsafe { &*(data.as_bytes().as_ptr() as *const Fat32Header) };
             h32.sectors_per_fat
         } else {
             u32::from(h.legacy_sectors_per_fat)
-        };
+        } * scale;
 
         self.sectors = if h.legacy_sectors == 0 {
             h.sectors
         } else {
             u32::from(h.legacy_sectors)
-        };
+        } * scale;
 
-        self.first_fat_sector = u32::from(h.reserved_sectors);
+        self.first_fat_sector = u32::from(h.reserved_sectors) * scale;
         self.first_data_sector =
             self.first_fat_sector + (self.fat_count * self.sectors_per_fat) + self.root_dir_sectors;
         self.data_sector_count = self.sectors - self.first_data_sector;
         self.data_cluster_count = self.data_sector_count / self.bytes_per_sector;
 
-        self.clusters = self.data_sector_count / u32::from(h.sectors_per_cluster);
+        self.clusters = self.data_sector_count / self.sectors_per_cluster;
 
         self.fat_type = if self.clusters < FAT12_MAX {
             FatType::FAT12
@@ -699,100 +1486,169 @@ impl<'a> Filesystem<'a> {
         if self.fat_type == FatType::FAT32 {
             let h32 = unsafe { &*(data.as_bytes().as_ptr() as *const Fat32Header) };
             self.root_cluster = h32.root_cluster;
+            self.fsinfo_sector = match h32.fsinfo_sector {
+                0 | 0xffff => 0,
+                sector => u32::from(sector) * scale,
+            };
         }
 
         Ok(())
     }
 
-    fn next_cluster(&self, cluster: u32) -> Result<u32, Error> {
+    // Byte offset of the entry for `cluster` within the FAT and its size
+    fn fat_entry_offset(&self, cluster: u32) -> Result<(u32, usize), Error> {
         match self.fat_type {
-            FatType::FAT12 => {
-                let fat_offset = cluster + (cluster / 2); // equivalent of x 1.5
-                let fat_sector = self.first_fat_sector + (fat_offset / self.bytes_per_sector);
-                let offset = fat_offset % self.bytes_per_sector;
-
-                let mut data = SectorBuf::new();
-                match self.read(u64::from(fat_sector), data.as_mut_bytes()) {
-                    Ok(_) => {}
-                    Err(e) => return Err(Error::Block(e)),
-                };
-                let lower_data = data.as_bytes()[offset as usize] as u16;
-                let upper_data = if offset < (SectorBuf::len() - 1) as u32 {
-                    data.as_bytes()[offset as usize + 1] as u16
-                } else {
-                    // read next sector to get upper byte if offset is 511
-                    match self.read(u64::from(fat_sector) + 1, data.as_mut_bytes()) {
-                        Ok(_) => {}
-                        Err(e) => return Err(Error::Block(e)),
-                    }
-                    data.as_bytes()[0] as u16
-                };
-
-                let next_cluster_raw = lower_data | (upper_data << 8);
+            FatType::FAT12 => Ok((cluster + (cluster / 2), 2)), // equivalent of x 1.5
+            FatType::FAT16 => Ok((cluster * 2, 2)),
+            FatType::FAT32 => Ok((cluster * 4, 4)),
+            _ => Err(Error::Unsupported),
+        }
+    }
 
-                let next_cluster = if cluster % 2 == 0 {
-                    next_cluster_raw & 0xfff
-                } else {
-                    next_cluster_raw >> 4
-                };
-                if next_cluster >= 0xff8 {
-                    Err(Error::EndOfFile)
+    // Read or update (in every copy of the FAT) the bytes at `offset` within
+    // the FAT. FAT12 entries may straddle two sectors.
+    fn access_fat(&self, offset: u32, bytes: &mut [u8], write: bool) -> Result<(), Error> {
+        let fat_count = if write { self.fat_count } else { 1 };
+        for fat in 0..fat_count {
+            let first_sector = self.first_fat_sector + fat * self.sectors_per_fat;
+            let mut data = SectorBuf::new();
+            let mut done = 0;
+            while done < bytes.len() {
+                let position = offset + done as u32;
+                let sector = u64::from(first_sector + position / SectorBuf::len() as u32);
+                let start = position as usize % SectorBuf::len();
+                let count = usize::min(bytes.len() - done, SectorBuf::len() - start);
+
+                self.read(sector, data.as_mut_bytes())
+                    .map_err(Error::Block)?;
+                if write {
+                    data.as_mut_bytes()[start..start + count]
+                        .copy_from_slice(&bytes[done..done + count]);
+                    self.write(sector, data.as_mut_bytes())
+                        .map_err(Error::Block)?;
                 } else {
-                    Ok(u32::from(next_cluster))
+                    bytes[done..done + count]
+                        .copy_from_slice(&data.as_bytes()[start..start + count]);
                 }
+                done += count;
             }
-            FatType::FAT16 => {
-                let mut data = SectorBuf::new();
+        }
+        Ok(())
+    }
 
-                let fat_offset = cluster * 2;
-                let fat_sector = self.first_fat_sector + (fat_offset / self.bytes_per_sector);
-                let offset = fat_offset % self.bytes_per_sector;
+    fn fat_entry(&self, cluster: u32) -> Result<u32, Error> {
+        let (offset, size) = self.fat_entry_offset(cluster)?;
+        let mut bytes = [0u8; 4];
+        self.access_fat(offset, &mut bytes[..size], false)?;
+        let raw = u32::from_le_bytes(bytes);
+
+        Ok(match self.fat_type {
+            FatType::FAT12 if cluster % 2 == 0 => raw & 0xfff,
+            FatType::FAT12 => raw >> 4,
+            FatType::FAT16 => raw,
+            _ => raw & 0x0fff_ffff,
+        })
+    }
 
-                match self.read(u64::from(fat_sector), data.as_mut_bytes()) {
-                    Ok(_) => {}
-                    Err(e) => return Err(Error::Block(e)),
-                };
+    fn set_fat_entry(&self, cluster: u32, value: u32) -> Result<(), Error> {
+        let (offset, size) = self.fat_entry_offset(cluster)?;
+        let mut bytes = [0u8; 4];
+        self.access_fat(offset, &mut bytes[..size], false)?;
+        let raw = u32::from_le_bytes(bytes);
+
+        // Keep the bits belonging to the neighbouring FAT12 entry and the
+        // reserved top bits of FAT32 entries
+        let raw = match self.fat_type {
+            FatType::FAT12 if cluster % 2 == 0 => (raw & 0xf000) | value,
+            FatType::FAT12 => (raw & 0x000f) | (value << 4),
+            FatType::FAT16 => value,
+            _ => (raw & 0xf000_0000) | value,
+        };
+        self.invalidate_free_count()?;
+        self.access_fat(offset, &mut raw.to_le_bytes()[..size], true)
+    }
+
+    // Value marking the last cluster of a chain, anything from this value
+    // with the bottom three bits cleared upwards also ends a chain.
+    fn end_of_chain(&self) -> u32 {
+        match self.fat_type {
+            FatType::FAT12 => 0xfff,
+            FatType::FAT16 => 0xffff,
+            _ => 0x0fff_ffff,
+        }
+    }
 
-                let bytes = data.as_bytes();
-                let next_cluster_raw = [bytes[offset as usize], bytes[(offset + 1) as usize]];
-                let next_cluster = u16::from_le_bytes(next_cluster_raw);
+    fn next_cluster(&self, cluster: u32) -> Result<u32, Error> {
+        let next_cluster = self.fat_entry(cluster)?;
+        if next_cluster >= self.end_of_chain() & !0x7 {
+            Err(Error::EndOfFile)
+        } else {
+            Ok(next_cluster)
+        }
+    }
 
-                if next_cluster >= 0xfff8 {
-                    Err(Error::EndOfFile)
-                } else {
-                    Ok(u32::from(next_cluster))
-                }
-            }
-            FatType::FAT32 => {
-                let mut data = SectorBuf::new();
+    // The FAT32 FSInfo sector caches the number of free clusters, rather
+    // than keeping it up to date mark it as unknown before the first change.
+    fn invalidate_free_count(&self) -> Result<(), Error> {
+        if self.fsinfo_sector == 0 || self.free_count_invalidated.get() {
+            return Ok(());
+        }
+        self.free_count_invalidated.set(true);
 
-                let fat_offset = cluster * 4;
-                let fat_sector = self.first_fat_sector + (fat_offset / self.bytes_per_sector);
-                let offset = fat_offset % self.bytes_per_sector;
+        let mut data = SectorBuf::new();
+        let sector = u64::from(self.fsinfo_sector);
+        self.read(sector, data.as_mut_bytes())
+            .map_err(Error::Block)?;
+        let bytes = data.as_mut_bytes();
+        if bytes[0..4] != *b"RRaA" || bytes[484..488] != *b"rrAa" {
+            return Ok(());
+        }
+        bytes[488..492].copy_from_slice(&0xffff_ffffu32.to_le_bytes());
+        self.write(sector, data.as_mut_bytes())
+            .map_err(Error::Block)
+    }
 
-                match self.read(u64::from(fat_sector), data.as_mut_bytes()) {
-                    Ok(_) => {}
-                    Err(e) => return Err(Error::Block(e)),
-                };
+    // Find a free cluster, mark it as the end of a chain and link it after
+    // `previous`. Clusters for directories need to be zeroed.
+    fn allocate_cluster(&self, previous: Option<u32>, zero: bool) -> Result<u32, Error> {
+        let first = self.next_free.get();
+        for i in 0..self.clusters {
+            let cluster = 2 + (first - 2 + i) % self.clusters;
+            if self.fat_entry(cluster)? != 0 {
+                continue;
+            }
 
-                let bytes = data.as_bytes();
-                let next_cluster_raw = [
-                    bytes[offset as usize],
-                    bytes[(offset + 1) as usize],
-                    bytes[(offset + 2) as usize],
-                    bytes[(offset + 3) as usize],
-                ];
-                let next_cluster_raw = u32::from_le_bytes(next_cluster_raw);
-                let next_cluster = next_cluster_raw & 0x0fff_ffff;
-                if next_cluster >= 0x0fff_fff8 {
-                    Err(Error::EndOfFile)
-                } else {
-                    Ok(next_cluster)
+            if zero {
+                let mut data = SectorBuf::new();
+                let first_sector = self.first_sector_of_cluster(cluster);
+                for sector in first_sector..first_sector + self.sectors_per_cluster {
+                    self.write(u64::from(sector), data.as_mut_bytes())
+                        .map_err(Error::Block)?;
                 }
             }
 
-            _ => Err(Error::Unsupported),
+            self.set_fat_entry(cluster, self.end_of_chain())?;
+            if let Some(previous) = previous {
+                self.set_fat_entry(previous, cluster)?;
+            }
+            self.next_free.set(cluster + 1);
+            return Ok(cluster);
+        }
+        Err(Error::NoSpace)
+    }
+
+    // Free the chain of clusters starting at `cluster`
+    fn free_chain(&self, mut cluster: u32) -> Result<(), Error> {
+        // Bounded in case the chain loops
+        for _ in 0..self.clusters {
+            if cluster < 2 || cluster >= self.clusters + 2 {
+                break;
+            }
+            let next = self.fat_entry(cluster)?;
+            self.set_fat_entry(cluster, 0)?;
+            cluster = next;
         }
+        Ok(())
     }
 
     fn first_sector_of_cluster(&self, cluster: u32) -> u32 {
@@ -805,18 +1661,22 @@ impl<'a> Filesystem<'a> {
                 let root_directory_start = self.first_data_sector - self.root_dir_sectors;
                 Ok(Directory {
                     filesystem: self,
+                    first_cluster: None,
                     cluster: None,
                     first_sector: root_directory_start,
                     sector: root_directory_start,
                     offset: 0,
+                    entry: None,
                 })
             }
             FatType::FAT32 => Ok(Directory {
                 filesystem: self,
+                first_cluster: Some(self.root_cluster),
                 cluster: Some(self.root_cluster),
                 first_sector: 0,
                 sector: 0,
                 offset: 0,
+                entry: None,
             }),
             _ => Err(Error::Unsupported),
         }
@@ -830,19 +1690,153 @@ impl<'a> Filesystem<'a> {
             sector_offset: 0,
             size,
             position: 0,
+            entry: None,
         })
     }
 
     fn get_directory(&self, cluster: u32) -> Result<Directory, Error> {
         Ok(Directory {
             filesystem: self,
+            first_cluster: Some(cluster),
             cluster: Some(cluster),
             first_sector: 0,
             sector: 0,
             offset: 0,
+            entry: None,
         })
     }
 
+    fn get_node(&self, de: &DirectoryEntry) -> Result<Node, Error> {
+        match de.file_type {
+            // ".." entries in subdirectories of the root use cluster 0
+            FileType::Directory if de.cluster == 0 => Ok(self.root()?.into()),
+            FileType::Directory => Ok(Directory {
+                entry: Some(de.location),
+                ..self.get_directory(de.cluster)?
+            }
+            .into()),
+            FileType::File => Ok(File {
+                entry: Some(de.location),
+                ..self.get_file(de.cluster, de.size)?
+            }
+            .into()),
+        }
+    }
+
+    // The directory starting at `cluster`, or the FAT12/16 root directory
+    fn directory(&self, cluster: Option<u32>) -> Result<Directory, Error> {
+        match cluster {
+            Some(cluster) => self.get_directory(cluster),
+            None => self.root(),
+        }
+    }
+
+    // Cluster stored in ".." entries for a subdirectory of `parent`
+    fn dot_dot_cluster(&self, parent: Option<u32>) -> u32 {
+        match parent {
+            Some(cluster) if self.fat_type != FatType::FAT32 || cluster != self.root_cluster => {
+                cluster
+            }
+            _ => 0,
+        }
+    }
+
+    fn slot_sector(&self, slot: Slot) -> u32 {
+        match slot.cluster {
+            Some(cluster) => self.first_sector_of_cluster(cluster) + slot.sector,
+            None => slot.sector,
+        }
+    }
+
+    // Mark the slots used by the entry at `location`, including its long
+    // name, as free
+    fn remove_entry(&self, location: &EntryLocation) -> Result<(), Error> {
+        let mut dir = Directory {
+            cluster: location.first.cluster,
+            sector: location.first.sector,
+            offset: location.first.offset,
+            ..self.directory(location.parent)?
+        };
+        let mut cache = SlotCache::new(self);
+        loop {
+            let slot = dir.next_slot(false)?;
+            cache.entry_mut(slot)?.name[0] = ENTRY_FREE;
+            if slot == location.entry {
+                break;
+            }
+        }
+        cache.flush()
+    }
+
+    // Whether `directory` is the directory starting at `cluster` or below it,
+    // following the ".." entries up to the root
+    fn is_in_subtree(&self, directory: &Directory, cluster: u32) -> Result<bool, Error> {
+        let mut current = self.dot_dot_cluster(directory.first_cluster);
+        while current != 0 {
+            if current == cluster {
+                return Ok(true);
+            }
+            let dot_dot = *SlotCache::new(self).entry(Slot {
+                cluster: Some(current),
+                sector: 0,
+                offset: 1,
+            })?;
+            current = (u32::from(dot_dot.cluster_high)) << 16 | u32::from(dot_dot.cluster_low);
+        }
+        Ok(false)
+    }
+
+    // Move the entry at `location` to `path`, relative to its parent
+    // directory unless absolute, returning its new location
+    fn rename_entry(&self, location: &EntryLocation, path: &str) -> Result<EntryLocation, Error> {
+        let (parent, name) = self.directory(location.parent)?.parent_of(path)?;
+        let entry = *SlotCache::new(self).entry(location.entry)?;
+        let new_location = match parent.find(name)? {
+            // Only the case of the name changes
+            Some(de) if de.location.entry == location.entry => {
+                let long_name = de.long_name.split(|c| *c == 0).next().unwrap();
+                let unchanged = if long_name.is_empty() {
+                    short_name(name) == Some(de.name)
+                } else {
+                    long_name == name.as_bytes()
+                };
+                if unchanged {
+                    return Ok(*location);
+                }
+                if !is_valid_name(name) {
+                    return Err(Error::InvalidName);
+                }
+                parent.write_entry(name, &entry)?
+            }
+            Some(_) => return Err(Error::AlreadyExists),
+            None => {
+                let cluster = (u32::from(entry.cluster_high)) << 16 | u32::from(entry.cluster_low);
+                if entry.flags & ATTR_DIRECTORY != 0 && self.is_in_subtree(&parent, cluster)? {
+                    return Err(Error::InvalidName);
+                }
+                parent.add_entry(name, &entry)?
+            }
+        };
+        self.remove_entry(location)?;
+
+        // Directories moved elsewhere need their ".." entry updating
+        let cluster = (u32::from(entry.cluster_high)) << 16 | u32::from(entry.cluster_low);
+        if entry.flags & ATTR_DIRECTORY != 0 && parent.first_cluster != location.parent {
+            let dot_dot = self.dot_dot_cluster(parent.first_cluster);
+            let mut cache = SlotCache::new(self);
+            let entry = cache.entry_mut(Slot {
+                cluster: Some(cluster),
+                sector: 0,
+                offset: 1,
+            })?;
+            entry.cluster_high = (dot_dot >> 16) as u16;
+            entry.cluster_low = dot_dot as u16;
+            cache.flush()?;
+        }
+
+        Ok(new_location)
+    }
+
     pub fn open(&self, path: &str) -> Result<Node, Error> {
         // path must be absolute path
         assert!(is_absolute_path(path));
@@ -894,17 +1888,15 @@ impl<'a> Filesystem<'a> {
                     Err(e) => return Err(e),
                     Ok(de) => {
                         if compare_name(sub, &de) {
-                            match de.file_type {
-                                FileType::Directory => {
-                                    if residual.is_empty() {
-                                        return Ok(self.get_directory(de.cluster).unwrap().into());
-                                    }
-                                    current_dir = self.get_directory(de.cluster).unwrap();
+                            if residual.is_empty() {
+                                return self.get_node(&de);
+                            }
+                            match self.get_node(&de)? {
+                                Node::Directory(d) => {
+                                    current_dir = d;
                                     break;
                                 }
-                                FileType::File => {
-                                    return Ok(self.get_file(de.cluster, de.size).unwrap().into())
-                                }
+                                Node::File(_) => return Err(Error::NotFound),
                             }
                         }
                     }
@@ -917,13 +1909,14 @@ impl<'a> Filesystem<'a> {
 #[cfg(test)]
 mod tests {
     use super::Read;
-    use crate::block::SectorBuf;
+    use crate::block::{BlockDevice, SectorBuf, SectorRead, SectorWrite};
     use crate::part::tests::*;
+    use std::cell::RefCell;
     use std::convert::TryInto;
     use std::path::PathBuf;
 
     fn fat_test_image_paths() -> Vec<PathBuf> {
-        let images = ["fat12.img", "fat16.img", "fat32.img"];
+        let images = ["fat12.img", "fat16.img", "fat32.img", "fat32-4k.img"];
 
         let mut workload_path = dirs::home_dir().unwrap();
         workload_path.push("workloads");
@@ -1055,12 +2048,49 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_fat_load_file() {
+        let images = fat_test_image_paths();
+
+        for image in &images {
+            let d = FakeDisk::new(image);
+            let len = d.len();
+            let mut fs = crate::fat::Filesystem::new(&d, 0, len);
+            fs.init().expect("Error initialising filesystem");
+
+            for n in 9..16 {
+                for o in 0..2 {
+                    let v = 2u32.pow(n) - o;
+                    let path = format!("/A/B/C/{v}");
+                    let mut f: crate::fat::File = fs
+                        .open(&path)
+                        .expect("Error opening file")
+                        .try_into()
+                        .unwrap();
+
+                    let data = vec![0u8; v as usize];
+                    let mut region = crate::mem::MemoryRegion::from_bytes(&data);
+                    f.load_file(&mut region).expect("expect load to work");
+                    assert!(data.iter().all(|b| *b == b'a'));
+
+                    // Loading only part of the file stops at the region size
+                    if v > SectorBuf::len() as u32 {
+                        f.seek(0).expect("expect seek to work");
+                        let mut region = crate::mem::MemoryRegion::from_bytes(&data[..300]);
+                        f.load_file(&mut region).expect("expect load to work");
+                        assert_eq!(f.position, SectorBuf::len() as u32);
+                    }
+                }
+            }
+        }
+    }
+
     #[test]
     fn test_fat_init() {
         let d = FakeDisk::new(&clear_disk_path());
-        match crate::part::find_efi_partition(&d) {
-            Ok((start, end)) => {
-                let mut f = crate::fat::Filesystem::new(&d, start, end);
+        match crate::part::partitions(&d).and_then(|p| p.find_efi_partition()) {
+            Ok(p) => {
+                let mut f = crate::fat::Filesystem::new(&d, p.first_lba, p.last_lba);
                 match f.init() {
                     Ok(()) => {
                         assert_eq!(f.sectors, 1_046_528);
@@ -1076,9 +2106,9 @@ mod tests {
     #[test]
     fn test_fat_open() {
         let d = FakeDisk::new(&clear_disk_path());
-        match crate::part::find_efi_partition(&d) {
-            Ok((start, end)) => {
-                let mut f = crate::fat::Filesystem::new(&d, start, end);
+        match crate::part::partitions(&d).and_then(|p| p.find_efi_partition()) {
+            Ok(p) => {
+                let mut f = crate::fat::Filesystem::new(&d, p.first_lba, p.last_lba);
                 match f.init() {
                     Ok(()) => {
                         let file: crate::fat::File = f
@@ -1182,6 +2212,361 @@ mod tests {
         }
     }
 
+    fn read_all(file: &mut super::File) -> Vec<u8> {
+        file.seek(0).unwrap();
+        let mut contents = Vec::new();
+        loop {
+            let mut data = SectorBuf::new();
+            match file.read(data.as_mut_bytes()) {
+                Ok(bytes) => contents.extend_from_slice(&data.as_bytes()[..bytes as usize]),
+                Err(super::Error::EndOfFile) => break,
+                Err(e) => panic!("{e:?}"),
+            }
+        }
+        contents
+    }
+
+    fn open_file<'a>(fs: &'a super::Filesystem, path: &str) -> super::File<'a> {
+        fs.open(path).unwrap().try_into().unwrap()
+    }
+
+    fn free_clusters(fs: &super::Filesystem) -> u32 {
+        (2..fs.clusters + 2)
+            .filter(|c| fs.fat_entry(*c).unwrap() == 0)
+            .count() as u32
+    }
+
+    fn pattern(len: usize, seed: u8) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8 ^ seed).collect()
+    }
+
+    #[test]
+    fn test_fat_cached_write() {
+        for image in &fat_test_image_paths() {
+            let disk = RamDisk::new(image);
+            let len = disk.len();
+            let cache = crate::cache::SectorCache::new(&disk);
+            let mut fs = crate::fat::Filesystem::new(&cache, 0, len);
+            fs.init().expect("Error initialising filesystem");
+            let root = fs.root().unwrap();
+
+            let data = pattern(5000, 7);
+            let mut f: super::File = root
+                .create("/A/B/C/cached", false)
+                .unwrap()
+                .try_into()
+                .unwrap();
+            f.write(&data).unwrap();
+            assert_eq!(read_all(&mut open_file(&fs, "/A/B/C/cached")), data);
+            root.open("/A/B/C/512").unwrap().delete().unwrap();
+            assert_eq!(root.open("/A/B/C/512").err(), Some(super::Error::NotFound));
+
+            // The changes went straight through to the disk
+            let mut fs = crate::fat::Filesystem::new(&disk, 0, len);
+            fs.init().unwrap();
+            assert_eq!(read_all(&mut open_file(&fs, "/A/B/C/cached")), data);
+            assert_eq!(fs.open("/A/B/C/512").err(), Some(super::Error::NotFound));
+        }
+    }
+
+    #[test]
+    fn test_dos_date_time() {
+        // 2026-10-17 13:45:31
+        let (date, time) = super::dos_date_time((26, 10, 17), (13, 45, 31)).unwrap();
+        assert_eq!(date, (46 << 9) | (10 << 5) | 17);
+        assert_eq!(time, (13 << 11) | (45 << 5) | 15);
+        assert_eq!(
+            super::dos_date_time((0, 1, 1), (0, 0, 0)),
+            Some((0x2821, 0))
+        );
+        assert_eq!(super::dos_date_time((26, 0, 17), (13, 45, 31)), None);
+        assert_eq!(super::dos_date_time((26, 10, 0), (13, 45, 31)), None);
+        assert_eq!(super::dos_date_time((26, 10, 17), (24, 0, 0)), None);
+    }
+
+    #[test]
+    fn test_fat_write() {
+        for image in &fat_test_image_paths() {
+            let disk = RamDisk::new(image);
+            let len = disk.len();
+            let mut fs = crate::fat::Filesystem::new(&disk, 0, len);
+            fs.init().expect("Error initialising filesystem");
+            let root = fs.root().unwrap();
+
+            for (path, size) in [
+                ("/NEW.TXT", 0),
+                ("/A/A long file name.data", 100),
+                ("/A/B/C/another-file", 3 * 4096 + 17),
+                ("/short.txt", 70000),
+            ] {
+                let mut f: super::File = root.create(path, false).unwrap().try_into().unwrap();
+                let data = pattern(size, size as u8);
+                // Write in uneven pieces so writes start and end mid sector
+                for chunk in data.chunks(700) {
+                    assert_eq!(f.write(chunk).unwrap(), chunk.len() as u32);
+                }
+                assert_eq!(f.get_size(), size as u32);
+                assert_eq!(read_all(&mut f), data);
+
+                // Check it is all on disk with a fresh filesystem
+                let mut fs = crate::fat::Filesystem::new(&disk, 0, len);
+                fs.init().unwrap();
+                let mut f = open_file(&fs, path);
+                assert_eq!(read_all(&mut f), data);
+
+                // Overwrite the middle and append
+                let mut expected = data.clone();
+                let position = size as u32 / 2;
+                f.seek(position).unwrap();
+                f.write(&[0xaa; 1000]).unwrap();
+                expected.truncate(position as usize);
+                expected.extend_from_slice(&[0xaa; 1000]);
+                if expected.len() < data.len() {
+                    expected.extend_from_slice(&data[expected.len()..]);
+                }
+                f.seek(f.get_size()).unwrap();
+                f.write(b"appended").unwrap();
+                expected.extend_from_slice(b"appended");
+                assert_eq!(read_all(&mut open_file(&fs, path)), expected);
+
+                // Loading from part way into a sector
+                let start = position as usize + 3;
+                f.seek(start as u32).unwrap();
+                let loaded = vec![0u8; expected.len() - start];
+                f.load_file(&mut crate::mem::MemoryRegion::from_bytes(&loaded))
+                    .unwrap();
+                assert_eq!(loaded, expected[start..]);
+            }
+
+            // Without a clock entries are dated at the start of 1980 and
+            // writes update the modification date
+            let mut f: super::File = root
+                .create("/dated.txt", false)
+                .unwrap()
+                .try_into()
+                .unwrap();
+            let slot = f.entry.unwrap().entry;
+            let mut cache = super::SlotCache::new(&fs);
+            let entry = *cache.entry(slot).unwrap();
+            assert_eq!({ entry.create_date }, super::DOS_EPOCH_DATE);
+            assert_eq!({ entry.access_date }, super::DOS_EPOCH_DATE);
+            assert_eq!({ entry.modify_date }, super::DOS_EPOCH_DATE);
+            cache.entry_mut(slot).unwrap().modify_date = 0;
+            cache.flush().unwrap();
+            f.write(b"dated").unwrap();
+            let entry = *super::SlotCache::new(&fs).entry(slot).unwrap();
+            assert_eq!({ entry.modify_date }, super::DOS_EPOCH_DATE);
+
+            assert!(matches!(
+                root.create("/new.txt", false),
+                Err(super::Error::AlreadyExists)
+            ));
+            assert!(matches!(
+                root.create("/A/a LONG file name.DATA", true),
+                Err(super::Error::AlreadyExists)
+            ));
+            assert!(matches!(
+                root.create("/bad:name", false),
+                Err(super::Error::InvalidName)
+            ));
+            assert!(matches!(
+                root.create("/missing/file", false),
+                Err(super::Error::NotFound)
+            ));
+        }
+    }
+
+    #[test]
+    fn test_fat_set_size() {
+        for image in &fat_test_image_paths() {
+            let disk = RamDisk::new(image);
+            let len = disk.len();
+            let mut fs = crate::fat::Filesystem::new(&disk, 0, len);
+            fs.init().expect("Error initialising filesystem");
+            let free = free_clusters(&fs);
+
+            let mut f: super::File = fs
+                .root()
+                .unwrap()
+                .create("file", false)
+                .unwrap()
+                .try_into()
+                .unwrap();
+            let data = pattern(20000, 1);
+            f.write(&data).unwrap();
+
+            f.set_size(1000).unwrap();
+            assert_eq!(read_all(&mut open_file(&fs, "/file")), data[..1000]);
+
+            f.set_size(30000).unwrap();
+            let mut expected = data[..1000].to_vec();
+            expected.resize(30000, 0);
+            assert_eq!(read_all(&mut open_file(&fs, "/file")), expected);
+
+            f.set_size(0).unwrap();
+            assert_eq!(open_file(&fs, "/file").get_size(), 0);
+            assert_eq!(free_clusters(&fs), free);
+        }
+    }
+
+    #[test]
+    fn test_fat_delete() {
+        for image in &fat_test_image_paths() {
+            let disk = RamDisk::new(image);
+            let len = disk.len();
+            let mut fs = crate::fat::Filesystem::new(&disk, 0, len);
+            fs.init().expect("Error initialising filesystem");
+            let free = free_clusters(&fs);
+
+            let mut f: super::File = fs
+                .root()
+                .unwrap()
+                .create("/A/B/To be deleted", false)
+                .unwrap()
+                .try_into()
+                .unwrap();
+            f.write(&pattern(50000, 2)).unwrap();
+            assert!(free_clusters(&fs) < free);
+
+            let node = fs.open("/A/B/to be DELETED").unwrap();
+            node.delete().unwrap();
+            assert!(matches!(
+                fs.open("/A/B/To be deleted"),
+                Err(super::Error::NotFound)
+            ));
+            assert_eq!(free_clusters(&fs), free);
+
+            // Neighbouring entries are untouched
+            assert!(fs.open("/A/B/C/512").is_ok());
+            assert!(matches!(
+                fs.open("/A/B").unwrap().delete(),
+                Err(super::Error::DirectoryNotEmpty)
+            ));
+            assert!(matches!(
+                super::Node::from(fs.root().unwrap()).delete(),
+                Err(super::Error::Unsupported)
+            ));
+        }
+    }
+
+    #[test]
+    fn test_fat_directories() {
+        for image in &fat_test_image_paths() {
+            let disk = RamDisk::new(image);
+            let len = disk.len();
+            let mut fs = crate::fat::Filesystem::new(&disk, 0, len);
+            fs.init().expect("Error initialising filesystem");
+            let free = free_clusters(&fs);
+            let root = fs.root().unwrap();
+
+            let dir: super::Directory = root
+                .create("/New Directory", true)
+                .unwrap()
+                .try_into()
+                .unwrap();
+            // Enough entries with long names to need more than one cluster
+            for i in 0..200 {
+                let mut f: super::File = dir
+                    .create(&format!("file number {i}"), false)
+                    .unwrap()
+                    .try_into()
+                    .unwrap();
+                f.write(format!("{i}").as_bytes()).unwrap();
+            }
+
+            let mut d: super::Directory = fs.open("/new directory").unwrap().try_into().unwrap();
+            let mut count = 0;
+            loop {
+                match d.next_entry() {
+                    Ok(de) if de.name[0] == b'.' => {
+                        assert_eq!(de.file_type, super::FileType::Directory)
+                    }
+                    Ok(_) => count += 1,
+                    Err(super::Error::EndOfFile) => break,
+                    Err(e) => panic!("{e:?}"),
+                }
+            }
+            assert_eq!(count, 200);
+            let mut f = open_file(&fs, "/New Directory/FILE NUMBER 123");
+            assert_eq!(read_all(&mut f), b"123");
+
+            // Rename within the directory and move elsewhere
+            let mut node = fs.open("/New Directory/file number 5").unwrap();
+            node.rename("renamed").unwrap();
+            assert!(fs.open("/New Directory/file number 5").is_err());
+            assert_eq!(
+                read_all(&mut open_file(&fs, "/New Directory/renamed")),
+                b"5"
+            );
+            node.rename("/A/moved").unwrap();
+            assert_eq!(read_all(&mut open_file(&fs, "/A/moved")), b"5");
+            assert!(matches!(
+                node.rename("/A/B"),
+                Err(super::Error::AlreadyExists)
+            ));
+
+            // Moving a directory updates its ".." entry
+            let mut node = fs.open("/New Directory").unwrap();
+            node.rename("/A/B/Sub").unwrap();
+            assert_eq!(
+                read_all(&mut open_file(&fs, "/A/B/Sub/../Sub/file number 7")),
+                b"7"
+            );
+            assert!(fs.open("/A/B/Sub/../C/512").is_ok());
+
+            // Not into itself or below
+            assert!(matches!(
+                node.rename("/A/B/Sub/Sub"),
+                Err(super::Error::InvalidName)
+            ));
+            assert!(matches!(
+                fs.open("/A/B").unwrap().rename("/A/B/Sub/B"),
+                Err(super::Error::InvalidName)
+            ));
+
+            // Changing only the case rewrites the name
+            node.rename("/A/B/SUB").unwrap();
+            let mut d: super::Directory = fs.open("/A/B").unwrap().try_into().unwrap();
+            let names: Vec<_> = core::iter::from_fn(|| d.next_entry().ok())
+                .map(|de| de.long_name)
+                .collect();
+            assert!(!names.iter().any(|n| n.starts_with(b"Sub\0")));
+            node.rename("/A/B/Sub").unwrap();
+            let mut d: super::Directory = fs.open("/A/B").unwrap().try_into().unwrap();
+            let names: Vec<_> = core::iter::from_fn(|| d.next_entry().ok())
+                .map(|de| de.long_name)
+                .collect();
+            assert!(names.iter().any(|n| n.starts_with(b"Sub\0")));
+
+            for i in (0..200).filter(|i| *i != 5) {
+                fs.open(&format!("/A/B/Sub/file number {i}"))
+                    .unwrap()
+                    .delete()
+                    .unwrap();
+            }
+            fs.open("/A/moved").unwrap().delete().unwrap();
+            fs.open("/A/B/Sub").unwrap().delete().unwrap();
+            assert_eq!(free_clusters(&fs), free);
+        }
+    }
+
+    #[test]
+    fn test_short_name() {
+        assert_eq!(super::short_name("NEW.TXT"), Some(*b"NEW     TXT"));
+        assert_eq!(super::short_name("A"), Some(*b"A          "));
+        assert_eq!(super::short_name("new.txt"), None);
+        assert_eq!(super::short_name("TOOLONGNAME"), None);
+        assert_eq!(super::short_name("A.B.C"), None);
+        assert_eq!(
+            super::short_name_alias("A long file name.data", 1),
+            *b"ALONGF~1DAT"
+        );
+        assert_eq!(super::short_name_alias(".hidden", 12), *b"HIDDE~12   ");
+        assert!(super::is_valid_name("file number 1"));
+        assert!(!super::is_valid_name("a/b"));
+        assert!(!super::is_valid_name("trailing."));
+    }
+
     #[test]
     fn test_compare_short_name() {
         let mut de = super::DirectoryEntry::default();
@@ -1191,6 +2576,11 @@ mod tests {
         assert!(super::compare_short_name("abcdefgh.ijk", &de));
         de.name.copy_from_slice(b"EFI-SYSTEM ");
         assert!(!super::compare_short_name("EFI", &de));
+        de.name.copy_from_slice(b"FEDORA~1EFI");
+        assert!(!super::compare_short_name("fedora.efi", &de));
+        de.name.copy_from_slice(b".          ");
+        assert!(super::compare_short_name(".", &de));
+        assert!(!super::compare_short_name("..", &de));
     }
 
     #[test]
@@ -55,6 +55,31 @@ impl StartInfo<'_> {
         }
         None
     }
+
+    pub fn find_all_compatible_regions<F>(&self, with: &[&str], mut per_region: F)
+    where
+        F: FnMut(*const u8, usize),
+    {
+        for node in self.fdt.all_nodes() {
+            let compatible = match node.compatible() {
+                Some(compatible) => compatible,
+                None => continue,
+            };
+            if !compatible.all().any(|c| with.contains(&c)) {
+                continue;
+            }
+            if let Some(status) = node.property("status").and_then(|p| p.as_str()) {
+                if status != "okay" && status != "ok" {
+                    continue;
+                }
+            }
+            if let Some(region) = node.reg().and_then(|mut regions| regions.next()) {
+                if let Some(size) = region.size {
+                    per_region(region.starting_address, size);
+                }
+            }
+        }
+    }
 }
 
 impl Info for StartInfo<'_> {
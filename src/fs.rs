@@ -0,0 +1,304 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2026 The Rust Hypervisor Firmware Authors
+
+//! Interfaces shared by the filesystems, the loaders and the EFI file protocol
+//!
+//! Opened files cannot be boxed without an allocator, so `Node` is an enum
+//! with a variant per filesystem and a `Filesystem` only has to produce its
+//! root directory. Anything that is just read as a single file, like an image
+//! already in memory, only needs to implement `Read`.
+
+use crate::{
+    block::{Error as BlockError, SectorBuf},
+    ext4, fat, iso9660,
+    mem::MemoryRegion,
+};
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Error {
+    Block(BlockError),
+    Unsupported,
+    NotFound,
+    EndOfFile,
+    InvalidOffset,
+    NodeTypeMismatch,
+    NoSpace,
+    AlreadyExists,
+    InvalidName,
+    DirectoryNotEmpty,
+    WriteProtected,
+    Corrupt,
+}
+
+pub trait Read {
+    fn read(&mut self, data: &mut [u8]) -> Result<u32, Error>;
+    fn seek(&mut self, offset: u32) -> Result<(), Error>;
+    fn get_size(&self) -> u32;
+
+    // Loads the file from the current position into the specified memory region
+    fn load_file(&mut self, mem: &mut MemoryRegion) -> Result<(), Error> {
+        let mut chunks = mem.as_bytes().chunks_exact_mut(SectorBuf::len());
+        for chunk in chunks.by_ref() {
+            self.read(chunk)?;
+        }
+        load_last_sector(self, chunks.into_remainder())
+    }
+}
+
+// Use tmp buffer for last, partial sector
+pub fn load_last_sector<R: Read + ?Sized>(file: &mut R, last: &mut [u8]) -> Result<(), Error> {
+    if last.is_empty() {
+        return Ok(());
+    }
+    let mut dst = SectorBuf::new();
+    let bytes = file.read(dst.as_mut_bytes())? as usize;
+    assert!(bytes >= last.len());
+    last.copy_from_slice(&dst.as_bytes()[..last.len()]);
+    Ok(())
+}
+
+pub trait Filesystem {
+    fn root(&self) -> Result<Node<'_>, Error>;
+
+    /// Open the file or directory at `path`
+    fn open(&self, path: &str) -> Result<Node<'_>, Error> {
+        self.root()?.open(path)
+    }
+
+    /// Whether files can be created, changed and removed
+    fn is_writable(&self) -> bool {
+        false
+    }
+
+    /// Write back any cached changes
+    fn flush(&self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// A file or directory on one of the supported filesystems
+pub enum Node<'a> {
+    Fat(fat::Node<'a>),
+    Ext4(ext4::Node<'a>),
+    Iso9660(iso9660::Node<'a>),
+}
+
+impl<'a> Node<'a> {
+    pub fn is_directory(&self) -> bool {
+        matches!(
+            self,
+            Self::Fat(fat::Node::Directory(_))
+                | Self::Ext4(ext4::Node::Directory(_))
+                | Self::Iso9660(iso9660::Node::Directory(_))
+        )
+    }
+
+    /// Open the file or directory at `path`, relative to this directory
+    /// unless it is absolute
+    pub fn open(&self, path: &str) -> Result<Node<'a>, Error> {
+        match self {
+            Self::Fat(fat::Node::Directory(d)) => Ok(Self::Fat(d.open(path)?)),
+            Self::Ext4(ext4::Node::Directory(d)) => Ok(Self::Ext4(d.open(path)?)),
+            Self::Iso9660(iso9660::Node::Directory(d)) => Ok(Self::Iso9660(d.open(path)?)),
+            _ => Err(Error::NodeTypeMismatch),
+        }
+    }
+
+    /// Create a file or directory at `path`, relative to this directory
+    /// unless it is absolute
+    pub fn create(&self, path: &str, directory: bool) -> Result<Node<'a>, Error> {
+        match self {
+            Self::Fat(fat::Node::Directory(d)) => Ok(Self::Fat(d.create(path, directory)?)),
+            Self::Fat(fat::Node::File(_)) => Err(Error::NodeTypeMismatch),
+            _ => Err(Error::WriteProtected),
+        }
+    }
+
+    /// Whether the directory has entries left for `next_node`
+    pub fn has_next(&mut self) -> Result<bool, Error> {
+        match self {
+            Self::Fat(fat::Node::Directory(d)) => d.has_next(),
+            Self::Ext4(ext4::Node::Directory(d)) => Ok(d.has_next()?),
+            Self::Iso9660(iso9660::Node::Directory(d)) => Ok(d.has_next()?),
+            _ => Err(Error::NodeTypeMismatch),
+        }
+    }
+
+    /// Returns the next entry of the directory and its name, or EndOfFile
+    /// after the last one
+    pub fn next_node(&mut self) -> Result<(Node<'a>, [u8; 255]), Error> {
+        match self {
+            Self::Fat(fat::Node::Directory(d)) => {
+                let (node, name) = d.next_node()?;
+                Ok((Self::Fat(node), name))
+            }
+            Self::Ext4(ext4::Node::Directory(d)) => {
+                let (node, name) = d.next_node()?;
+                Ok((Self::Ext4(node), name))
+            }
+            Self::Iso9660(iso9660::Node::Directory(d)) => {
+                let (node, name) = d.next_node()?;
+                Ok((Self::Iso9660(node), name))
+            }
+            _ => Err(Error::NodeTypeMismatch),
+        }
+    }
+
+    pub fn position(&self) -> Result<u32, Error> {
+        match self {
+            Self::Fat(fat::Node::File(f)) => Ok(f.position()),
+            Self::Ext4(ext4::Node::File(f)) => Ok(f.position()),
+            Self::Iso9660(iso9660::Node::File(f)) => Ok(f.position()),
+            _ => Err(Error::NodeTypeMismatch),
+        }
+    }
+
+    pub fn write(&mut self, data: &[u8]) -> Result<u32, Error> {
+        match self {
+            Self::Fat(fat::Node::File(f)) => f.write(data),
+            Self::Fat(fat::Node::Directory(_)) => Err(Error::NodeTypeMismatch),
+            _ => Err(Error::WriteProtected),
+        }
+    }
+
+    pub fn set_size(&mut self, size: u32) -> Result<(), Error> {
+        match self {
+            Self::Fat(fat::Node::File(f)) => f.set_size(size),
+            Self::Fat(fat::Node::Directory(_)) => Err(Error::NodeTypeMismatch),
+            _ => Err(Error::WriteProtected),
+        }
+    }
+
+    /// Remove the file or empty directory
+    pub fn delete(&self) -> Result<(), Error> {
+        match self {
+            Self::Fat(node) => node.delete(),
+            _ => Err(Error::WriteProtected),
+        }
+    }
+
+    /// Rename or move the file or directory to `path`, relative to its
+    /// current directory unless it is absolute
+    pub fn rename(&mut self, path: &str) -> Result<(), Error> {
+        match self {
+            Self::Fat(node) => node.rename(path),
+            _ => Err(Error::WriteProtected),
+        }
+    }
+}
+
+impl<'a> Read for Node<'a> {
+    fn read(&mut self, data: &mut [u8]) -> Result<u32, Error> {
+        match self {
+            Self::Fat(node) => node.read(data),
+            Self::Ext4(node) => node.read(data),
+            Self::Iso9660(node) => node.read(data),
+        }
+    }
+
+    fn seek(&mut self, position: u32) -> Result<(), Error> {
+        match self {
+            Self::Fat(node) => node.seek(position),
+            Self::Ext4(node) => node.seek(position),
+            Self::Iso9660(node) => node.seek(position),
+        }
+    }
+
+    fn get_size(&self) -> u32 {
+        match self {
+            Self::Fat(node) => node.get_size(),
+            Self::Ext4(node) => node.get_size(),
+            Self::Iso9660(node) => node.get_size(),
+        }
+    }
+
+    fn load_file(&mut self, mem: &mut MemoryRegion) -> Result<(), Error> {
+        match self {
+            Self::Fat(node) => node.load_file(mem),
+            Self::Ext4(node) => node.load_file(mem),
+            Self::Iso9660(node) => node.load_file(mem),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::{Error, Filesystem, Read};
+    use crate::block::SectorBuf;
+    use crate::part::tests::*;
+
+    fn workload_path(image: &str) -> PathBuf {
+        let mut workload_path = dirs::home_dir().unwrap();
+        workload_path.push("workloads");
+        workload_path.join(image)
+    }
+
+    // Reads a file and lists a directory the way the loaders and the EFI file
+    // protocol do
+    fn check_filesystem(fs: &dyn Filesystem) {
+        let mut file = fs.open("/a/b/c/1024").expect("Error opening file");
+        assert!(!file.is_directory());
+        assert_eq!(file.get_size(), 1024);
+        let mut data = SectorBuf::new();
+        let mut contents = Vec::new();
+        loop {
+            match file.read(data.as_mut_bytes()) {
+                Ok(bytes) => contents.extend_from_slice(&data.as_bytes()[..bytes as usize]),
+                Err(Error::EndOfFile) => break,
+                Err(e) => panic!("{e:?}"),
+            }
+        }
+        assert_eq!(contents, [b'a'; 1024]);
+        assert_eq!(file.position(), Ok(1024));
+
+        let mut dir = fs.root().unwrap().open("largedir").unwrap();
+        assert!(dir.is_directory());
+        let mut names = Vec::new();
+        while dir.has_next().unwrap() {
+            let (node, name) = dir.next_node().unwrap();
+            let name = crate::common::ascii_strip(&name).to_string();
+            if name != "." && name != ".." {
+                assert!(!node.is_directory());
+                names.push(name);
+            }
+        }
+        assert_eq!(dir.next_node().err(), Some(Error::EndOfFile));
+        names.sort();
+        let mut expected: Vec<String> = (0..=100).map(|n| n.to_string()).collect();
+        expected.sort();
+        assert_eq!(names, expected);
+
+        assert_eq!(fs.open("/a/b/c/missing").err(), Some(Error::NotFound));
+        if !fs.is_writable() {
+            assert_eq!(file.write(b"a").err(), Some(Error::WriteProtected));
+            assert_eq!(file.delete().err(), Some(Error::WriteProtected));
+        }
+    }
+
+    #[test]
+    fn test_filesystems() {
+        for image in ["fat12.img", "fat16.img", "fat32.img"] {
+            let d = FakeDisk::new(&workload_path(image));
+            let mut fs = crate::fat::Filesystem::new(&d, 0, d.len());
+            fs.init().expect("Error initialising filesystem");
+            check_filesystem(&fs);
+        }
+
+        for image in ["ext2.img", "ext4.img"] {
+            let d = FakeDisk::new(&workload_path(image));
+            let mut fs = crate::ext4::Filesystem::new(&d, 0, d.len() / SectorBuf::len() as u64);
+            fs.init().expect("Error initialising filesystem");
+            check_filesystem(&fs);
+        }
+
+        for image in ["iso9660.img", "joliet.img"] {
+            let d = FakeDisk::new(&workload_path(image));
+            let mut fs =
+                crate::iso9660::Filesystem::new(&d, 0, d.len() / SectorBuf::len() as u64 - 1);
+            fs.init().expect("Error initialising filesystem");
+            check_filesystem(&fs);
+        }
+    }
+}
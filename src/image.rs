@@ -0,0 +1,855 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2026 The Rust Hypervisor Firmware Authors
+
+// Loader for the arm64 and riscv64 Linux "Image" format, see
+// Documentation/arch/arm64/booting.rst and
+// Documentation/arch/riscv/boot-image-header.rst in the kernel tree
+
+use heapless::Vec;
+use log::error;
+
+use crate::{
+    block::SectorBuf,
+    bootinfo::{EntryType, Info, MemoryEntry},
+    compression::{self, Format},
+    fs::{self, Read},
+    mem::MemoryRegion,
+    overlay::{self, Tree},
+};
+
+#[derive(Debug)]
+pub enum Error {
+    #[allow(dead_code)]
+    File(fs::Error),
+    MagicMissing,
+    WrongArchitecture,
+    BigEndian,
+    NoKernelMemory,
+    NoInitrdMemory,
+    NoDeviceTree,
+    NoDeviceTreeMemory,
+    InvalidDeviceTree,
+    InvalidDeviceTreeOverlay,
+    DeviceTreeOverlayUnresolved,
+    #[allow(dead_code)]
+    Compression(compression::Error),
+}
+
+impl From<fs::Error> for Error {
+    fn from(e: fs::Error) -> Error {
+        Error::File(e)
+    }
+}
+
+impl From<compression::Error> for Error {
+    fn from(e: compression::Error) -> Error {
+        Error::Compression(e)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Arch {
+    Arm64,
+    Riscv64,
+}
+
+#[cfg(not(target_arch = "riscv64"))]
+const ARCH: Arch = Arch::Arm64;
+#[cfg(target_arch = "riscv64")]
+const ARCH: Arch = Arch::Riscv64;
+
+const HEADER_SIZE: usize = 64;
+
+// Both architectures want the image at a 2 MiB aligned base, the initrd and
+// device tree use the same alignment to keep them out of the kernel's block
+// mappings
+const ALIGN: u64 = 2 << 20;
+
+#[derive(Debug, PartialEq)]
+struct Header {
+    arch: Arch,
+    text_offset: u64,
+    image_size: u64,
+}
+
+impl Header {
+    fn parse(data: &[u8]) -> Result<Self, Error> {
+        if data.len() < HEADER_SIZE {
+            return Err(Error::MagicMissing);
+        }
+        let u64_at =
+            |offset: usize| u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap());
+
+        let arch = if &data[56..60] == b"ARM\x64" {
+            Arch::Arm64
+        } else if &data[56..60] == b"RSC\x05" || &data[48..56] == b"RISCV\0\0\0" {
+            Arch::Riscv64
+        } else {
+            return Err(Error::MagicMissing);
+        };
+
+        // Bit 0 of the flags is the kernel's endianness on both architectures
+        if u64_at(24) & 1 != 0 {
+            return Err(Error::BigEndian);
+        }
+
+        let (text_offset, image_size) = match u64_at(16) {
+            // arm64 kernels before v3.17 leave the size unset and expect this
+            // offset
+            0 if arch == Arch::Arm64 => (0x80000, 0),
+            size => (u64_at(8), size),
+        };
+
+        Ok(Header {
+            arch,
+            text_offset,
+            image_size,
+        })
+    }
+}
+
+pub const fn align_up(addr: u64, align: u64) -> u64 {
+    (addr + align - 1) & !(align - 1)
+}
+
+const MAX_RANGES: usize = 16;
+
+// Free RAM as the firmware sees it, as [start, end) ranges
+struct Memory {
+    ram: Vec<(u64, u64), MAX_RANGES>,
+    reserved: Vec<(u64, u64), MAX_RANGES>,
+}
+
+impl Memory {
+    fn new(info: &dyn Info) -> Self {
+        let mut memory = Memory {
+            ram: Vec::new(),
+            reserved: Vec::new(),
+        };
+        for i in 0..info.num_entries() {
+            let entry = info.entry(i);
+            if entry.entry_type == EntryType::Ram {
+                let _ = memory.ram.push((entry.addr, entry.addr + entry.size));
+            }
+        }
+        // The firmware is still running while everything is loaded so its
+        // code, data and stack are as off limits as the device tree
+        for descriptor in info.memory_layout() {
+            let range = (descriptor.range)();
+            memory.reserve(range.start as u64, range.end as u64);
+        }
+        if let Some(fdt) = info.fdt_reservation() {
+            memory.reserve(fdt.addr, fdt.addr + fdt.size);
+        }
+        memory
+    }
+
+    fn reserve(&mut self, start: u64, end: u64) {
+        if self.reserved.push((start, end)).is_err() {
+            panic!("Too many reserved memory ranges");
+        }
+    }
+
+    // Find the lowest aligned address at or above min with size bytes of RAM
+    // that overlap no reserved range
+    fn allocate(&self, min: u64, size: u64, align: u64) -> Option<u64> {
+        let mut addr = align_up(min, align);
+        'search: loop {
+            let end = addr.checked_add(size)?;
+            for &(start, reserved_end) in &self.reserved {
+                if start < end && addr < reserved_end {
+                    addr = align_up(reserved_end, align);
+                    continue 'search;
+                }
+            }
+            if self
+                .ram
+                .iter()
+                .any(|&(start, ram_end)| start <= addr && end <= ram_end)
+            {
+                return Some(addr);
+            }
+            // Not enough RAM left here, try the next range
+            addr = self
+                .ram
+                .iter()
+                .filter(|&&(start, _)| start > addr)
+                .map(|&(start, _)| align_up(start, align))
+                .min()?;
+        }
+    }
+
+    // Find the largest free [start, end) range with an aligned start at or
+    // above min, for data whose size is only known once it has been written
+    fn largest(&self, min: u64, align: u64) -> Option<(u64, u64)> {
+        let starts = core::iter::once(min)
+            .chain(self.ram.iter().map(|&(start, _)| start))
+            .chain(self.reserved.iter().map(|&(_, end)| end))
+            .map(|start| align_up(u64::max(start, min), align));
+        starts
+            .filter_map(|start| {
+                let &(_, ram_end) = self
+                    .ram
+                    .iter()
+                    .find(|&&(ram_start, ram_end)| ram_start <= start && start < ram_end)?;
+                let mut end = ram_end;
+                for &(reserved_start, reserved_end) in &self.reserved {
+                    if reserved_start <= start && start < reserved_end {
+                        return None;
+                    }
+                    if reserved_start > start {
+                        end = u64::min(end, reserved_start);
+                    }
+                }
+                Some((start, end))
+            })
+            .max_by_key(|&(start, end)| end - start)
+    }
+}
+
+pub const FDT_MAGIC: u32 = 0xd00d_feed;
+pub const FDT_BEGIN_NODE: u32 = 1;
+pub const FDT_END_NODE: u32 = 2;
+pub const FDT_PROP: u32 = 3;
+pub const FDT_NOP: u32 = 4;
+pub const FDT_END: u32 = 9;
+
+// Properties of /chosen written by the loader, any existing ones are replaced
+// or dropped
+const CHOSEN_PROPERTIES: [&[u8]; 3] = [b"bootargs", b"linux,initrd-start", b"linux,initrd-end"];
+
+// Upper bound of what update_chosen() adds to the device tree besides the
+// command line
+const CHOSEN_OVERHEAD: u64 = 256;
+
+// arm64 refuses larger device trees
+const FDT_MAX_SIZE: u64 = 2 << 20;
+
+// Upper bound of what merging an overlay adds to the device tree beyond the
+// overlay's own size, for the paths of its symbols in the base tree
+const OVERLAY_OVERHEAD: u64 = 4096;
+
+pub fn be32(data: &[u8], offset: usize) -> Result<u32, Error> {
+    match data.get(offset..offset + 4) {
+        Some(bytes) => Ok(u32::from_be_bytes(bytes.try_into().unwrap())),
+        None => Err(Error::InvalidDeviceTree),
+    }
+}
+
+// The NUL terminated string at offset without its terminator
+pub fn c_str(data: &[u8], offset: usize) -> Result<&[u8], Error> {
+    let s = data.get(offset..).ok_or(Error::InvalidDeviceTree)?;
+    match s.iter().position(|&b| b == 0) {
+        Some(len) => Ok(&s[..len]),
+        None => Err(Error::InvalidDeviceTree),
+    }
+}
+
+// Copy the flattened device tree in src to dst with bootargs and the initrd
+// range, if any, set in /chosen. Returns the size of the new tree.
+fn update_chosen(
+    src: &[u8],
+    dst: &mut [u8],
+    bootargs: &[u8],
+    initrd: Option<(u64, u64)>,
+) -> Result<usize, Error> {
+    let size = be32(src, 4)? as usize;
+    let src = src.get(..size).ok_or(Error::InvalidDeviceTree)?;
+    dst.get_mut(..size)
+        .ok_or(Error::NoDeviceTreeMemory)?
+        .copy_from_slice(src);
+    let mut tree = Tree::new(dst)?;
+
+    let root = tree.root()?;
+    let chosen = match tree.subnode(root, b"chosen")? {
+        Some(chosen) => chosen,
+        None => tree.add_subnode(root, b"chosen")?,
+    };
+    // Properties are added right after the node, which stays where it is
+    let mut value: Vec<u8, { CMDLINE_MAX_LEN + 1 }> = Vec::new();
+    value
+        .extend_from_slice(bootargs)
+        .map_err(|_| Error::NoDeviceTreeMemory)?;
+    value.push(0).map_err(|_| Error::NoDeviceTreeMemory)?;
+    tree.set_property(chosen, CHOSEN_PROPERTIES[0], &value)?;
+    match initrd {
+        Some((start, end)) => {
+            tree.set_property(chosen, CHOSEN_PROPERTIES[1], &start.to_be_bytes())?;
+            tree.set_property(chosen, CHOSEN_PROPERTIES[2], &end.to_be_bytes())?;
+        }
+        None => {
+            tree.delete_property(chosen, CHOSEN_PROPERTIES[1])?;
+            tree.delete_property(chosen, CHOSEN_PROPERTIES[2])?;
+        }
+    }
+    Ok(tree.size())
+}
+
+// Decompress f into out, which is all the memory there is for it
+fn decompress(
+    format: Format,
+    f: &mut dyn Read,
+    out: &mut [u8],
+    no_memory: Error,
+) -> Result<usize, Error> {
+    match compression::decompress(format, f, out) {
+        Ok(len) => Ok(len),
+        Err(compression::Error::OutputTooLarge) => Err(no_memory),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// Load f, decompressing it if needed, into the start of out and return its
+// size
+fn load(f: &mut dyn Read, out: &mut [u8], no_memory: Error) -> Result<usize, Error> {
+    let mut data = SectorBuf::new();
+    f.seek(0)?;
+    let bytes = match f.read(data.as_mut_bytes()) {
+        Ok(bytes) => bytes as usize,
+        Err(fs::Error::EndOfFile) => 0,
+        Err(e) => return Err(e.into()),
+    };
+    if let Some(format) = Format::detect(&data.as_bytes()[..bytes]) {
+        return decompress(format, f, out, no_memory);
+    }
+
+    let size = f.get_size() as usize;
+    if size > out.len() {
+        return Err(no_memory);
+    }
+    let mut region = MemoryRegion::new(out.as_mut_ptr() as u64, size as u64);
+    f.seek(0)?;
+    f.load_file(&mut region)?;
+    Ok(size)
+}
+
+// Load the initrds back-to-back into out, each starting 4 byte aligned as the
+// kernel unpacks them as one stream of cpio archives, and return their total
+// size
+fn load_initrds(files: &mut [&mut dyn Read], out: &mut [u8]) -> Result<usize, Error> {
+    let mut size: usize = 0;
+    for f in files.iter_mut() {
+        let start = size.next_multiple_of(4);
+        let padding = out.get_mut(size..start).ok_or(Error::NoInitrdMemory)?;
+        padding.fill(0);
+        size = start + load(*f, &mut out[start..], Error::NoInitrdMemory)?;
+    }
+    Ok(size)
+}
+
+const CMDLINE_MAX_LEN: usize = 4096;
+
+pub struct Kernel {
+    memory: Memory,
+    fdt: Option<MemoryEntry>,
+    // [start, end) of the kernel
+    image: (u64, u64),
+    initrd: Option<(u64, u64)>,
+    cmdline: [u8; CMDLINE_MAX_LEN],
+    cmdline_len: usize,
+}
+
+impl Kernel {
+    pub fn new(info: &dyn Info) -> Self {
+        Self {
+            memory: Memory::new(info),
+            fdt: info.fdt_reservation(),
+            image: (0, 0),
+            initrd: None,
+            cmdline: [0; CMDLINE_MAX_LEN],
+            cmdline_len: 0,
+        }
+    }
+
+    pub fn load_kernel(&mut self, info: &dyn Info, f: &mut dyn Read) -> Result<(), Error> {
+        let mut data = SectorBuf::new();
+        f.seek(0)?;
+        let bytes = f.read(data.as_mut_bytes())? as usize;
+        if let Some(format) = Format::detect(&data.as_bytes()[..bytes]) {
+            return self.load_compressed_kernel(info, format, f);
+        }
+        let header = Header::parse(&data.as_bytes()[..bytes])?;
+        if header.arch != ARCH {
+            return Err(Error::WrongArchitecture);
+        }
+
+        // The image goes text_offset bytes above a 2 MiB aligned base and
+        // needs image_size bytes there, which includes its BSS
+        let file_size = f.get_size() as u64;
+        let size = header.text_offset + u64::max(header.image_size, file_size);
+        let base = self
+            .memory
+            .allocate(info.kernel_load_addr(), size, ALIGN)
+            .ok_or(Error::NoKernelMemory)?;
+
+        let addr = base + header.text_offset;
+        let mut region = MemoryRegion::new(addr, file_size);
+        f.seek(0)?;
+        f.load_file(&mut region)?;
+
+        self.image = (addr, base + size);
+        Ok(())
+    }
+
+    // The header is only readable once decompressed, so the image is
+    // decompressed to the base and then moved up by its text_offset
+    fn load_compressed_kernel(
+        &mut self,
+        info: &dyn Info,
+        format: Format,
+        f: &mut dyn Read,
+    ) -> Result<(), Error> {
+        let (base, end) = self
+            .memory
+            .largest(info.kernel_load_addr(), ALIGN)
+            .ok_or(Error::NoKernelMemory)?;
+        let mut region = MemoryRegion::new(base, end - base);
+        let data = region.as_bytes();
+        let len = decompress(format, f, data, Error::NoKernelMemory)?;
+        let header = Header::parse(&data[..len])?;
+        if header.arch != ARCH {
+            return Err(Error::WrongArchitecture);
+        }
+
+        let size = header.text_offset + u64::max(header.image_size, len as u64);
+        if size > end - base {
+            return Err(Error::NoKernelMemory);
+        }
+        data.copy_within(..len, header.text_offset as usize);
+
+        self.image = (base + header.text_offset, base + size);
+        Ok(())
+    }
+
+    // The next allocation goes above everything loaded so far
+    fn end(&self) -> u64 {
+        match self.initrd {
+            Some((_, end)) => end,
+            None => self.image.1,
+        }
+    }
+
+    /// Load the initrds into one, see `load_initrds`
+    pub fn load_initrd(&mut self, files: &mut [&mut dyn Read]) -> Result<(), Error> {
+        let (addr, end) = self
+            .memory
+            .largest(self.end(), ALIGN)
+            .ok_or(Error::NoInitrdMemory)?;
+        let mut region = MemoryRegion::new(addr, end - addr);
+        let size = load_initrds(files, region.as_bytes())?;
+        self.initrd = Some((addr, addr + size as u64));
+        Ok(())
+    }
+
+    /// Replace the firmware's device tree with the one in `f`, if given,
+    /// and apply `overlays` to it
+    pub fn load_device_tree(
+        &mut self,
+        f: Option<&mut dyn Read>,
+        overlays: &mut [&mut dyn Read],
+    ) -> Result<(), Error> {
+        let base_size = match &f {
+            Some(f) => u64::from(f.get_size()),
+            None => self.fdt.ok_or(Error::NoDeviceTree)?.size,
+        };
+        let overlay_sizes = overlays.iter().map(|o| u64::from(o.get_size()));
+        let size = base_size
+            + overlay_sizes.clone().sum::<u64>()
+            + overlays.len() as u64 * OVERLAY_OVERHEAD;
+        if size > FDT_MAX_SIZE {
+            return Err(Error::NoDeviceTreeMemory);
+        }
+        // Each overlay is loaded after the tree in turn
+        let scratch_size = overlay_sizes.max().unwrap_or(0);
+        let addr = self
+            .memory
+            .allocate(self.end(), size + scratch_size, ALIGN)
+            .ok_or(Error::NoDeviceTreeMemory)?;
+
+        let mut region = MemoryRegion::new(addr, size + scratch_size);
+        let (tree, scratch) = region.as_bytes().split_at_mut(size as usize);
+        match f {
+            Some(f) => {
+                load(f, tree, Error::NoDeviceTreeMemory)?;
+            }
+            None => {
+                let fdt = self.fdt.ok_or(Error::NoDeviceTree)?;
+                let mut src = MemoryRegion::new(fdt.addr, fdt.size);
+                tree[..fdt.size as usize].copy_from_slice(src.as_bytes());
+            }
+        }
+        let mut tree = Tree::new(tree)?;
+        for o in overlays.iter_mut() {
+            let len = load(*o, scratch, Error::NoDeviceTreeMemory)?;
+            overlay::apply(&mut tree, &mut Tree::new(&mut scratch[..len])?)?;
+        }
+
+        // Only the tree stays, write_device_tree() goes around it
+        let size = tree.size() as u64;
+        self.memory.reserve(addr, addr + size);
+        self.fdt = Some(MemoryEntry {
+            addr,
+            size,
+            entry_type: EntryType::Reserved,
+        });
+        Ok(())
+    }
+
+    pub fn append_cmdline(&mut self, addition: &[u8]) {
+        if addition.is_empty() {
+            return;
+        }
+        let mut len = self.cmdline_len;
+        if len != 0 {
+            self.cmdline[len] = b' ';
+            len += 1;
+        }
+        // Leave room for the NUL terminator
+        assert!(len + addition.len() < CMDLINE_MAX_LEN);
+        self.cmdline[len..len + addition.len()].copy_from_slice(addition);
+        self.cmdline_len = len + addition.len();
+    }
+
+    // Write the firmware's device tree with the command line and initrd in
+    // /chosen above the kernel and initrd, returning its address and size
+    fn write_device_tree(&self) -> Result<(u64, u64), Error> {
+        let fdt = self.fdt.ok_or(Error::NoDeviceTree)?;
+        let cmdline = &self.cmdline[..self.cmdline_len];
+        let size = fdt.size + cmdline.len() as u64 + CHOSEN_OVERHEAD;
+        if size > FDT_MAX_SIZE {
+            return Err(Error::NoDeviceTreeMemory);
+        }
+        let addr = self
+            .memory
+            .allocate(self.end(), size, ALIGN)
+            .ok_or(Error::NoDeviceTreeMemory)?;
+
+        let mut src = MemoryRegion::new(fdt.addr, fdt.size);
+        let mut dst = MemoryRegion::new(addr, size);
+        let size = update_chosen(src.as_bytes(), dst.as_bytes(), cmdline, self.initrd)?;
+        Ok((addr, size as u64))
+    }
+
+    #[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
+    pub fn boot(&mut self) {
+        let fdt = match self.write_device_tree() {
+            Ok(fdt) => fdt,
+            Err(err) => {
+                error!("Failed to prepare the device tree: {err:?}");
+                return;
+            }
+        };
+        // SAFETY: The kernel, initrd and device tree are in memory reserved
+        // for nothing else and the firmware is done with the hardware
+        unsafe { self.jump(fdt) }
+    }
+
+    // The kernel is entered with the MMU off, so everything it reads from
+    // memory must be written back from the data cache first
+    #[cfg(target_arch = "aarch64")]
+    unsafe fn jump(&self, (fdt, fdt_size): (u64, u64)) -> ! {
+        use aarch64_cpu::registers::{Readable, SCTLR_EL1};
+        use core::arch::asm;
+
+        let ctr: u64;
+        asm!("mrs {}, ctr_el0", out(reg) ctr);
+        let line = 4 << ((ctr >> 16) & 0xf);
+        let ranges = [
+            self.image,
+            self.initrd.unwrap_or((0, 0)),
+            (fdt, fdt + fdt_size),
+        ];
+        for (start, end) in ranges {
+            let mut addr = start & !(line - 1);
+            while addr < end {
+                asm!("dc civac, {}", in(reg) addr);
+                addr += line;
+            }
+        }
+        asm!("dsb sy");
+
+        // Turn off the MMU (bit 0) and data cache (bit 2) without touching the
+        // stack, which is no longer coherent afterwards
+        let sctlr = SCTLR_EL1.get() & !0b101;
+        asm!(
+            "msr daifset, #0xf",
+            "msr sctlr_el1, {sctlr}",
+            "isb",
+            "ic iallu",
+            "dsb nsh",
+            "isb",
+            "br {entry}",
+            sctlr = in(reg) sctlr,
+            entry = in(reg) self.image.0,
+            in("x0") fdt,
+            in("x1") 0u64,
+            in("x2") 0u64,
+            in("x3") 0u64,
+            options(noreturn),
+        )
+    }
+
+    #[cfg(target_arch = "riscv64")]
+    unsafe fn jump(&self, (fdt, _): (u64, u64)) -> ! {
+        core::arch::asm!(
+            "fence.i",
+            "jr {entry}",
+            entry = in(reg) self.image.0,
+            in("a0") crate::arch::riscv64::boot_hart_id(),
+            in("a1") fdt,
+            options(noreturn),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Arch, Error, Header, Memory};
+    use crate::overlay::{
+        tests::{dtb, Item::*},
+        Tree,
+    };
+
+    fn header(text_offset: u64, image_size: u64, arch: Arch) -> [u8; 64] {
+        let mut data = [0; 64];
+        data[8..16].copy_from_slice(&text_offset.to_le_bytes());
+        data[16..24].copy_from_slice(&image_size.to_le_bytes());
+        match arch {
+            Arch::Arm64 => data[56..60].copy_from_slice(b"ARM\x64"),
+            Arch::Riscv64 => data[56..60].copy_from_slice(b"RSC\x05"),
+        }
+        data
+    }
+
+    #[test]
+    fn test_header() {
+        let data = header(0, 0x1e0_0000, Arch::Arm64);
+        assert_eq!(
+            Header::parse(&data).unwrap(),
+            Header {
+                arch: Arch::Arm64,
+                text_offset: 0,
+                image_size: 0x1e0_0000,
+            }
+        );
+
+        // Old arm64 kernels without a size
+        let data = header(0, 0, Arch::Arm64);
+        assert_eq!(Header::parse(&data).unwrap().text_offset, 0x80000);
+
+        let data = header(0x20_0000, 0x160_0000, Arch::Riscv64);
+        assert_eq!(
+            Header::parse(&data).unwrap(),
+            Header {
+                arch: Arch::Riscv64,
+                text_offset: 0x20_0000,
+                image_size: 0x160_0000,
+            }
+        );
+
+        // Only the deprecated magic
+        let mut data = header(0x20_0000, 0x160_0000, Arch::Riscv64);
+        data[56..60].fill(0);
+        data[48..56].copy_from_slice(b"RISCV\0\0\0");
+        assert_eq!(Header::parse(&data).unwrap().arch, Arch::Riscv64);
+
+        let mut data = header(0, 0x1e0_0000, Arch::Arm64);
+        data[24] = 1;
+        assert!(matches!(Header::parse(&data), Err(Error::BigEndian)));
+
+        // A bzImage is not an Image
+        let mut data = [0; 64];
+        data[0..2].copy_from_slice(b"MZ");
+        assert!(matches!(Header::parse(&data), Err(Error::MagicMissing)));
+        assert!(matches!(
+            Header::parse(&data[..32]),
+            Err(Error::MagicMissing)
+        ));
+    }
+
+    #[test]
+    fn test_allocate() {
+        const M: u64 = 1 << 20;
+        let mut memory = Memory {
+            ram: heapless::Vec::new(),
+            reserved: heapless::Vec::new(),
+        };
+        memory.ram.push((0x4000_0000, 0x4400_0000)).unwrap();
+        memory.ram.push((0x1_0000_0000, 0x1_4000_0000)).unwrap();
+        memory.reserve(0x4000_0000, 0x4040_0000);
+        memory.reserve(0x4060_0000, 0x4072_0000);
+
+        // Fits below the firmware
+        assert_eq!(memory.allocate(0x4040_0000, M, 2 * M), Some(0x4040_0000));
+        // Does not, so goes past it
+        assert_eq!(
+            memory.allocate(0x4040_0000, 3 * M, 2 * M),
+            Some(0x4080_0000)
+        );
+        assert_eq!(memory.allocate(0x4040_0001, M, 2 * M), Some(0x4080_0000));
+        // Moves on to the next RAM range
+        assert_eq!(
+            memory.allocate(0x4040_0000, 64 * M, 2 * M),
+            Some(0x1_0000_0000)
+        );
+        assert_eq!(memory.allocate(0x4040_0000, 2048 * M, 2 * M), None);
+
+        // The gap below the firmware is smaller than the rest of the range
+        assert_eq!(
+            memory.largest(0x4040_0000, 2 * M),
+            Some((0x1_0000_0000, 0x1_4000_0000))
+        );
+        memory.reserve(0x1_0000_0000, 0x1_3e00_0000);
+        assert_eq!(
+            memory.largest(0x4040_0000, 2 * M),
+            Some((0x4080_0000, 0x4400_0000))
+        );
+        assert_eq!(
+            memory.largest(0x4040_0001, 2 * M),
+            Some((0x4080_0000, 0x4400_0000))
+        );
+        assert_eq!(
+            memory.largest(0x1_3e00_0000, 2 * M),
+            Some((0x1_3e00_0000, 0x1_4000_0000))
+        );
+        assert_eq!(memory.largest(0x1_4000_0000, 2 * M), None);
+    }
+
+    #[test]
+    fn test_load_initrds() {
+        let fs = crate::part::tests::ext4_filesystem();
+        let open = |path| crate::fs::Filesystem::open(&fs, path).unwrap();
+
+        // A microcode update then a compressed initrd
+        let mut first = open("/a/b/c/511");
+        let mut second = open("/large.gz");
+        let large = crate::compression::tests::test_file("/large");
+        let mut out = vec![0xff; 4 << 20];
+        let size = super::load_initrds(&mut [&mut first, &mut second], &mut out).unwrap();
+        assert_eq!(size, 512 + large.len());
+        assert_eq!(out[..511], [b'a'; 511]);
+        assert_eq!(out[511], 0);
+        assert_eq!(out[512..size], large);
+
+        let mut first = open("/a/b/c/511");
+        let mut second = open("/large");
+        assert!(matches!(
+            super::load_initrds(&mut [&mut first, &mut second], &mut out[..large.len()]),
+            Err(Error::NoInitrdMemory)
+        ));
+        let mut second = open("/large.zst");
+        assert!(matches!(
+            super::load_initrds(&mut [&mut second], &mut out[..4096]),
+            Err(Error::NoInitrdMemory)
+        ));
+    }
+
+    fn update(src: &[u8], bootargs: &[u8], initrd: Option<(u64, u64)>) -> Vec<u8> {
+        let mut dst = vec![0; src.len() + bootargs.len() + super::CHOSEN_OVERHEAD as usize];
+        let size = super::update_chosen(src, &mut dst, bootargs, initrd).unwrap();
+        dst.truncate(size);
+        dst
+    }
+
+    fn properties(data: &mut [u8]) -> Vec<(String, Vec<u8>)> {
+        crate::overlay::tests::properties(&Tree::new(data).unwrap())
+    }
+
+    fn property(path: &str, value: &[u8]) -> (String, Vec<u8>) {
+        (path.to_string(), value.to_vec())
+    }
+
+    #[test]
+    fn test_update_chosen() {
+        let memory = Node("memory@40000000", &[Property("device_type", b"memory\0")]);
+
+        // /chosen is created when missing
+        let mut data = update(&dtb(&[memory]), b"console=ttyAMA0", None);
+        assert_eq!(
+            properties(&mut data),
+            [
+                property("/memory@40000000:device_type", b"memory\0"),
+                property("/chosen:bootargs", b"console=ttyAMA0\0"),
+            ]
+        );
+
+        // Existing properties are replaced and others kept
+        let memory = Node("memory@40000000", &[Property("device_type", b"memory\0")]);
+        let src = dtb(&[
+            memory,
+            Node(
+                "chosen",
+                &[
+                    Property("bootargs", b"console=hvc0\0"),
+                    Property("stdout-path", b"/pl011@9000000\0"),
+                    Property("linux,initrd-start", &0x4800_0000u64.to_be_bytes()),
+                    Property("linux,initrd-end", &0x4810_0000u64.to_be_bytes()),
+                    Nop,
+                ],
+            ),
+        ]);
+        let mut data = update(
+            &src,
+            b"console=hvc0 quiet",
+            Some((0x4880_0000, 0x4890_0000)),
+        );
+        assert_eq!(
+            properties(&mut data),
+            [
+                property("/memory@40000000:device_type", b"memory\0"),
+                property("/chosen:bootargs", b"console=hvc0 quiet\0"),
+                property("/chosen:stdout-path", b"/pl011@9000000\0"),
+                property("/chosen:linux,initrd-start", &0x4880_0000u64.to_be_bytes()),
+                property("/chosen:linux,initrd-end", &0x4890_0000u64.to_be_bytes()),
+            ]
+        );
+        // The names already in the strings block are reused
+        assert_eq!(data[32..36], src[32..36]);
+
+        // An initrd from the firmware's own tree is not passed on
+        let mut data = update(&src, b"", None);
+        assert_eq!(
+            properties(&mut data),
+            [
+                property("/memory@40000000:device_type", b"memory\0"),
+                property("/chosen:bootargs", b"\0"),
+                property("/chosen:stdout-path", b"/pl011@9000000\0"),
+            ]
+        );
+
+        // New properties go before the subnodes of /chosen, where the kernel
+        // stops looking for them
+        let src = dtb(&[Node(
+            "chosen",
+            &[
+                Property("stdout-path", b"/pl011@9000000\0"),
+                Node("framebuffer@0", &[Property("status", b"okay\0")]),
+            ],
+        )]);
+        let mut data = update(&src, b"quiet", Some((0x4880_0000, 0x4890_0000)));
+        assert_eq!(
+            properties(&mut data),
+            [
+                property("/chosen:stdout-path", b"/pl011@9000000\0"),
+                property("/chosen:bootargs", b"quiet\0"),
+                property("/chosen:linux,initrd-start", &0x4880_0000u64.to_be_bytes()),
+                property("/chosen:linux,initrd-end", &0x4890_0000u64.to_be_bytes()),
+                property("/chosen/framebuffer@0:status", b"okay\0"),
+            ]
+        );
+
+        // Not a device tree
+        let mut dst = [0; 512];
+        assert!(matches!(
+            super::update_chosen(&[0; 64], &mut dst, b"", None),
+            Err(Error::InvalidDeviceTree)
+        ));
+        // No room for the result
+        let src = dtb(&[]);
+        let mut dst = vec![0; src.len()];
+        assert!(matches!(
+            super::update_chosen(&src, &mut dst, b"quiet", None),
+            Err(Error::NoDeviceTreeMemory)
+        ));
+    }
+}
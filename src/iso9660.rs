@@ -0,0 +1,888 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2026 The Rust Hypervisor Firmware Authors
+
+//! Read-only ISO 9660 support and El Torito boot catalog parsing
+//!
+//! Names come from Rock Ridge `NM` entries if present, then from the Joliet
+//! supplementary volume descriptor, and otherwise from the plain ISO 9660
+//! identifiers with the version suffix removed. Lookups ignore ASCII case.
+
+use crate::{
+    block::{BlockDevice, Error as BlockError, SectorBuf, SectorRead},
+    fat, fs,
+    mem::MemoryRegion,
+};
+
+// Logical blocks are 2048 bytes, i.e. four sectors
+const BLOCK_SIZE: u64 = 2048;
+const SECTORS_PER_BLOCK: u64 = BLOCK_SIZE / 512;
+
+const VOLUME_DESCRIPTOR_START: u64 = 16;
+// Bound on the number of volume descriptors scanned for the terminator
+const MAX_VOLUME_DESCRIPTORS: u64 = 64;
+const STANDARD_IDENTIFIER: &[u8] = b"CD001";
+
+const DESCRIPTOR_BOOT_RECORD: u8 = 0;
+const DESCRIPTOR_PRIMARY: u8 = 1;
+const DESCRIPTOR_SUPPLEMENTARY: u8 = 2;
+const DESCRIPTOR_TERMINATOR: u8 = 255;
+
+// Escape sequences for UCS-2 levels 1 to 3
+const JOLIET_ESCAPES: [&[u8]; 3] = [b"%/@", b"%/C", b"%/E"];
+
+const FLAG_DIRECTORY: u8 = 0x2;
+const FLAG_MULTI_EXTENT: u8 = 0x80;
+// Length of a directory record with a one byte identifier
+const MIN_RECORD_LENGTH: usize = 34;
+
+// System Use Sharing Protocol "SP" check bytes
+const SUSP_CHECK: [u8; 2] = [0xbe, 0xef];
+// Bound on the number of continuation areas followed for a single record
+const MAX_CONTINUATIONS: u32 = 8;
+
+const EL_TORITO_IDENTIFIER: &[u8] = b"EL TORITO SPECIFICATION";
+const CATALOG_ENTRY_SIZE: u64 = 32;
+// Bound on the number of catalog entries examined
+const MAX_CATALOG_ENTRIES: u64 = 256;
+const CATALOG_VALIDATION: u8 = 0x01;
+const CATALOG_BOOTABLE: u8 = 0x88;
+const CATALOG_SECTION_HEADER: u8 = 0x90;
+const CATALOG_FINAL_SECTION_HEADER: u8 = 0x91;
+const CATALOG_EXTENSION: u8 = 0x44;
+const PLATFORM_EFI: u8 = 0xef;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Error {
+    Block(BlockError),
+    NotIso9660,
+    Unsupported,
+    Corrupt,
+    NotFound,
+    EndOfFile,
+    NodeTypeMismatch,
+}
+
+impl From<Error> for fs::Error {
+    fn from(e: Error) -> fs::Error {
+        match e {
+            Error::Block(e) => fs::Error::Block(e),
+            Error::NotFound => fs::Error::NotFound,
+            Error::EndOfFile => fs::Error::EndOfFile,
+            Error::NodeTypeMismatch => fs::Error::NodeTypeMismatch,
+            Error::Corrupt => fs::Error::Corrupt,
+            Error::NotIso9660 | Error::Unsupported => fs::Error::Unsupported,
+        }
+    }
+}
+
+fn le16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap())
+}
+
+fn le32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Names {
+    Iso9660,
+    Joliet,
+    // Bytes to skip at the start of each system use area
+    RockRidge(usize),
+}
+
+// The contiguous data of a file or directory
+#[derive(Clone, Copy)]
+struct Extent {
+    sector: u64,
+    size: u32,
+}
+
+pub struct Filesystem<'a> {
+    device: &'a dyn SectorRead,
+    start: u64,
+    last: u64,
+    root: Extent,
+    names: Names,
+}
+
+pub enum Node<'a> {
+    File(File<'a>),
+    Directory(Directory<'a>),
+}
+
+impl<'a> TryFrom<Node<'a>> for File<'a> {
+    type Error = Error;
+
+    fn try_from(from: Node<'a>) -> Result<Self, Self::Error> {
+        match from {
+            Node::File(f) => Ok(f),
+            _ => Err(Self::Error::NodeTypeMismatch),
+        }
+    }
+}
+
+impl<'a> TryFrom<Node<'a>> for Directory<'a> {
+    type Error = Error;
+
+    fn try_from(from: Node<'a>) -> Result<Self, Self::Error> {
+        match from {
+            Node::Directory(d) => Ok(d),
+            _ => Err(Self::Error::NodeTypeMismatch),
+        }
+    }
+}
+
+impl<'a> fs::Read for Node<'a> {
+    fn read(&mut self, data: &mut [u8]) -> Result<u32, fs::Error> {
+        match self {
+            Self::File(file) => file.read(data),
+            Self::Directory(_) => Err(fs::Error::Unsupported),
+        }
+    }
+
+    fn seek(&mut self, position: u32) -> Result<(), fs::Error> {
+        match self {
+            Self::File(file) => file.seek(position),
+            Self::Directory(directory) if position == 0 => {
+                directory.offset = 0;
+                Ok(())
+            }
+            Self::Directory(_) => Err(fs::Error::Unsupported),
+        }
+    }
+
+    fn get_size(&self) -> u32 {
+        match self {
+            Self::File(file) => file.get_size(),
+            Self::Directory(_) => 0,
+        }
+    }
+
+    fn load_file(&mut self, mem: &mut MemoryRegion) -> Result<(), fs::Error> {
+        match self {
+            Self::File(file) => file.load_file(mem),
+            Self::Directory(_) => Err(fs::Error::Unsupported),
+        }
+    }
+}
+
+pub struct File<'a> {
+    filesystem: &'a Filesystem<'a>,
+    extent: Extent,
+    position: u32,
+}
+
+pub struct Directory<'a> {
+    filesystem: &'a Filesystem<'a>,
+    extent: Extent,
+    offset: u32,
+}
+
+struct DirectoryEntry {
+    name: [u8; 255],
+    extent: Extent,
+    flags: u8,
+}
+
+impl<'a> File<'a> {
+    pub fn position(&self) -> u32 {
+        self.position
+    }
+}
+
+impl<'a> fs::Read for File<'a> {
+    fn read(&mut self, data: &mut [u8]) -> Result<u32, fs::Error> {
+        assert_eq!(data.len(), SectorBuf::len());
+
+        if self.position >= self.extent.size {
+            return Err(fs::Error::EndOfFile);
+        }
+
+        let sector = self.extent.sector + u64::from(self.position) / SectorBuf::len() as u64;
+        self.filesystem
+            .read(sector, data)
+            .map_err(fs::Error::Block)?;
+
+        // After a seek the position may be part way into the sector
+        let skip = self.position % SectorBuf::len() as u32;
+        data.copy_within(skip as usize.., 0);
+        let bytes_read = u32::min(
+            SectorBuf::len() as u32 - skip,
+            self.extent.size - self.position,
+        );
+        self.position += bytes_read;
+        Ok(bytes_read)
+    }
+
+    fn seek(&mut self, position: u32) -> Result<(), fs::Error> {
+        if position > self.extent.size {
+            return Err(fs::Error::EndOfFile);
+        }
+        self.position = position;
+        Ok(())
+    }
+
+    fn get_size(&self) -> u32 {
+        self.extent.size
+    }
+
+    // Files are contiguous so everything but a partial first and last sector
+    // is read with a single request
+    fn load_file(&mut self, mem: &mut MemoryRegion) -> Result<(), fs::Error> {
+        let mut data = mem.as_bytes();
+        if self.position as usize % SectorBuf::len() != 0 && !data.is_empty() {
+            let mut first = SectorBuf::new();
+            let bytes = self.read(first.as_mut_bytes())? as usize;
+            let count = usize::min(bytes, data.len());
+            data[..count].copy_from_slice(&first.as_bytes()[..count]);
+            data = &mut data[count..];
+        }
+
+        let sectors = data.len() / SectorBuf::len();
+        if sectors > 0 {
+            if self.position >= self.extent.size {
+                return Err(fs::Error::EndOfFile);
+            }
+            let (chunk, rest) = data.split_at_mut(sectors * SectorBuf::len());
+            let sector = self.extent.sector + u64::from(self.position) / SectorBuf::len() as u64;
+            self.filesystem
+                .read_sectors(sector, chunk)
+                .map_err(fs::Error::Block)?;
+            self.position = u32::try_from(u64::from(self.position) + chunk.len() as u64)
+                .unwrap_or(u32::MAX)
+                .min(self.extent.size);
+            data = rest;
+        }
+
+        if !data.is_empty() {
+            let mut last = SectorBuf::new();
+            let bytes = self.read(last.as_mut_bytes())? as usize;
+            assert!(bytes >= data.len());
+            data.copy_from_slice(&last.as_bytes()[..data.len()]);
+        }
+        Ok(())
+    }
+}
+
+impl<'a> Directory<'a> {
+    fn next_entry(&mut self) -> Result<DirectoryEntry, Error> {
+        loop {
+            if self.offset >= self.extent.size {
+                return Err(Error::EndOfFile);
+            }
+
+            let position = self.extent.sector * SectorBuf::len() as u64 + u64::from(self.offset);
+            let mut length = [0u8; 1];
+            self.filesystem.read_bytes(position, &mut length)?;
+            let length = usize::from(length[0]);
+
+            // Records never cross a block boundary, the rest of a block
+            // without room for another record is zero filled
+            let block_remaining = (BLOCK_SIZE - u64::from(self.offset) % BLOCK_SIZE) as usize;
+            if length == 0 {
+                self.offset += block_remaining as u32;
+                continue;
+            }
+            if length < MIN_RECORD_LENGTH || length > block_remaining {
+                return Err(Error::Corrupt);
+            }
+
+            let mut record = [0u8; 255];
+            let record = &mut record[..length];
+            self.filesystem.read_bytes(position, record)?;
+            self.offset += length as u32;
+
+            if 33 + usize::from(record[32]) > length {
+                return Err(Error::Corrupt);
+            }
+            return Ok(DirectoryEntry {
+                name: self.filesystem.record_name(record)?,
+                extent: Extent {
+                    // Skip any extended attribute record
+                    sector: (u64::from(le32(record, 2)) + u64::from(record[1])) * SECTORS_PER_BLOCK,
+                    size: le32(record, 10),
+                },
+                flags: record[25],
+            });
+        }
+    }
+
+    fn find(&mut self, name: &[u8]) -> Result<DirectoryEntry, Error> {
+        loop {
+            match self.next_entry() {
+                Ok(de)
+                    if name
+                        .eq_ignore_ascii_case(crate::common::ascii_strip(&de.name).as_bytes()) =>
+                {
+                    return Ok(de)
+                }
+                Ok(_) => {}
+                Err(Error::EndOfFile) => return Err(Error::NotFound),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    pub fn has_next(&mut self) -> Result<bool, Error> {
+        let offset = self.offset;
+        let result = match self.next_entry() {
+            Ok(_) => Ok(true),
+            Err(Error::EndOfFile) => Ok(false),
+            Err(e) => Err(e),
+        };
+        self.offset = offset;
+        result
+    }
+
+    pub fn next_node(&mut self) -> Result<(Node<'a>, [u8; 255]), Error> {
+        let de = self.next_entry()?;
+        Ok((self.filesystem.node(&de)?, de.name))
+    }
+
+    /// Open the file or directory at `path`, relative to this directory
+    /// unless it is absolute
+    pub fn open(&self, path: &str) -> Result<Node<'a>, Error> {
+        let mut current = if fat::is_absolute_path(path) {
+            self.filesystem.root_entry()
+        } else {
+            DirectoryEntry {
+                name: [0; 255],
+                extent: self.extent,
+                flags: FLAG_DIRECTORY,
+            }
+        };
+
+        for name in path
+            .split(['/', '\\'])
+            .filter(|name| !name.is_empty() && *name != ".")
+        {
+            if current.flags & FLAG_DIRECTORY == 0 {
+                return Err(Error::NotFound);
+            }
+            let mut directory = Directory {
+                filesystem: self.filesystem,
+                extent: current.extent,
+                offset: 0,
+            };
+            current = directory.find(name.as_bytes())?;
+        }
+
+        self.filesystem.node(&current)
+    }
+}
+
+impl<'a> SectorRead for Filesystem<'a> {
+    fn read(&self, sector: u64, data: &mut [u8]) -> Result<(), BlockError> {
+        if self.start + sector > self.last {
+            Err(BlockError::BlockIO)
+        } else {
+            self.device.read(self.start + sector, data)
+        }
+    }
+
+    fn read_sectors(&self, sector: u64, data: &mut [u8]) -> Result<(), BlockError> {
+        let count = (data.len() / SectorBuf::len()) as u64;
+        if count == 0 || self.start + sector + count - 1 > self.last {
+            Err(BlockError::BlockIO)
+        } else {
+            self.device.read_sectors(self.start + sector, data)
+        }
+    }
+}
+
+impl<'a> Filesystem<'a> {
+    pub fn new(device: &'a dyn SectorRead, start: u64, last: u64) -> Filesystem<'a> {
+        Filesystem {
+            device,
+            start,
+            last,
+            root: Extent { sector: 0, size: 0 },
+            names: Names::Iso9660,
+        }
+    }
+
+    pub fn init(&mut self) -> Result<(), Error> {
+        let mut primary = None;
+        let mut joliet = None;
+        self.volume_descriptors(|descriptor| {
+            let root = Extent {
+                sector: u64::from(le32(descriptor, 158)) * SECTORS_PER_BLOCK,
+                size: le32(descriptor, 166),
+            };
+            match descriptor[0] {
+                DESCRIPTOR_PRIMARY if primary.is_none() => {
+                    if u64::from(le16(descriptor, 128)) != BLOCK_SIZE {
+                        return Err(Error::Unsupported);
+                    }
+                    primary = Some(root);
+                }
+                DESCRIPTOR_SUPPLEMENTARY
+                    if joliet.is_none() && JOLIET_ESCAPES.contains(&&descriptor[88..91]) =>
+                {
+                    joliet = Some(root)
+                }
+                _ => {}
+            }
+            Ok(false)
+        })?;
+
+        self.root = primary.ok_or(Error::NotIso9660)?;
+        if let Some(skip) = self.rock_ridge_skip()? {
+            self.names = Names::RockRidge(skip);
+        } else if let Some(root) = joliet {
+            self.root = root;
+            self.names = Names::Joliet;
+        }
+        Ok(())
+    }
+
+    // Call `f` with the first sector of each volume descriptor until it
+    // returns true or the set terminator is reached
+    fn volume_descriptors<F>(&self, mut f: F) -> Result<(), Error>
+    where
+        F: FnMut(&[u8]) -> Result<bool, Error>,
+    {
+        let mut buf = SectorBuf::new();
+        for index in 0..MAX_VOLUME_DESCRIPTORS {
+            let sector = (VOLUME_DESCRIPTOR_START + index) * SECTORS_PER_BLOCK;
+            self.read(sector, buf.as_mut_bytes())
+                .map_err(|_| Error::NotIso9660)?;
+            let descriptor = buf.as_bytes();
+            if &descriptor[1..6] != STANDARD_IDENTIFIER {
+                return Err(Error::NotIso9660);
+            }
+            if descriptor[0] == DESCRIPTOR_TERMINATOR || f(descriptor)? {
+                return Ok(());
+            }
+        }
+        Err(Error::Corrupt)
+    }
+
+    // Rock Ridge is in use if the first record of the root directory starts
+    // its system use area with an "SP" entry
+    fn rock_ridge_skip(&self) -> Result<Option<usize>, Error> {
+        let mut record = [0u8; MIN_RECORD_LENGTH + 7];
+        self.read_bytes(self.root.sector * SectorBuf::len() as u64, &mut record)?;
+        let sp = &record[MIN_RECORD_LENGTH..];
+        if usize::from(record[0]) < record.len()
+            || &sp[..2] != b"SP"
+            || sp[2] != 7
+            || sp[4..6] != SUSP_CHECK
+        {
+            return Ok(None);
+        }
+        Ok(Some(usize::from(sp[6])))
+    }
+
+    fn root_entry(&self) -> DirectoryEntry {
+        DirectoryEntry {
+            name: [0; 255],
+            extent: self.root,
+            flags: FLAG_DIRECTORY,
+        }
+    }
+
+    pub fn root(&self) -> Directory {
+        Directory {
+            filesystem: self,
+            extent: self.root,
+            offset: 0,
+        }
+    }
+
+    /// Open the file or directory at `path`
+    pub fn open(&self, path: &str) -> Result<Node, Error> {
+        self.root().open(path)
+    }
+
+    fn node(&self, de: &DirectoryEntry) -> Result<Node, Error> {
+        // Files over 4GiB are split across several records
+        if de.flags & FLAG_MULTI_EXTENT != 0 {
+            return Err(Error::Unsupported);
+        }
+        if de.flags & FLAG_DIRECTORY != 0 {
+            Ok(Node::Directory(Directory {
+                filesystem: self,
+                extent: de.extent,
+                offset: 0,
+            }))
+        } else {
+            Ok(Node::File(File {
+                filesystem: self,
+                extent: de.extent,
+                position: 0,
+            }))
+        }
+    }
+
+    // Read `data.len()` bytes at byte `offset` into the filesystem
+    fn read_bytes(&self, offset: u64, data: &mut [u8]) -> Result<(), Error> {
+        let sector_size = SectorBuf::len() as u64;
+        let mut buf = SectorBuf::new();
+        let mut done = 0;
+        while done < data.len() {
+            let position = offset + done as u64;
+            let start = (position % sector_size) as usize;
+            let count = usize::min(SectorBuf::len() - start, data.len() - done);
+            self.read(position / sector_size, buf.as_mut_bytes())
+                .map_err(Error::Block)?;
+            data[done..done + count].copy_from_slice(&buf.as_bytes()[start..start + count]);
+            done += count;
+        }
+        Ok(())
+    }
+
+    fn record_name(&self, record: &[u8]) -> Result<[u8; 255], Error> {
+        let mut name = [0u8; 255];
+        let identifier = &record[33..33 + usize::from(record[32])];
+        match identifier {
+            [0] => name[0] = b'.',
+            [1] => name[..2].copy_from_slice(b".."),
+            _ => {
+                if let Names::RockRidge(skip) = self.names {
+                    // The identifier is padded to an even length
+                    let start = (34 + identifier.len()) & !1;
+                    let start = usize::min(start + skip, record.len());
+                    if self.rock_ridge_name(&record[start..], &mut name)? {
+                        return Ok(name);
+                    }
+                }
+
+                if self.names == Names::Joliet {
+                    for (c, ucs2) in name.iter_mut().zip(identifier.chunks_exact(2)) {
+                        let ucs2 = u16::from_be_bytes([ucs2[0], ucs2[1]]);
+                        *c = if ucs2 < 0x80 { ucs2 as u8 } else { b'?' };
+                    }
+                } else {
+                    name[..identifier.len()].copy_from_slice(identifier);
+                }
+
+                // Drop the ";1" version and the dot of names without an
+                // extension
+                let mut length = name
+                    .iter()
+                    .position(|c| *c == b';' || *c == 0)
+                    .unwrap_or(name.len());
+                if length > 1 && name[length - 1] == b'.' {
+                    length -= 1;
+                }
+                name[length..].fill(0);
+            }
+        }
+        Ok(name)
+    }
+
+    // Collect the "NM" entries of a system use area and the continuation
+    // areas it points to, returns false if there were none
+    fn rock_ridge_name(&self, system_use: &[u8], name: &mut [u8; 255]) -> Result<bool, Error> {
+        let mut area = [0u8; BLOCK_SIZE as usize];
+        let mut area_length = system_use.len();
+        area[..area_length].copy_from_slice(system_use);
+        let mut length = 0;
+        let mut found = false;
+
+        for _ in 0..MAX_CONTINUATIONS {
+            let mut continuation = None;
+            let mut offset = 0;
+            while offset + 4 <= area_length {
+                let entry_length = usize::from(area[offset + 2]);
+                if entry_length < 4 || offset + entry_length > area_length {
+                    break;
+                }
+                let entry = &area[offset..offset + entry_length];
+                match &entry[..2] {
+                    b"NM" if entry_length >= 5 => {
+                        let part = &entry[5..];
+                        let count = usize::min(part.len(), name.len() - length);
+                        name[length..length + count].copy_from_slice(&part[..count]);
+                        length += count;
+                        found = true;
+                    }
+                    b"CE" if entry_length >= 28 => {
+                        continuation = Some((le32(entry, 4), le32(entry, 12), le32(entry, 20)));
+                    }
+                    b"ST" => break,
+                    _ => {}
+                }
+                offset += entry_length;
+            }
+
+            let Some((block, offset, size)) = continuation else {
+                break;
+            };
+            if u64::from(offset) + u64::from(size) > BLOCK_SIZE {
+                return Err(Error::Corrupt);
+            }
+            area_length = size as usize;
+            self.read_bytes(
+                u64::from(block) * BLOCK_SIZE + u64::from(offset),
+                &mut area[..area_length],
+            )?;
+        }
+        Ok(found)
+    }
+}
+
+impl<'a> fs::Filesystem for Filesystem<'a> {
+    fn root(&self) -> Result<fs::Node<'_>, fs::Error> {
+        Ok(fs::Node::Iso9660(Node::Directory(self.root())))
+    }
+
+    fn open(&self, path: &str) -> Result<fs::Node<'_>, fs::Error> {
+        Ok(fs::Node::Iso9660(self.open(path)?))
+    }
+}
+
+/// Find the EFI boot image listed in the El Torito boot catalog of a CD,
+/// returning its first and last sector. Images whose size is not recorded
+/// are assumed to extend to the end of the device.
+pub fn find_efi_image(device: &dyn BlockDevice) -> Result<(u64, u64), Error> {
+    let last = device
+        .get_capacity()
+        .checked_sub(1)
+        .ok_or(Error::NotIso9660)?;
+    let fs = Filesystem::new(device, 0, last);
+
+    let mut catalog = None;
+    fs.volume_descriptors(|descriptor| {
+        if descriptor[0] == DESCRIPTOR_BOOT_RECORD
+            && descriptor[7..7 + EL_TORITO_IDENTIFIER.len()] == *EL_TORITO_IDENTIFIER
+        {
+            catalog = Some(u64::from(le32(descriptor, 0x47)) * BLOCK_SIZE);
+        }
+        Ok(catalog.is_some())
+    })?;
+    let catalog = catalog.ok_or(Error::NotFound)?;
+
+    let entry = |index: u64| -> Result<[u8; CATALOG_ENTRY_SIZE as usize], Error> {
+        let mut entry = [0u8; CATALOG_ENTRY_SIZE as usize];
+        fs.read_bytes(catalog + index * CATALOG_ENTRY_SIZE, &mut entry)?;
+        Ok(entry)
+    };
+    // The image is in 512 byte units, but starts on a block boundary
+    let image = |entry: &[u8]| -> Result<(u64, u64), Error> {
+        let start = u64::from(le32(entry, 8)) * SECTORS_PER_BLOCK;
+        let count = u64::from(le16(entry, 6));
+        if start > last {
+            return Err(Error::Corrupt);
+        }
+        if count > 1 && count < u64::from(u16::MAX) {
+            Ok((start, u64::min(start + count - 1, last)))
+        } else {
+            Ok((start, last))
+        }
+    };
+
+    // The validation entry's words sum to zero
+    let validation = entry(0)?;
+    let sum = validation
+        .chunks_exact(2)
+        .fold(0u16, |sum, word| sum.wrapping_add(le16(word, 0)));
+    if validation[0] != CATALOG_VALIDATION || validation[30..32] != [0x55, 0xaa] || sum != 0 {
+        return Err(Error::Corrupt);
+    }
+
+    let default = entry(1)?;
+    if validation[1] == PLATFORM_EFI && default[0] == CATALOG_BOOTABLE {
+        return image(&default);
+    }
+
+    let mut index = 2;
+    while index < MAX_CATALOG_ENTRIES {
+        let header = entry(index)?;
+        index += 1;
+        if header[0] != CATALOG_SECTION_HEADER && header[0] != CATALOG_FINAL_SECTION_HEADER {
+            break;
+        }
+        for _ in 0..le16(&header, 2) {
+            let section = entry(index)?;
+            index += 1;
+            if header[1] == PLATFORM_EFI && section[0] == CATALOG_BOOTABLE {
+                return image(&section);
+            }
+            while index < MAX_CATALOG_ENTRIES && entry(index)?[0] == CATALOG_EXTENSION {
+                index += 1;
+            }
+        }
+        if header[0] == CATALOG_FINAL_SECTION_HEADER {
+            break;
+        }
+    }
+    Err(Error::NotFound)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use crate::block::SectorBuf;
+    use crate::fs::Read;
+    use crate::part::tests::*;
+
+    fn workload_path(image: &str) -> PathBuf {
+        let mut workload_path = dirs::home_dir().unwrap();
+        workload_path.push("workloads");
+        workload_path.join(image)
+    }
+
+    fn iso_test_image_paths() -> Vec<PathBuf> {
+        ["iso9660.img", "joliet.img"]
+            .iter()
+            .map(|image| workload_path(image))
+            .collect()
+    }
+
+    fn read_all(f: &mut super::File) -> Vec<u8> {
+        let mut contents = Vec::new();
+        loop {
+            let mut data = SectorBuf::new();
+            match f.read(data.as_mut_bytes()) {
+                Ok(bytes) => contents.extend_from_slice(&data.as_bytes()[..bytes as usize]),
+                Err(crate::fs::Error::EndOfFile) => break,
+                Err(e) => panic!("{e:?}"),
+            }
+        }
+        contents
+    }
+
+    #[test]
+    fn test_iso9660_file_reads() {
+        for image in &iso_test_image_paths() {
+            let d = FakeDisk::new(image);
+            let mut fs = super::Filesystem::new(&d, 0, d.len() / SectorBuf::len() as u64 - 1);
+            fs.init().expect("Error initialising filesystem");
+
+            for n in 9..16 {
+                for o in 0..2 {
+                    let v = 2u32.pow(n) - o;
+                    let mut f: super::File = fs
+                        .open(&format!("/a/b/c/{v}"))
+                        .expect("Error opening file")
+                        .try_into()
+                        .unwrap();
+                    assert_eq!(f.get_size(), v);
+                    let contents = read_all(&mut f);
+                    assert_eq!(contents.len(), v as usize);
+                    assert!(contents.iter().all(|c| *c == b'a'));
+                }
+            }
+
+            let expected: Vec<u8> = (1..=200000)
+                .flat_map(|n| format!("{n}\n").into_bytes())
+                .take(1048576)
+                .collect();
+            let mut f: super::File = fs.open("/large").unwrap().try_into().unwrap();
+            assert_eq!(read_all(&mut f), expected);
+
+            // Unaligned start and end
+            f.seek(1000).unwrap();
+            let data = vec![0u8; 5000];
+            let mut region = crate::mem::MemoryRegion::from_bytes(&data);
+            f.load_file(&mut region).unwrap();
+            assert_eq!(data, expected[1000..6000]);
+            assert_eq!(f.position(), 6144);
+
+            f.seek(1048570).unwrap();
+            let mut data = SectorBuf::new();
+            assert_eq!(f.read(data.as_mut_bytes()).unwrap(), 6);
+            assert_eq!(
+                f.read(data.as_mut_bytes()),
+                Err(crate::fs::Error::EndOfFile)
+            );
+        }
+    }
+
+    #[test]
+    fn test_iso9660_open() {
+        for image in &iso_test_image_paths() {
+            let d = FakeDisk::new(image);
+            let mut fs = super::Filesystem::new(&d, 0, d.len() / SectorBuf::len() as u64 - 1);
+            fs.init().expect("Error initialising filesystem");
+
+            let size = |path| match fs.open(path) {
+                Ok(super::Node::File(f)) => Ok(f.get_size()),
+                Ok(super::Node::Directory(_)) => Ok(0),
+                Err(e) => Err(e),
+            };
+            assert_eq!(size("/a/b/c/512"), Ok(512));
+            assert_eq!(size("\\A\\B\\C\\1024"), Ok(1024));
+            assert_eq!(size("/a/./b/../b//c/2048"), Ok(2048));
+            assert_eq!(size("/LongFileNameTest"), Ok(0));
+            assert_eq!(size("/a"), Ok(0));
+            assert_eq!(size("/"), Ok(0));
+            assert_eq!(size("/a/b/c/511/x"), Err(super::Error::NotFound));
+            assert_eq!(size("/missing"), Err(super::Error::NotFound));
+
+            let dir: super::Directory = fs.open("/a/b").unwrap().try_into().unwrap();
+            match dir.open("c/4096") {
+                Ok(super::Node::File(f)) => assert_eq!(f.get_size(), 4096),
+                _ => panic!("Error opening relative path"),
+            }
+        }
+
+        // Rock Ridge names are not length limited, the name is split
+        // between the record and a continuation area
+        let d = FakeDisk::new(&workload_path("iso9660.img"));
+        let mut fs = super::Filesystem::new(&d, 0, d.len() / SectorBuf::len() as u64 - 1);
+        fs.init().expect("Error initialising filesystem");
+        assert!(fs.open(&"x".repeat(200)).is_ok());
+        assert_eq!(
+            fs.open(&"x".repeat(199)).err(),
+            Some(super::Error::NotFound)
+        );
+    }
+
+    #[test]
+    fn test_iso9660_directory() {
+        for image in &iso_test_image_paths() {
+            let d = FakeDisk::new(image);
+            let mut fs = super::Filesystem::new(&d, 0, d.len() / SectorBuf::len() as u64 - 1);
+            fs.init().expect("Error initialising filesystem");
+
+            let mut dir: super::Directory = fs.open("/largedir").unwrap().try_into().unwrap();
+            let mut names = Vec::new();
+            while dir.has_next().unwrap() {
+                let (node, name) = dir.next_node().unwrap();
+                let name = crate::common::ascii_strip(&name).to_string();
+                assert_eq!(
+                    matches!(node, super::Node::File(_)),
+                    name != "." && name != ".."
+                );
+                names.push(name);
+            }
+            assert!(dir.next_node().is_err());
+            names.sort();
+            let mut expected: Vec<String> = (0..=100).map(|n| n.to_string()).collect();
+            expected.extend([".".to_string(), "..".to_string()]);
+            expected.sort();
+            assert_eq!(names, expected);
+        }
+    }
+
+    #[test]
+    fn test_el_torito() {
+        for image in &iso_test_image_paths() {
+            let d = FakeDisk::new(image);
+            let (start, end) = super::find_efi_image(&d).expect("Error finding EFI image");
+            assert_eq!(end - start + 1, 8 * 1024 * 1024 / SectorBuf::len() as u64);
+
+            let mut fs = crate::fat::Filesystem::new(&d, start, end);
+            fs.init().expect("Error initialising FAT image");
+            let f: crate::fat::File = fs.open("/a/b/c/d").unwrap().try_into().unwrap();
+            assert_eq!(f.get_size(), 32768);
+
+            let disk = crate::bootdev::BootDisk::probe(0, &d).unwrap();
+            assert!(disk.iso9660);
+            assert_eq!((disk.esp_start, disk.esp_end), (start, end));
+        }
+
+        let d = FakeDisk::new(&workload_path("fat16.img"));
+        assert_eq!(
+            super::find_efi_image(&d).err(),
+            Some(super::Error::NotIso9660)
+        );
+        let mut fs = super::Filesystem::new(&d, 0, d.len() / SectorBuf::len() as u64 - 1);
+        assert_eq!(fs.init(), Err(super::Error::NotIso9660));
+    }
+}
@@ -1,108 +1,577 @@
 // SPDX-License-Identifier: Apache-2.0
 // Copyright © 2019 Intel Corporation
 
+use core::{cmp::Ordering, fmt::Write};
+
+use heapless::Vec;
+use log::{info, warn};
+
 use crate::{
     block::SectorBuf,
     bootinfo,
-    bzimage::{self, Kernel},
-    common::ascii_strip,
-    fat::{self, Read},
+    common::{ascii_strip, format_guid},
+    fs::{self, Filesystem, Read},
+    pe,
 };
 
-const ENTRY_DIRECTORY: &str = "/loader/entries";
+#[cfg(target_arch = "x86_64")]
+use crate::bzimage::{self, Kernel};
+#[cfg(not(target_arch = "x86_64"))]
+use crate::image::{self, Kernel};
+
+pub const ENTRY_DIRECTORY: &str = "/loader/entries";
+const UKI_DIRECTORY: &str = "/EFI/Linux";
 
+const MAX_INITRDS: usize = 8;
+const MAX_DEVICETREE_OVERLAYS: usize = 8;
+// Entries listed in the boot menu
+const MAX_ENTRIES: usize = 32;
+
+// Value of the `architecture` key for entries this firmware can boot
+#[cfg(target_arch = "aarch64")]
+const ARCHITECTURE: &str = "aa64";
+#[cfg(target_arch = "x86_64")]
+const ARCHITECTURE: &str = "x64";
+#[cfg(target_arch = "riscv64")]
+const ARCHITECTURE: &str = "riscv64";
+
+/// A Boot Loader Specification type #1 entry
 pub struct LoaderConfig {
+    pub title: [u8; 128],
+    pub version: [u8; 64],
+    pub machine_id: [u8; 64],
+    pub sort_key: [u8; 64],
+    pub architecture: [u8; 16],
     pub bzimage_path: [u8; 260],
-    pub initrd_path: [u8; 260],
+    pub efi_path: [u8; 260],
+    pub initrd_paths: Vec<[u8; 260], MAX_INITRDS>,
+    pub devicetree_path: [u8; 260],
+    pub devicetree_overlay_paths: Vec<[u8; 260], MAX_DEVICETREE_OVERLAYS>,
     pub cmdline: [u8; 4096],
 }
 
 impl Default for LoaderConfig {
     fn default() -> Self {
         Self {
+            title: [0; 128],
+            version: [0; 64],
+            machine_id: [0; 64],
+            sort_key: [0; 64],
+            architecture: [0; 16],
             bzimage_path: [0; 260],
-            initrd_path: [0; 260],
+            efi_path: [0; 260],
+            initrd_paths: Vec::new(),
+            devicetree_path: [0; 260],
+            devicetree_overlay_paths: Vec::new(),
             cmdline: [0; 4096],
         }
     }
 }
 
+/// What the selected boot entry starts
+#[allow(clippy::large_enum_variant)]
+pub enum Boot<'a> {
+    Kernel(Kernel),
+    /// An EFI program on `filesystem` with its load options and the initrds,
+    /// also on `filesystem`, that a kernel's EFI stub is given
+    Efi {
+        filesystem: &'a dyn Filesystem,
+        path: [u8; 260],
+        options: [u8; 4096],
+        initrds: Vec<[u8; 260], MAX_INITRDS>,
+    },
+}
+
 #[allow(dead_code)]
 #[derive(Debug)]
 pub enum Error {
-    File(fat::Error),
+    File(fs::Error),
+    #[cfg(target_arch = "x86_64")]
     BzImage(bzimage::Error),
+    #[cfg(not(target_arch = "x86_64"))]
+    Image(image::Error),
+    Pe(pe::Error),
     UnterminatedString,
     InvalidPattern,
+    FileTooLarge,
+    InvalidUtf8,
+    ValueTooLong,
+    NoBootable,
 }
 
-impl From<fat::Error> for Error {
-    fn from(e: fat::Error) -> Error {
+impl From<fs::Error> for Error {
+    fn from(e: fs::Error) -> Error {
         Error::File(e)
     }
 }
 
+#[cfg(target_arch = "x86_64")]
 impl From<bzimage::Error> for Error {
     fn from(e: bzimage::Error) -> Error {
         Error::BzImage(e)
     }
 }
 
-/// Given a `loader.conf` file, find the `default` option value.
-fn default_entry_pattern(f: &mut fat::File) -> Result<[u8; 260], fat::Error> {
-    let mut data = [0; 4096];
-    assert!(f.get_size() as usize <= data.len());
-    assert!(data.len() >= SectorBuf::len());
+#[cfg(not(target_arch = "x86_64"))]
+impl From<image::Error> for Error {
+    fn from(e: image::Error) -> Error {
+        Error::Image(e)
+    }
+}
 
-    let mut entry_pattern = [0; 260];
-    let mut offset = 0;
+impl From<pe::Error> for Error {
+    fn from(e: pe::Error) -> Error {
+        Error::Pe(e)
+    }
+}
+
+/// The two kinds of Boot Loader Specification entries
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum EntryKind {
+    /// A `.conf` file in `/loader/entries`
+    Config,
+    /// A unified kernel image in `/EFI/Linux`
+    Uki,
+}
+
+impl EntryKind {
+    fn directory(self) -> &'static str {
+        match self {
+            EntryKind::Config => ENTRY_DIRECTORY,
+            EntryKind::Uki => UKI_DIRECTORY,
+        }
+    }
+
+    // Glob matching the file names of entries of this kind
+    fn pattern(self) -> &'static [u8] {
+        match self {
+            EntryKind::Config => b"*.conf\0",
+            EntryKind::Uki => b"*.[eE][fF][iI]\0",
+        }
+    }
+}
+
+// Look through the regular files in `directory` until `f` returns true for
+// one of them, returning its name. A missing directory is treated as empty.
+fn find_file(
+    filesystem: &dyn Filesystem,
+    directory: &str,
+    f: &mut dyn FnMut(&[u8; 255]) -> Result<bool, Error>,
+) -> Result<Option<[u8; 255]>, Error> {
+    let mut dir = match filesystem.open(directory) {
+        Ok(node) => node,
+        Err(fs::Error::NotFound) => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
     loop {
+        match dir.next_node() {
+            Ok((node, name)) if !node.is_directory() && f(&name)? => return Ok(Some(name)),
+            Ok(_) => {}
+            Err(fs::Error::EndOfFile) => return Ok(None),
+            Err(err) => return Err(err.into()),
+        }
+    }
+}
+
+// Read a whole configuration file into `data`
+fn read_config<'a>(f: &mut dyn Read, data: &'a mut [u8; 4096]) -> Result<&'a str, Error> {
+    let size = f.get_size() as usize;
+    if size > data.len() {
+        return Err(Error::FileTooLarge);
+    }
+
+    let mut offset = 0;
+    while offset < size {
         match f.read(&mut data[offset..offset + SectorBuf::len()]) {
-            Err(fat::Error::EndOfFile) => break,
-            Err(e) => return Err(e),
+            Err(fs::Error::EndOfFile) => break,
+            Err(e) => return Err(e.into()),
             Ok(_) => {
                 offset += SectorBuf::len();
             }
         }
     }
 
-    let conf = unsafe { core::str::from_utf8_unchecked(&data) };
-    for line in conf.lines() {
-        if let Some(mut pattern) = line.strip_prefix("default") {
-            pattern = pattern.trim();
-            entry_pattern[0..pattern.len()].copy_from_slice(pattern.as_bytes());
+    core::str::from_utf8(&data[..size]).map_err(|_| Error::InvalidUtf8)
+}
+
+// The non-empty, non-comment lines of a configuration file split into their
+// key and value
+fn config_lines(conf: &str) -> impl Iterator<Item = (&str, &str)> {
+    conf.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(
+            |line| match line.split_once(|c: char| c.is_ascii_whitespace()) {
+                Some((key, value)) => (key, value.trim()),
+                None => (line, ""),
+            },
+        )
+}
+
+fn copy_value(dst: &mut [u8], value: &str) -> Result<(), Error> {
+    // Keep a terminating NUL
+    if value.len() >= dst.len() {
+        return Err(Error::ValueTooLong);
+    }
+    dst.fill(0);
+    dst[..value.len()].copy_from_slice(value.as_bytes());
+    Ok(())
+}
+
+fn push_value<const N: usize>(dst: &mut Vec<[u8; 260], N>, value: &str) -> Result<(), Error> {
+    let mut path = [0; 260];
+    copy_value(&mut path, value)?;
+    dst.push(path).map_err(|_| Error::ValueTooLong)
+}
+
+// Multiple `options` lines are joined with spaces
+fn append_value(dst: &mut [u8], value: &str) -> Result<(), Error> {
+    let len = ascii_strip(dst).len();
+    let start = if len == 0 { 0 } else { len + 1 };
+    if start + value.len() >= dst.len() {
+        return Err(Error::ValueTooLong);
+    }
+    if len != 0 {
+        dst[len] = b' ';
+    }
+    dst[start..start + value.len()].copy_from_slice(value.as_bytes());
+    Ok(())
+}
+
+/// How long the boot menu waits for a key before booting the default entry
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Timeout {
+    /// The menu is only shown if a key is pressed within this many seconds,
+    /// with 0 it is not shown unless a key has already been pressed
+    Seconds(u32),
+    /// The menu is always shown and waits until an entry is chosen
+    Forever,
+    /// The menu is never shown
+    Disabled,
+}
+
+/// The settings from `loader.conf`
+struct LoaderConf {
+    default: [u8; 260],
+    timeout: Timeout,
+}
+
+impl Default for LoaderConf {
+    fn default() -> Self {
+        Self {
+            default: [0; 260],
+            timeout: Timeout::Seconds(0),
+        }
+    }
+}
+
+/// Parse the `default` and `timeout` options of a `loader.conf` file
+fn parse_loader_conf(f: &mut dyn Read) -> Result<LoaderConf, Error> {
+    let mut data = [0; 4096];
+    let conf = read_config(f, &mut data)?;
+
+    let mut loader_conf = LoaderConf::default();
+    for (key, value) in config_lines(conf) {
+        match key {
+            "default" => copy_value(&mut loader_conf.default, value)?,
+            "timeout" => {
+                loader_conf.timeout = match value {
+                    "menu-force" => Timeout::Forever,
+                    "menu-hidden" => Timeout::Seconds(0),
+                    "menu-disabled" => Timeout::Disabled,
+                    seconds => match seconds.parse() {
+                        Ok(seconds) => Timeout::Seconds(seconds),
+                        Err(_) => {
+                            warn!("Ignoring invalid timeout: {seconds}");
+                            continue;
+                        }
+                    },
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Ok(loader_conf)
+}
+
+// The fields of an entry that decide the order entries are listed in, and
+// its title in the boot menu
+struct EntryInfo {
+    filesystem: usize,
+    kind: EntryKind,
+    name: [u8; 255],
+    /// The file name without the boot counter
+    id: [u8; 255],
+    counter: Option<BootCounter>,
+    title: [u8; 128],
+    sort_key: [u8; 64],
+    machine_id: [u8; 64],
+    version: [u8; 64],
+}
+
+impl EntryInfo {
+    fn new(
+        filesystem: usize,
+        kind: EntryKind,
+        name: &[u8; 255],
+        entry: &LoaderConfig,
+    ) -> EntryInfo {
+        let (prefix, counter, extension) = split_counter(ascii_strip(name));
+        let mut id = [0; 255];
+        id[..prefix.len()].copy_from_slice(prefix.as_bytes());
+        id[prefix.len()..prefix.len() + extension.len()].copy_from_slice(extension.as_bytes());
+        EntryInfo {
+            filesystem,
+            kind,
+            name: *name,
+            id,
+            counter,
+            title: entry.title,
+            sort_key: entry.sort_key,
+            machine_id: entry.machine_id,
+            version: entry.version,
         }
     }
+}
+
+/// The boot counter of an entry named `<name>+<tries left>[-<tries done>]`,
+/// counting down the attempts left to boot it successfully
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct BootCounter {
+    left: u32,
+    done: u32,
+}
 
-    Ok(entry_pattern)
+/// Split an entry file name into the name before the boot counter, the
+/// counter and the extension (with its `.`). Without a counter the whole name
+/// apart from the extension is returned.
+fn split_counter(file_name: &str) -> (&str, Option<BootCounter>, &str) {
+    let (base, extension) = match file_name.rfind('.') {
+        Some(dot) => file_name.split_at(dot),
+        None => (file_name, ""),
+    };
+    let number = |s: &str| match s.bytes().all(|c| c.is_ascii_digit()) {
+        true => s.parse().ok(),
+        false => None,
+    };
+    let counter = base.rsplit_once('+').and_then(|(prefix, counter)| {
+        let (left, done) = match counter.split_once('-') {
+            Some((left, done)) => (number(left)?, number(done)?),
+            None => (number(counter)?, 0),
+        };
+        Some((prefix, BootCounter { left, done }))
+    });
+    match counter {
+        Some((prefix, counter)) => (prefix, Some(counter), extension),
+        None => (base, None, extension),
+    }
+}
+
+/// Order entries as the Boot Loader Specification describes, the first entry
+/// being the default. Entries whose boot counter has run out go last. Entries
+/// with a `sort-key` come first, ordered by it and then by `machine-id` and
+/// the newest `version`. The others, and any left tied, are ordered by the
+/// newest file name without the boot counter and then by the most tries left
+/// and fewest done.
+fn compare_entries(a: &EntryInfo, b: &EntryInfo) -> Ordering {
+    let is_bad = |e: &EntryInfo| e.counter.is_some_and(|c| c.left == 0);
+    if is_bad(a) != is_bad(b) {
+        return is_bad(a).cmp(&is_bad(b));
+    }
+
+    let (a_key, b_key) = (ascii_strip(&a.sort_key), ascii_strip(&b.sort_key));
+    let ordering = match (a_key.is_empty(), b_key.is_empty()) {
+        (false, true) => return Ordering::Less,
+        (true, false) => return Ordering::Greater,
+        (true, true) => Ordering::Equal,
+        (false, false) => a_key
+            .cmp(b_key)
+            .then_with(|| ascii_strip(&a.machine_id).cmp(ascii_strip(&b.machine_id)))
+            .then_with(|| compare_versions(&b.version, &a.version)),
+    };
+    ordering
+        .then_with(|| compare_versions(&b.id, &a.id))
+        .then_with(|| match (a.counter, b.counter) {
+            (Some(a), Some(b)) => b.left.cmp(&a.left).then(a.done.cmp(&b.done)),
+            _ => Ordering::Equal,
+        })
 }
 
-/// Given a glob-like pattern, select a boot entry from `/loader/entries/`,
-/// falling back to the first entry encountered if no match is found.
-fn find_entry(fs: &fat::Filesystem, pattern: &[u8]) -> Result<[u8; 255], Error> {
-    let mut dir: fat::Directory = fs.open(ENTRY_DIRECTORY)?.try_into()?;
-    let mut fallback = None;
+/// Compare two version strings the way systemd and the UAPI Version Format
+/// Specification do: numbers compare numerically, `~` sorts before anything
+/// (including the end of the string), `-` and `^` before `.` and letters and
+/// more segments make a version newer. Other characters are ignored.
+fn compare_versions(a: &[u8], b: &[u8]) -> Ordering {
+    fn is_valid(c: u8) -> bool {
+        c.is_ascii_alphanumeric() || b"~-^.".contains(&c)
+    }
+    fn span(s: &[u8], f: fn(&u8) -> bool) -> usize {
+        s.iter().position(|c| !f(c)).unwrap_or(s.len())
+    }
+    // The one starting with `c` is older, if both do it is skipped
+    fn separator(a: &mut &[u8], b: &mut &[u8], c: u8) -> Option<Ordering> {
+        let (a_sep, b_sep) = (a.first() == Some(&c), b.first() == Some(&c));
+        if a_sep && b_sep {
+            *a = &a[1..];
+            *b = &b[1..];
+        }
+        (a_sep || b_sep).then(|| b_sep.cmp(&a_sep))
+    }
+
+    let end = |s: &[u8]| s.iter().position(|c| *c == 0).unwrap_or(s.len());
+    let (mut a, mut b) = (&a[..end(a)], &b[..end(b)]);
     loop {
-        match dir.next_entry() {
-            Ok(de) => {
-                if !de.is_file() {
-                    continue;
-                }
-                let file_name = de.long_name();
-                // return the first matching file name
-                if compare_entry(&file_name, pattern)? {
-                    return Ok(file_name);
+        a = &a[a.iter().position(|c| is_valid(*c)).unwrap_or(a.len())..];
+        b = &b[b.iter().position(|c| is_valid(*c)).unwrap_or(b.len())..];
+
+        // `~` is older than anything, even the end of the string
+        match separator(&mut a, &mut b, b'~') {
+            Some(Ordering::Equal) => continue,
+            Some(ordering) => return ordering,
+            None => {}
+        }
+
+        if a.is_empty() || b.is_empty() {
+            return a.len().cmp(&b.len());
+        }
+
+        for c in *b"-^." {
+            if let Some(ordering) = separator(&mut a, &mut b, c).filter(|o| o.is_ne()) {
+                return ordering;
+            }
+        }
+
+        let (a_len, b_len) = if a.first().is_some_and(u8::is_ascii_digit)
+            || b.first().is_some_and(u8::is_ascii_digit)
+        {
+            // Numbers are newer than letters, compare them by length after
+            // skipping leading zeros and then digit by digit
+            a = &a[span(a, |c| *c == b'0')..];
+            b = &b[span(b, |c| *c == b'0')..];
+            let (a_len, b_len) = (span(a, u8::is_ascii_digit), span(b, u8::is_ascii_digit));
+            let ordering = a_len.cmp(&b_len).then(a[..a_len].cmp(&b[..b_len]));
+            if ordering != Ordering::Equal {
+                return ordering;
+            }
+            (a_len, b_len)
+        } else {
+            let (a_len, b_len) = (
+                span(a, u8::is_ascii_alphabetic),
+                span(b, u8::is_ascii_alphabetic),
+            );
+            let ordering = a[..a_len].cmp(&b[..b_len]);
+            if ordering != Ordering::Equal {
+                return ordering;
+            }
+            (a_len, b_len)
+        };
+        a = &a[a_len..];
+        b = &b[b_len..];
+    }
+}
+
+// Whether an entry with this `architecture` value can be booted here
+fn is_native_architecture(architecture: &[u8]) -> bool {
+    let architecture = ascii_strip(architecture);
+    architecture.is_empty() || architecture.eq_ignore_ascii_case(ARCHITECTURE)
+}
+
+// Replace the firmware's device tree with the entry's, if it has one, and
+// apply the entry's overlays
+#[cfg(not(target_arch = "x86_64"))]
+fn load_device_tree(
+    kernel: &mut Kernel,
+    fs: &dyn Filesystem,
+    entry: &LoaderConfig,
+) -> Result<(), Error> {
+    let devicetree_path = ascii_strip(&entry.devicetree_path);
+    if devicetree_path.is_empty() && entry.devicetree_overlay_paths.is_empty() {
+        return Ok(());
+    }
+    let mut devicetree = match devicetree_path {
+        "" => None,
+        path => Some(fs.open(path)?),
+    };
+    let mut overlay_files: Vec<fs::Node, MAX_DEVICETREE_OVERLAYS> = Vec::new();
+    for overlay_path in &entry.devicetree_overlay_paths {
+        // Cannot fail as there are as many paths at most
+        let _ = overlay_files.push(fs.open(ascii_strip(overlay_path))?);
+    }
+    let mut overlays: Vec<&mut dyn Read, MAX_DEVICETREE_OVERLAYS> = overlay_files
+        .iter_mut()
+        .map(|f| f as &mut dyn Read)
+        .collect();
+    kernel.load_device_tree(
+        devicetree.as_mut().map(|f| f as &mut dyn Read),
+        &mut overlays,
+    )?;
+    Ok(())
+}
+
+/// List the boot entries in `/loader/entries/` and `/EFI/Linux/` on all of
+/// `filesystems` in sort order. Only `.conf` files and unified kernel images
+/// for this architecture that can be parsed are listed, if there are too many
+/// the last in sort order are left out.
+fn list_entries(filesystems: &[&dyn Filesystem]) -> Result<Vec<EntryInfo, MAX_ENTRIES>, Error> {
+    let mut entries: Vec<EntryInfo, MAX_ENTRIES> = Vec::new();
+    let mut left_out = 0;
+    let directories = filesystems
+        .iter()
+        .enumerate()
+        .flat_map(|entry| [(entry, EntryKind::Config), (entry, EntryKind::Uki)]);
+    for ((index, filesystem), kind) in directories {
+        // Entries may live on only one of the partitions
+        find_file(*filesystem, kind.directory(), &mut |file_name| {
+            if !compare_entry(file_name, kind.pattern())? {
+                return Ok(false);
+            }
+            let entry = match read_entry(*filesystem, kind, file_name) {
+                Ok(entry) => entry,
+                Err(err) => {
+                    warn!("Skipping boot entry {}: {err:?}", ascii_strip(file_name));
+                    return Ok(false);
                 }
-                // only fallback to entry files ending with `.conf`
-                if fallback.is_none() && compare_entry(&file_name, b"*.conf\0")? {
-                    fallback = Some(file_name);
+            };
+            if !is_native_architecture(&entry.architecture) {
+                return Ok(false);
+            }
+
+            let info = EntryInfo::new(index, kind, file_name, &entry);
+            let position = entries
+                .iter()
+                .position(|e| compare_entries(&info, e) == Ordering::Less)
+                .unwrap_or(entries.len());
+            if entries.is_full() {
+                left_out += 1;
+                if position == entries.len() {
+                    return Ok(false);
                 }
+                entries.pop();
+            }
+            // There is room as the last entry was removed if it was full
+            let _ = entries.insert(position, info);
+            Ok(false)
+        })?;
+    }
+    if left_out > 0 {
+        warn!("Too many boot entries, leaving out the last {left_out}");
+    }
+    Ok(entries)
+}
+
+/// Select the first of `entries` whose file name, without any boot counter,
+/// matches the glob-like `pattern`, or the first of all of them if there is
+/// no pattern or nothing matches.
+fn find_entry(entries: &[EntryInfo], pattern: &[u8]) -> Result<usize, Error> {
+    if pattern[0] != 0 {
+        for (index, entry) in entries.iter().enumerate() {
+            if compare_entry(&entry.id, pattern)? {
+                return Ok(index);
             }
-            Err(fat::Error::EndOfFile) => break,
-            Err(err) => return Err(err.into()),
         }
     }
-    fallback.ok_or_else(|| fat::Error::NotFound.into())
+    Ok(0)
 }
 
 /// Attempt to match a file name with a glob-like pattern.
@@ -157,8 +626,16 @@ fn compare_entry(file_name: &[u8], pattern: &[u8]) -> Result<bool, Error> {
                     }
                     return Ok(*pattern.get(idx).ok_or(Error::UnterminatedString)? == b'\0');
                 }
-                // TODO
-                b'[' => todo!("patterns containing `[...]` sets are not supported"),
+                b'[' => {
+                    if *f == b'\0' {
+                        return Ok(false);
+                    }
+                    let (matched, len) = match_set(&pattern[idx..], *f)?;
+                    if !matched {
+                        return Ok(false);
+                    }
+                    idx += len;
+                }
                 _ if *p != *f => return Ok(false),
                 _ => (),
             }
@@ -170,122 +647,497 @@ fn compare_entry(file_name: &[u8], pattern: &[u8]) -> Result<bool, Error> {
     compare_entry_inner(name_iter, pattern, 32)
 }
 
-fn parse_entry(f: &mut fat::File) -> Result<LoaderConfig, fat::Error> {
-    let mut data = [0; 4096];
-    assert!(f.get_size() as usize <= data.len());
-    assert!(data.len() >= SectorBuf::len());
+/// Match `c` with the `[...]` set at the start of `set`, just after the `[`.
+/// Sets may hold ranges (`a-z`) and are negated by a leading `!` or `^`, a
+/// `]` straight after the opening is part of the set. Returns whether `c`
+/// matched and the length of the rest of the set including the `]`.
+fn match_set(set: &[u8], c: u8) -> Result<(bool, usize), Error> {
+    let negate = matches!(set.first(), Some(b'!' | b'^'));
+    let mut idx = usize::from(negate);
+    let mut matched = false;
+    loop {
+        let start = *set.get(idx).ok_or(Error::InvalidPattern)?;
+        match start {
+            b'\0' => return Err(Error::InvalidPattern),
+            b']' if idx > usize::from(negate) => return Ok((matched != negate, idx + 1)),
+            _ => {}
+        }
+        idx += 1;
+        match (set.get(idx), set.get(idx + 1)) {
+            (Some(b'-'), Some(&end)) if end != b']' && end != b'\0' => {
+                matched |= (start..=end).contains(&c);
+                idx += 2;
+            }
+            _ => matched |= start == c,
+        }
+    }
+}
 
-    let mut loader_config = LoaderConfig::default();
+fn parse_entry(f: &mut dyn Read) -> Result<LoaderConfig, Error> {
+    let mut data = [0; 4096];
+    let conf = read_config(f, &mut data)?;
 
-    let mut offset = 0;
-    loop {
-        match f.read(&mut data[offset..offset + SectorBuf::len()]) {
-            Err(fat::Error::EndOfFile) => break,
-            Err(e) => return Err(e),
-            Ok(_) => {
-                offset += SectorBuf::len();
+    let mut entry = LoaderConfig::default();
+    for (key, value) in config_lines(conf) {
+        match key {
+            "title" => copy_value(&mut entry.title, value)?,
+            "version" => copy_value(&mut entry.version, value)?,
+            "machine-id" => copy_value(&mut entry.machine_id, value)?,
+            "sort-key" => copy_value(&mut entry.sort_key, value)?,
+            "architecture" => copy_value(&mut entry.architecture, value)?,
+            "linux" => copy_value(&mut entry.bzimage_path, value)?,
+            "efi" => copy_value(&mut entry.efi_path, value)?,
+            "initrd" => push_value(&mut entry.initrd_paths, value)?,
+            "devicetree" => copy_value(&mut entry.devicetree_path, value)?,
+            "devicetree-overlay" => {
+                for overlay in value.split_ascii_whitespace() {
+                    push_value(&mut entry.devicetree_overlay_paths, overlay)?;
+                }
             }
+            "options" => append_value(&mut entry.cmdline, value)?,
+            _ => {}
         }
     }
 
-    let conf = unsafe { core::str::from_utf8_unchecked(&data) };
-    for line in conf.lines() {
-        if let Some(entry) = line.strip_prefix("linux") {
-            let entry = entry.trim();
-            loader_config.bzimage_path[0..entry.len()].copy_from_slice(entry.as_bytes());
+    Ok(entry)
+}
+
+// The key/value pairs of an os-release file, with any quotes removed
+fn os_release_lines(osrel: &str) -> impl Iterator<Item = (&str, &str)> {
+    osrel
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(|line| line.split_once('='))
+        .map(|(key, value)| {
+            let quoted = value.len() >= 2
+                && (value.starts_with('"') && value.ends_with('"')
+                    || value.starts_with('\'') && value.ends_with('\''));
+            match quoted {
+                true => (key, &value[1..value.len() - 1]),
+                false => (key, value),
+            }
+        })
+}
+
+// Read a text section, without the trailing newline or NUL
+fn read_section<'a>(
+    image: &mut pe::Loader,
+    name: &[u8],
+    data: &'a mut [u8; 4096],
+) -> Result<Option<&'a str>, Error> {
+    match image.section(name)? {
+        Some(mut f) => Ok(Some(
+            read_config(&mut f, data)?.trim_end_matches(|c: char| c.is_whitespace() || c == '\0'),
+        )),
+        None => Ok(None),
+    }
+}
+
+/// Describe a unified kernel image as an entry from its `.osrel`, `.cmdline`
+/// and `.uname` sections, as systemd-boot does. The sort key is the
+/// os-release `IMAGE_ID` or `ID` and the version is the kernel release or
+/// else the os-release `IMAGE_VERSION`, `VERSION_ID` or `BUILD_ID`.
+fn parse_uki(image: &mut pe::Loader) -> Result<LoaderConfig, Error> {
+    let mut entry = LoaderConfig::default();
+    let mut data = [0; 4096];
+
+    if let Some(osrel) = read_section(image, b".osrel", &mut data)? {
+        let (mut pretty_name, mut name, mut image_id, mut id) = ("", "", "", "");
+        let (mut image_version, mut version_id, mut build_id) = ("", "", "");
+        for (key, value) in os_release_lines(osrel) {
+            match key {
+                "PRETTY_NAME" => pretty_name = value,
+                "NAME" => name = value,
+                "IMAGE_ID" => image_id = value,
+                "ID" => id = value,
+                "IMAGE_VERSION" => image_version = value,
+                "VERSION_ID" => version_id = value,
+                "BUILD_ID" => build_id = value,
+                _ => {}
+            }
         }
-        if let Some(entry) = line.strip_prefix("options") {
-            let entry = entry.trim();
-            loader_config.cmdline[0..entry.len()].copy_from_slice(entry.as_bytes());
+        if let Some(title) = [pretty_name, name].into_iter().find(|v| !v.is_empty()) {
+            copy_value(&mut entry.title, title)?;
         }
-        if let Some(entry) = line.strip_prefix("initrd") {
-            let entry = entry.trim();
-            loader_config.initrd_path[0..entry.len()].copy_from_slice(entry.as_bytes());
+        if let Some(sort_key) = [image_id, id].into_iter().find(|v| !v.is_empty()) {
+            copy_value(&mut entry.sort_key, sort_key)?;
         }
+        if let Some(version) = [image_version, version_id, build_id]
+            .into_iter()
+            .find(|v| !v.is_empty())
+        {
+            copy_value(&mut entry.version, version)?;
+        }
+    }
+    if let Some(uname) = read_section(image, b".uname", &mut data)? {
+        copy_value(&mut entry.version, uname)?;
+    }
+    if let Some(cmdline) = read_section(image, b".cmdline", &mut data)? {
+        copy_value(&mut entry.cmdline, cmdline)?;
     }
 
-    Ok(loader_config)
+    Ok(entry)
 }
 
-fn default_entry_path(fs: &fat::Filesystem) -> Result<[u8; 260], Error> {
-    let mut f = match fs.open("/loader/loader.conf")? {
-        fat::Node::File(f) => f,
-        _ => return Err(fat::Error::NotFound.into()),
-    };
-    let default_entry_pattern = default_entry_pattern(&mut f)?;
+// The path of the entry called `file_name` in the directory for its kind
+fn entry_path(kind: EntryKind, file_name: &[u8]) -> Result<[u8; 260], Error> {
+    let mut path = [0u8; 260];
+    let directory = kind.directory();
+    let file_name = ascii_strip(file_name);
+    let len = directory.len() + 1 + file_name.len();
+    if len >= path.len() {
+        return Err(Error::ValueTooLong);
+    }
+    path[..directory.len()].copy_from_slice(directory.as_bytes());
+    path[directory.len()] = b'/';
+    path[directory.len() + 1..len].copy_from_slice(file_name.as_bytes());
+    Ok(path)
+}
+
+// Parse the entry called `file_name` in the directory for its kind
+fn read_entry(
+    filesystem: &dyn Filesystem,
+    kind: EntryKind,
+    file_name: &[u8],
+) -> Result<LoaderConfig, Error> {
+    let path = entry_path(kind, file_name)?;
+    let mut f = filesystem.open(ascii_strip(&path))?;
+    match kind {
+        EntryKind::Config => parse_entry(&mut f),
+        EntryKind::Uki => {
+            let mut entry = parse_uki(&mut pe::Loader::new(&mut f))?;
+            entry.efi_path = path;
+            Ok(entry)
+        }
+    }
+}
+
+/// Count an attempt to boot the entry called `file_name` by renaming it with
+/// one try less left and one more done, as systemd-boot does, returning its
+/// new path. Once there are no tries left the entry is not renamed.
+fn count_boot(
+    filesystem: &dyn Filesystem,
+    kind: EntryKind,
+    file_name: &[u8],
+    counter: BootCounter,
+) -> Result<Option<[u8; 260]>, Error> {
+    if counter.left == 0 {
+        return Ok(None);
+    }
+    let (prefix, _, extension) = split_counter(ascii_strip(file_name));
+    let mut new_name: heapless::String<255> = heapless::String::new();
+    let (left, done) = (counter.left - 1, counter.done.saturating_add(1));
+    write!(new_name, "{prefix}+{left}-{done}{extension}").map_err(|_| Error::ValueTooLong)?;
+
+    let path = entry_path(kind, file_name)?;
+    filesystem.open(ascii_strip(&path))?.rename(&new_name)?;
+    filesystem.flush()?;
+    Ok(Some(entry_path(kind, new_name.as_bytes())?))
+}
 
-    let default_entry = find_entry(fs, &default_entry_pattern)?;
-    let default_entry = ascii_strip(&default_entry);
+// Whether the kernel command line already selects the root filesystem
+fn has_root_parameter(cmdline: &[u8]) -> bool {
+    cmdline
+        .split(|c| c.is_ascii_whitespace())
+        .any(|param| param.starts_with(b"root="))
+}
 
-    let mut entry_path = [0u8; 260];
-    entry_path[0..ENTRY_DIRECTORY.len()].copy_from_slice(ENTRY_DIRECTORY.as_bytes());
-    entry_path[ENTRY_DIRECTORY.len()] = b'/';
-    entry_path[ENTRY_DIRECTORY.len() + 1..ENTRY_DIRECTORY.len() + default_entry.len() + 1]
-        .copy_from_slice(default_entry.as_bytes());
-    Ok(entry_path)
+// Whether `rhf.efistub` on the firmware command line asks for kernels to be
+// started through their EFI stub
+fn has_efi_stub_parameter(cmdline: &[u8]) -> bool {
+    cmdline
+        .split(|c| c.is_ascii_whitespace())
+        .any(|param| param == b"rhf.efistub")
 }
 
-pub fn load_default_entry(
-    fs: &fat::Filesystem,
-    info: &dyn bootinfo::Info,
-) -> Result<Kernel, Error> {
-    let default_entry_path = default_entry_path(fs)?;
-    let default_entry_path = ascii_strip(&default_entry_path);
+// The discoverable root partition as `root=PARTUUID=<uuid>` if neither command
+// line has a `root=` parameter
+fn root_parameter(
+    firmware_cmdline: &[u8],
+    cmdline: &[u8],
+    root_guid: Option<[u8; 16]>,
+) -> Option<[u8; 50]> {
+    let guid = root_guid?;
+    if has_root_parameter(firmware_cmdline) || has_root_parameter(cmdline) {
+        return None;
+    }
+    let mut root = *b"root=PARTUUID=00000000-0000-0000-0000-000000000000";
+    format_guid(&guid, (&mut root[14..]).try_into().unwrap());
+    Some(root)
+}
 
-    let mut f = match fs.open(default_entry_path)? {
-        fat::Node::File(f) => f,
-        _ => return Err(Error::File(fat::Error::NotFound)),
+// Load options for starting the kernel of `entry` through its EFI stub, the
+// command line a directly booted kernel gets. The stub loads the initrds
+// through the Load File 2 protocol rather than from `initrd=` options, so
+// kernels before 5.8 whose stubs lack Load File 2 support get no initrd.
+fn efi_stub_options(
+    entry: &LoaderConfig,
+    firmware_cmdline: &[u8],
+    root_guid: Option<[u8; 16]>,
+) -> Result<[u8; 4096], Error> {
+    // Leave room for the NUL terminator
+    let mut options: Vec<u8, 4095> = Vec::new();
+    let mut append = |parts: &[&[u8]]| -> Result<(), Error> {
+        if !options.is_empty() {
+            options.push(b' ').map_err(|_| Error::ValueTooLong)?;
+        }
+        for part in parts {
+            options
+                .extend_from_slice(part)
+                .map_err(|_| Error::ValueTooLong)?;
+        }
+        Ok(())
     };
-    let entry = parse_entry(&mut f)?;
 
-    let bzimage_path = ascii_strip(&entry.bzimage_path);
-    let initrd_path = ascii_strip(&entry.initrd_path);
-    let cmdline = ascii_strip(&entry.cmdline);
+    let cmdline = ascii_strip(&entry.cmdline).as_bytes();
+    for part in [firmware_cmdline, cmdline] {
+        if !part.is_empty() {
+            append(&[part])?;
+        }
+    }
+    if let Some(root) = root_parameter(firmware_cmdline, cmdline, root_guid) {
+        append(&[&root])?;
+    }
+
+    let mut result = [0; 4096];
+    result[..options.len()].copy_from_slice(&options);
+    Ok(result)
+}
 
-    let mut kernel = Kernel::new(info);
+/// The boot entries on the ESP and the Extended Boot Loader Partition (which
+/// may be a FAT or ext4 `/boot`) in sort order, with the default entry and
+/// menu timeout from `loader.conf`
+pub struct BootEntries<'a> {
+    filesystems: Vec<&'a dyn Filesystem, 2>,
+    entries: Vec<EntryInfo, MAX_ENTRIES>,
+    /// The index of the default entry
+    pub default: usize,
+    pub timeout: Timeout,
+}
 
-    let mut bzimage_file = fs.open(bzimage_path)?;
-    kernel.load_kernel(info, &mut bzimage_file)?;
+impl<'a> BootEntries<'a> {
+    /// Find the entries and read `loader.conf`, which is only read from the
+    /// ESP. Without one (or its `default`) the first entry in sort order is
+    /// the default.
+    pub fn new(
+        esp: &'a dyn Filesystem,
+        xbootldr: Option<&'a dyn Filesystem>,
+    ) -> Result<BootEntries<'a>, Error> {
+        let mut filesystems: Vec<&'a dyn Filesystem, 2> = Vec::new();
+        let _ = filesystems.push(esp);
+        if let Some(xbootldr) = xbootldr {
+            let _ = filesystems.push(xbootldr);
+        }
 
-    if !initrd_path.is_empty() {
-        let mut initrd_file = fs.open(initrd_path)?;
-        kernel.load_initrd(&mut initrd_file)?;
+        let conf = match esp.open("/loader/loader.conf") {
+            Ok(mut f) => parse_loader_conf(&mut f)?,
+            Err(fs::Error::NotFound) => LoaderConf::default(),
+            Err(err) => return Err(err.into()),
+        };
+        let entries = list_entries(&filesystems)?;
+        let default = find_entry(&entries, &conf.default)?;
+        Ok(BootEntries {
+            filesystems,
+            entries,
+            default,
+            timeout: conf.timeout,
+        })
     }
 
-    kernel.append_cmdline(info.cmdline());
-    kernel.append_cmdline(cmdline.as_bytes());
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// The title of the entry at `index`, its file name if it has none
+    pub fn title(&self, index: usize) -> &str {
+        let entry = &self.entries[index];
+        match ascii_strip(&entry.title) {
+            "" => ascii_strip(&entry.id),
+            title => title,
+        }
+    }
 
-    Ok(kernel)
+    pub fn version(&self, index: usize) -> &str {
+        ascii_strip(&self.entries[index].version)
+    }
+
+    /// The command line of the entry at `index`
+    pub fn cmdline(&self, index: usize) -> Result<[u8; 4096], Error> {
+        let entry = &self.entries[index];
+        Ok(read_entry(self.filesystems[entry.filesystem], entry.kind, &entry.name)?.cmdline)
+    }
+
+    /// Load the entry at `index`, with `cmdline` replacing its command line
+    /// if given. The kernel and initrd, or the EFI program, are read from the
+    /// same partition as the entry. On x86 the kernel and initrd of a unified
+    /// kernel image are loaded from its sections, elsewhere the image is
+    /// started as an EFI program. If neither the entry nor the firmware
+    /// command line has a `root=` parameter then `root_guid`, the
+    /// discoverable root partition, is passed to a kernel as
+    /// `root=PARTUUID=<uuid>`. With `rhf.efistub` on the firmware command
+    /// line a kernel with an EFI stub is started as an EFI program too, so
+    /// that it gets the EFI system table and runtime services.
+    pub fn load(
+        &self,
+        index: usize,
+        cmdline: Option<&[u8; 4096]>,
+        info: &dyn bootinfo::Info,
+        root_guid: Option<[u8; 16]>,
+    ) -> Result<Boot<'a>, Error> {
+        let EntryInfo {
+            filesystem,
+            kind,
+            name: file_name,
+            ..
+        } = self.entries[index];
+        let fs = self.filesystems[filesystem];
+        let mut entry = read_entry(fs, kind, &file_name)?;
+        if let Some(cmdline) = cmdline {
+            entry.cmdline = *cmdline;
+        }
+
+        if let Some(counter) = self.entries[index].counter {
+            match count_boot(fs, kind, &file_name, counter) {
+                Ok(Some(path)) if kind == EntryKind::Uki => entry.efi_path = path,
+                Ok(_) => {}
+                Err(err) => warn!(
+                    "Failed to update the boot counter of {}: {err:?}",
+                    ascii_strip(&file_name)
+                ),
+            }
+        }
+
+        let title = match ascii_strip(&entry.title) {
+            "" => ascii_strip(&file_name),
+            title => title,
+        };
+        info!("Booting {title}");
+
+        let bzimage_path = ascii_strip(&entry.bzimage_path);
+        let cmdline = ascii_strip(&entry.cmdline);
+
+        let is_efi = match kind {
+            EntryKind::Config => bzimage_path.is_empty(),
+            EntryKind::Uki => !cfg!(target_arch = "x86_64"),
+        };
+        if is_efi {
+            if ascii_strip(&entry.efi_path).is_empty() {
+                return Err(Error::NoBootable);
+            }
+            return Ok(Boot::Efi {
+                filesystem: fs,
+                path: entry.efi_path,
+                options: entry.cmdline,
+                initrds: Vec::new(),
+            });
+        }
+
+        if kind == EntryKind::Config && has_efi_stub_parameter(info.cmdline()) {
+            let mut f = fs.open(bzimage_path)?;
+            if pe::Loader::new(&mut f).is_executable() {
+                return Ok(Boot::Efi {
+                    filesystem: fs,
+                    path: entry.bzimage_path,
+                    options: efi_stub_options(&entry, info.cmdline(), root_guid)?,
+                    initrds: entry.initrd_paths,
+                });
+            }
+            warn!("Kernel has no EFI stub, booting it directly");
+        }
+
+        #[cfg(target_arch = "x86_64")]
+        if !ascii_strip(&entry.devicetree_path).is_empty()
+            || !entry.devicetree_overlay_paths.is_empty()
+        {
+            warn!("Devicetrees are not used on x86-64, ignoring");
+        }
+
+        let mut kernel = Kernel::new(info);
+
+        match kind {
+            EntryKind::Config => {
+                let mut bzimage_file = fs.open(bzimage_path)?;
+                kernel.load_kernel(info, &mut bzimage_file)?;
+
+                // All of them at once as they are loaded as one initrd
+                let mut initrd_files: Vec<fs::Node, MAX_INITRDS> = Vec::new();
+                for initrd_path in &entry.initrd_paths {
+                    // Cannot fail as there are as many paths at most
+                    let _ = initrd_files.push(fs.open(ascii_strip(initrd_path))?);
+                }
+                let mut initrd_files: Vec<&mut dyn Read, MAX_INITRDS> = initrd_files
+                    .iter_mut()
+                    .map(|f| f as &mut dyn Read)
+                    .collect();
+                if !initrd_files.is_empty() {
+                    kernel.load_initrd(&mut initrd_files)?;
+                }
+
+                #[cfg(not(target_arch = "x86_64"))]
+                load_device_tree(&mut kernel, fs, &entry)?;
+            }
+            EntryKind::Uki => {
+                let mut f = fs.open(ascii_strip(&entry.efi_path))?;
+                let mut image = pe::Loader::new(&mut f);
+                match image.section(b".linux")? {
+                    Some(mut linux) => kernel.load_kernel(info, &mut linux)?,
+                    None => return Err(Error::NoBootable),
+                }
+                if let Some(mut initrd) = image.section(b".initrd")? {
+                    kernel.load_initrd(&mut [&mut initrd])?;
+                }
+            }
+        }
+
+        kernel.append_cmdline(info.cmdline());
+        kernel.append_cmdline(cmdline.as_bytes());
+
+        if let Some(root) = root_parameter(info.cmdline(), cmdline.as_bytes(), root_guid) {
+            kernel.append_cmdline(&root);
+        }
+
+        Ok(Boot::Kernel(kernel))
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::fat::Read;
+    use crate::fs::Read;
     use crate::part::tests::*;
     use core::convert::TryInto;
 
     #[test]
     fn test_default_entry() {
         let d = FakeDisk::new(&clear_disk_path());
-        let (start, end) = crate::part::find_efi_partition(&d).unwrap();
+        let p = crate::part::partitions(&d)
+            .and_then(|p| p.find_efi_partition())
+            .unwrap();
+        let (start, end) = (p.first_lba, p.last_lba);
         let mut fs = crate::fat::Filesystem::new(&d, start, end);
         fs.init().expect("Error initialising filesystem");
 
         let mut f: crate::fat::File = fs.open("/loader/loader.conf").unwrap().try_into().unwrap();
-        let s = super::default_entry_pattern(&mut f).unwrap();
-        let s = super::ascii_strip(&s);
+        let conf = super::parse_loader_conf(&mut f).unwrap();
+        let s = super::ascii_strip(&conf.default);
         assert_eq!(s, "Clear-linux-kvm-5.0.6-318");
 
-        let default_entry_path = super::default_entry_path(&fs).unwrap();
-        let default_entry_path = super::ascii_strip(&default_entry_path);
-
+        let entries = super::BootEntries::new(&fs, None).unwrap();
+        let default_entry = &entries.entries[entries.default];
+        assert_eq!(
+            (default_entry.filesystem, default_entry.kind),
+            (0, super::EntryKind::Config)
+        );
         assert_eq!(
-            default_entry_path,
-            format!("/loader/entries/{s}.conf").as_str()
+            super::ascii_strip(&default_entry.name),
+            format!("{s}.conf").as_str()
         );
 
-        let mut f: crate::fat::File = fs.open(default_entry_path).unwrap().try_into().unwrap();
-        let entry = super::parse_entry(&mut f).unwrap();
+        let entry = super::read_entry(&fs, default_entry.kind, &default_entry.name).unwrap();
         let s = super::ascii_strip(&entry.bzimage_path);
         assert_eq!(s, "/EFI/org.clearlinux/kernel-org.clearlinux.kvm.5.0.6-318");
         let s = super::ascii_strip(&entry.cmdline);
@@ -293,6 +1145,573 @@ mod tests {
         assert_eq!(s, "root=PARTUUID=ae06d187-e9fc-4d3b-9e5b-8e6ff28e894f console=tty0 console=ttyS0,115200n8 console=hvc0 quiet init=/usr/lib/systemd/systemd-bootchart initcall_debug tsc=reliable no_timer_check noreplace-smp cryptomgr.notests rootfstype=ext4,btrfs,xfs kvm-intel.nested=1 rw");
     }
 
+    // An entry file held in memory
+    struct Bytes<'a> {
+        data: &'a [u8],
+        position: usize,
+    }
+
+    impl Read for Bytes<'_> {
+        fn read(&mut self, data: &mut [u8]) -> Result<u32, crate::fs::Error> {
+            if self.position >= self.data.len() {
+                return Err(crate::fs::Error::EndOfFile);
+            }
+            let len = usize::min(data.len(), self.data.len() - self.position);
+            data[..len].copy_from_slice(&self.data[self.position..self.position + len]);
+            self.position += len;
+            Ok(len as u32)
+        }
+
+        fn seek(&mut self, position: u32) -> Result<(), crate::fs::Error> {
+            self.position = position as usize;
+            Ok(())
+        }
+
+        fn get_size(&self) -> u32 {
+            self.data.len() as u32
+        }
+    }
+
+    fn parse(data: &str) -> Result<super::LoaderConfig, super::Error> {
+        super::parse_entry(&mut Bytes {
+            data: data.as_bytes(),
+            position: 0,
+        })
+    }
+
+    #[test]
+    fn test_parse_loader_conf() {
+        use super::Timeout;
+        let parse = |data: &str| {
+            super::parse_loader_conf(&mut Bytes {
+                data: data.as_bytes(),
+                position: 0,
+            })
+            .unwrap()
+        };
+
+        let conf = parse("# comment\ndefault fedora-*\ntimeout 5\n");
+        assert_eq!(super::ascii_strip(&conf.default), "fedora-*");
+        assert_eq!(conf.timeout, Timeout::Seconds(5));
+        assert_eq!(parse("").timeout, Timeout::Seconds(0));
+        assert_eq!(parse("timeout menu-force").timeout, Timeout::Forever);
+        assert_eq!(
+            parse("timeout 3\ntimeout menu-hidden").timeout,
+            Timeout::Seconds(0)
+        );
+        assert_eq!(parse("timeout menu-disabled").timeout, Timeout::Disabled);
+        assert_eq!(
+            parse("timeout 3\ntimeout soon").timeout,
+            Timeout::Seconds(3)
+        );
+    }
+
+    #[test]
+    fn test_parse_entry() {
+        let entry = parse(
+            "# Comment\n\
+             title      Fedora Linux 40\n\
+             version 6.8.5-301.fc40.x86_64\n\
+             machine-id 6a9857a393724b7a981ebb5b8495b9ea\n\
+             sort-key fedora\n\
+             architecture x64\n\
+             linux /6a98/6.8.5/linux\n\
+             initrd /6a98/6.8.5/microcode\n\
+             initrd\t/6a98/6.8.5/initrd\n\
+             options root=/dev/vda2\n\
+             options   quiet  rw\n\
+             devicetree /6a98/6.8.5/board.dtb\n\
+             devicetree-overlay /a.dtbo /b.dtbo\n\
+             devicetree-overlay /c.dtbo\n\
+             linuxefi /ignored\n\
+             unknown-key value\n",
+        )
+        .unwrap();
+        assert_eq!(super::ascii_strip(&entry.title), "Fedora Linux 40");
+        assert_eq!(super::ascii_strip(&entry.version), "6.8.5-301.fc40.x86_64");
+        assert_eq!(
+            super::ascii_strip(&entry.machine_id),
+            "6a9857a393724b7a981ebb5b8495b9ea"
+        );
+        assert_eq!(super::ascii_strip(&entry.sort_key), "fedora");
+        assert_eq!(super::ascii_strip(&entry.architecture), "x64");
+        assert_eq!(super::ascii_strip(&entry.bzimage_path), "/6a98/6.8.5/linux");
+        assert_eq!(super::ascii_strip(&entry.efi_path), "");
+        let initrds: Vec<&str> = entry
+            .initrd_paths
+            .iter()
+            .map(|p| super::ascii_strip(p))
+            .collect();
+        assert_eq!(initrds, ["/6a98/6.8.5/microcode", "/6a98/6.8.5/initrd"]);
+        assert_eq!(
+            super::ascii_strip(&entry.cmdline),
+            "root=/dev/vda2 quiet  rw"
+        );
+        assert_eq!(
+            super::ascii_strip(&entry.devicetree_path),
+            "/6a98/6.8.5/board.dtb"
+        );
+        let overlays: Vec<&str> = entry
+            .devicetree_overlay_paths
+            .iter()
+            .map(|p| super::ascii_strip(p))
+            .collect();
+        assert_eq!(overlays, ["/a.dtbo", "/b.dtbo", "/c.dtbo"]);
+
+        let entry = parse("title Shell\r\nefi /EFI/tools/shell.efi\r\noptions -nostartup").unwrap();
+        assert_eq!(super::ascii_strip(&entry.efi_path), "/EFI/tools/shell.efi");
+        assert_eq!(super::ascii_strip(&entry.cmdline), "-nostartup");
+
+        let long = format!("linux /{}", "a".repeat(300));
+        assert!(matches!(parse(&long), Err(super::Error::ValueTooLong)));
+        assert!(matches!(
+            parse(&"a".repeat(5000)),
+            Err(super::Error::FileTooLarge)
+        ));
+    }
+
+    #[test]
+    fn test_compare_versions() {
+        use core::cmp::Ordering::{Equal, Greater, Less};
+
+        // Pairs in increasing order, from the UAPI Version Format Specification
+        // and systemd's tests
+        for (older, newer) in [
+            ("1", "2"),
+            ("9", "10"),
+            ("1.9", "1.10"),
+            ("122.1", "123"),
+            ("123~rc1", "123"),
+            ("123~rc1", "123~rc2"),
+            ("123", "123.1"),
+            ("123-1", "123.1"),
+            ("123-9", "123.1-1"),
+            ("123", "123^post1"),
+            ("123^post1", "123.1"),
+            ("123.a", "123a"),
+            ("123a", "123b"),
+            ("123A", "123a"),
+            ("abc", "abcd"),
+            ("a", "1"),
+            ("6.8.5-301.fc40", "6.8.11-300.fc40"),
+            ("fedora-6.8.5.conf", "fedora-6.10.0.conf"),
+        ] {
+            assert_eq!(
+                super::compare_versions(older.as_bytes(), newer.as_bytes()),
+                Less,
+                "{older} < {newer}"
+            );
+            assert_eq!(
+                super::compare_versions(newer.as_bytes(), older.as_bytes()),
+                Greater,
+                "{newer} > {older}"
+            );
+        }
+
+        for (a, b) in [
+            ("", ""),
+            ("123", "0123"),
+            ("1_2", "1+2"),
+            ("1.2\0\0\0", "1.2"),
+        ] {
+            assert_eq!(
+                super::compare_versions(a.as_bytes(), b.as_bytes()),
+                Equal,
+                "{a} = {b}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_compare_entries() {
+        fn entry(name: &str, sort_key: &str, machine_id: &str, version: &str) -> super::EntryInfo {
+            let mut config = super::LoaderConfig::default();
+            super::copy_value(&mut config.sort_key, sort_key).unwrap();
+            super::copy_value(&mut config.machine_id, machine_id).unwrap();
+            super::copy_value(&mut config.version, version).unwrap();
+            let mut file_name = [0; 255];
+            super::copy_value(&mut file_name, name).unwrap();
+            super::EntryInfo::new(0, super::EntryKind::Config, &file_name, &config)
+        }
+
+        let mut entries = [
+            entry("other-1.conf", "", "", ""),
+            entry("other-2.conf", "", "", "1"),
+            entry("b.conf", "fedora", "2", "6.9"),
+            entry("c.conf", "fedora", "1", "6.8"),
+            entry("d.conf", "fedora", "1", "6.10"),
+            entry("a.conf", "arch", "", ""),
+            entry("e.conf", "fedora", "1", "6.10"),
+        ];
+        entries.sort_by(super::compare_entries);
+        let names: Vec<&str> = entries
+            .iter()
+            .map(|e| super::ascii_strip(&e.name))
+            .collect();
+        assert_eq!(
+            names,
+            [
+                "a.conf",
+                "e.conf",
+                "d.conf",
+                "c.conf",
+                "b.conf",
+                "other-2.conf",
+                "other-1.conf"
+            ]
+        );
+
+        // Entries that ran out of tries go last, otherwise the boot counter
+        // only breaks ties
+        let mut entries = [
+            entry("linux-6.8+0-3.conf", "", "", ""),
+            entry("linux-6.9+0-3.conf", "", "", ""),
+            entry("linux-6.9+1-2.conf", "", "", ""),
+            entry("linux-6.9+2-1.conf", "", "", ""),
+            entry("linux-6.9+2-0.conf", "", "", ""),
+            entry("linux-6.9.conf", "", "", ""),
+            entry("linux-6.7+3.conf", "", "", ""),
+        ];
+        entries.sort_by(super::compare_entries);
+        let names: Vec<&str> = entries
+            .iter()
+            .map(|e| super::ascii_strip(&e.name))
+            .collect();
+        assert_eq!(
+            names,
+            [
+                "linux-6.9+2-0.conf",
+                "linux-6.9+2-1.conf",
+                "linux-6.9+1-2.conf",
+                "linux-6.9.conf",
+                "linux-6.7+3.conf",
+                "linux-6.9+0-3.conf",
+                "linux-6.8+0-3.conf",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_split_counter() {
+        use super::{split_counter, BootCounter};
+        let counter = |left, done| Some(BootCounter { left, done });
+
+        assert_eq!(
+            split_counter("foo+3-0.conf"),
+            ("foo", counter(3, 0), ".conf")
+        );
+        assert_eq!(split_counter("foo+3.efi"), ("foo", counter(3, 0), ".efi"));
+        assert_eq!(
+            split_counter("a+b+0-12.conf"),
+            ("a+b", counter(0, 12), ".conf")
+        );
+        assert_eq!(split_counter("foo.conf"), ("foo", None, ".conf"));
+        assert_eq!(split_counter("foo+.conf"), ("foo+", None, ".conf"));
+        assert_eq!(split_counter("foo+1-x.conf"), ("foo+1-x", None, ".conf"));
+        assert_eq!(split_counter("foo+-1.conf"), ("foo+-1", None, ".conf"));
+        assert_eq!(split_counter("foo+1"), ("foo", counter(1, 0), ""));
+    }
+
+    // Create `path` on a FAT filesystem along with the entry directories
+    fn write_file(fs: &crate::fat::Filesystem, path: &str, contents: &[u8]) {
+        let root = fs.root().unwrap();
+        for directory in ["/loader", "/loader/entries", "/EFI", "/EFI/Linux"] {
+            if root.open(directory).is_err() {
+                root.create(directory, true).unwrap();
+            }
+        }
+        let mut f: crate::fat::File = root.create(path, false).unwrap().try_into().unwrap();
+        f.write(contents).unwrap();
+    }
+
+    fn write_files(fs: &crate::fat::Filesystem, files: &[(&str, &str)]) {
+        for (path, contents) in files {
+            write_file(fs, path, contents.as_bytes());
+        }
+    }
+
+    // A PE image for x86-64 with just the named sections, each starting on a
+    // sector boundary
+    fn uki(sections: &[(&str, &[u8])]) -> Vec<u8> {
+        const SECTION_TABLE: usize = 0x40 + 24 + 240;
+        let mut image = vec![0u8; 1024];
+        image[..2].copy_from_slice(b"MZ");
+        image[0x3c..0x40].copy_from_slice(&0x40u32.to_le_bytes());
+        image[0x40..0x44].copy_from_slice(b"PE\0\0");
+        image[0x44..0x46].copy_from_slice(&0x8664u16.to_le_bytes());
+        image[0x46..0x48].copy_from_slice(&(sections.len() as u16).to_le_bytes());
+        image[0x54..0x56].copy_from_slice(&240u16.to_le_bytes());
+        image[0x58..0x5a].copy_from_slice(&0x20bu16.to_le_bytes());
+        for (i, (name, contents)) in sections.iter().enumerate() {
+            let offset = image.len() as u32;
+            let size = contents.len() as u32;
+            let header = &mut image[SECTION_TABLE + i * 40..SECTION_TABLE + (i + 1) * 40];
+            header[..name.len()].copy_from_slice(name.as_bytes());
+            header[8..12].copy_from_slice(&size.to_le_bytes());
+            header[12..16].copy_from_slice(&offset.to_le_bytes());
+            header[16..20].copy_from_slice(&size.next_multiple_of(512).to_le_bytes());
+            header[20..24].copy_from_slice(&offset.to_le_bytes());
+            image.extend_from_slice(contents);
+            image.resize(image.len().next_multiple_of(512), 0);
+        }
+        image
+    }
+
+    // The filesystem, kind and file name of the first entry matching `pattern`
+    fn find_entry(
+        filesystems: &[&dyn crate::fs::Filesystem],
+        pattern: &[u8],
+    ) -> (usize, super::EntryKind, String) {
+        let entries = super::list_entries(filesystems).unwrap();
+        let entry = &entries[super::find_entry(&entries, pattern).unwrap()];
+        let name = super::ascii_strip(&entry.name).to_string();
+        (entry.filesystem, entry.kind, name)
+    }
+
+    fn default_entry(
+        esp: &dyn crate::fs::Filesystem,
+        xbootldr: Option<&dyn crate::fs::Filesystem>,
+    ) -> (usize, super::EntryKind, String) {
+        let entries = super::BootEntries::new(esp, xbootldr).unwrap();
+        let entry = &entries.entries[entries.default];
+        let name = super::ascii_strip(&entry.name).to_string();
+        (entry.filesystem, entry.kind, name)
+    }
+
+    #[test]
+    fn test_find_entry() {
+        let image = dirs::home_dir()
+            .unwrap()
+            .join("workloads")
+            .join("fat16.img");
+        let esp_disk = RamDisk::new(&image);
+        let mut esp = crate::fat::Filesystem::new(&esp_disk, 0, esp_disk.len());
+        esp.init().unwrap();
+        let xbootldr_disk = RamDisk::new(&image);
+        let mut xbootldr = crate::fat::Filesystem::new(&xbootldr_disk, 0, xbootldr_disk.len());
+        xbootldr.init().unwrap();
+
+        let name = |(index, _, name): (usize, _, String)| (index, name);
+
+        assert!(super::BootEntries::new(&esp, None).unwrap().is_empty());
+
+        write_files(
+            &esp,
+            &[
+                ("/loader/entries/fedora-6.8.conf", "linux /vmlinuz-6.8\n"),
+                ("/loader/entries/fedora-6.10.conf", "linux /vmlinuz-6.10\n"),
+                (
+                    "/loader/entries/other-arch-7.0.conf",
+                    "linux /vmlinuz\narchitecture ia32\n",
+                ),
+                ("/loader/entries/fedora-9.0.txt", "linux /vmlinuz\n"),
+                (
+                    "/loader/entries/fedora-8.0.conf",
+                    &format!("linux /{}\n", "a".repeat(300)),
+                ),
+            ],
+        );
+        // Without loader.conf the newest entry that can be booted is used
+        assert_eq!(
+            name(default_entry(&esp, None)),
+            (0, "fedora-6.10.conf".to_string())
+        );
+
+        // The newest entry matching the pattern, or the newest of all
+        assert_eq!(
+            name(find_entry(&[&esp], b"*6.[0-8].conf\0")),
+            (0, "fedora-6.8.conf".to_string())
+        );
+        assert_eq!(
+            name(find_entry(&[&esp], b"debian*\0")),
+            (0, "fedora-6.10.conf".to_string())
+        );
+
+        write_files(
+            &esp,
+            &[("/loader/loader.conf", "timeout 3\ndefault fedora-6.8*\n")],
+        );
+        assert_eq!(
+            name(default_entry(&esp, None)),
+            (0, "fedora-6.8.conf".to_string())
+        );
+
+        // Entries with a sort key come first wherever they are
+        write_files(
+            &xbootldr,
+            &[(
+                "/loader/entries/arch.conf",
+                "linux /vmlinuz\nsort-key arch\n",
+            )],
+        );
+        assert_eq!(
+            name(find_entry(&[&esp, &xbootldr], b"\0")),
+            (1, "arch.conf".to_string())
+        );
+        assert_eq!(
+            name(default_entry(&esp, Some(&xbootldr))),
+            (0, "fedora-6.8.conf".to_string())
+        );
+    }
+
+    #[test]
+    fn test_boot_counting() {
+        let image = dirs::home_dir()
+            .unwrap()
+            .join("workloads")
+            .join("fat16.img");
+        let disk = RamDisk::new(&image);
+        let mut esp = crate::fat::Filesystem::new(&disk, 0, disk.len());
+        esp.init().unwrap();
+
+        write_files(
+            &esp,
+            &[
+                (
+                    "/loader/entries/linux-6.10+0-3.conf",
+                    "linux /vmlinuz-6.10\n",
+                ),
+                ("/loader/entries/linux-6.9+1-2.conf", "linux /vmlinuz-6.9\n"),
+                ("/loader/loader.conf", "default linux-6.10.conf\n"),
+            ],
+        );
+        // The pattern is matched without the counter and entries without
+        // tries left are only used if nothing else matches
+        assert_eq!(
+            default_entry(&esp, None),
+            (
+                0,
+                super::EntryKind::Config,
+                "linux-6.10+0-3.conf".to_string()
+            )
+        );
+        assert_eq!(
+            find_entry(&[&esp], b"\0"),
+            (
+                0,
+                super::EntryKind::Config,
+                "linux-6.9+1-2.conf".to_string()
+            )
+        );
+
+        let entries = super::list_entries(&[&esp]).unwrap();
+        let counter = entries[0].counter.unwrap();
+        let path = super::count_boot(&esp, entries[0].kind, &entries[0].name, counter).unwrap();
+        assert_eq!(
+            super::ascii_strip(&path.unwrap()),
+            "/loader/entries/linux-6.9+0-3.conf"
+        );
+        assert!(esp.open("/loader/entries/linux-6.9+0-3.conf").is_ok());
+        assert!(esp.open("/loader/entries/linux-6.9+1-2.conf").is_err());
+
+        // Once there are no tries left the name stays the same
+        let counter = entries[1].counter.unwrap();
+        let path = super::count_boot(&esp, entries[1].kind, &entries[1].name, counter).unwrap();
+        assert!(path.is_none());
+        assert!(esp.open("/loader/entries/linux-6.10+0-3.conf").is_ok());
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_uki_entry() {
+        let image = dirs::home_dir()
+            .unwrap()
+            .join("workloads")
+            .join("fat16.img");
+        let disk = RamDisk::new(&image);
+        let mut esp = crate::fat::Filesystem::new(&disk, 0, disk.len());
+        esp.init().unwrap();
+
+        let osrel = b"NAME=Fedora\nPRETTY_NAME=\"Fedora Linux 40\"\nID=fedora\nVERSION_ID='40'\n";
+        let cmdline = format!("root=/dev/vda2 {}\n", "quiet ".repeat(100));
+        write_file(
+            &esp,
+            "/EFI/Linux/fedora-6.9.efi",
+            &uki(&[
+                (".osrel", osrel),
+                (".cmdline", cmdline.as_bytes()),
+                (".uname", b"6.9.0-200.fc40.x86_64\0"),
+                (".linux", b"kernel"),
+            ]),
+        );
+        let entry = super::read_entry(&esp, super::EntryKind::Uki, b"fedora-6.9.efi\0").unwrap();
+        assert_eq!(super::ascii_strip(&entry.title), "Fedora Linux 40");
+        assert_eq!(super::ascii_strip(&entry.sort_key), "fedora");
+        assert_eq!(super::ascii_strip(&entry.version), "6.9.0-200.fc40.x86_64");
+        assert_eq!(super::ascii_strip(&entry.cmdline), cmdline.trim_end());
+        assert_eq!(
+            super::ascii_strip(&entry.efi_path),
+            "/EFI/Linux/fedora-6.9.efi"
+        );
+
+        // Without a kernel release the os-release version is used
+        write_file(
+            &esp,
+            "/EFI/Linux/fedora.EFI",
+            &uki(&[(".osrel", b"ID=fedora\nVERSION_ID=41\n")]),
+        );
+        let entry = super::read_entry(&esp, super::EntryKind::Uki, b"fedora.EFI\0").unwrap();
+        assert_eq!(super::ascii_strip(&entry.title), "");
+        assert_eq!(super::ascii_strip(&entry.version), "41");
+
+        // Images are sorted with the type #1 entries, files that are not
+        // images are skipped
+        write_files(
+            &esp,
+            &[
+                ("/loader/entries/fedora-6.10.conf", "linux /vmlinuz-6.10\n"),
+                ("/EFI/Linux/broken.efi", "not an image"),
+            ],
+        );
+        assert_eq!(
+            default_entry(&esp, None),
+            (0, super::EntryKind::Uki, "fedora.EFI".to_string())
+        );
+        assert_eq!(
+            find_entry(&[&esp], b"fedora-*\0"),
+            (0, super::EntryKind::Uki, "fedora-6.9.efi".to_string())
+        );
+        assert_eq!(
+            find_entry(&[&esp], b"*.conf\0"),
+            (0, super::EntryKind::Config, "fedora-6.10.conf".to_string())
+        );
+    }
+
+    #[test]
+    fn test_has_root_parameter() {
+        assert!(super::has_root_parameter(b"root=/dev/vda2 rw"));
+        assert!(super::has_root_parameter(b"console=ttyS0\troot=PARTUUID=x"));
+        assert!(!super::has_root_parameter(b""));
+        assert!(!super::has_root_parameter(b"console=ttyS0 rootfstype=ext4"));
+        assert!(!super::has_root_parameter(b"myroot=/dev/vda2"));
+    }
+
+    #[test]
+    fn test_efi_stub_options() {
+        assert!(super::has_efi_stub_parameter(b"console=ttyS0 rhf.efistub"));
+        assert!(!super::has_efi_stub_parameter(b"rhf.efistub=0"));
+        assert!(!super::has_efi_stub_parameter(b""));
+
+        let guid = [0x11; 16];
+        let entry = parse(
+            "linux /6a98/6.8.5/linux\n\
+             initrd /6a98/6.8.5/microcode\n\
+             initrd /6a98/6.8.5/initrd\n\
+             options quiet rw\n",
+        )
+        .unwrap();
+        let options = super::efi_stub_options(&entry, b"rhf.efistub", Some(guid)).unwrap();
+        assert_eq!(
+            super::ascii_strip(&options),
+            "rhf.efistub quiet rw root=PARTUUID=11111111-1111-1111-1111-111111111111"
+        );
+
+        // An entry choosing its own root
+        let entry = parse("linux /vmlinuz\noptions root=/dev/vda2\n").unwrap();
+        let options = super::efi_stub_options(&entry, b"", Some(guid)).unwrap();
+        assert_eq!(super::ascii_strip(&options), "root=/dev/vda2");
+    }
+
     macro_rules! entry_pattern_matches {
         (match $entry:literal with {
             $(
@@ -332,6 +1751,22 @@ mod tests {
             escaped_regular_char: b"foo\\bar.conf\0" => true,
             escaped_special_char: b"foo\\?ar.conf\0" => false,
             trailing_escape: b"foobar.conf\\\0" => false,
+
+            set: b"fo[aeiou]bar.conf\0" => true,
+            mismatched_set: b"fo[ae]bar.conf\0" => false,
+            range: b"[a-g]oobar.conf\0" => true,
+            mismatched_range: b"[g-z]oobar.conf\0" => false,
+            negated_set: b"[!b]oobar.conf\0" => true,
+            caret_negated_set: b"[^f]oobar.conf\0" => false,
+            bracket_in_set: b"foobar[]x].conf\0" => false,
+            literal_dash: b"foobar.con[-f]\0" => true,
+            set_wildcard: b"*[0-9].conf\0" => false,
+            set_at_end: b"foobar.conf[a]\0" => false,
         }
     }
+
+    #[test]
+    fn test_unterminated_set() {
+        assert!(super::compare_entry(b"foobar.conf\0", b"foo[bar.conf\0").is_err());
+    }
 }
@@ -16,6 +16,7 @@
 #[cfg(all(not(test), not(feature = "integration_tests")))]
 use core::panic::PanicInfo;
 
+use heapless::Vec;
 use log::{error, info, warn};
 #[cfg(all(
     not(test),
@@ -36,24 +37,40 @@ mod common;
 
 mod arch;
 mod block;
+#[cfg(target_arch = "x86_64")]
 mod boot;
+mod bootdev;
 mod bootinfo;
+#[cfg(target_arch = "x86_64")]
 mod bzimage;
+mod cache;
 #[cfg(target_arch = "x86_64")]
 mod cmos;
+#[cfg(any(target_arch = "aarch64", target_arch = "riscv64", test))]
+mod compression;
 #[cfg(target_arch = "x86_64")]
 mod coreboot;
 mod delay;
 mod efi;
+mod ext4;
 mod fat;
 #[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
 mod fdt;
+mod fs;
+#[cfg(any(target_arch = "aarch64", target_arch = "riscv64", test))]
+mod image;
 #[cfg(all(test, feature = "integration_tests"))]
 mod integration;
+mod iso9660;
 mod layout;
 mod loader;
 mod logger;
 mod mem;
+mod menu;
+mod mmio;
+mod nvme;
+#[cfg(any(target_arch = "aarch64", target_arch = "riscv64", test))]
+mod overlay;
 mod part;
 mod pci;
 mod pe;
@@ -64,6 +81,7 @@ mod rtc;
 mod rtc_goldfish;
 #[cfg(target_arch = "aarch64")]
 mod rtc_pl031;
+mod scsi;
 #[cfg(target_arch = "riscv64")]
 mod uart_mmio;
 #[cfg(target_arch = "aarch64")]
@@ -92,55 +110,145 @@ fn panic(_: &PanicInfo) -> ! {
 
 const VIRTIO_PCI_VENDOR_ID: u16 = 0x1af4;
 const VIRTIO_PCI_BLOCK_DEVICE_ID: u16 = 0x1042;
+const VIRTIO_PCI_SCSI_DEVICE_ID: u16 = 0x1048;
+const VIRTIO_MMIO_BLOCK_DEVICE_ID: u32 = 0x2;
+const VIRTIO_MMIO_SCSI_DEVICE_ID: u32 = 0x8;
+
+const MAX_SCSI_CONTROLLERS: usize = 4;
 
 #[allow(dead_code)]
 #[derive(Debug)]
 enum Error {
     Virtio(virtio::Error),
     Partition(part::Error),
-    Fat(fat::Error),
+    Filesystem(fs::Error),
     Loader(loader::Error),
     Pe(pe::Error),
     ImageTooLarge,
 }
 
 fn boot_from_device(
-    device: &mut block::VirtioBlockDevice,
+    device: &dyn block::BlockDevice,
+    disk: &bootdev::BootDisk,
     info: &dyn bootinfo::Info,
 ) -> Result<(), Error> {
-    if let Err(err) = device.init() {
-        error!("Error configuring block device: {err:?}");
-        return Err(Error::Virtio(err));
-    }
-    info!(
-        "Virtio block device configured. Capacity: {} sectors",
-        device.get_capacity()
-    );
-
-    let (start, end) = match part::find_efi_partition(device) {
-        Ok(p) => p,
-        Err(err) => {
-            error!("Failed to find EFI partition: {err:?}");
-            return Err(Error::Partition(err));
+    // Everything reads and writes the disk through the cache, including the
+    // EFI Block I/O protocol, so that no copy of a sector goes stale
+    let cache = cache::SectorCache::new(device);
+    let device: &dyn block::BlockDevice = &cache;
+
+    // Installer CDs carry the boot loader in the ISO 9660 filesystem as well
+    // as in the El Torito image, starting it from the former lets it read the
+    // rest of the CD through the Simple File System protocol
+    if disk.iso9660 {
+        let mut iso = iso9660::Filesystem::new(device, 0, device.get_capacity() - 1);
+        match iso.init().and_then(|()| iso.open(efi::EFI_BOOT_PATH)) {
+            Ok(mut file) => {
+                cache.log_stats();
+                return boot_efi(
+                    &mut file,
+                    &iso,
+                    device,
+                    info,
+                    efi::EFI_BOOT_PATH,
+                    "",
+                    &[],
+                    None,
+                );
+            }
+            Err(err) => warn!("No EFI binary in ISO 9660 filesystem: {err:?}"),
         }
-    };
-    info!("Found EFI partition");
+    }
 
-    let mut f = fat::Filesystem::new(device, start, end);
+    let mut f = fat::Filesystem::new(device, disk.esp_start, disk.esp_end);
     if let Err(err) = f.init() {
         error!("Failed to create filesystem: {err:?}");
-        return Err(Error::Fat(err));
+        return Err(Error::Filesystem(err));
     }
     info!("Filesystem ready");
 
-    match loader::load_default_entry(&f, info) {
-        Ok(mut kernel) => {
-            info!("Jumping to kernel");
-            kernel.boot();
-            return Ok(());
+    // The boot partition may be FAT or ext4
+    let mut xbootldr_fat = None;
+    let mut xbootldr_ext4 = None;
+    if let Some((start, end)) = disk.xbootldr {
+        let mut fat = fat::Filesystem::new(device, start, end);
+        if fat.init().is_ok() {
+            xbootldr_fat = Some(fat);
+        } else {
+            let mut ext4 = ext4::Filesystem::new(device, start, end);
+            match ext4.init() {
+                Ok(()) => xbootldr_ext4 = Some(ext4),
+                Err(err) => warn!("Failed to create boot partition filesystem: {err:?}"),
+            }
+        }
+    }
+    let xbootldr = match (&xbootldr_fat, &xbootldr_ext4) {
+        (Some(fat), _) => Some(fat as &dyn fs::Filesystem),
+        (None, Some(ext4)) => Some(ext4 as &dyn fs::Filesystem),
+        (None, None) => None,
+    };
+
+    // Load options for the default EFI binary, which may be edited in the
+    // boot menu
+    let mut options = [0; 4096];
+    match loader::BootEntries::new(&f, xbootldr) {
+        Ok(entries) => {
+            let choice = menu::choose(&entries);
+            match choice.entry {
+                Some(index) => {
+                    let cmdline = choice.cmdline.as_ref();
+                    match entries.load(index, cmdline, info, disk.root_guid) {
+                        Ok(loader::Boot::Kernel(mut kernel)) => {
+                            info!("Jumping to kernel");
+                            cache.log_stats();
+                            kernel.boot();
+                            return Ok(());
+                        }
+                        Ok(loader::Boot::Efi {
+                            filesystem,
+                            path,
+                            options,
+                            initrds,
+                        }) => {
+                            let path = common::ascii_strip(&path);
+                            // The partition the entry was read from
+                            let esp: &dyn fs::Filesystem = &f;
+                            let partition_start = match disk.xbootldr {
+                                Some((start, _)) if !core::ptr::addr_eq(filesystem, esp) => start,
+                                _ => disk.esp_start,
+                            };
+                            match filesystem.open(path) {
+                                Ok(mut file) => {
+                                    cache.log_stats();
+                                    let options = common::ascii_strip(&options);
+                                    if let Err(err) = boot_efi(
+                                        &mut file,
+                                        filesystem,
+                                        device,
+                                        info,
+                                        path,
+                                        options,
+                                        &initrds,
+                                        Some(partition_start),
+                                    ) {
+                                        warn!("Error booting EFI entry: {err:?}");
+                                    }
+                                }
+                                Err(err) => warn!("Failed to open EFI entry {path}: {err:?}"),
+                            }
+                            // Fall through to the default EFI binary
+                        }
+                        Err(err) => {
+                            warn!("Error loading boot entry: {err:?}");
+                            // Fall through to EFI boot
+                        }
+                    }
+                }
+                None => options = choice.cmdline.unwrap_or(options),
+            }
         }
         Err(err) => {
-            warn!("Error loading default entry: {err:?}");
+            warn!("Error finding boot entries: {err:?}");
             // Fall through to EFI boot
         }
     }
@@ -151,12 +259,37 @@ fn boot_from_device(
         Ok(file) => file,
         Err(err) => {
             error!("Failed to load default EFI binary: {err:?}");
-            return Err(Error::Fat(err));
+            return Err(Error::Filesystem(err));
         }
     };
-    info!("Found bootloader: {}", efi::EFI_BOOT_PATH);
+    cache.log_stats();
+    let options = common::ascii_strip(&options);
+    boot_efi(
+        &mut file,
+        &f,
+        device,
+        info,
+        efi::EFI_BOOT_PATH,
+        options,
+        &[],
+        Some(disk.esp_start),
+    )
+}
 
-    let mut l = pe::Loader::new(&mut file);
+#[allow(clippy::too_many_arguments)]
+fn boot_efi(
+    file: &mut dyn fs::Read,
+    fs: &dyn fs::Filesystem,
+    device: &dyn block::BlockDevice,
+    info: &dyn bootinfo::Info,
+    path: &str,
+    options: &str,
+    initrds: &[[u8; 260]],
+    partition_start: Option<u64>,
+) -> Result<(), Error> {
+    info!("Found bootloader: {path}");
+
+    let mut l = pe::Loader::new(file);
 
     let (entry_addr, load_addr, size) = match l.load(info.kernel_load_addr()) {
         Ok(load_info) => load_info,
@@ -173,13 +306,26 @@ fn boot_from_device(
     }
 
     info!("Executable loaded");
-    efi::efi_exec(entry_addr, load_addr, size, info, &f, device);
+    efi::efi_exec(
+        entry_addr,
+        load_addr,
+        size,
+        info,
+        fs,
+        device,
+        path,
+        options,
+        initrds,
+        partition_start,
+    );
     Ok(())
 }
 
 #[cfg(target_arch = "x86_64")]
 #[no_mangle]
 pub extern "C" fn rust64_start(#[cfg(not(feature = "coreboot"))] pvh_info: &pvh::StartInfo) -> ! {
+    use crate::bootinfo::Info;
+
     serial::PORT.borrow_mut().init();
     logger::init();
 
@@ -192,6 +338,8 @@ pub extern "C" fn rust64_start(#[cfg(not(feature = "coreboot"))] pvh_info: &pvh:
     #[cfg(not(feature = "coreboot"))]
     let info = pvh_info;
 
+    mmio::add_cmdline_devices(info.cmdline());
+
     main(info)
 }
 
@@ -217,6 +365,10 @@ pub extern "C" fn rust64_start(x0: *const u8) -> ! {
         pci::init(base as u64, length as u64);
     }
 
+    info.find_all_compatible_regions(&["virtio,mmio"], |base, length| {
+        mmio::add_device(base as u64, length as u64)
+    });
+
     main(&info)
 }
 
@@ -229,6 +381,7 @@ pub extern "C" fn rust64_start(a0: u64, a1: *const u8) -> ! {
     logger::init();
 
     info!("Starting on RV64 0x{:x} 0x{:x}", a0, a1 as u64,);
+    arch::riscv64::set_boot_hart_id(a0);
 
     let info = fdt::StartInfo::new(
         a1,
@@ -255,10 +408,16 @@ pub extern "C" fn rust64_start(a0: u64, a1: *const u8) -> ! {
         pci::init(base as u64, length as u64);
     }
 
+    info.find_all_compatible_regions(&["virtio,mmio"], |base, length| {
+        mmio::add_device(base as u64, length as u64)
+    });
+
     main(&info);
 }
 
 fn main(info: &dyn bootinfo::Info) -> ! {
+    use crate::block::{BlockDevice, SectorRead};
+
     info!("Booting with {}", info.name());
 
     pci::print_bus();
@@ -266,22 +425,194 @@ fn main(info: &dyn bootinfo::Info) -> ! {
     let mut next_address = info.pci_bar_memory().map(|m| m.addr);
     let max_address = info.pci_bar_memory().map(|m| m.addr + m.size);
 
+    let mut setup_pci_device = |pci_device: &mut pci::PciDevice| {
+        pci_device.init();
+
+        next_address = pci_device.allocate_bars(next_address);
+        if next_address > max_address {
+            panic!("PCI BAR allocation space exceeded")
+        }
+    };
+
+    let mut pci_transports: Vec<pci::VirtioPciTransport, { bootdev::MAX_DEVICES }> = Vec::new();
     pci::with_devices(
         VIRTIO_PCI_VENDOR_ID,
         VIRTIO_PCI_BLOCK_DEVICE_ID,
         |mut pci_device| {
-            pci_device.init();
+            setup_pci_device(&mut pci_device);
+
+            if pci_transports
+                .push(pci::VirtioPciTransport::new(pci_device))
+                .is_err()
+            {
+                warn!("Too many virtio-blk devices, ignoring remaining PCI devices");
+                return true;
+            }
+            false
+        },
+    );
 
-            next_address = pci_device.allocate_bars(next_address);
-            if next_address > max_address {
-                panic!("PCI BAR allocation space exceeded")
+    let mut nvme_devices: Vec<nvme::NvmeDevice, { bootdev::MAX_DEVICES }> = Vec::new();
+    pci::with_class(
+        nvme::NVME_PCI_CLASS,
+        nvme::NVME_PCI_SUBCLASS,
+        nvme::NVME_PCI_PROG_IF,
+        |mut pci_device| {
+            setup_pci_device(&mut pci_device);
+
+            match nvme::NvmeDevice::new(pci_device) {
+                Ok(device) => {
+                    if nvme_devices.push(device).is_err() {
+                        warn!("Too many NVMe controllers, ignoring remaining devices");
+                        return true;
+                    }
+                }
+                Err(err) => warn!("Error setting up NVMe controller: {err:?}"),
             }
+            false
+        },
+    );
 
-            let mut pci_transport = pci::VirtioPciTransport::new(pci_device);
-            let mut device = block::VirtioBlockDevice::new(&mut pci_transport);
-            boot_from_device(&mut device, info).is_ok()
+    let mut mmio_transports: Vec<mmio::VirtioMmioTransport, { bootdev::MAX_DEVICES }> = Vec::new();
+    mmio::with_devices(VIRTIO_MMIO_BLOCK_DEVICE_ID, |mmio_transport| {
+        if mmio_transports.push(mmio_transport).is_err() {
+            warn!("Too many virtio-blk devices, ignoring remaining MMIO devices");
+            return true;
+        }
+        false
+    });
+
+    let mut scsi_pci_transports: Vec<pci::VirtioPciTransport, MAX_SCSI_CONTROLLERS> = Vec::new();
+    pci::with_devices(
+        VIRTIO_PCI_VENDOR_ID,
+        VIRTIO_PCI_SCSI_DEVICE_ID,
+        |mut pci_device| {
+            setup_pci_device(&mut pci_device);
+
+            if scsi_pci_transports
+                .push(pci::VirtioPciTransport::new(pci_device))
+                .is_err()
+            {
+                warn!("Too many virtio-scsi devices, ignoring remaining PCI devices");
+                return true;
+            }
+            false
         },
     );
 
-    panic!("Unable to boot from any virtio-blk device")
+    let mut scsi_mmio_transports: Vec<mmio::VirtioMmioTransport, MAX_SCSI_CONTROLLERS> = Vec::new();
+    mmio::with_devices(VIRTIO_MMIO_SCSI_DEVICE_ID, |mmio_transport| {
+        if scsi_mmio_transports.push(mmio_transport).is_err() {
+            warn!("Too many virtio-scsi devices, ignoring remaining MMIO devices");
+            return true;
+        }
+        false
+    });
+
+    // The virtio devices must not move once initialized as the virtqueues
+    // live inside them.
+    let mut virtio_devices: Vec<block::VirtioBlockDevice, { bootdev::MAX_DEVICES }> = Vec::new();
+    let transports = pci_transports
+        .iter_mut()
+        .map(|t| t as &mut dyn virtio::VirtioTransport)
+        .chain(
+            mmio_transports
+                .iter_mut()
+                .map(|t| t as &mut dyn virtio::VirtioTransport),
+        );
+    for transport in transports {
+        if virtio_devices
+            .push(block::VirtioBlockDevice::new(transport))
+            .is_err()
+        {
+            warn!("Too many virtio-blk devices, ignoring remaining devices");
+            break;
+        }
+    }
+
+    let mut scsi_controllers: Vec<scsi::VirtioScsiController, MAX_SCSI_CONTROLLERS> = Vec::new();
+    let transports = scsi_pci_transports
+        .iter_mut()
+        .map(|t| t as &mut dyn virtio::VirtioTransport)
+        .chain(
+            scsi_mmio_transports
+                .iter_mut()
+                .map(|t| t as &mut dyn virtio::VirtioTransport),
+        );
+    for transport in transports {
+        // Cannot fail as there is at most one controller per transport
+        let _ = scsi_controllers.push(scsi::VirtioScsiController::new(transport));
+        // The controller is initialized in place as the virtqueue lives inside it
+        let controller = scsi_controllers.last_mut().unwrap();
+        if let Err(err) = controller.init() {
+            error!("Error configuring virtio-scsi controller: {err:?}");
+            scsi_controllers.pop();
+        }
+    }
+
+    let mut scsi_disks: Vec<scsi::ScsiDisk, { bootdev::MAX_DEVICES }> = Vec::new();
+    for controller in scsi_controllers.iter() {
+        controller.scan(|disk| {
+            if scsi_disks.push(disk).is_err() {
+                warn!("Too many SCSI disks, ignoring remaining disks");
+                return true;
+            }
+            false
+        });
+    }
+
+    let mut devices: Vec<&dyn block::BlockDevice, { bootdev::MAX_DEVICES }> = Vec::new();
+    for device in virtio_devices.iter_mut() {
+        if let Err(err) = device.init() {
+            error!("Error configuring block device: {err:?}");
+            continue;
+        }
+        info!(
+            "Virtio block device configured. Capacity: {} sectors, {} byte blocks",
+            device.get_capacity(),
+            device.block_size()
+        );
+        if devices.push(device).is_err() {
+            warn!("Too many block devices, ignoring remaining devices");
+            break;
+        }
+    }
+    for device in nvme_devices.iter_mut() {
+        if let Err(err) = device.init() {
+            error!("Error configuring NVMe controller: {err:?}");
+            continue;
+        }
+        if devices.push(device).is_err() {
+            warn!("Too many block devices, ignoring remaining devices");
+            break;
+        }
+    }
+
+    for disk in scsi_disks.iter() {
+        if devices.push(disk).is_err() {
+            warn!("Too many block devices, ignoring remaining devices");
+            break;
+        }
+    }
+
+    let mut disks: Vec<bootdev::BootDisk, { bootdev::MAX_DEVICES }> = Vec::new();
+    for (index, device) in devices.iter().enumerate() {
+        match bootdev::BootDisk::probe(index, *device) {
+            Ok(disk) => {
+                info!("Found EFI partition on block device {index}");
+                // Cannot fail as there is at most one disk per device
+                disks.push(disk).unwrap();
+            }
+            Err(err) => warn!("No EFI partition on block device {index}: {err:?}"),
+        }
+    }
+
+    for disk in bootdev::boot_order(&disks, info.cmdline()) {
+        info!("Booting from block device {}", disk.index);
+        if boot_from_device(devices[disk.index], &disk, info).is_ok() {
+            break;
+        }
+    }
+
+    panic!("Unable to boot from any block device")
 }
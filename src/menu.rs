@@ -0,0 +1,518 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2026 The Rust Hypervisor Firmware Authors
+
+//! A text boot menu on the serial console
+//!
+//! The menu lists the boot entries in sort order followed by the default EFI
+//! binary. An entry is chosen with the arrow or number keys and booted with
+//! Enter, `e` edits its command line first. Terminals send the arrow and
+//! editing keys as VT100 escape sequences.
+
+use core::fmt::{self, Write};
+
+use crate::{
+    delay, efi,
+    loader::{BootEntries, Timeout},
+    rtc,
+    serial::{self, Serial},
+};
+
+// How long to wait for the rest of an escape sequence
+const ESCAPE_TIMEOUT_MS: u64 = 50;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Key {
+    Up,
+    Down,
+    Left,
+    Right,
+    Home,
+    End,
+    Delete,
+    Backspace,
+    Enter,
+    Escape,
+    Char(u8),
+}
+
+/// Decode the key starting with `first`, reading the rest of any escape
+/// sequence with `next`. Unknown sequences and control characters are
+/// ignored.
+fn decode_key(first: u8, next: &mut dyn FnMut() -> Option<u8>) -> Option<Key> {
+    match first {
+        b'\r' | b'\n' => Some(Key::Enter),
+        0x08 | 0x7f => Some(Key::Backspace),
+        0x1b => match next() {
+            None => Some(Key::Escape),
+            Some(b'[') | Some(b'O') => match next()? {
+                b'A' => Some(Key::Up),
+                b'B' => Some(Key::Down),
+                b'C' => Some(Key::Right),
+                b'D' => Some(Key::Left),
+                b'H' => Some(Key::Home),
+                b'F' => Some(Key::End),
+                // "ESC [ n ~" sequences
+                n @ b'1'..=b'8' => match (n, next()?) {
+                    (b'1' | b'7', b'~') => Some(Key::Home),
+                    (b'4' | b'8', b'~') => Some(Key::End),
+                    (b'3', b'~') => Some(Key::Delete),
+                    _ => None,
+                },
+                _ => None,
+            },
+            Some(_) => None,
+        },
+        c if c.is_ascii_graphic() || c == b' ' => Some(Key::Char(c)),
+        _ => None,
+    }
+}
+
+// Wait up to `ms` milliseconds for a key
+fn read_key(ms: u64) -> Option<Key> {
+    let mut byte = None;
+    delay::wait_until(ms, || {
+        byte = serial::read_byte();
+        byte.is_some()
+    });
+    decode_key(byte?, &mut || {
+        let mut byte = None;
+        delay::wait_until(ESCAPE_TIMEOUT_MS, || {
+            byte = serial::read_byte();
+            byte.is_some()
+        });
+        byte
+    })
+}
+
+// Wait for a key until the real time clock moves to the next second, the
+// delay loops are not calibrated well enough to count down with
+fn read_key_until_next_second() -> Option<Key> {
+    let start = match rtc::read_time() {
+        Ok(time) => time,
+        Err(_) => return read_key(1000),
+    };
+    loop {
+        if let Some(key) = read_key(10) {
+            return Some(key);
+        }
+        if rtc::read_time() != Ok(start) {
+            return None;
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Action {
+    Select,
+    Boot,
+    Edit,
+}
+
+// The item selected in the menu, `count` being the number of items
+fn select(selected: &mut usize, count: usize, key: Key) -> Option<Action> {
+    match key {
+        Key::Up => *selected = selected.saturating_sub(1),
+        Key::Down => *selected = (*selected + 1).min(count - 1),
+        Key::Home => *selected = 0,
+        Key::End => *selected = count - 1,
+        Key::Char(c @ b'1'..=b'9') if usize::from(c - b'1') < count => {
+            *selected = usize::from(c - b'1')
+        }
+        Key::Enter => return Some(Action::Boot),
+        Key::Char(b'e') => return Some(Action::Edit),
+        _ => return None,
+    }
+    Some(Action::Select)
+}
+
+/// A command line being edited, the terminal cursor is kept at `cursor`
+/// using backspaces so that lines wider than the terminal are not redrawn.
+/// The line is UTF-8 and `cursor` is a byte offset that is always on a
+/// character boundary, each character taking one column.
+struct Editor {
+    line: [u8; 4096],
+    len: usize,
+    cursor: usize,
+}
+
+impl Editor {
+    /// Returns `None` if the line is not UTF-8
+    fn new(line: &[u8; 4096]) -> Option<Editor> {
+        let len = line.iter().position(|c| *c == 0).unwrap_or(line.len());
+        core::str::from_utf8(&line[..len]).ok()?;
+        Some(Editor {
+            line: *line,
+            len,
+            cursor: len,
+        })
+    }
+
+    fn text(&self, start: usize) -> &str {
+        // Only ASCII characters are inserted and whole characters removed
+        core::str::from_utf8(&self.line[start..self.len]).unwrap_or_default()
+    }
+
+    // Length of the character before the cursor
+    fn previous_len(&self) -> usize {
+        self.text(0)[..self.cursor]
+            .chars()
+            .next_back()
+            .map_or(0, char::len_utf8)
+    }
+
+    // Length of the character at the cursor
+    fn next_len(&self) -> usize {
+        self.text(self.cursor)
+            .chars()
+            .next()
+            .map_or(0, char::len_utf8)
+    }
+
+    // Print the line from the cursor and move back to it, leaving `erase`
+    // blanks after the end for removed characters
+    fn redraw_tail(&self, out: &mut dyn Write, erase: usize) -> fmt::Result {
+        out.write_str(self.text(self.cursor))?;
+        for _ in 0..erase {
+            out.write_char(' ')?;
+        }
+        for _ in 0..self.text(self.cursor).chars().count() + erase {
+            out.write_char('\x08')?;
+        }
+        Ok(())
+    }
+
+    /// Handle `key`, returning whether editing is finished: `Some(true)` to
+    /// boot with the line and `Some(false)` to discard it
+    fn key(&mut self, key: Key, out: &mut dyn Write) -> Result<Option<bool>, fmt::Error> {
+        match key {
+            Key::Enter => return Ok(Some(true)),
+            Key::Escape => return Ok(Some(false)),
+            // Keep a terminating NUL
+            Key::Char(c) if self.len < self.line.len() - 1 => {
+                self.line
+                    .copy_within(self.cursor..self.len, self.cursor + 1);
+                self.line[self.cursor] = c;
+                self.len += 1;
+                out.write_char(char::from(c))?;
+                self.cursor += 1;
+                self.redraw_tail(out, 0)?;
+            }
+            Key::Backspace if self.cursor > 0 => {
+                self.cursor -= self.previous_len();
+                out.write_char('\x08')?;
+                self.remove(out)?;
+            }
+            Key::Delete if self.cursor < self.len => self.remove(out)?,
+            Key::Left if self.cursor > 0 => {
+                self.cursor -= self.previous_len();
+                out.write_char('\x08')?;
+            }
+            Key::Right if self.cursor < self.len => {
+                let len = self.next_len();
+                out.write_str(&self.text(self.cursor)[..len])?;
+                self.cursor += len;
+            }
+            Key::Home => {
+                for _ in self.text(0)[..self.cursor].chars() {
+                    out.write_char('\x08')?;
+                }
+                self.cursor = 0;
+            }
+            Key::End => {
+                out.write_str(self.text(self.cursor))?;
+                self.cursor = self.len;
+            }
+            _ => {}
+        }
+        Ok(None)
+    }
+
+    // Remove the character at the cursor
+    fn remove(&mut self, out: &mut dyn Write) -> fmt::Result {
+        let len = self.next_len();
+        self.line
+            .copy_within(self.cursor + len..self.len, self.cursor);
+        self.line[self.len - len..self.len].fill(0);
+        self.len -= len;
+        self.redraw_tail(out, 1)
+    }
+}
+
+/// What was chosen in the menu
+pub struct Choice {
+    /// The index of the boot entry, `None` for the default EFI binary
+    pub entry: Option<usize>,
+    /// The command line if it was edited in the menu
+    pub cmdline: Option<[u8; 4096]>,
+}
+
+fn draw(entries: &BootEntries, selected: usize) -> fmt::Result {
+    let mut out = Serial;
+    // Clear the screen and move to the top left
+    write!(out, "\x1b[2J\x1b[H\nBoot menu\n\n")?;
+    for index in 0..entries.len() {
+        let marker = if index == selected { '>' } else { ' ' };
+        write!(out, "{marker} {}. {}", index + 1, entries.title(index))?;
+        match entries.version(index) {
+            "" => writeln!(out)?,
+            version => writeln!(out, " ({version})")?,
+        }
+    }
+    let marker = if selected == entries.len() { '>' } else { ' ' };
+    writeln!(
+        out,
+        "{marker} {}. EFI default loader ({})",
+        entries.len() + 1,
+        efi::EFI_BOOT_PATH
+    )?;
+    write!(
+        out,
+        "\nUp/Down or number to select, Enter to boot, e to edit the command line\n"
+    )
+}
+
+fn choice(entries: &BootEntries, selected: usize, cmdline: Option<[u8; 4096]>) -> Choice {
+    Choice {
+        entry: (selected < entries.len()).then_some(selected),
+        cmdline,
+    }
+}
+
+// Edit the command line of the selected item, returning it if it is to be
+// booted with
+fn edit(entries: &BootEntries, selected: usize) -> Result<Option<[u8; 4096]>, fmt::Error> {
+    let cmdline = match selected < entries.len() {
+        true => match entries.cmdline(selected) {
+            Ok(cmdline) => cmdline,
+            Err(err) => {
+                writeln!(Serial, "\nFailed to read the entry: {err:?}")?;
+                return Ok(None);
+            }
+        },
+        false => [0; 4096],
+    };
+
+    let mut editor = match Editor::new(&cmdline) {
+        Some(editor) => editor,
+        None => {
+            writeln!(
+                Serial,
+                "\nThe command line is not UTF-8 and cannot be edited"
+            )?;
+            return Ok(None);
+        }
+    };
+    write!(
+        Serial,
+        "\nEnter to boot, Escape to cancel\n> {}",
+        editor.text(0)
+    )?;
+    loop {
+        if let Some(key) = read_key(1000) {
+            if let Some(boot) = editor.key(key, &mut Serial)? {
+                return Ok(boot.then_some(editor.line));
+            }
+        }
+    }
+}
+
+fn run(entries: &BootEntries) -> Result<Choice, fmt::Error> {
+    let count = entries.len() + 1;
+    let mut selected = match entries.is_empty() {
+        true => entries.len(),
+        false => entries.default,
+    };
+
+    let mut timeout = match entries.timeout {
+        Timeout::Seconds(0) => match serial::read_byte() {
+            // A key was pressed before the menu would have been skipped
+            Some(_) => None,
+            None => return Ok(choice(entries, selected, None)),
+        },
+        Timeout::Seconds(seconds) => Some(seconds),
+        Timeout::Forever => None,
+        Timeout::Disabled => return Ok(choice(entries, selected, None)),
+    };
+
+    draw(entries, selected)?;
+    loop {
+        if let Some(seconds) = timeout {
+            if seconds == 0 {
+                return Ok(choice(entries, selected, None));
+            }
+            write!(Serial, "\r\x1b[KBooting in {seconds} s")?;
+            timeout = Some(seconds - 1);
+        }
+
+        let key = match timeout {
+            Some(_) => read_key_until_next_second(),
+            None => read_key(1000),
+        };
+        let key = match key {
+            Some(key) => key,
+            None => continue,
+        };
+        timeout = None;
+        match select(&mut selected, count, key) {
+            Some(Action::Boot) => return Ok(choice(entries, selected, None)),
+            Some(Action::Edit) => {
+                if let Some(cmdline) = edit(entries, selected)? {
+                    return Ok(choice(entries, selected, Some(cmdline)));
+                }
+                draw(entries, selected)?;
+            }
+            Some(Action::Select) => draw(entries, selected)?,
+            None => {}
+        }
+    }
+}
+
+/// Show the boot menu, unless it is disabled or the timeout is 0 and no key
+/// has been pressed, and return what was chosen. The default entry is chosen
+/// when the timeout runs out without a key being pressed.
+pub fn choose(entries: &BootEntries) -> Choice {
+    let choice = run(entries).unwrap_or_else(|_| choice(entries, entries.default, None));
+    // Leave the line the menu was on before the boot messages
+    let _ = writeln!(Serial);
+    choice
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_key, select, Action, Editor, Key};
+
+    fn decode(bytes: &[u8]) -> Option<Key> {
+        let mut bytes = bytes.iter().copied();
+        let first = bytes.next().unwrap();
+        decode_key(first, &mut || bytes.next())
+    }
+
+    #[test]
+    fn test_decode_key() {
+        assert_eq!(decode(b"a"), Some(Key::Char(b'a')));
+        assert_eq!(decode(b"\r"), Some(Key::Enter));
+        assert_eq!(decode(b"\x7f"), Some(Key::Backspace));
+        assert_eq!(decode(b"\x1b"), Some(Key::Escape));
+        assert_eq!(decode(b"\x1b[A"), Some(Key::Up));
+        assert_eq!(decode(b"\x1bOB"), Some(Key::Down));
+        assert_eq!(decode(b"\x1b[D"), Some(Key::Left));
+        assert_eq!(decode(b"\x1b[1~"), Some(Key::Home));
+        assert_eq!(decode(b"\x1b[F"), Some(Key::End));
+        assert_eq!(decode(b"\x1b[3~"), Some(Key::Delete));
+        assert_eq!(decode(b"\x1b[3"), None);
+        assert_eq!(decode(b"\x1b[Z"), None);
+        assert_eq!(decode(b"\x01"), None);
+    }
+
+    #[test]
+    fn test_select() {
+        let mut selected = 1;
+        assert_eq!(select(&mut selected, 3, Key::Up), Some(Action::Select));
+        assert_eq!(selected, 0);
+        select(&mut selected, 3, Key::Up);
+        assert_eq!(selected, 0);
+        select(&mut selected, 3, Key::End);
+        select(&mut selected, 3, Key::Down);
+        assert_eq!(selected, 2);
+        select(&mut selected, 3, Key::Char(b'2'));
+        assert_eq!(selected, 1);
+        assert_eq!(select(&mut selected, 3, Key::Char(b'4')), None);
+        assert_eq!(selected, 1);
+        assert_eq!(select(&mut selected, 3, Key::Enter), Some(Action::Boot));
+        assert_eq!(
+            select(&mut selected, 3, Key::Char(b'e')),
+            Some(Action::Edit)
+        );
+    }
+
+    // Apply `keys` to the line, returning the result and what the terminal
+    // would show
+    fn edit(line: &str, keys: &[Key]) -> (String, String) {
+        let mut buffer = [0; 4096];
+        buffer[..line.len()].copy_from_slice(line.as_bytes());
+        let mut editor = Editor::new(&buffer).unwrap();
+        let mut screen: Vec<char> = line.chars().collect();
+        let mut cursor = screen.len();
+        for key in keys {
+            let mut out = String::new();
+            assert_eq!(editor.key(*key, &mut out), Ok(None));
+            for c in out.chars() {
+                match c {
+                    '\x08' => cursor -= 1,
+                    c if cursor == screen.len() => {
+                        screen.push(c);
+                        cursor += 1;
+                    }
+                    c => {
+                        screen[cursor] = c;
+                        cursor += 1;
+                    }
+                }
+            }
+            assert_eq!(cursor, editor.text(0)[..editor.cursor].chars().count());
+        }
+        let screen: String = screen.into_iter().collect();
+        (editor.text(0).to_string(), screen.trim_end().to_string())
+    }
+
+    #[test]
+    fn test_editor() {
+        let (line, screen) = edit("quiet", &[Key::Char(b' '), Key::Char(b'1')]);
+        assert_eq!((line.as_str(), screen.as_str()), ("quiet 1", "quiet 1"));
+
+        let keys = [Key::Home, Key::Delete, Key::Right, Key::Char(b'x')];
+        let (line, screen) = edit("root=/dev/vda", &keys);
+        assert_eq!(
+            (line.as_str(), screen.as_str()),
+            ("oxot=/dev/vda", "oxot=/dev/vda")
+        );
+
+        let keys = [
+            Key::Left,
+            Key::Left,
+            Key::Backspace,
+            Key::End,
+            Key::Backspace,
+        ];
+        let (line, screen) = edit("abcdef", &keys);
+        assert_eq!((line.as_str(), screen.as_str()), ("abce", "abce"));
+
+        // Nothing happens at the ends of the line
+        let keys = [
+            Key::Right,
+            Key::Delete,
+            Key::Home,
+            Key::Left,
+            Key::Backspace,
+        ];
+        let (line, screen) = edit("ab", &keys);
+        assert_eq!((line.as_str(), screen.as_str()), ("ab", "ab"));
+
+        // Characters are moved over and removed whole
+        let keys = [
+            Key::Left,
+            Key::Left,
+            Key::Backspace,
+            Key::Home,
+            Key::Right,
+            Key::Delete,
+            Key::End,
+            Key::Left,
+            Key::Char(b'x'),
+        ];
+        let (line, screen) = edit("\u{e9}t\u{e9} \u{2603}!", &keys);
+        assert_eq!(
+            (line.as_str(), screen.as_str()),
+            ("\u{e9}\u{e9}\u{2603}x!", "\u{e9}\u{e9}\u{2603}x!")
+        );
+
+        let mut buffer = [0; 4096];
+        buffer[..2].copy_from_slice(&[b'a', 0xff]);
+        assert!(Editor::new(&buffer).is_none());
+
+        let mut editor = Editor::new(&[0; 4096]).unwrap();
+        let mut out = String::new();
+        assert_eq!(editor.key(Key::Enter, &mut out), Ok(Some(true)));
+        assert_eq!(editor.key(Key::Escape, &mut out), Ok(Some(false)));
+    }
+}
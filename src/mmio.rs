@@ -0,0 +1,285 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2026 The Rust Hypervisor Firmware Authors
+
+use atomic_refcell::AtomicRefCell;
+use heapless::Vec;
+use log::{info, warn};
+
+use crate::{
+    mem,
+    virtio::{Error as VirtioError, VirtioTransport},
+};
+
+const MAX_DEVICES: usize = 32;
+
+// "virt" in little endian
+const VIRTIO_MMIO_MAGIC: u32 = 0x7472_6976;
+const VIRTIO_MMIO_VERSION_LEGACY: u32 = 1;
+const VIRTIO_MMIO_VERSION_MODERN: u32 = 2;
+
+// Every virtio-mmio device needs at least the registers and some configuration space
+const VIRTIO_MMIO_MIN_SIZE: u64 = 0x200;
+
+static MMIO_DEVICES: AtomicRefCell<Vec<(u64, u64), MAX_DEVICES>> = AtomicRefCell::new(Vec::new());
+
+/// Register a virtio-mmio device window found by the platform (FDT or command line)
+pub fn add_device(base: u64, length: u64) {
+    if length < VIRTIO_MMIO_MIN_SIZE {
+        warn!("Ignoring virtio-mmio device at 0x{base:x}: region too small (0x{length:x})");
+        return;
+    }
+
+    let mut devices = MMIO_DEVICES.borrow_mut();
+    if devices.iter().any(|&(b, _)| b == base) {
+        return;
+    }
+    if devices.push((base, length)).is_err() {
+        warn!("Too many virtio-mmio devices, ignoring device at 0x{base:x}");
+        return;
+    }
+    info!("Found virtio-mmio device at 0x{base:x} size=0x{length:x}");
+}
+
+// Parse a size or address using the kernel's memparse() syntax: a decimal or
+// "0x" prefixed hexadecimal number with an optional K, M or G suffix.
+fn parse_size(s: &str) -> Option<u64> {
+    let (digits, shift) = match s.as_bytes().last()? {
+        b'k' | b'K' => (&s[..s.len() - 1], 10),
+        b'm' | b'M' => (&s[..s.len() - 1], 20),
+        b'g' | b'G' => (&s[..s.len() - 1], 30),
+        _ => (s, 0),
+    };
+
+    let value = match digits
+        .strip_prefix("0x")
+        .or_else(|| digits.strip_prefix("0X"))
+    {
+        Some(hex) => u64::from_str_radix(hex, 16).ok()?,
+        None => digits.parse::<u64>().ok()?,
+    };
+
+    value.checked_shl(shift)
+}
+
+// Parse the value of a `virtio_mmio.device=<size>@<baseaddr>:<irq>[:<id>]`
+// parameter, returning the base address and size of the device window.
+fn parse_device_param(param: &str) -> Option<(u64, u64)> {
+    let (size, rest) = param.split_once('@')?;
+    let (base, _irq) = rest.split_once(':')?;
+    Some((parse_size(base)?, parse_size(size)?))
+}
+
+/// Register the devices listed with `virtio_mmio.device=` on the command line
+pub fn add_cmdline_devices(cmdline: &[u8]) {
+    let cmdline = match core::str::from_utf8(cmdline) {
+        Ok(cmdline) => cmdline,
+        Err(_) => return,
+    };
+
+    for param in cmdline.split_ascii_whitespace() {
+        if let Some(device) = param.strip_prefix("virtio_mmio.device=") {
+            match parse_device_param(device) {
+                Some((base, length)) => add_device(base, length),
+                None => warn!("Invalid virtio_mmio.device parameter: {device}"),
+            }
+        }
+    }
+}
+
+pub fn with_devices<F>(target_device_id: u32, mut per_device: F)
+where
+    F: FnMut(VirtioMmioTransport) -> bool,
+{
+    let devices = MMIO_DEVICES.borrow().clone();
+    for (base, length) in devices {
+        let region = mem::MemoryRegion::new(base, length);
+        // Device ID 0 denotes an unpopulated slot
+        if region.io_read_u32(0x000) != VIRTIO_MMIO_MAGIC
+            || region.io_read_u32(0x008) != target_device_id
+        {
+            continue;
+        }
+        if per_device(VirtioMmioTransport::new(base, length)) {
+            break;
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct VirtioMmioTransport {
+    region: mem::MemoryRegion,
+}
+
+impl VirtioMmioTransport {
+    pub fn new(base: u64, length: u64) -> VirtioMmioTransport {
+        VirtioMmioTransport {
+            region: mem::MemoryRegion::new(base, length),
+        }
+    }
+}
+
+// MMIO Device Register Layout (modern, version 2):
+/// le32 magic_value;               // 0x000 // read-only, "virt"
+/// le32 version;                   // 0x004 // read-only
+/// le32 device_id;                 // 0x008 // read-only
+/// le32 vendor_id;                 // 0x00c // read-only
+/// le32 device_features;           // 0x010 // read-only
+/// le32 device_features_sel;       // 0x014 // write-only
+/// le32 driver_features;           // 0x020 // write-only
+/// le32 driver_features_sel;       // 0x024 // write-only
+/// le32 queue_sel;                 // 0x030 // write-only
+/// le32 queue_num_max;             // 0x034 // read-only
+/// le32 queue_num;                 // 0x038 // write-only
+/// le32 queue_ready;               // 0x044 // read-write
+/// le32 queue_notify;              // 0x050 // write-only
+/// le32 interrupt_status;          // 0x060 // read-only
+/// le32 interrupt_ack;             // 0x064 // write-only
+/// le32 status;                    // 0x070 // read-write
+/// le64 queue_desc;                // 0x080 // write-only
+/// le64 queue_driver;              // 0x090 // write-only (available ring)
+/// le64 queue_device;              // 0x0a0 // write-only (used ring)
+/// le32 config_generation;         // 0x0fc // read-only
+/// device specific configuration   // 0x100+
+impl VirtioTransport for VirtioMmioTransport {
+    fn init(&mut self, device_type: u32) -> Result<(), VirtioError> {
+        // magic_value: 0x000
+        if self.region.io_read_u32(0x000) != VIRTIO_MMIO_MAGIC {
+            warn!("No virtio-mmio magic value detected");
+            return Err(VirtioError::UnsupportedDevice);
+        }
+
+        // version: 0x004
+        match self.region.io_read_u32(0x004) {
+            VIRTIO_MMIO_VERSION_MODERN => {}
+            VIRTIO_MMIO_VERSION_LEGACY => return Err(VirtioError::LegacyOnly),
+            _ => return Err(VirtioError::UnsupportedDevice),
+        }
+
+        // device_id: 0x008
+        if self.region.io_read_u32(0x008) != device_type {
+            return Err(VirtioError::UnsupportedDevice);
+        }
+
+        Ok(())
+    }
+
+    fn get_status(&self) -> u32 {
+        // status: 0x070
+        self.region.io_read_u32(0x070)
+    }
+
+    fn set_status(&self, value: u32) {
+        // status: 0x070
+        self.region.io_write_u32(0x070, value);
+    }
+
+    fn add_status(&self, value: u32) {
+        self.set_status(self.get_status() | value);
+    }
+
+    fn reset(&self) {
+        self.set_status(0);
+    }
+
+    fn get_features(&self) -> u64 {
+        // device_features_sel: 0x014
+        self.region.io_write_u32(0x014, 0);
+        // device_features: 0x010
+        let mut device_features: u64 = u64::from(self.region.io_read_u32(0x010));
+        // device_features_sel: 0x014
+        self.region.io_write_u32(0x014, 1);
+        // device_features: 0x010
+        device_features |= u64::from(self.region.io_read_u32(0x010)) << 32;
+
+        device_features
+    }
+
+    fn set_features(&self, features: u64) {
+        // driver_features_sel: 0x024
+        self.region.io_write_u32(0x024, 0);
+        // driver_features: 0x020
+        self.region.io_write_u32(0x020, features as u32);
+        // driver_features_sel: 0x024
+        self.region.io_write_u32(0x024, 1);
+        // driver_features: 0x020
+        self.region.io_write_u32(0x020, (features >> 32) as u32);
+    }
+
+    fn set_queue(&self, queue: u16) {
+        // queue_sel: 0x030
+        self.region.io_write_u32(0x030, u32::from(queue));
+    }
+
+    fn get_queue_max_size(&self) -> u16 {
+        // queue_num_max: 0x034
+        let max = self.region.io_read_u32(0x034);
+        u16::try_from(max).unwrap_or(u16::MAX)
+    }
+
+    fn set_queue_size(&self, queue_size: u16) {
+        // queue_num: 0x038
+        self.region.io_write_u32(0x038, u32::from(queue_size));
+    }
+
+    fn set_descriptors_address(&self, addr: u64) {
+        // queue_desc: 0x080
+        self.region.io_write_u32(0x080, (addr & 0xffff_ffff) as u32);
+        self.region.io_write_u32(0x080 + 4, (addr >> 32) as u32);
+    }
+
+    fn set_avail_ring(&self, addr: u64) {
+        // queue_driver: 0x090
+        self.region.io_write_u32(0x090, (addr & 0xffff_ffff) as u32);
+        self.region.io_write_u32(0x090 + 4, (addr >> 32) as u32);
+    }
+
+    fn set_used_ring(&self, addr: u64) {
+        // queue_device: 0x0a0
+        self.region.io_write_u32(0x0a0, (addr & 0xffff_ffff) as u32);
+        self.region.io_write_u32(0x0a0 + 4, (addr >> 32) as u32);
+    }
+
+    fn set_queue_enable(&self) {
+        // queue_ready: 0x044
+        self.region.io_write_u32(0x044, 0x1);
+    }
+
+    fn notify_queue(&self, queue: u16) {
+        // queue_notify: 0x050
+        self.region.io_write_u32(0x050, u32::from(queue));
+    }
+
+    fn read_device_config(&self, offset: u64) -> u32 {
+        // device specific configuration: 0x100
+        self.region.io_read_u32(0x100 + offset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(super::parse_size("512"), Some(512));
+        assert_eq!(super::parse_size("0x200"), Some(0x200));
+        assert_eq!(super::parse_size("4K"), Some(4096));
+        assert_eq!(super::parse_size("1M"), Some(1 << 20));
+        assert_eq!(super::parse_size("0xd000"), Some(0xd000));
+        assert_eq!(super::parse_size(""), None);
+        assert_eq!(super::parse_size("K"), None);
+        assert_eq!(super::parse_size("0xg"), None);
+    }
+
+    #[test]
+    fn test_parse_device_param() {
+        assert_eq!(
+            super::parse_device_param("4K@0xd0000000:5"),
+            Some((0xd000_0000, 0x1000))
+        );
+        assert_eq!(
+            super::parse_device_param("0x200@0xfeb00000:12:3"),
+            Some((0xfeb0_0000, 0x200))
+        );
+        assert_eq!(super::parse_device_param("4K@0xd0000000"), None);
+        assert_eq!(super::parse_device_param("0xd0000000:5"), None);
+    }
+}
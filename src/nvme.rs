@@ -0,0 +1,570 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2026 The Rust Hypervisor Firmware Authors
+
+use core::{
+    cell::{RefCell, SyncUnsafeCell},
+    sync::atomic::{fence, AtomicUsize, Ordering},
+};
+
+use log::info;
+
+use crate::{
+    block::{
+        is_supported_block_size, read_unaligned, write_unaligned, BlockDevice, Error as BlockError,
+        SectorBuf, SectorRead, SectorWrite,
+    },
+    delay, mem,
+    pci::PciDevice,
+};
+
+// Mass storage controller, non-volatile memory controller, NVM Express
+pub const NVME_PCI_CLASS: u8 = 0x01;
+pub const NVME_PCI_SUBCLASS: u8 = 0x08;
+pub const NVME_PCI_PROG_IF: u8 = 0x02;
+
+const MAX_CONTROLLERS: usize = 4;
+
+const PAGE_SIZE: usize = 4096;
+const QUEUE_SIZE: usize = 16;
+const PRP_LIST_ENTRIES: usize = PAGE_SIZE / core::mem::size_of::<u64>();
+
+const ADMIN_QUEUE_ID: u16 = 0;
+const IO_QUEUE_ID: u16 = 1;
+
+// Admin command opcodes
+const ADMIN_CREATE_IO_SQ: u8 = 0x01;
+const ADMIN_CREATE_IO_CQ: u8 = 0x05;
+const ADMIN_IDENTIFY: u8 = 0x06;
+
+// NVM command opcodes
+const NVM_FLUSH: u8 = 0x00;
+const NVM_WRITE: u8 = 0x01;
+const NVM_READ: u8 = 0x02;
+
+// Identify controller or namespace structures (CNS values)
+const IDENTIFY_NAMESPACE: u32 = 0x00;
+const IDENTIFY_CONTROLLER: u32 = 0x01;
+const IDENTIFY_ACTIVE_NAMESPACES: u32 = 0x02;
+
+#[derive(Debug)]
+pub enum Error {
+    NoQueueMemory,
+    UnsupportedDevice,
+    QueueTooSmall,
+    ControllerFatal,
+    Timeout,
+    #[allow(dead_code)]
+    CommandFailed(u16),
+    NoNamespace,
+    #[allow(dead_code)]
+    UnsupportedBlockSize(u32),
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+/// A submission queue entry
+struct Command {
+    opcode: u8,
+    flags: u8,
+    cid: u16,
+    nsid: u32,
+    _reserved: u64,
+    mptr: u64,
+    prp1: u64,
+    prp2: u64,
+    cdw10: u32,
+    cdw11: u32,
+    cdw12: u32,
+    cdw13: u32,
+    cdw14: u32,
+    cdw15: u32,
+}
+
+impl Command {
+    const fn new() -> Self {
+        Command {
+            opcode: 0,
+            flags: 0,
+            cid: 0,
+            nsid: 0,
+            _reserved: 0,
+            mptr: 0,
+            prp1: 0,
+            prp2: 0,
+            cdw10: 0,
+            cdw11: 0,
+            cdw12: 0,
+            cdw13: 0,
+            cdw14: 0,
+            cdw15: 0,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+/// A completion queue entry
+struct Completion {
+    result: u32,
+    _reserved: u32,
+    sq_head: u16,
+    sq_id: u16,
+    cid: u16,
+    // Bit 0 is the phase tag, bits 15-1 the status field
+    status: u16,
+}
+
+impl Completion {
+    const fn new() -> Self {
+        Completion {
+            result: 0,
+            _reserved: 0,
+            sq_head: 0,
+            sq_id: 0,
+            cid: 0,
+            status: 0,
+        }
+    }
+}
+
+#[repr(C)]
+#[repr(align(4096))]
+struct Page<T>(T);
+
+#[repr(C)]
+/// Memory shared with a controller. Every queue and the PRP list need to be
+/// page aligned so each gets a page of its own.
+struct QueueMemory {
+    admin_sq: Page<[Command; QUEUE_SIZE]>,
+    admin_cq: Page<[Completion; QUEUE_SIZE]>,
+    io_sq: Page<[Command; QUEUE_SIZE]>,
+    io_cq: Page<[Completion; QUEUE_SIZE]>,
+    prp_list: Page<[u64; PRP_LIST_ENTRIES]>,
+    // Identify data and bounce buffer for transfers that are not dword aligned
+    buffer: Page<[u8; PAGE_SIZE]>,
+}
+
+impl QueueMemory {
+    const fn new() -> Self {
+        QueueMemory {
+            admin_sq: Page([Command::new(); QUEUE_SIZE]),
+            admin_cq: Page([Completion::new(); QUEUE_SIZE]),
+            io_sq: Page([Command::new(); QUEUE_SIZE]),
+            io_cq: Page([Completion::new(); QUEUE_SIZE]),
+            prp_list: Page([0; PRP_LIST_ENTRIES]),
+            buffer: Page([0; PAGE_SIZE]),
+        }
+    }
+}
+
+// There is no heap so the queues of each controller come from a fixed pool
+static QUEUE_MEMORY: SyncUnsafeCell<[QueueMemory; MAX_CONTROLLERS]> =
+    SyncUnsafeCell::new([const { QueueMemory::new() }; MAX_CONTROLLERS]);
+static NEXT_QUEUE_MEMORY: AtomicUsize = AtomicUsize::new(0);
+
+fn allocate_queue_memory() -> Option<&'static mut QueueMemory> {
+    let index = NEXT_QUEUE_MEMORY.fetch_add(1, Ordering::Relaxed);
+    if index >= MAX_CONTROLLERS {
+        return None;
+    }
+    // SAFETY: Each index is only ever handed out once
+    Some(unsafe { &mut (*QUEUE_MEMORY.get())[index] })
+}
+
+#[derive(Default)]
+struct QueueState {
+    sq_tail: usize,
+    cq_head: usize,
+    phase: u16,
+}
+
+struct DriverState {
+    memory: &'static mut QueueMemory,
+    queues: [QueueState; 2],
+    next_cid: u16,
+}
+
+/// Device driver for an NVMe controller, exposing its first active namespace
+pub struct NvmeDevice {
+    region: mem::MemoryRegion,
+    state: RefCell<DriverState>,
+    doorbell_stride: u64,
+    timeout_ms: u64,
+    nsid: u32,
+    capacity: u64,
+    block_size: usize,
+    max_transfer: usize,
+}
+
+// Controller Registers (BAR0):
+/// le64 cap;                       // 0x00 // read-only, controller capabilities
+/// le32 vs;                        // 0x08 // read-only, version
+/// le32 intms;                     // 0x0c // interrupt mask set
+/// le32 intmc;                     // 0x10 // interrupt mask clear
+/// le32 cc;                        // 0x14 // controller configuration
+/// le32 reserved;                  // 0x18
+/// le32 csts;                      // 0x1c // read-only, controller status
+/// le32 nssr;                      // 0x20 // NVM subsystem reset
+/// le32 aqa;                       // 0x24 // admin queue attributes
+/// le64 asq;                       // 0x28 // admin submission queue base
+/// le64 acq;                       // 0x30 // admin completion queue base
+/// doorbells                       // 0x1000 + (2 * qid + is_cq) * (4 << cap.dstrd)
+impl NvmeDevice {
+    pub fn new(pci_device: PciDevice) -> Result<NvmeDevice, Error> {
+        let memory = allocate_queue_memory().ok_or(Error::NoQueueMemory)?;
+
+        // The controller fetches commands and data itself
+        pci_device.enable_bus_master();
+
+        Ok(NvmeDevice {
+            region: pci_device.bar_region(0),
+            state: RefCell::new(DriverState {
+                memory,
+                queues: Default::default(),
+                next_cid: 0,
+            }),
+            doorbell_stride: 4,
+            timeout_ms: 500,
+            nsid: 0,
+            capacity: 0,
+            block_size: SectorBuf::len(),
+            max_transfer: PAGE_SIZE,
+        })
+    }
+
+    fn read_u64(&self, offset: u64) -> u64 {
+        u64::from(self.region.io_read_u32(offset))
+            | u64::from(self.region.io_read_u32(offset + 4)) << 32
+    }
+
+    fn write_u64(&self, offset: u64, value: u64) {
+        self.region.io_write_u32(offset, value as u32);
+        self.region.io_write_u32(offset + 4, (value >> 32) as u32);
+    }
+
+    fn doorbell(&self, qid: u16, completion: bool) -> u64 {
+        0x1000 + (2 * u64::from(qid) + u64::from(completion)) * self.doorbell_stride
+    }
+
+    pub fn init(&mut self) -> Result<(), Error> {
+        const CAP_CSS_NVM: u64 = 1 << 37;
+        const CC_EN: u32 = 1;
+        // 64 byte submission and 16 byte completion queue entries
+        const CC_IOSQES: u32 = 6 << 16;
+        const CC_IOCQES: u32 = 4 << 20;
+        const CSTS_RDY: u32 = 1;
+        const CSTS_CFS: u32 = 2;
+
+        // cap: 0x00
+        let cap = self.read_u64(0x00);
+        if cap & CAP_CSS_NVM == 0 {
+            return Err(Error::UnsupportedDevice);
+        }
+        // Only 4K pages (MPS = 0) are used
+        if (cap >> 48) & 0xf != 0 {
+            return Err(Error::UnsupportedDevice);
+        }
+        // MQES is zero based
+        if (cap & 0xffff) as usize + 1 < QUEUE_SIZE {
+            return Err(Error::QueueTooSmall);
+        }
+        self.doorbell_stride = 4 << ((cap >> 32) & 0xf);
+        // TO is in 500ms units
+        self.timeout_ms = u64::max((cap >> 24) & 0xff, 1) * 500;
+
+        // Disable the controller to reset it. cc: 0x14, csts: 0x1c
+        let cc = self.region.io_read_u32(0x14);
+        if cc & CC_EN != 0 {
+            self.region.io_write_u32(0x14, cc & !CC_EN);
+        }
+        if !delay::wait_until(self.timeout_ms, || {
+            self.region.io_read_u32(0x1c) & CSTS_RDY == 0
+        }) {
+            return Err(Error::Timeout);
+        }
+
+        {
+            let mut state = self.state.borrow_mut();
+            state.memory.admin_cq.0 = [Completion::new(); QUEUE_SIZE];
+            state.memory.io_cq.0 = [Completion::new(); QUEUE_SIZE];
+            for queue in state.queues.iter_mut() {
+                *queue = QueueState {
+                    sq_tail: 0,
+                    cq_head: 0,
+                    phase: 1,
+                };
+            }
+
+            // Program the admin queues. aqa: 0x24, asq: 0x28, acq: 0x30
+            let size = (QUEUE_SIZE - 1) as u32;
+            self.region.io_write_u32(0x24, size << 16 | size);
+            self.write_u64(0x28, state.memory.admin_sq.0.as_ptr() as u64);
+            self.write_u64(0x30, state.memory.admin_cq.0.as_ptr() as u64);
+        }
+
+        // Enable with the NVM command set and 4K pages
+        self.region
+            .io_write_u32(0x14, CC_EN | CC_IOSQES | CC_IOCQES);
+        if !delay::wait_until(self.timeout_ms, || {
+            self.region.io_read_u32(0x1c) & (CSTS_RDY | CSTS_CFS) != 0
+        }) {
+            return Err(Error::Timeout);
+        }
+        if self.region.io_read_u32(0x1c) & CSTS_CFS != 0 {
+            return Err(Error::ControllerFatal);
+        }
+
+        // MDTS is in units of the minimum page size, zero means no limit
+        self.identify(IDENTIFY_CONTROLLER, 0)?;
+        let mdts = self.state.borrow().memory.buffer.0[77];
+        self.max_transfer = PRP_LIST_ENTRIES * PAGE_SIZE;
+        if mdts != 0 {
+            let mdts_bytes = PAGE_SIZE.checked_shl(u32::from(mdts)).unwrap_or(usize::MAX);
+            self.max_transfer = usize::min(self.max_transfer, mdts_bytes);
+        }
+
+        // Controllers predating NVMe 1.1 cannot list namespaces
+        self.nsid = match self.identify(IDENTIFY_ACTIVE_NAMESPACES, 0) {
+            Ok(()) => {
+                let buffer = &self.state.borrow().memory.buffer.0;
+                u32::from_le_bytes(buffer[0..4].try_into().unwrap())
+            }
+            Err(_) => 1,
+        };
+        if self.nsid == 0 {
+            return Err(Error::NoNamespace);
+        }
+
+        self.identify(IDENTIFY_NAMESPACE, self.nsid)?;
+        let lba_size = {
+            let buffer = &self.state.borrow().memory.buffer.0;
+            self.capacity = u64::from_le_bytes(buffer[0..8].try_into().unwrap());
+            // flbas: byte 26, LBA format descriptors from byte 128
+            let format = usize::from(buffer[26] & 0xf);
+            let lbads = buffer[128 + 4 * format + 2];
+            1u32.checked_shl(u32::from(lbads)).unwrap_or(0)
+        };
+        if self.capacity == 0 {
+            return Err(Error::NoNamespace);
+        }
+        if !is_supported_block_size(lba_size as usize) {
+            return Err(Error::UnsupportedBlockSize(lba_size));
+        }
+        self.block_size = lba_size as usize;
+        self.capacity *= u64::from(lba_size) / SectorBuf::len() as u64;
+
+        // Create the I/O queue pair, physically contiguous and without interrupts
+        let size = (QUEUE_SIZE - 1) as u32;
+        let (io_sq, io_cq) = {
+            let state = self.state.borrow();
+            (
+                state.memory.io_sq.0.as_ptr() as u64,
+                state.memory.io_cq.0.as_ptr() as u64,
+            )
+        };
+        self.submit(
+            ADMIN_QUEUE_ID,
+            Command {
+                opcode: ADMIN_CREATE_IO_CQ,
+                prp1: io_cq,
+                cdw10: size << 16 | u32::from(IO_QUEUE_ID),
+                cdw11: 1,
+                ..Default::default()
+            },
+        )?;
+        self.submit(
+            ADMIN_QUEUE_ID,
+            Command {
+                opcode: ADMIN_CREATE_IO_SQ,
+                prp1: io_sq,
+                cdw10: size << 16 | u32::from(IO_QUEUE_ID),
+                cdw11: u32::from(IO_QUEUE_ID) << 16 | 1,
+                ..Default::default()
+            },
+        )?;
+
+        info!(
+            "NVMe namespace {} configured. Capacity: {} sectors, {} byte blocks",
+            self.nsid, self.capacity, self.block_size
+        );
+
+        Ok(())
+    }
+
+    fn identify(&self, cns: u32, nsid: u32) -> Result<(), Error> {
+        let buffer = self.state.borrow().memory.buffer.0.as_ptr() as u64;
+        self.submit(
+            ADMIN_QUEUE_ID,
+            Command {
+                opcode: ADMIN_IDENTIFY,
+                nsid,
+                prp1: buffer,
+                cdw10: cns,
+                ..Default::default()
+            },
+        )
+        .map(|_| ())
+    }
+
+    // Submit a command and poll for its completion
+    fn submit(&self, qid: u16, mut command: Command) -> Result<u32, Error> {
+        let mut state = self.state.borrow_mut();
+        let state = &mut *state;
+
+        command.cid = state.next_cid;
+        state.next_cid = state.next_cid.wrapping_add(1);
+
+        let queue = &mut state.queues[usize::from(qid)];
+        let (sq, cq) = match qid {
+            ADMIN_QUEUE_ID => (&mut state.memory.admin_sq.0, &state.memory.admin_cq.0),
+            _ => (&mut state.memory.io_sq.0, &state.memory.io_cq.0),
+        };
+
+        unsafe { core::ptr::write_volatile(&mut sq[queue.sq_tail], command) };
+        queue.sq_tail = (queue.sq_tail + 1) % QUEUE_SIZE;
+        fence(Ordering::SeqCst);
+        self.region
+            .io_write_u32(self.doorbell(qid, false), queue.sq_tail as u32);
+
+        let entry = &cq[queue.cq_head];
+        let phase = queue.phase;
+        if !delay::wait_until(self.timeout_ms, || {
+            let status = unsafe { core::ptr::read_volatile(&entry.status) };
+            status & 1 == phase
+        }) {
+            return Err(Error::Timeout);
+        }
+        fence(Ordering::Acquire);
+        let completion = unsafe { core::ptr::read_volatile(entry) };
+
+        queue.cq_head += 1;
+        if queue.cq_head == QUEUE_SIZE {
+            queue.cq_head = 0;
+            queue.phase ^= 1;
+        }
+        self.region
+            .io_write_u32(self.doorbell(qid, true), queue.cq_head as u32);
+
+        match completion.status >> 1 {
+            0 => Ok(completion.result),
+            status => Err(Error::CommandFailed(status)),
+        }
+    }
+
+    // Transfer `length` bytes at `address` with a single command
+    fn transfer(&self, opcode: u8, sector: u64, address: u64, length: usize) -> Result<(), Error> {
+        let lba = sector * SectorBuf::len() as u64 / self.block_size as u64;
+        let offset = address as usize % PAGE_SIZE;
+        let first_page = address - offset as u64;
+        let pages = (offset + length).div_ceil(PAGE_SIZE);
+
+        let prp2 = match pages {
+            1 => 0,
+            2 => first_page + PAGE_SIZE as u64,
+            _ => {
+                let mut state = self.state.borrow_mut();
+                let prp_list = &mut state.memory.prp_list.0;
+                for (i, entry) in prp_list[..pages - 1].iter_mut().enumerate() {
+                    *entry = first_page + ((i + 1) * PAGE_SIZE) as u64;
+                }
+                prp_list.as_ptr() as u64
+            }
+        };
+
+        self.submit(
+            IO_QUEUE_ID,
+            Command {
+                opcode,
+                nsid: self.nsid,
+                prp1: address,
+                prp2,
+                cdw10: lba as u32,
+                cdw11: (lba >> 32) as u32,
+                // Number of logical blocks, zero based
+                cdw12: (length / self.block_size - 1) as u32,
+                ..Default::default()
+            },
+        )
+        .map(|_| ())
+    }
+
+    fn io(&self, opcode: u8, sector: u64, data: &mut [u8]) -> Result<(), BlockError> {
+        if data.is_empty() || data.len() % self.block_size != 0 {
+            return Err(BlockError::InvalidDataBufSize);
+        }
+
+        // PRP entries must be dword aligned, bounce anything else
+        if data.as_ptr() as usize % 4 != 0 {
+            let buffer = self.state.borrow().memory.buffer.0.as_ptr() as u64;
+            for (i, chunk) in data.chunks_mut(PAGE_SIZE).enumerate() {
+                let sector = sector + (i * PAGE_SIZE / SectorBuf::len()) as u64;
+                if opcode == NVM_WRITE {
+                    self.state.borrow_mut().memory.buffer.0[..chunk.len()].copy_from_slice(chunk);
+                }
+                self.transfer(opcode, sector, buffer, chunk.len())
+                    .map_err(|_| BlockError::BlockIO)?;
+                if opcode == NVM_READ {
+                    chunk.copy_from_slice(&self.state.borrow().memory.buffer.0[..chunk.len()]);
+                }
+            }
+            return Ok(());
+        }
+
+        for (i, chunk) in data.chunks_mut(self.max_transfer).enumerate() {
+            let sector = sector + (i * self.max_transfer / SectorBuf::len()) as u64;
+            self.transfer(opcode, sector, chunk.as_mut_ptr() as u64, chunk.len())
+                .map_err(|_| BlockError::BlockIO)?;
+        }
+        Ok(())
+    }
+}
+
+impl SectorRead for NvmeDevice {
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn read(&self, sector: u64, data: &mut [u8]) -> Result<(), BlockError> {
+        if data.len() != SectorBuf::len() {
+            return Err(BlockError::InvalidDataBufSize);
+        }
+        self.read_sectors(sector, data)
+    }
+
+    fn read_sectors(&self, sector: u64, data: &mut [u8]) -> Result<(), BlockError> {
+        read_unaligned(self.block_size, sector, data, |sector, data| {
+            self.io(NVM_READ, sector, data)
+        })
+    }
+}
+
+impl SectorWrite for NvmeDevice {
+    fn write(&self, sector: u64, data: &mut [u8]) -> Result<(), BlockError> {
+        write_unaligned(
+            self.block_size,
+            sector,
+            data,
+            |sector, data| self.io(NVM_READ, sector, data),
+            |sector, data| self.io(NVM_WRITE, sector, data),
+        )
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        self.submit(
+            IO_QUEUE_ID,
+            Command {
+                opcode: NVM_FLUSH,
+                nsid: self.nsid,
+                ..Default::default()
+            },
+        )
+        .map(|_| ())
+        .map_err(|_| BlockError::BlockIO)
+    }
+}
+
+impl BlockDevice for NvmeDevice {
+    fn get_capacity(&self) -> u64 {
+        self.capacity
+    }
+}
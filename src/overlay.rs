@@ -0,0 +1,922 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2026 The Rust Hypervisor Firmware Authors
+
+// Applying device tree overlays the way libfdt's fdt_overlay_apply() does,
+// see Documentation/devicetree/overlay-notes.rst in the kernel tree. Overlays
+// are compiled with symbols (dtc -@): the phandles of the overlay's own nodes
+// are moved above those of the base tree using its __local_fixups__,
+// references to labels of the base tree are resolved through its __fixups__
+// and the base's __symbols__, and then the __overlay__ node of each fragment
+// is merged into the fragment's target.
+
+use heapless::Vec;
+
+use crate::image::{
+    align_up, be32, c_str, Error, FDT_BEGIN_NODE, FDT_END, FDT_END_NODE, FDT_MAGIC, FDT_NOP,
+    FDT_PROP,
+};
+
+// Header fields, as offsets into the header
+const TOTAL_SIZE: usize = 4;
+const OFF_STRUCT: usize = 8;
+const OFF_STRINGS: usize = 12;
+const SIZE_STRINGS: usize = 32;
+const SIZE_STRUCT: usize = 36;
+
+const MAX_PATH: usize = 256;
+const MAX_DEPTH: usize = 32;
+
+fn padded(len: usize) -> usize {
+    align_up(len as u64, 4) as usize
+}
+
+// The value of a string property without its terminator
+fn string(value: &[u8]) -> &[u8] {
+    value.split(|&c| c == 0).next().unwrap_or_default()
+}
+
+fn split_once(s: &[u8], separator: u8) -> Option<(&[u8], &[u8])> {
+    let i = s.iter().position(|&c| c == separator)?;
+    Some((&s[..i], &s[i + 1..]))
+}
+
+/// A flattened device tree at the start of a buffer that has room for it to
+/// grow. Nodes and properties are referred to by their offset in the buffer,
+/// which changes when something is added or resized before them.
+pub struct Tree<'a> {
+    data: &'a mut [u8],
+}
+
+impl<'a> Tree<'a> {
+    pub fn new(data: &'a mut [u8]) -> Result<Self, Error> {
+        if be32(data, 0)? != FDT_MAGIC || be32(data, 20)? < 17 {
+            return Err(Error::InvalidDeviceTree);
+        }
+        let tree = Tree { data };
+        let size = be32(tree.data, TOTAL_SIZE)? as usize;
+        let structure = be32(tree.data, OFF_STRUCT)? as usize;
+        let structure_size = be32(tree.data, SIZE_STRUCT)? as usize;
+        let strings = be32(tree.data, OFF_STRINGS)? as usize;
+        let strings_size = be32(tree.data, SIZE_STRINGS)? as usize;
+        // The strings follow the structure, which is the block that grows
+        if structure + structure_size > strings
+            || strings + strings_size > size
+            || size > tree.data.len()
+        {
+            return Err(Error::InvalidDeviceTree);
+        }
+        Ok(tree)
+    }
+
+    pub fn size(&self) -> usize {
+        self.header(TOTAL_SIZE)
+    }
+
+    // Checked by new()
+    fn header(&self, field: usize) -> usize {
+        u32::from_be_bytes(self.data[field..field + 4].try_into().unwrap()) as usize
+    }
+
+    fn set_header(&mut self, field: usize, value: usize) {
+        self.data[field..field + 4].copy_from_slice(&(value as u32).to_be_bytes());
+    }
+
+    fn write_u32(&mut self, offset: usize, value: u32) {
+        self.data[offset..offset + 4].copy_from_slice(&value.to_be_bytes());
+    }
+
+    // The token at `offset` and the offset of the one after it
+    fn token(&self, offset: usize) -> Result<(u32, usize), Error> {
+        let end = self.header(OFF_STRUCT) + self.header(SIZE_STRUCT);
+        let structure = &self.data[..end];
+        let token = be32(structure, offset)?;
+        let next = match token {
+            FDT_BEGIN_NODE => padded(offset + 4 + c_str(structure, offset + 4)?.len() + 1),
+            FDT_PROP => padded(offset + 12 + be32(structure, offset + 4)? as usize),
+            FDT_END_NODE | FDT_NOP | FDT_END => offset + 4,
+            _ => return Err(Error::InvalidDeviceTree),
+        };
+        if next > end {
+            return Err(Error::InvalidDeviceTree);
+        }
+        Ok((token, next))
+    }
+
+    pub fn root(&self) -> Result<usize, Error> {
+        let mut offset = self.header(OFF_STRUCT);
+        loop {
+            match self.token(offset)? {
+                (FDT_NOP, next) => offset = next,
+                (FDT_BEGIN_NODE, _) => return Ok(offset),
+                _ => return Err(Error::InvalidDeviceTree),
+            }
+        }
+    }
+
+    fn name(&self, node: usize) -> Result<&[u8], Error> {
+        c_str(self.data, node + 4)
+    }
+
+    // Offset of the FDT_END_NODE token of `node`
+    fn node_end(&self, node: usize) -> Result<usize, Error> {
+        let mut depth = 0;
+        let mut offset = node;
+        loop {
+            let (token, next) = self.token(offset)?;
+            match token {
+                FDT_BEGIN_NODE => depth += 1,
+                FDT_END_NODE => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return Ok(offset);
+                    }
+                }
+                FDT_END => return Err(Error::InvalidDeviceTree),
+                _ => {}
+            }
+            offset = next;
+        }
+    }
+
+    // The property or subnode at or after `offset` inside a node, as its
+    // token, its offset and the offset after it, or None at the end of the
+    // node. Start with the offset after the node's FDT_BEGIN_NODE token.
+    fn child(&self, mut offset: usize) -> Result<Option<(u32, usize, usize)>, Error> {
+        loop {
+            let (token, next) = self.token(offset)?;
+            match token {
+                FDT_NOP => offset = next,
+                FDT_PROP => return Ok(Some((token, offset, next))),
+                FDT_BEGIN_NODE => {
+                    return Ok(Some((token, offset, self.node_end(offset)? + 4)));
+                }
+                FDT_END_NODE => return Ok(None),
+                _ => return Err(Error::InvalidDeviceTree),
+            }
+        }
+    }
+
+    fn first_child(&self, node: usize) -> Result<usize, Error> {
+        Ok(self.token(node)?.1)
+    }
+
+    // Name and value of the property at `offset`
+    fn property_at(&self, offset: usize) -> Result<(&[u8], &[u8]), Error> {
+        let len = be32(self.data, offset + 4)? as usize;
+        let strings = &self.data[self.header(OFF_STRINGS)..][..self.header(SIZE_STRINGS)];
+        let name = c_str(strings, be32(self.data, offset + 8)? as usize)?;
+        let value = self
+            .data
+            .get(offset + 12..offset + 12 + len)
+            .ok_or(Error::InvalidDeviceTree)?;
+        Ok((name, value))
+    }
+
+    fn find_property(&self, node: usize, name: &[u8]) -> Result<Option<usize>, Error> {
+        let mut offset = self.first_child(node)?;
+        // Properties come before subnodes
+        while let Some((FDT_PROP, at, next)) = self.child(offset)? {
+            if self.property_at(at)?.0 == name {
+                return Ok(Some(at));
+            }
+            offset = next;
+        }
+        Ok(None)
+    }
+
+    pub fn property(&self, node: usize, name: &[u8]) -> Result<Option<&[u8]>, Error> {
+        match self.find_property(node, name)? {
+            Some(at) => Ok(Some(self.property_at(at)?.1)),
+            None => Ok(None),
+        }
+    }
+
+    fn phandle(&self, node: usize) -> Result<Option<u32>, Error> {
+        for name in [&b"phandle"[..], b"linux,phandle"] {
+            if let Some(value) = self.property(node, name)? {
+                return Ok(Some(be32(value, 0)?));
+            }
+        }
+        Ok(None)
+    }
+
+    // Like libfdt a name without a unit address matches a node with one
+    pub fn subnode(&self, node: usize, name: &[u8]) -> Result<Option<usize>, Error> {
+        let mut offset = self.first_child(node)?;
+        while let Some((token, at, next)) = self.child(offset)? {
+            if token == FDT_BEGIN_NODE {
+                let node_name = self.name(at)?;
+                if node_name == name
+                    || (!name.contains(&b'@')
+                        && node_name.split(|&c| c == b'@').next() == Some(name))
+                {
+                    return Ok(Some(at));
+                }
+            }
+            offset = next;
+        }
+        Ok(None)
+    }
+
+    // The node at an absolute path or one starting with an alias
+    fn path_offset(&self, path: &[u8]) -> Result<Option<usize>, Error> {
+        let root = self.root()?;
+        let (mut node, rest) = match path.first() {
+            Some(b'/') => (root, path),
+            _ => {
+                let (alias, rest) = split_once(path, b'/').unwrap_or((path, b""));
+                let aliases = match self.subnode(root, b"aliases")? {
+                    Some(aliases) => aliases,
+                    None => return Ok(None),
+                };
+                let target = match self.property(aliases, alias)? {
+                    Some(target) if target.first() == Some(&b'/') => string(target),
+                    _ => return Ok(None),
+                };
+                match self.path_offset(target)? {
+                    Some(node) => (node, rest),
+                    None => return Ok(None),
+                }
+            }
+        };
+        for name in rest.split(|&c| c == b'/').filter(|name| !name.is_empty()) {
+            node = match self.subnode(node, name)? {
+                Some(subnode) => subnode,
+                None => return Ok(None),
+            };
+        }
+        Ok(Some(node))
+    }
+
+    fn path(&self, node: usize) -> Result<Vec<u8, MAX_PATH>, Error> {
+        let mut path = Vec::new();
+        // Where the name of each node above the current one starts in path
+        let mut starts: Vec<usize, MAX_DEPTH> = Vec::new();
+        let mut offset = self.root()?;
+        loop {
+            let (token, next) = self.token(offset)?;
+            match token {
+                FDT_BEGIN_NODE => {
+                    starts
+                        .push(path.len())
+                        .map_err(|_| Error::InvalidDeviceTree)?;
+                    if starts.len() > 1 {
+                        path.push(b'/').map_err(|_| Error::InvalidDeviceTree)?;
+                        path.extend_from_slice(self.name(offset)?)
+                            .map_err(|_| Error::InvalidDeviceTree)?;
+                    }
+                    if offset == node {
+                        if path.is_empty() {
+                            path.push(b'/').unwrap();
+                        }
+                        return Ok(path);
+                    }
+                }
+                FDT_END_NODE => {
+                    let start = starts.pop().ok_or(Error::InvalidDeviceTree)?;
+                    path.truncate(start);
+                }
+                FDT_END => return Err(Error::InvalidDeviceTree),
+                _ => {}
+            }
+            offset = next;
+        }
+    }
+
+    fn max_phandle(&self) -> Result<u32, Error> {
+        let mut max = 0;
+        let mut offset = self.header(OFF_STRUCT);
+        loop {
+            match self.token(offset)? {
+                (FDT_END, _) => return Ok(max),
+                (FDT_PROP, next) => {
+                    let (name, value) = self.property_at(offset)?;
+                    if name == b"phandle" || name == b"linux,phandle" {
+                        max = u32::max(max, be32(value, 0)?);
+                    }
+                    offset = next;
+                }
+                (_, next) => offset = next,
+            }
+        }
+    }
+
+    fn node_by_phandle(&self, phandle: u32) -> Result<Option<usize>, Error> {
+        let mut offset = self.header(OFF_STRUCT);
+        loop {
+            match self.token(offset)? {
+                (FDT_END, _) => return Ok(None),
+                (FDT_BEGIN_NODE, next) => {
+                    if self.phandle(offset)? == Some(phandle) {
+                        return Ok(Some(offset));
+                    }
+                    offset = next;
+                }
+                (_, next) => offset = next,
+            }
+        }
+    }
+
+    // Replace the `remove` bytes at `offset` with `insert` bytes for the
+    // caller to fill in, moving everything after them
+    fn make_room(&mut self, offset: usize, remove: usize, insert: usize) -> Result<(), Error> {
+        let size = self.size();
+        if size - remove + insert > self.data.len() {
+            return Err(Error::NoDeviceTreeMemory);
+        }
+        self.data
+            .copy_within(offset + remove..size, offset + insert);
+        if offset < self.header(OFF_STRINGS) {
+            let structure_size = self.header(SIZE_STRUCT);
+            let strings = self.header(OFF_STRINGS);
+            self.set_header(SIZE_STRUCT, structure_size - remove + insert);
+            self.set_header(OFF_STRINGS, strings - remove + insert);
+        } else {
+            let strings_size = self.header(SIZE_STRINGS);
+            self.set_header(SIZE_STRINGS, strings_size - remove + insert);
+        }
+        self.set_header(TOTAL_SIZE, size - remove + insert);
+        Ok(())
+    }
+
+    // Offset of name in the strings block, adding it at the end if it is not
+    // already there
+    fn string_offset(&mut self, name: &[u8]) -> Result<u32, Error> {
+        let start = self.header(OFF_STRINGS);
+        let size = self.header(SIZE_STRINGS);
+        let strings = &self.data[start..start + size];
+        let found = strings
+            .windows(name.len() + 1)
+            .position(|w| w[..name.len()] == *name && w[name.len()] == 0);
+        if let Some(offset) = found {
+            return Ok(offset as u32);
+        }
+        self.make_room(start + size, 0, name.len() + 1)?;
+        self.data[start + size..start + size + name.len()].copy_from_slice(name);
+        self.data[start + size + name.len()] = 0;
+        Ok(size as u32)
+    }
+
+    /// Set a property of `node`, adding it after the existing properties and
+    /// before any subnodes if it is new
+    pub fn set_property(&mut self, node: usize, name: &[u8], value: &[u8]) -> Result<(), Error> {
+        // The strings come after the structure so this moves no node
+        let name_offset = self.string_offset(name)?;
+        let (at, remove) = match self.find_property(node, name)? {
+            Some(at) => (at, padded(12 + be32(self.data, at + 4)? as usize)),
+            None => {
+                // After the existing properties
+                let mut offset = self.first_child(node)?;
+                while let Some((FDT_PROP, _, next)) = self.child(offset)? {
+                    offset = next;
+                }
+                (offset, 0)
+            }
+        };
+        let len = padded(12 + value.len());
+        self.make_room(at, remove, len)?;
+        self.write_u32(at, FDT_PROP);
+        self.write_u32(at + 4, value.len() as u32);
+        self.write_u32(at + 8, name_offset);
+        self.data[at + 12..at + 12 + value.len()].copy_from_slice(value);
+        self.data[at + 12 + value.len()..at + len].fill(0);
+        Ok(())
+    }
+
+    pub fn delete_property(&mut self, node: usize, name: &[u8]) -> Result<(), Error> {
+        if let Some(at) = self.find_property(node, name)? {
+            let len = padded(12 + be32(self.data, at + 4)? as usize);
+            self.make_room(at, len, 0)?;
+        }
+        Ok(())
+    }
+
+    /// Add an empty subnode after the existing ones, returning its offset
+    pub fn add_subnode(&mut self, node: usize, name: &[u8]) -> Result<usize, Error> {
+        let at = self.node_end(node)?;
+        let len = padded(4 + name.len() + 1) + 4;
+        self.make_room(at, 0, len)?;
+        self.write_u32(at, FDT_BEGIN_NODE);
+        self.data[at + 4..at + 4 + name.len()].copy_from_slice(name);
+        self.data[at + 4 + name.len()..at + len - 4].fill(0);
+        self.write_u32(at + len - 4, FDT_END_NODE);
+        Ok(at)
+    }
+}
+
+/// Apply `overlay` to `base`, which grows into the rest of its buffer. The
+/// overlay is changed in the process.
+pub fn apply(base: &mut Tree, overlay: &mut Tree) -> Result<(), Error> {
+    let delta = base.max_phandle()?;
+    renumber_phandles(overlay, delta)?;
+    resolve_fixups(base, overlay)?;
+
+    let root = overlay.root()?;
+    let mut offset = overlay.first_child(root)?;
+    while let Some((token, fragment, next)) = overlay.child(offset)? {
+        if token == FDT_BEGIN_NODE {
+            if let Some(content) = overlay.subnode(fragment, b"__overlay__")? {
+                let target = target(base, overlay, fragment)?;
+                merge(base, target, overlay, content)?;
+            }
+        }
+        offset = next;
+    }
+
+    update_symbols(base, overlay)
+}
+
+// Move the phandles of the overlay's nodes up by `delta`, along with the
+// references to them listed in __local_fixups__
+fn renumber_phandles(overlay: &mut Tree, delta: u32) -> Result<(), Error> {
+    if delta == 0 {
+        return Ok(());
+    }
+
+    let mut offset = overlay.header(OFF_STRUCT);
+    loop {
+        match overlay.token(offset)? {
+            (FDT_END, _) => break,
+            (FDT_PROP, next) => {
+                let (name, value) = overlay.property_at(offset)?;
+                if name == b"phandle" || name == b"linux,phandle" {
+                    let phandle = be32(value, 0)?
+                        .checked_add(delta)
+                        .filter(|&phandle| phandle != u32::MAX)
+                        .ok_or(Error::InvalidDeviceTreeOverlay)?;
+                    overlay.write_u32(offset + 12, phandle);
+                }
+                offset = next;
+            }
+            (_, next) => offset = next,
+        }
+    }
+
+    let root = overlay.root()?;
+    match overlay.subnode(root, b"__local_fixups__")? {
+        Some(fixups) => local_fixups(overlay, fixups, root, delta),
+        None => Ok(()),
+    }
+}
+
+// Apply the `fixups` node of __local_fixups__ to `node`, the overlay node at
+// the same path. Its properties list the offsets of phandles in the
+// properties of the same name.
+fn local_fixups(overlay: &mut Tree, fixups: usize, node: usize, delta: u32) -> Result<(), Error> {
+    let mut offset = overlay.first_child(fixups)?;
+    while let Some((token, at, next)) = overlay.child(offset)? {
+        if token == FDT_PROP {
+            let target = overlay
+                .find_property(node, overlay.property_at(at)?.0)?
+                .ok_or(Error::InvalidDeviceTreeOverlay)?;
+            let target_len = be32(overlay.data, target + 4)? as usize;
+            let len = be32(overlay.data, at + 4)? as usize;
+            for i in (0..len).step_by(4) {
+                let position = be32(overlay.data, at + 12 + i)? as usize;
+                if position + 4 > target_len {
+                    return Err(Error::InvalidDeviceTreeOverlay);
+                }
+                let phandle = be32(overlay.data, target + 12 + position)?;
+                overlay.write_u32(target + 12 + position, phandle.wrapping_add(delta));
+            }
+        } else {
+            let subnode = overlay
+                .subnode(node, overlay.name(at)?)?
+                .ok_or(Error::InvalidDeviceTreeOverlay)?;
+            local_fixups(overlay, at, subnode, delta)?;
+        }
+        offset = next;
+    }
+    Ok(())
+}
+
+// Point the references to labels of the base tree at the labelled nodes.
+// Each property of __fixups__ is named after a label and lists the places it
+// is used in as "<path>:<property>:<offset>" strings.
+fn resolve_fixups(base: &Tree, overlay: &mut Tree) -> Result<(), Error> {
+    let root = overlay.root()?;
+    let fixups = match overlay.subnode(root, b"__fixups__")? {
+        Some(fixups) => fixups,
+        None => return Ok(()),
+    };
+    let symbols = base
+        .subnode(base.root()?, b"__symbols__")?
+        .ok_or(Error::DeviceTreeOverlayUnresolved)?;
+
+    let mut offset = overlay.first_child(fixups)?;
+    while let Some((token, at, next)) = overlay.child(offset)? {
+        offset = next;
+        if token != FDT_PROP {
+            continue;
+        }
+
+        let label = overlay.property_at(at)?.0;
+        let path = base
+            .property(symbols, label)?
+            .ok_or(Error::DeviceTreeOverlayUnresolved)?;
+        let phandle = base
+            .path_offset(string(path))?
+            .map(|node| base.phandle(node))
+            .transpose()?
+            .flatten()
+            .ok_or(Error::DeviceTreeOverlayUnresolved)?;
+
+        let end = at + 12 + be32(overlay.data, at + 4)? as usize;
+        let mut start = at + 12;
+        while start < end {
+            let (position, len) = {
+                let fixup = c_str(&overlay.data[..end], start)?;
+                let (path, rest) =
+                    split_once(fixup, b':').ok_or(Error::InvalidDeviceTreeOverlay)?;
+                let (name, position) =
+                    split_once(rest, b':').ok_or(Error::InvalidDeviceTreeOverlay)?;
+                let position: usize = core::str::from_utf8(position)
+                    .ok()
+                    .and_then(|position| position.parse().ok())
+                    .ok_or(Error::InvalidDeviceTreeOverlay)?;
+                let property = overlay
+                    .path_offset(path)?
+                    .map(|node| overlay.find_property(node, name))
+                    .transpose()?
+                    .flatten()
+                    .ok_or(Error::InvalidDeviceTreeOverlay)?;
+                if position + 4 > be32(overlay.data, property + 4)? as usize {
+                    return Err(Error::InvalidDeviceTreeOverlay);
+                }
+                (property + 12 + position, fixup.len())
+            };
+            overlay.write_u32(position, phandle);
+            start += len + 1;
+        }
+    }
+    Ok(())
+}
+
+// The node of the base tree a fragment applies to
+fn target(base: &Tree, overlay: &Tree, fragment: usize) -> Result<usize, Error> {
+    let node = if let Some(phandle) = overlay.property(fragment, b"target")? {
+        base.node_by_phandle(be32(phandle, 0)?)?
+    } else if let Some(path) = overlay.property(fragment, b"target-path")? {
+        base.path_offset(string(path))?
+    } else {
+        return Err(Error::InvalidDeviceTreeOverlay);
+    };
+    node.ok_or(Error::DeviceTreeOverlayUnresolved)
+}
+
+// Merge the properties and subnodes of the overlay's `node` into the base's
+// `target`, replacing properties that are already there
+fn merge(base: &mut Tree, target: usize, overlay: &Tree, node: usize) -> Result<(), Error> {
+    let mut offset = overlay.first_child(node)?;
+    while let Some((token, at, next)) = overlay.child(offset)? {
+        if token == FDT_PROP {
+            let (name, value) = overlay.property_at(at)?;
+            base.set_property(target, name, value)?;
+        } else {
+            let name = overlay.name(at)?;
+            let subnode = match base.subnode(target, name)? {
+                Some(subnode) => subnode,
+                None => base.add_subnode(target, name)?,
+            };
+            merge(base, subnode, overlay, at)?;
+        }
+        offset = next;
+    }
+    Ok(())
+}
+
+// Add the labels of the overlay's nodes to the base's __symbols__, so that
+// later overlays can refer to them, with the paths the nodes now have
+fn update_symbols(base: &mut Tree, overlay: &Tree) -> Result<(), Error> {
+    let root = overlay.root()?;
+    let symbols = match overlay.subnode(root, b"__symbols__")? {
+        Some(symbols) => symbols,
+        None => return Ok(()),
+    };
+
+    let mut offset = overlay.first_child(symbols)?;
+    while let Some((token, at, next)) = overlay.child(offset)? {
+        offset = next;
+        if token != FDT_PROP {
+            continue;
+        }
+
+        // Only labels inside "/<fragment>/__overlay__" are for the base tree
+        let (label, path) = overlay.property_at(at)?;
+        let path = string(path);
+        let (fragment, rest) = match path.strip_prefix(b"/").and_then(|p| split_once(p, b'/')) {
+            Some(parts) => parts,
+            None => continue,
+        };
+        let rest = match rest.strip_prefix(b"__overlay__") {
+            Some(rest) if rest.is_empty() || rest[0] == b'/' => rest,
+            _ => continue,
+        };
+        let fragment = overlay
+            .subnode(root, fragment)?
+            .ok_or(Error::InvalidDeviceTreeOverlay)?;
+
+        let mut path = base.path(target(base, overlay, fragment)?)?;
+        if path == b"/" && !rest.is_empty() {
+            path.clear();
+        }
+        path.extend_from_slice(rest)
+            .map_err(|_| Error::InvalidDeviceTree)?;
+        path.push(0).map_err(|_| Error::InvalidDeviceTree)?;
+
+        let base_root = base.root()?;
+        let base_symbols = match base.subnode(base_root, b"__symbols__")? {
+            Some(symbols) => symbols,
+            None => base.add_subnode(base_root, b"__symbols__")?,
+        };
+        base.set_property(base_symbols, label, &path)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::{apply, Tree};
+    use crate::image::Error;
+
+    pub enum Item<'a> {
+        Node(&'a str, &'a [Item<'a>]),
+        Property(&'a str, &'a [u8]),
+        Nop,
+    }
+    use Item::{Node, Property};
+
+    pub fn cells(values: &[u32]) -> Vec<u8> {
+        values.iter().flat_map(|v| v.to_be_bytes()).collect()
+    }
+
+    /// A device tree with `items` in its root node
+    pub fn dtb(items: &[Item]) -> Vec<u8> {
+        fn add(structure: &mut Vec<u8>, strings: &mut Vec<u8>, name: &str, items: &[Item]) {
+            structure.extend_from_slice(&1u32.to_be_bytes());
+            structure.extend_from_slice(name.as_bytes());
+            structure.push(0);
+            structure.resize(structure.len().next_multiple_of(4), 0);
+            for item in items {
+                match item {
+                    Node(name, items) => add(structure, strings, name, items),
+                    Property(name, value) => {
+                        let offset = strings.len() as u32;
+                        strings.extend_from_slice(name.as_bytes());
+                        strings.push(0);
+                        structure.extend_from_slice(&3u32.to_be_bytes());
+                        structure.extend_from_slice(&(value.len() as u32).to_be_bytes());
+                        structure.extend_from_slice(&offset.to_be_bytes());
+                        structure.extend_from_slice(value);
+                        structure.resize(structure.len().next_multiple_of(4), 0);
+                    }
+                    Item::Nop => structure.extend_from_slice(&4u32.to_be_bytes()),
+                }
+            }
+            structure.extend_from_slice(&2u32.to_be_bytes());
+        }
+
+        let mut structure = Vec::new();
+        let mut strings = Vec::new();
+        add(&mut structure, &mut strings, "", items);
+        structure.extend_from_slice(&9u32.to_be_bytes());
+
+        let structure_offset = 40 + 16;
+        let strings_offset = structure_offset + structure.len();
+        let size = strings_offset + strings.len();
+        let mut data = Vec::new();
+        for value in [
+            0xd00d_feed,
+            size as u32,
+            structure_offset as u32,
+            strings_offset as u32,
+            40,
+            17,
+            16,
+            0,
+            strings.len() as u32,
+            structure.len() as u32,
+        ] {
+            data.extend_from_slice(&value.to_be_bytes());
+        }
+        data.extend_from_slice(&[0; 16]);
+        data.extend_from_slice(&structure);
+        data.extend_from_slice(&strings);
+        data
+    }
+
+    /// All properties as "path:name" and value, in order
+    pub fn properties(tree: &Tree) -> Vec<(String, Vec<u8>)> {
+        let mut properties = Vec::new();
+        let mut nodes = Vec::new();
+        let mut offset = tree.root().unwrap();
+        loop {
+            let (token, next) = tree.token(offset).unwrap();
+            match token {
+                1 => nodes.push(offset),
+                2 => {
+                    nodes.pop();
+                }
+                3 => {
+                    let path = tree.path(*nodes.last().unwrap()).unwrap();
+                    let (name, value) = tree.property_at(offset).unwrap();
+                    let path = format!(
+                        "{}:{}",
+                        core::str::from_utf8(&path).unwrap(),
+                        core::str::from_utf8(name).unwrap()
+                    );
+                    properties.push((path, value.to_vec()));
+                }
+                9 => break,
+                _ => {}
+            }
+            offset = next;
+        }
+        properties
+    }
+
+    fn base() -> Vec<u8> {
+        dtb(&[
+            Property("compatible", b"base\0"),
+            Node(
+                "soc",
+                &[
+                    Node(
+                        "uart@1000",
+                        &[
+                            Property("status", b"disabled\0"),
+                            Property("phandle", &[0, 0, 0, 1]),
+                        ],
+                    ),
+                    Node("intc", &[Property("phandle", &[0, 0, 0, 2])]),
+                ],
+            ),
+            Node("__symbols__", &[Property("uart0", b"/soc/uart@1000\0")]),
+        ])
+    }
+
+    #[test]
+    fn test_apply() {
+        let link = cells(&[1, 0xffff_ffff]);
+        let mut overlay = dtb(&[
+            Node(
+                "fragment@0",
+                &[
+                    Property("target", &[0xff; 4]),
+                    Node(
+                        "__overlay__",
+                        &[
+                            Property("status", b"okay\0"),
+                            Node(
+                                "extra",
+                                &[
+                                    Property("reg", &[0, 0, 0, 5]),
+                                    Property("phandle", &[0, 0, 0, 1]),
+                                    Property("ref", &[0, 0, 0, 1]),
+                                ],
+                            ),
+                        ],
+                    ),
+                ],
+            ),
+            Node(
+                "fragment@1",
+                &[
+                    Property("target-path", b"/\0"),
+                    Node(
+                        "__overlay__",
+                        &[Node("new-node", &[Property("link", &link)])],
+                    ),
+                ],
+            ),
+            Node(
+                "__fixups__",
+                &[Property(
+                    "uart0",
+                    b"/fragment@0:target:0\0/fragment@1/__overlay__/new-node:link:4\0",
+                )],
+            ),
+            Node(
+                "__local_fixups__",
+                &[
+                    Node(
+                        "fragment@0",
+                        &[Node(
+                            "__overlay__",
+                            &[Node("extra", &[Property("ref", &[0; 4])])],
+                        )],
+                    ),
+                    Node(
+                        "fragment@1",
+                        &[Node(
+                            "__overlay__",
+                            &[Node("new-node", &[Property("link", &[0; 4])])],
+                        )],
+                    ),
+                ],
+            ),
+            Node(
+                "__symbols__",
+                &[
+                    Property("extra", b"/fragment@0/__overlay__/extra\0"),
+                    Property("new", b"/fragment@1/__overlay__/new-node\0"),
+                ],
+            ),
+        ]);
+
+        let mut data = base();
+        data.resize(data.len() + overlay.len() + 4096, 0);
+        let mut tree = Tree::new(&mut data).unwrap();
+        apply(&mut tree, &mut Tree::new(&mut overlay).unwrap()).unwrap();
+
+        let expected: Vec<(String, Vec<u8>)> = [
+            ("/:compatible", b"base\0".to_vec()),
+            ("/soc/uart@1000:status", b"okay\0".to_vec()),
+            ("/soc/uart@1000:phandle", cells(&[1])),
+            ("/soc/uart@1000/extra:reg", cells(&[5])),
+            ("/soc/uart@1000/extra:phandle", cells(&[3])),
+            ("/soc/uart@1000/extra:ref", cells(&[3])),
+            ("/soc/intc:phandle", cells(&[2])),
+            ("/__symbols__:uart0", b"/soc/uart@1000\0".to_vec()),
+            ("/__symbols__:extra", b"/soc/uart@1000/extra\0".to_vec()),
+            ("/__symbols__:new", b"/new-node\0".to_vec()),
+            ("/new-node:link", cells(&[3, 1])),
+        ]
+        .into_iter()
+        .map(|(path, value)| (path.to_string(), value))
+        .collect();
+        assert_eq!(properties(&tree), expected);
+
+        // A later overlay can refer to the labels of an earlier one
+        let mut overlay = dtb(&[
+            Node(
+                "fragment@0",
+                &[
+                    Property("target", &[0xff; 4]),
+                    Node("__overlay__", &[Property("status", b"fail\0")]),
+                ],
+            ),
+            Node(
+                "__fixups__",
+                &[Property("extra", b"/fragment@0:target:0\0")],
+            ),
+        ]);
+        apply(&mut tree, &mut Tree::new(&mut overlay).unwrap()).unwrap();
+        assert!(properties(&tree).contains(&(
+            "/soc/uart@1000/extra:status".to_string(),
+            b"fail\0".to_vec()
+        )));
+    }
+
+    #[test]
+    fn test_apply_errors() {
+        let fragment = |target: &[u8], fixups: &[Item]| {
+            dtb(&[
+                Node(
+                    "fragment@0",
+                    &[
+                        Property("target-path", target),
+                        Node("__overlay__", &[Property("big", &[0xaa; 256])]),
+                    ],
+                ),
+                Node("__fixups__", fixups),
+            ])
+        };
+
+        // Labels the base tree does not have
+        let mut data = base();
+        data.resize(data.len() + 4096, 0);
+        let mut tree = Tree::new(&mut data).unwrap();
+        let mut overlay = fragment(
+            b"/soc\0",
+            &[Property("missing", b"/fragment@0/__overlay__:big:0\0")],
+        );
+        assert!(matches!(
+            apply(&mut tree, &mut Tree::new(&mut overlay).unwrap()),
+            Err(Error::DeviceTreeOverlayUnresolved)
+        ));
+
+        // Targets that are not there
+        let mut overlay = fragment(b"/nowhere\0", &[]);
+        assert!(matches!(
+            apply(&mut tree, &mut Tree::new(&mut overlay).unwrap()),
+            Err(Error::DeviceTreeOverlayUnresolved)
+        ));
+
+        // No room for the result
+        let mut data = base();
+        data.resize(data.len() + 64, 0);
+        let mut tree = Tree::new(&mut data).unwrap();
+        let mut overlay = fragment(b"/soc\0", &[]);
+        assert!(matches!(
+            apply(&mut tree, &mut Tree::new(&mut overlay).unwrap()),
+            Err(Error::NoDeviceTreeMemory)
+        ));
+
+        // Not a device tree
+        assert!(matches!(
+            Tree::new(&mut [0; 64]),
+            Err(Error::InvalidDeviceTree)
+        ));
+    }
+}
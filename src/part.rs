@@ -1,50 +1,246 @@
 // SPDX-License-Identifier: Apache-2.0
 // Copyright © 2019 Intel Corporation
 
-use crate::block::{Error as BlockError, SectorBuf, SectorRead};
+use log::{error, warn};
+
+use crate::{
+    block::{BlockDevice, Error as BlockError, SectorBuf, SectorRead},
+    common::{crc32, parse_guid, Crc32},
+};
 
 #[repr(C, packed)]
 /// GPT header
 struct Header {
     signature: u64,
     _revision: u32,
-    _header_size: u32,
-    _header_crc: u32,
+    header_size: u32,
+    header_crc: u32,
     _reserved: u32,
-    _current_lba: u64,
+    current_lba: u64,
     _backup_lba: u64,
     first_usable_lba: u64,
-    _last_usable_lba: u64,
-    _disk_guid: [u8; 16],
+    last_usable_lba: u64,
+    disk_guid: [u8; 16],
     first_part_lba: u64,
     part_count: u32,
-    _part_entry_size: u32,
-    _part_crc: u32,
+    part_entry_size: u32,
+    part_crc: u32,
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+/// GPT partition entry
+struct GptEntry {
+    type_guid: [u8; 16],
+    guid: [u8; 16],
+    first_lba: u64,
+    last_lba: u64,
+    attributes: u64,
+    _partition_name: [u32; 18],
 }
 
 #[repr(C, packed)]
+#[derive(Clone, Copy)]
+/// MBR (and EBR) partition entry
+struct MbrEntry {
+    status: u8,
+    _first_chs: [u8; 3],
+    system_id: u8,
+    _last_chs: [u8; 3],
+    first_lba: u32,
+    sectors: u32,
+}
+
+const MBR_DISK_SIGNATURE_OFFSET: usize = 440;
+const MBR_ENTRIES_OFFSET: usize = 446;
+const MBR_BOOT_SIGNATURE: [u8; 2] = [0x55, 0xaa];
+const MBR_STATUS_ACTIVE: u8 = 0x80;
+
+const MBR_TYPE_EMPTY: u8 = 0x00;
+const MBR_TYPE_GPT_PROTECTIVE: u8 = 0xee;
+const MBR_TYPE_EFI: u8 = 0xef;
+const MBR_TYPE_XBOOTLDR: u8 = 0xea;
+const MBR_TYPE_LINUX: u8 = 0x83;
+const MBR_TYPES_EXTENDED: [u8; 3] = [0x05, 0x0f, 0x85];
+const MBR_TYPES_FAT: [u8; 6] = [0x01, 0x04, 0x06, 0x0b, 0x0c, 0x0e];
+
+// Bound on the EBR chain in case it loops
+const MAX_LOGICAL_PARTITIONS: u32 = 128;
+
+// Discoverable Partitions Specification: do not mount automatically
+const GPT_ATTRIBUTE_NO_AUTO: u64 = 1 << 63;
+
+const fn guid(s: &str) -> [u8; 16] {
+    match parse_guid(s) {
+        Some(guid) => guid,
+        None => panic!("invalid GUID"),
+    }
+}
+
+const ESP_TYPE_GUID: [u8; 16] = guid("C12A7328-F81F-11D2-BA4B-00A0C93EC93B");
+
+/// CPU architecture of a root or /usr partition
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Arch {
+    X86,
+    X86_64,
+    Arm,
+    Arm64,
+    RiscV64,
+}
+
+impl Arch {
+    #[cfg(target_arch = "x86_64")]
+    pub const NATIVE: Arch = Arch::X86_64;
+    #[cfg(target_arch = "aarch64")]
+    pub const NATIVE: Arch = Arch::Arm64;
+    #[cfg(target_arch = "riscv64")]
+    pub const NATIVE: Arch = Arch::RiscV64;
+}
+
+/// Partition type as defined by the UAPI Discoverable Partitions
+/// Specification
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PartitionType {
+    Esp,
+    Xbootldr,
+    Root(Arch),
+    Usr(Arch),
+    Swap,
+    Home,
+    Srv,
+    Var,
+    VarTmp,
+    LinuxData,
+    Unknown,
+}
+
+const DISCOVERABLE_TYPES: [([u8; 16], PartitionType); 18] = [
+    (ESP_TYPE_GUID, PartitionType::Esp),
+    (
+        guid("BC13C2FF-59E6-4262-A352-B275FD6F7172"),
+        PartitionType::Xbootldr,
+    ),
+    (
+        guid("44479540-F297-41B2-9AF7-D131D5F0458A"),
+        PartitionType::Root(Arch::X86),
+    ),
+    (
+        guid("4F68BCE3-E8CD-4DB1-96E7-FBCAF984B709"),
+        PartitionType::Root(Arch::X86_64),
+    ),
+    (
+        guid("69DAD710-2CE4-4E3C-B16C-21A1D49ABED3"),
+        PartitionType::Root(Arch::Arm),
+    ),
+    (
+        guid("B921B045-1DF0-41C3-AF44-4C6F280D3FAE"),
+        PartitionType::Root(Arch::Arm64),
+    ),
+    (
+        guid("72EC70A6-CF74-40E6-BD49-4BDA08E8F224"),
+        PartitionType::Root(Arch::RiscV64),
+    ),
+    (
+        guid("75250D76-8CC6-458E-BD66-BD47CC81A812"),
+        PartitionType::Usr(Arch::X86),
+    ),
+    (
+        guid("8484680C-9521-48C6-9C11-B0720656F69E"),
+        PartitionType::Usr(Arch::X86_64),
+    ),
+    (
+        guid("7D0359A3-02B3-4F0A-865C-654403E70625"),
+        PartitionType::Usr(Arch::Arm),
+    ),
+    (
+        guid("B0E01050-EE5F-4390-949A-9101B17104E9"),
+        PartitionType::Usr(Arch::Arm64),
+    ),
+    (
+        guid("BEAEC34B-8442-439B-A40B-984381ED097D"),
+        PartitionType::Usr(Arch::RiscV64),
+    ),
+    (
+        guid("0657FD6D-A4AB-43C4-84E5-0933C84B4F4F"),
+        PartitionType::Swap,
+    ),
+    (
+        guid("933AC7E1-2EB4-4F13-B844-0E14E2AEF915"),
+        PartitionType::Home,
+    ),
+    (
+        guid("3B8F8425-20E0-4F3B-907F-1A25A76F98E8"),
+        PartitionType::Srv,
+    ),
+    (
+        guid("4D21B016-B534-45C2-A9FB-5C16E091FD2D"),
+        PartitionType::Var,
+    ),
+    (
+        guid("7EC6F557-3BC5-4ACA-B293-16EF5DF639D1"),
+        PartitionType::VarTmp,
+    ),
+    (
+        guid("0FC63DAF-8483-4772-8E79-3D69D8477DE4"),
+        PartitionType::LinuxData,
+    ),
+];
+
 #[derive(Clone, Copy, Default)]
 pub struct PartitionEntry {
+    /// Partition number as used in device paths, counting from 1. Logical
+    /// MBR partitions are numbered from 5.
+    pub number: u32,
+    /// GPT partition type, zero for MBR partitions
     pub type_guid: [u8; 16],
+    /// MBR partition type, zero for GPT partitions
+    pub system_id: u8,
+    /// Whether the MBR partition is marked active
+    pub bootable: bool,
+    /// GPT unique partition GUID, or the MBR disk signature in the first four
+    /// bytes
     pub guid: [u8; 16],
     pub first_lba: u64,
     pub last_lba: u64,
-    _flags: u64,
-    _partition_name: [u32; 18],
+    /// GPT partition attribute flags, zero for MBR partitions
+    pub attributes: u64,
 }
 
 impl PartitionEntry {
+    pub fn is_mbr(&self) -> bool {
+        self.system_id != MBR_TYPE_EMPTY
+    }
+
     pub fn is_efi_partition(&self) -> bool {
-        // GUID is C12A7328-F81F-11D2-BA4B-00A0C93EC93B in mixed-endian
-        // 0-3, 4-5, 6-7 are LE, 8-19, and 10-15 are BE
-        self.type_guid
-            == [
-                0x28, 0x73, 0x2a, 0xc1, // LE C12A7328
-                0x1f, 0xf8, // LE F81F
-                0xd2, 0x11, // LE 11D2
-                0xba, 0x4b, // BE BA4B
-                0x00, 0xa0, 0xc9, 0x3e, 0xc9, 0x3b, // BE 00A0C93EC93B
-            ]
+        // Older images often use an active FAT partition instead
+        self.is_esp_type()
+            || (self.is_mbr() && self.bootable && MBR_TYPES_FAT.contains(&self.system_id))
+    }
+
+    /// Whether the partition is explicitly typed as an EFI System Partition
+    pub fn is_esp_type(&self) -> bool {
+        if self.is_mbr() {
+            return self.system_id == MBR_TYPE_EFI;
+        }
+        self.type_guid == ESP_TYPE_GUID
+    }
+
+    pub fn partition_type(&self) -> PartitionType {
+        if self.is_efi_partition() {
+            return PartitionType::Esp;
+        }
+        if self.is_mbr() {
+            return match self.system_id {
+                MBR_TYPE_XBOOTLDR => PartitionType::Xbootldr,
+                MBR_TYPE_LINUX => PartitionType::LinuxData,
+                _ => PartitionType::Unknown,
+            };
+        }
+        DISCOVERABLE_TYPES
+            .iter()
+            .find(|(guid, _)| *guid == self.type_guid)
+            .map_or(PartitionType::Unknown, |(_, t)| *t)
     }
 }
 
@@ -53,83 +249,425 @@ pub enum Error {
     #[allow(dead_code)]
     Block(BlockError),
     HeaderNotFound,
+    InvalidHeaderSize,
+    HeaderChecksumMismatch,
+    HeaderLocationMismatch,
+    InvalidPartitionEntrySize,
+    PartitionArrayChecksumMismatch,
     ViolatesSpecification,
-    ExceededPartitionCount,
     NoEFIPartition,
 }
 
-pub fn get_partitions(r: &dyn SectorRead, parts_out: &mut [PartitionEntry]) -> Result<u32, Error> {
+// Read and validate the GPT header in logical block `lba`
+fn read_gpt_header(r: &dyn SectorRead, lba: u64) -> Result<Header, Error> {
     let mut data = SectorBuf::new();
-    match r.read(1, data.as_mut_bytes()) {
+    let sectors_per_block = (r.block_size() / SectorBuf::len()) as u64;
+    match r.read(lba * sectors_per_block, data.as_mut_bytes()) {
         Ok(_) => {}
         Err(e) => return Err(Error::Block(e)),
     };
 
     // Safe as sizeof header is less than 512 bytes (size of data)
-    let h = unsafe { &*(data.as_bytes().as_ptr() as *const Header) };
+    let h = unsafe { core::ptr::read_unaligned(data.as_bytes().as_ptr() as *const Header) };
 
     // GPT magic constant
     if h.signature != 0x5452_4150_2049_4645u64 {
         return Err(Error::HeaderNotFound);
     }
 
-    if h.first_usable_lba < 34 {
+    let header_size = h.header_size as usize;
+    if header_size < core::mem::size_of::<Header>() || header_size > SectorBuf::len() {
+        return Err(Error::InvalidHeaderSize);
+    }
+
+    // The checksum covers the header with the checksum field itself zeroed
+    data.as_mut_bytes()[16..20].fill(0);
+    if crc32(&data.as_bytes()[..header_size]) != h.header_crc {
+        return Err(Error::HeaderChecksumMismatch);
+    }
+
+    if h.current_lba != lba {
+        return Err(Error::HeaderLocationMismatch);
+    }
+
+    Ok(h)
+}
+
+// Read and validate the GPT with its header in logical block `lba`,
+// including the checksum over its partition entries
+fn read_gpt(r: &dyn SectorRead, lba: u64) -> Result<Header, Error> {
+    let h = read_gpt_header(r, lba)?;
+
+    // At least 16KiB are reserved for the partition entries
+    let block_size = r.block_size() as u64;
+    let sectors_per_block = block_size / SectorBuf::len() as u64;
+    if h.first_usable_lba < 2 + 16384 / block_size {
         return Err(Error::ViolatesSpecification);
     }
 
-    let part_count = h.part_count;
-    let mut checked_part_count = 0;
+    let entry_size = h.part_entry_size as u64;
+    if entry_size < core::mem::size_of::<GptEntry>() as u64 || !entry_size.is_power_of_two() {
+        return Err(Error::InvalidPartitionEntrySize);
+    }
 
-    let first_usable_lba = h.first_usable_lba;
-    let first_part_lba = h.first_part_lba;
+    // The partition entries are either before (primary) or after (backup)
+    // the usable blocks
+    let array_bytes = u64::from(h.part_count) * entry_size;
+    let array_end = h.first_part_lba + array_bytes.div_ceil(block_size);
+    if array_end > h.first_usable_lba && h.first_part_lba <= h.last_usable_lba {
+        return Err(Error::ViolatesSpecification);
+    }
 
-    let mut current_part = 0u32;
+    let first_part_sector = h.first_part_lba * sectors_per_block;
 
-    for lba in first_part_lba..first_usable_lba {
-        match r.read(lba, data.as_mut_bytes()) {
+    let mut data = SectorBuf::new();
+    let mut crc = Crc32::new();
+    for (i, offset) in (0..array_bytes).step_by(SectorBuf::len()).enumerate() {
+        match r.read(first_part_sector + i as u64, data.as_mut_bytes()) {
             Ok(_) => {}
             Err(e) => return Err(Error::Block(e)),
         }
+        let length = u64::min(SectorBuf::len() as u64, array_bytes - offset);
+        crc.update(&data.as_bytes()[..length as usize]);
+    }
 
-        // Safe as size of partition struct * 4 is 512 bytes (size of data)
-        let parts = unsafe {
-            core::slice::from_raw_parts(data.as_bytes().as_ptr() as *const PartitionEntry, 4)
-        };
+    if crc.finish() != h.part_crc {
+        return Err(Error::PartitionArrayChecksumMismatch);
+    }
 
-        for p in parts {
-            if p.guid == [0; 16] {
-                continue;
+    Ok(h)
+}
+
+// Find the backup GPT header to use in place of a primary one that failed to
+// validate with `err`
+fn backup_gpt_lba(r: &dyn BlockDevice, err: &Error) -> Option<u64> {
+    // Without a primary header only look for a backup behind a protective MBR,
+    // a disk repartitioned with an MBR may still have a stale backup GPT
+    if matches!(err, Error::HeaderNotFound)
+        && !read_mbr(r, 0).is_ok_and(|(_, entries)| {
+            entries
+                .iter()
+                .any(|e| e.system_id == MBR_TYPE_GPT_PROTECTIVE)
+        })
+    {
+        return None;
+    }
+
+    // The backup header is in the last logical block of the disk
+    let sectors_per_block = (r.block_size() / SectorBuf::len()) as u64;
+    (r.get_capacity() / sectors_per_block).checked_sub(1)
+}
+
+// Read the primary GPT, falling back to the backup GPT if it is damaged
+fn find_gpt(r: &dyn BlockDevice) -> Result<Header, Error> {
+    let err = match read_gpt(r, 1) {
+        Ok(h) => return Ok(h),
+        Err(err) => err,
+    };
+
+    let Some(backup_lba) = backup_gpt_lba(r, &err) else {
+        return Err(err);
+    };
+    match read_gpt(r, backup_lba) {
+        Ok(h) => {
+            warn!("Primary GPT is damaged ({err:?}), using the backup GPT");
+            Ok(h)
+        }
+        Err(backup_err) => {
+            error!("Primary GPT is damaged ({err:?}) and so is the backup GPT ({backup_err:?})");
+            Err(err)
+        }
+    }
+}
+
+// Read the MBR (or an EBR) at `sector`, returning its disk signature and
+// partition entries
+fn read_mbr(r: &dyn SectorRead, sector: u64) -> Result<(u32, [MbrEntry; 4]), Error> {
+    let mut data = SectorBuf::new();
+    match r.read(sector, data.as_mut_bytes()) {
+        Ok(_) => {}
+        Err(e) => return Err(Error::Block(e)),
+    };
+
+    let data = data.as_bytes();
+    if data[510..512] != MBR_BOOT_SIGNATURE {
+        return Err(Error::HeaderNotFound);
+    }
+
+    let signature = u32::from_le_bytes(
+        data[MBR_DISK_SIGNATURE_OFFSET..MBR_DISK_SIGNATURE_OFFSET + 4]
+            .try_into()
+            .unwrap(),
+    );
+    // Safe as the four entries end at byte 510 of the sector
+    let entries = unsafe { *(data[MBR_ENTRIES_OFFSET..].as_ptr() as *const [MbrEntry; 4]) };
+
+    // A FAT boot sector also ends with the boot signature, reject anything
+    // that does not look like a partition table
+    if entries
+        .iter()
+        .any(|e| e.status != 0 && e.status != MBR_STATUS_ACTIVE)
+    {
+        return Err(Error::HeaderNotFound);
+    }
+
+    Ok((signature, entries))
+}
+
+// Read the MBR, rejecting the protective MBR of a GPT disk
+fn read_msdos_mbr(r: &dyn SectorRead) -> Result<(u32, [MbrEntry; 4]), Error> {
+    let (signature, entries) = read_mbr(r, 0)?;
+    if entries
+        .iter()
+        .any(|e| e.system_id == MBR_TYPE_GPT_PROTECTIVE)
+    {
+        return Err(Error::HeaderNotFound);
+    }
+    Ok((signature, entries))
+}
+
+fn mbr_signature_guid(signature: u32) -> [u8; 16] {
+    let mut guid = [0; 16];
+    guid[0..4].copy_from_slice(&signature.to_le_bytes());
+    guid
+}
+
+enum Table {
+    Gpt {
+        first_part_sector: u64,
+        entry_size: u64,
+        count: u32,
+    },
+    Mbr {
+        entries: [MbrEntry; 4],
+        extended_lba: Option<u64>,
+    },
+}
+
+/// Iterator over the partitions of a disk that reads the partition table as
+/// it goes. The LBAs of the entries are converted from logical blocks of the
+/// device into 512 byte sectors.
+pub struct Partitions<'a> {
+    device: &'a dyn SectorRead,
+    sectors_per_block: u64,
+    disk_guid: [u8; 16],
+    table: Table,
+    // Index of the next GPT entry or primary MBR entry
+    next: u32,
+    // Next EBR in the chain of logical MBR partitions and its number
+    ebr_lba: Option<u64>,
+    logical: u32,
+    data: SectorBuf,
+    data_sector: Option<u64>,
+}
+
+/// Open the partition table of a disk, which is the GPT or, failing that, an
+/// MBR
+pub fn partitions(r: &dyn BlockDevice) -> Result<Partitions, Error> {
+    let sectors_per_block = (r.block_size() / SectorBuf::len()) as u64;
+
+    let (disk_guid, table) = match find_gpt(r) {
+        Ok(h) => (
+            h.disk_guid,
+            Table::Gpt {
+                first_part_sector: h.first_part_lba * sectors_per_block,
+                entry_size: u64::from(h.part_entry_size),
+                count: h.part_count,
+            },
+        ),
+        Err(Error::HeaderNotFound) => {
+            let (signature, entries) = read_msdos_mbr(r)?;
+            let extended_lba = entries
+                .iter()
+                .find(|e| MBR_TYPES_EXTENDED.contains(&e.system_id) && e.sectors != 0)
+                .map(|e| u64::from(e.first_lba));
+            (
+                mbr_signature_guid(signature),
+                Table::Mbr {
+                    entries,
+                    extended_lba,
+                },
+            )
+        }
+        Err(e) => return Err(e),
+    };
+
+    let ebr_lba = match table {
+        Table::Mbr { extended_lba, .. } => extended_lba,
+        Table::Gpt { .. } => None,
+    };
+
+    Ok(Partitions {
+        device: r,
+        sectors_per_block,
+        disk_guid,
+        table,
+        next: 0,
+        ebr_lba,
+        logical: 5,
+        data: SectorBuf::new(),
+        data_sector: None,
+    })
+}
+
+impl Partitions<'_> {
+    /// The GPT disk GUID, or the MBR disk signature in the first four bytes
+    pub fn disk_guid(&self) -> [u8; 16] {
+        self.disk_guid
+    }
+
+    /// Find the first EFI system partition
+    pub fn find_efi_partition(self) -> Result<PartitionEntry, Error> {
+        // An active FAT partition is only used if there is no explicit ESP
+        let mut fallback = None;
+        for p in self {
+            let p = p?;
+            if p.is_esp_type() {
+                return Ok(p);
+            }
+            if p.is_efi_partition() && fallback.is_none() {
+                fallback = Some(p);
             }
-            parts_out[current_part as usize] = *p;
-            current_part += 1;
         }
+        fallback.ok_or(Error::NoEFIPartition)
+    }
 
-        checked_part_count += 4;
-        if checked_part_count >= part_count {
-            break;
+    /// Find the first partition of the given type that is not marked
+    /// no-auto as described by the Discoverable Partitions Specification
+    pub fn find_partition(
+        self,
+        partition_type: PartitionType,
+    ) -> Result<Option<PartitionEntry>, Error> {
+        for p in self {
+            let p = p?;
+            if p.partition_type() == partition_type && p.attributes & GPT_ATTRIBUTE_NO_AUTO == 0 {
+                return Ok(Some(p));
+            }
         }
+        Ok(None)
     }
 
-    Ok(current_part)
-}
+    fn read(&mut self, sector: u64) -> Result<&[u8], Error> {
+        if self.data_sector != Some(sector) {
+            self.data_sector = None;
+            match self.device.read(sector, self.data.as_mut_bytes()) {
+                Ok(_) => {}
+                Err(e) => return Err(Error::Block(e)),
+            }
+            self.data_sector = Some(sector);
+        }
+        Ok(self.data.as_bytes())
+    }
 
-/// Find EFI partition
-pub fn find_efi_partition(r: &dyn SectorRead) -> Result<(u64, u64), Error> {
-    // Assume no more than 16 partitions on the disk
-    let mut parts = [PartitionEntry::default(); 16];
+    fn mbr_partition(&self, number: u32, e: &MbrEntry, base: u64) -> PartitionEntry {
+        let first_lba = base + u64::from(e.first_lba);
+        PartitionEntry {
+            number,
+            system_id: e.system_id,
+            bootable: e.status == MBR_STATUS_ACTIVE,
+            guid: self.disk_guid,
+            first_lba: first_lba * self.sectors_per_block,
+            last_lba: (first_lba + u64::from(e.sectors)) * self.sectors_per_block - 1,
+            ..Default::default()
+        }
+    }
+
+    fn next_gpt(
+        &mut self,
+        first_part_sector: u64,
+        entry_size: u64,
+        count: u32,
+    ) -> Option<Result<PartitionEntry, Error>> {
+        while self.next < count {
+            let offset = u64::from(self.next) * entry_size;
+            self.next += 1;
 
-    let part_count = get_partitions(r, &mut parts)? as usize;
+            // Entries are a power of two of at least 128 bytes so the part
+            // that is used never straddles sectors
+            let sector = first_part_sector + offset / SectorBuf::len() as u64;
+            let start = (offset % SectorBuf::len() as u64) as usize;
+            let p = match self.read(sector) {
+                Ok(data) => unsafe {
+                    core::ptr::read_unaligned(data[start..].as_ptr() as *const GptEntry)
+                },
+                Err(e) => return Some(Err(e)),
+            };
 
-    for (checked_part_count, p) in (parts[0..part_count]).iter().enumerate() {
-        if p.is_efi_partition() {
-            return Ok((p.first_lba, p.last_lba));
+            if p.guid == [0; 16] {
+                continue;
+            }
+            return Some(Ok(PartitionEntry {
+                number: self.next,
+                type_guid: p.type_guid,
+                guid: p.guid,
+                first_lba: p.first_lba * self.sectors_per_block,
+                last_lba: (p.last_lba + 1) * self.sectors_per_block - 1,
+                attributes: p.attributes,
+                ..Default::default()
+            }));
         }
-        if checked_part_count == part_count {
-            return Err(Error::ExceededPartitionCount);
+        None
+    }
+
+    fn next_mbr(
+        &mut self,
+        entries: [MbrEntry; 4],
+        extended_lba: Option<u64>,
+    ) -> Option<Result<PartitionEntry, Error>> {
+        while (self.next as usize) < entries.len() {
+            let e = entries[self.next as usize];
+            self.next += 1;
+            if e.system_id == MBR_TYPE_EMPTY
+                || e.sectors == 0
+                || MBR_TYPES_EXTENDED.contains(&e.system_id)
+            {
+                continue;
+            }
+            return Some(Ok(self.mbr_partition(self.next, &e, 0)));
         }
+
+        // Logical partitions are described by a chain of EBRs: the first entry
+        // is relative to the EBR itself, the second links to the next EBR
+        // relative to the start of the extended partition.
+        while let (Some(ebr_lba), Some(extended_lba)) = (self.ebr_lba, extended_lba) {
+            self.ebr_lba = None;
+            if self.logical >= 5 + MAX_LOGICAL_PARTITIONS {
+                break;
+            }
+            let ebr = match read_mbr(self.device, ebr_lba * self.sectors_per_block) {
+                Ok((_, ebr)) => ebr,
+                Err(e) => return Some(Err(e)),
+            };
+            if MBR_TYPES_EXTENDED.contains(&ebr[1].system_id) && ebr[1].first_lba != 0 {
+                self.ebr_lba = Some(extended_lba + u64::from(ebr[1].first_lba));
+            }
+
+            let number = self.logical;
+            self.logical += 1;
+            if ebr[0].system_id != MBR_TYPE_EMPTY && ebr[0].sectors != 0 {
+                return Some(Ok(self.mbr_partition(number, &ebr[0], ebr_lba)));
+            }
+        }
+        None
     }
+}
 
-    Err(Error::NoEFIPartition)
+impl Iterator for Partitions<'_> {
+    type Item = Result<PartitionEntry, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.table {
+            Table::Gpt {
+                first_part_sector,
+                entry_size,
+                count,
+            } => self.next_gpt(first_part_sector, entry_size, count),
+            Table::Mbr {
+                entries,
+                extended_lba,
+            } => self.next_mbr(entries, extended_lba),
+        }
+    }
 }
 
 #[cfg(test)]
@@ -145,7 +683,8 @@ pub mod tests {
     use std::path::{Path, PathBuf};
 
     use crate::block;
-    use crate::block::{SectorBuf, SectorRead};
+    use crate::block::{BlockDevice, SectorBuf, SectorRead, SectorWrite};
+    use crate::common::crc32;
 
     pub struct FakeDisk {
         file: RefCell<File>,
@@ -182,6 +721,192 @@ pub mod tests {
         }
     }
 
+    impl SectorWrite for FakeDisk {
+        fn write(&self, _: u64, _: &mut [u8]) -> Result<(), block::Error> {
+            Err(block::Error::BlockNotSupported)
+        }
+
+        fn flush(&self) -> Result<(), block::Error> {
+            Ok(())
+        }
+    }
+
+    impl BlockDevice for FakeDisk {
+        fn get_capacity(&self) -> u64 {
+            self.len() / SectorBuf::len() as u64
+        }
+    }
+
+    /// The ext4 test image, mounted. The disk is leaked so the filesystem
+    /// can be handed out.
+    pub fn ext4_filesystem() -> crate::ext4::Filesystem<'static> {
+        let mut image = dirs::home_dir().unwrap();
+        image.push("workloads");
+        image.push("ext4.img");
+        let disk: &'static FakeDisk = Box::leak(Box::new(FakeDisk::new(&image)));
+        let mut fs = crate::ext4::Filesystem::new(disk, 0, disk.len() / SectorBuf::len() as u64);
+        fs.init().unwrap();
+        fs
+    }
+
+    /// A writable in memory copy of a disk image
+    pub struct RamDisk {
+        data: RefCell<Vec<u8>>,
+    }
+
+    impl RamDisk {
+        pub fn new<P: AsRef<Path>>(path: &P) -> RamDisk {
+            RamDisk {
+                data: RefCell::new(std::fs::read(path).expect("missing disk image")),
+            }
+        }
+
+        pub fn len(&self) -> u64 {
+            self.data.borrow().len() as u64
+        }
+    }
+
+    impl SectorRead for RamDisk {
+        fn read(&self, sector: u64, data: &mut [u8]) -> Result<(), block::Error> {
+            let offset = sector as usize * SectorBuf::len();
+            data.copy_from_slice(&self.data.borrow()[offset..offset + data.len()]);
+            Ok(())
+        }
+    }
+
+    impl SectorWrite for RamDisk {
+        fn write(&self, sector: u64, data: &mut [u8]) -> Result<(), block::Error> {
+            let offset = sector as usize * SectorBuf::len();
+            self.data.borrow_mut()[offset..offset + data.len()].copy_from_slice(data);
+            Ok(())
+        }
+
+        fn flush(&self) -> Result<(), block::Error> {
+            Ok(())
+        }
+    }
+
+    impl BlockDevice for RamDisk {
+        fn get_capacity(&self) -> u64 {
+            self.len() / SectorBuf::len() as u64
+        }
+    }
+
+    // An in memory disk with logical blocks of `block_size` bytes
+    struct MemDisk {
+        data: Vec<u8>,
+        block_size: usize,
+    }
+
+    impl SectorRead for MemDisk {
+        fn block_size(&self) -> usize {
+            self.block_size
+        }
+
+        fn read(&self, sector: u64, data: &mut [u8]) -> Result<(), block::Error> {
+            block::read_unaligned(self.block_size, sector, data, |sector, data| {
+                let offset = sector as usize * SectorBuf::len();
+                assert_eq!(offset % self.block_size, 0);
+                data.copy_from_slice(&self.data[offset..offset + data.len()]);
+                Ok(())
+            })
+        }
+    }
+
+    impl SectorWrite for MemDisk {
+        fn write(&self, _: u64, _: &mut [u8]) -> Result<(), block::Error> {
+            Err(block::Error::BlockNotSupported)
+        }
+
+        fn flush(&self) -> Result<(), block::Error> {
+            Ok(())
+        }
+    }
+
+    impl BlockDevice for MemDisk {
+        fn get_capacity(&self) -> u64 {
+            (self.data.len() / SectorBuf::len()) as u64
+        }
+    }
+
+    fn mbr_entry(
+        sector: &mut [u8],
+        index: usize,
+        status: u8,
+        system_id: u8,
+        first: u32,
+        size: u32,
+    ) {
+        let entry = &mut sector[446 + index * 16..446 + (index + 1) * 16];
+        entry[0] = status;
+        entry[4] = system_id;
+        entry[8..12].copy_from_slice(&first.to_le_bytes());
+        entry[12..16].copy_from_slice(&size.to_le_bytes());
+        sector[510..512].copy_from_slice(&[0x55, 0xaa]);
+    }
+
+    // Build a disk with a protective MBR, primary and backup GPT with 128
+    // partition entries of `entry_size` bytes. The first `count` entries are
+    // used, the last of them is an EFI system partition.
+    fn gpt_disk(block_size: usize, blocks: u64, entry_size: usize, count: usize) -> MemDisk {
+        let mut types = vec![([0x33; 16], 0); count];
+        types[count - 1].0 = super::ESP_TYPE_GUID;
+        gpt_disk_with_types(block_size, blocks, entry_size, &types)
+    }
+
+    // GPT disk with a partition of each (type GUID, attributes)
+    fn gpt_disk_with_types(
+        block_size: usize,
+        blocks: u64,
+        entry_size: usize,
+        types: &[([u8; 16], u64)],
+    ) -> MemDisk {
+        let mut data = vec![0u8; blocks as usize * block_size];
+        mbr_entry(&mut data[0..512], 0, 0, 0xee, 1, blocks as u32 - 1);
+
+        let entry_blocks = (128 * entry_size / block_size) as u64;
+        let first_usable = 2 + entry_blocks;
+        let last_usable = blocks - 2 - entry_blocks;
+
+        let mut entries = vec![0u8; 128 * entry_size];
+        for (i, (entry, (type_guid, attributes))) in
+            entries.chunks_mut(entry_size).zip(types).enumerate()
+        {
+            entry[0..16].copy_from_slice(type_guid);
+            entry[16..32].copy_from_slice(&[i as u8 + 1; 16]);
+            entry[32..40].copy_from_slice(&(first_usable + 10 * i as u64).to_le_bytes());
+            entry[40..48].copy_from_slice(&(first_usable + 10 * i as u64 + 9).to_le_bytes());
+            entry[48..56].copy_from_slice(&attributes.to_le_bytes());
+        }
+
+        for (lba, backup_lba, part_lba) in [
+            (1, blocks - 1, 2),
+            (blocks - 1, 1, blocks - 1 - entry_blocks),
+        ] {
+            let offset = part_lba as usize * block_size;
+            data[offset..offset + entries.len()].copy_from_slice(&entries);
+
+            let offset = lba as usize * block_size;
+            let h = &mut data[offset..offset + 92];
+            h[0..8].copy_from_slice(b"EFI PART");
+            h[8..12].copy_from_slice(&0x0001_0000u32.to_le_bytes());
+            h[12..16].copy_from_slice(&92u32.to_le_bytes());
+            h[24..32].copy_from_slice(&lba.to_le_bytes());
+            h[32..40].copy_from_slice(&backup_lba.to_le_bytes());
+            h[40..48].copy_from_slice(&first_usable.to_le_bytes());
+            h[48..56].copy_from_slice(&last_usable.to_le_bytes());
+            h[56..72].copy_from_slice(&[0x11; 16]);
+            h[72..80].copy_from_slice(&part_lba.to_le_bytes());
+            h[80..84].copy_from_slice(&128u32.to_le_bytes());
+            h[84..88].copy_from_slice(&(entry_size as u32).to_le_bytes());
+            h[88..92].copy_from_slice(&crc32(&entries).to_le_bytes());
+            let header_crc = crc32(h);
+            h[16..20].copy_from_slice(&header_crc.to_le_bytes());
+        }
+
+        MemDisk { data, block_size }
+    }
+
     pub fn clear_disk_path() -> PathBuf {
         let mut disk_path = dirs::home_dir().unwrap();
         disk_path.push("workloads");
@@ -194,12 +919,246 @@ pub mod tests {
     fn test_find_efi_partition() {
         let d = FakeDisk::new(&clear_disk_path());
 
-        match super::find_efi_partition(&d) {
-            Ok((start, end)) => {
+        match super::partitions(&d).and_then(|p| p.find_efi_partition()) {
+            Ok(p) => {
+                let (start, end) = (p.first_lba, p.last_lba);
                 assert_eq!(start, 2048);
                 assert_eq!(end, 1_048_575);
             }
             Err(e) => panic!("{e:?}"),
         }
     }
+
+    #[test]
+    fn test_get_partitions_4k() {
+        let d = gpt_disk(4096, 64, 128, 2);
+        assert_eq!(super::partitions(&d).unwrap().disk_guid(), [0x11; 16]);
+        let p = super::partitions(&d)
+            .and_then(|p| p.find_efi_partition())
+            .unwrap();
+        assert_eq!(p.number, 2);
+        assert_eq!(p.guid, [0x02; 16]);
+        assert_eq!(p.first_lba, 16 * 8);
+        assert_eq!(p.last_lba, 26 * 8 - 1);
+    }
+
+    #[test]
+    fn test_many_partitions() {
+        for entry_size in [128, 256, 1024] {
+            let d = gpt_disk(512, 512, entry_size, 24);
+            let parts: Vec<super::PartitionEntry> = super::partitions(&d)
+                .unwrap()
+                .collect::<Result<_, _>>()
+                .unwrap();
+            assert_eq!(parts.len(), 24);
+            for (i, p) in parts.iter().enumerate() {
+                assert_eq!(p.number, i as u32 + 1);
+                assert_eq!(p.guid, [i as u8 + 1; 16]);
+            }
+            let p = super::partitions(&d)
+                .and_then(|p| p.find_efi_partition())
+                .unwrap();
+            assert_eq!(p.number, 24);
+        }
+    }
+
+    #[test]
+    fn test_partition_types() {
+        use super::{Arch, PartitionType};
+
+        let root_x86_64 = crate::common::parse_guid("4F68BCE3-E8CD-4DB1-96E7-FBCAF984B709");
+        let root_arm64 = crate::common::parse_guid("B921B045-1DF0-41C3-AF44-4C6F280D3FAE");
+        let xbootldr = crate::common::parse_guid("BC13C2FF-59E6-4262-A352-B275FD6F7172");
+        let (root, _) = super::DISCOVERABLE_TYPES
+            .iter()
+            .find(|(_, t)| *t == PartitionType::Root(Arch::NATIVE))
+            .unwrap();
+
+        let d = gpt_disk_with_types(
+            512,
+            512,
+            128,
+            &[
+                (super::ESP_TYPE_GUID, 0),
+                (xbootldr.unwrap(), 0),
+                (root_arm64.unwrap(), 0),
+                (root_x86_64.unwrap(), 0),
+                ([0x33; 16], 0),
+            ],
+        );
+        let types: Vec<PartitionType> = super::partitions(&d)
+            .unwrap()
+            .map(|p| p.unwrap().partition_type())
+            .collect();
+        assert_eq!(
+            types,
+            [
+                PartitionType::Esp,
+                PartitionType::Xbootldr,
+                PartitionType::Root(Arch::Arm64),
+                PartitionType::Root(Arch::X86_64),
+                PartitionType::Unknown,
+            ]
+        );
+
+        let p = super::partitions(&d)
+            .and_then(|p| p.find_partition(PartitionType::Xbootldr))
+            .unwrap()
+            .unwrap();
+        assert_eq!(p.number, 2);
+
+        let mbr = |system_id| super::PartitionEntry {
+            system_id,
+            ..Default::default()
+        };
+        assert_eq!(mbr(0xea).partition_type(), PartitionType::Xbootldr);
+        assert_eq!(mbr(0xef).partition_type(), PartitionType::Esp);
+        assert_eq!(mbr(0x83).partition_type(), PartitionType::LinuxData);
+        assert_eq!(mbr(0x07).partition_type(), PartitionType::Unknown);
+
+        // Partitions flagged no-auto are skipped
+        let d = gpt_disk_with_types(
+            512,
+            512,
+            128,
+            &[
+                (super::ESP_TYPE_GUID, 0),
+                (*root, super::GPT_ATTRIBUTE_NO_AUTO),
+                (*root, 0),
+            ],
+        );
+        let p = super::partitions(&d)
+            .and_then(|p| p.find_partition(PartitionType::Root(Arch::NATIVE)))
+            .unwrap()
+            .unwrap();
+        assert_eq!(p.number, 3);
+        assert_eq!(p.guid, [3; 16]);
+
+        let d = gpt_disk(512, 128, 128, 1);
+        assert!(super::partitions(&d)
+            .and_then(|p| p.find_partition(PartitionType::Root(Arch::NATIVE)))
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_gpt_backup() {
+        let esp_start = |d: &MemDisk| {
+            super::partitions(d)
+                .and_then(|p| p.find_efi_partition())
+                .map(|p| p.first_lba)
+        };
+
+        let d = gpt_disk(512, 128, 128, 1);
+        assert_eq!(esp_start(&d).unwrap(), 34);
+
+        // Damaged primary header
+        let mut d = gpt_disk(512, 128, 128, 1);
+        d.data[512 + 56] ^= 0xff;
+        assert!(matches!(
+            super::read_gpt_header(&d, 1),
+            Err(super::Error::HeaderChecksumMismatch)
+        ));
+        assert_eq!(esp_start(&d).unwrap(), 34);
+        assert_eq!(super::partitions(&d).unwrap().disk_guid(), [0x11; 16]);
+
+        // Damaged primary partition array
+        let mut d = gpt_disk(512, 128, 128, 1);
+        d.data[2 * 512 + 40] ^= 0xff;
+        assert!(matches!(
+            super::read_gpt(&d, 1),
+            Err(super::Error::PartitionArrayChecksumMismatch)
+        ));
+        assert_eq!(esp_start(&d).unwrap(), 34);
+
+        // Missing primary header
+        let mut d = gpt_disk(512, 128, 128, 1);
+        d.data[512..1024].fill(0);
+        assert_eq!(esp_start(&d).unwrap(), 34);
+
+        // Both headers damaged
+        let mut d = gpt_disk(512, 128, 128, 1);
+        d.data[512 + 56] ^= 0xff;
+        d.data[127 * 512 + 56] ^= 0xff;
+        assert!(matches!(
+            esp_start(&d),
+            Err(super::Error::HeaderChecksumMismatch)
+        ));
+
+        // Without a protective MBR a missing primary header means no GPT
+        let mut d = gpt_disk(512, 128, 128, 1);
+        d.data[0..1024].fill(0);
+        assert!(matches!(esp_start(&d), Err(super::Error::HeaderNotFound)));
+    }
+
+    #[test]
+    fn test_get_partitions_mbr() {
+        let mut disk = vec![0u8; 1024 * 512];
+        disk[440..444].copy_from_slice(&0x1234_5678u32.to_le_bytes());
+        // Primary Linux partition, an extended partition and an active FAT16
+        mbr_entry(&mut disk[0..512], 0, 0, 0x83, 64, 64);
+        mbr_entry(&mut disk[0..512], 1, 0, 0x05, 256, 512);
+        mbr_entry(&mut disk[0..512], 3, 0x80, 0x06, 128, 64);
+        // Two logical partitions, the second one an ESP
+        mbr_entry(&mut disk[256 * 512..257 * 512], 0, 0, 0x83, 16, 32);
+        mbr_entry(&mut disk[256 * 512..257 * 512], 1, 0, 0x05, 128, 128);
+        mbr_entry(&mut disk[384 * 512..385 * 512], 0, 0, 0xef, 16, 100);
+
+        let d = MemDisk {
+            data: disk,
+            block_size: 512,
+        };
+        let partitions = super::partitions(&d).unwrap();
+        assert_eq!(partitions.disk_guid()[0..4], [0x78, 0x56, 0x34, 0x12]);
+        let parts: Vec<super::PartitionEntry> = partitions.collect::<Result<_, _>>().unwrap();
+        let found: Vec<(u32, u8, bool, u64, u64)> = parts
+            .iter()
+            .map(|p| (p.number, p.system_id, p.bootable, p.first_lba, p.last_lba))
+            .collect();
+        assert_eq!(
+            found,
+            [
+                (1, 0x83, false, 64, 127),
+                (4, 0x06, true, 128, 191),
+                (5, 0x83, false, 272, 303),
+                (6, 0xef, false, 400, 499),
+            ]
+        );
+        assert!(parts
+            .iter()
+            .all(|p| p.guid[0..4] == [0x78, 0x56, 0x34, 0x12]));
+
+        // The ESP wins over the active FAT partition before it
+        let p = super::partitions(&d)
+            .and_then(|p| p.find_efi_partition())
+            .unwrap();
+        assert_eq!(p.number, 6);
+
+        // Which is used when there is no ESP
+        let mut disk = d.data;
+        mbr_entry(&mut disk[384 * 512..385 * 512], 0, 0, 0x83, 16, 100);
+        let d = MemDisk {
+            data: disk,
+            block_size: 512,
+        };
+        let p = super::partitions(&d)
+            .and_then(|p| p.find_efi_partition())
+            .unwrap();
+        assert_eq!(p.number, 4);
+    }
+
+    #[test]
+    fn test_protective_mbr() {
+        let mut disk = vec![0u8; 64 * 512];
+        mbr_entry(&mut disk[0..512], 0, 0, 0xee, 1, 63);
+
+        let d = MemDisk {
+            data: disk,
+            block_size: 512,
+        };
+        assert!(matches!(
+            super::partitions(&d),
+            Err(super::Error::HeaderNotFound)
+        ));
+    }
 }
@@ -172,6 +172,24 @@ where
     }
 }
 
+pub fn with_class<F>(class: u8, subclass: u8, prog_if: u8, mut per_device: F)
+where
+    F: FnMut(PciDevice) -> bool,
+{
+    let target_class_code = u32::from(class) << 16 | u32::from(subclass) << 8 | u32::from(prog_if);
+    for device in 0..MAX_DEVICES {
+        let (vendor_id, _) = get_device_details(0, device, 0);
+        if vendor_id == INVALID_VENDOR_ID {
+            continue;
+        }
+        // Class code, subclass and programming interface are bits 31-8
+        let class_code = PCI_CONFIG.borrow_mut().read(0, device, 0, 0x8) >> 8;
+        if class_code == target_class_code && per_device(PciDevice::new(0, device, 0)) {
+            break;
+        }
+    }
+}
+
 fn naturally_align(address: u64, size: u64) -> u64 {
     address.div_ceil(size) * size
 }
@@ -327,6 +345,14 @@ impl PciDevice {
         }
     }
 
+    pub fn enable_bus_master(&self) {
+        self.write_u32(0x4, self.read_u32(0x4) | 0x4);
+    }
+
+    pub fn bar_region(&self, bar: usize) -> mem::MemoryRegion {
+        mem::MemoryRegion::new(self.bars[bar].address, self.bars[bar].size)
+    }
+
     pub fn allocate_bars(&mut self, start_address: Option<u64>) -> Option<u64> {
         let mut next_address = start_address;
 
@@ -573,6 +599,9 @@ impl VirtioTransport for VirtioPciTransport {
     }
 
     fn notify_queue(&self, queue: u16) {
+        // queue_select: 0x16
+        self.region.io_write_u16(0x16, queue);
+
         // queue_notify_off: 0x1e
         let queue_notify_off = self.region.io_read_u16(0x1e);
 
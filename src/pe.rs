@@ -1,10 +1,18 @@
 // SPDX-License-Identifier: Apache-2.0
 // Copyright © 2019 Intel Corporation
 
-use crate::{block::SectorBuf, mem::MemoryRegion};
+use core::mem::size_of;
+
+use crate::{
+    block::SectorBuf,
+    fs::{self, Read},
+    mem::MemoryRegion,
+};
+
+const HEADER_SIZE: usize = 1024;
 
 pub struct Loader<'a> {
-    file: &'a mut dyn crate::fat::Read,
+    file: &'a mut dyn Read,
     num_sections: u16,
     image_base: u64,
     image_size: u32,
@@ -18,7 +26,7 @@ pub enum Error {
 
 #[repr(C, packed)]
 struct Section {
-    _name: [u8; 8],
+    name: [u8; 8],
     virt_size: u32,
     virt_address: u32,
     raw_size: u32,
@@ -26,6 +34,55 @@ struct Section {
     _unused: [u8; 16],
 }
 
+/// The contents of a section of an executable, read from the file without
+/// loading it. Sections must start on a sector boundary.
+pub struct SectionFile<'a> {
+    file: &'a mut dyn Read,
+    offset: u32,
+    size: u32,
+    position: u32,
+}
+
+impl Read for SectionFile<'_> {
+    fn read(&mut self, data: &mut [u8]) -> Result<u32, fs::Error> {
+        if self.position >= self.size {
+            return Err(fs::Error::EndOfFile);
+        }
+        let bytes = self.file.read(data)?.min(self.size - self.position);
+        self.position += SectorBuf::len() as u32;
+        Ok(bytes)
+    }
+
+    fn seek(&mut self, position: u32) -> Result<(), fs::Error> {
+        if position >= self.size {
+            return Err(fs::Error::EndOfFile);
+        }
+        self.file.seek(self.offset + position)?;
+        self.position = position;
+        Ok(())
+    }
+
+    fn get_size(&self) -> u32 {
+        self.size
+    }
+
+    // Leave it to the file so large sections load in as few requests as it
+    // can manage rather than a sector at a time
+    fn load_file(&mut self, mem: &mut MemoryRegion) -> Result<(), fs::Error> {
+        let len = mem.as_bytes().len() as u64;
+        if u64::from(self.position) + len > u64::from(self.size) {
+            return Err(fs::Error::EndOfFile);
+        }
+        self.file.seek(self.offset + self.position)?;
+        self.file.load_file(mem)?;
+        self.position += len as u32;
+        if self.position < self.size {
+            self.file.seek(self.offset + self.position)?;
+        }
+        Ok(())
+    }
+}
+
 impl<'a> Loader<'a> {
     #[cfg(target_arch = "aarch64")]
     const MACHINE_TYPE: u16 = 0xaa64;
@@ -42,7 +99,7 @@ impl<'a> Loader<'a> {
     ))]
     const OPTIONAL_HEADER_MAGIC: u16 = 0x20b; // PE32+
 
-    pub fn new(file: &'a mut dyn crate::fat::Read) -> Loader<'a> {
+    pub fn new(file: &'a mut dyn Read) -> Loader<'a> {
         Loader {
             file,
             num_sections: 0,
@@ -51,8 +108,9 @@ impl<'a> Loader<'a> {
         }
     }
 
-    pub fn load(&mut self, load_addr: u64) -> Result<(u64, u64, u64), Error> {
-        const HEADER_SIZE: usize = 1024;
+    // Read the headers of a supported executable, returning them and the
+    // offset of the PE header within them
+    fn read_header(&mut self) -> Result<([u8; HEADER_SIZE], u32), Error> {
         let mut data = [0_u8; HEADER_SIZE];
         assert!(data.len() % 512 == 0);
 
@@ -108,13 +166,78 @@ impl<'a> Loader<'a> {
 
         self.num_sections = pe_region.read_u16(6);
 
-        let optional_header_size = pe_region.read_u16(20);
         let optional_region = MemoryRegion::from_bytes(&data[(24 + pe_header_offset) as usize..]);
 
         if optional_region.read_u16(0) != Self::OPTIONAL_HEADER_MAGIC {
             return Err(Error::InvalidExecutable);
         }
 
+        Ok((data, pe_header_offset))
+    }
+
+    // The section table, which must be within the headers that were read
+    fn sections<'d>(
+        &self,
+        data: &'d [u8; HEADER_SIZE],
+        pe_header_offset: u32,
+    ) -> Result<&'d [Section], Error> {
+        let pe_region = MemoryRegion::from_bytes(&data[pe_header_offset as usize..]);
+        let optional_header_size = pe_region.read_u16(20);
+        let start = (24 + pe_header_offset + u32::from(optional_header_size)) as usize;
+        let end = start + self.num_sections as usize * size_of::<Section>();
+        if end > data.len() {
+            return Err(Error::InvalidExecutable);
+        }
+
+        let sections = &data[start..end];
+        Ok(unsafe {
+            core::slice::from_raw_parts(
+                sections.as_ptr() as *const Section,
+                self.num_sections as usize,
+            )
+        })
+    }
+
+    /// Whether the file is an executable for this architecture
+    pub fn is_executable(&mut self) -> bool {
+        self.file.seek(0).is_ok() && self.read_header().is_ok()
+    }
+
+    /// Find the section called `name` without loading the executable,
+    /// returning its contents as a file
+    pub fn section(&mut self, name: &[u8]) -> Result<Option<SectionFile<'_>>, Error> {
+        match self.file.seek(0) {
+            Ok(_) => {}
+            Err(_) => return Err(Error::FileError),
+        }
+        let (data, pe_header_offset) = self.read_header()?;
+        let section = self
+            .sections(&data, pe_header_offset)?
+            .iter()
+            .find(|s| s.name.split(|c| *c == 0).next() == Some(name))
+            .map(|s| (s.raw_offset, core::cmp::min(s.raw_size, s.virt_size)));
+        let (offset, size) = match section {
+            Some(section) => section,
+            None => return Ok(None),
+        };
+
+        match self.file.seek(offset) {
+            Ok(_) => {}
+            Err(_) => return Err(Error::FileError),
+        }
+        Ok(Some(SectionFile {
+            file: &mut *self.file,
+            offset,
+            size,
+            position: 0,
+        }))
+    }
+
+    pub fn load(&mut self, load_addr: u64) -> Result<(u64, u64, u64), Error> {
+        let (data, pe_header_offset) = self.read_header()?;
+        let sector_size = SectorBuf::len();
+        let optional_region = MemoryRegion::from_bytes(&data[(24 + pe_header_offset) as usize..]);
+
         let entry_point = optional_region.read_u32(16);
 
         self.image_base = optional_region.read_u64(24);
@@ -127,13 +250,7 @@ impl<'a> Loader<'a> {
         self.image_size = optional_region.read_u32(56);
         let size_of_headers = optional_region.read_u32(60);
 
-        let sections = &data[(24 + pe_header_offset + u32::from(optional_header_size)) as usize..];
-        let sections: &[Section] = unsafe {
-            core::slice::from_raw_parts(
-                sections.as_ptr() as *const Section,
-                self.num_sections as usize,
-            )
-        };
+        let sections = self.sections(&data, pe_header_offset)?;
 
         let image_info = (
             address + u64::from(entry_point),
@@ -149,18 +266,12 @@ impl<'a> Loader<'a> {
             Err(_) => return Err(Error::FileError),
         }
 
-        let mut header_offset = 0u64;
-        while header_offset < u64::from(size_of_headers) {
-            match self
-                .file
-                .read(loaded_region.as_mut_slice(header_offset, sector_size as u64))
-            {
-                Ok(_) => {}
-                Err(_) => {
-                    return Err(Error::FileError);
-                }
-            }
-            header_offset += sector_size as u64;
+        let header_size = u64::from(size_of_headers).next_multiple_of(sector_size as u64);
+        let mut header_region =
+            MemoryRegion::from_bytes(loaded_region.as_mut_slice(0, header_size));
+        match self.file.load_file(&mut header_region) {
+            Ok(_) => {}
+            Err(_) => return Err(Error::FileError),
         }
 
         for section in sections {
@@ -178,26 +289,18 @@ impl<'a> Loader<'a> {
                 Err(_) => return Err(Error::FileError),
             }
 
-            let mut section_data = SectorBuf::new();
-
-            let mut section_offset = 0;
             let section_size = core::cmp::min(section.raw_size, section.virt_size);
-            while section_offset < section_size {
-                let remaining_bytes =
-                    core::cmp::min(section_size - section_offset, sector_size as u32);
-                match self.file.read(section_data.as_mut_bytes()) {
-                    Ok(_) => {}
-                    Err(_) => {
-                        return Err(Error::FileError);
-                    }
-                }
+            if section_size == 0 {
+                continue;
+            }
 
-                let l: &mut [u8] = loaded_region.as_mut_slice(
-                    u64::from(section.virt_address + section_offset),
-                    u64::from(remaining_bytes),
-                );
-                l.copy_from_slice(&section_data.as_bytes()[0..remaining_bytes as usize]);
-                section_offset += remaining_bytes;
+            let mut section_region = MemoryRegion::from_bytes(
+                loaded_region
+                    .as_mut_slice(u64::from(section.virt_address), u64::from(section_size)),
+            );
+            match self.file.load_file(&mut section_region) {
+                Ok(_) => {}
+                Err(_) => return Err(Error::FileError),
             }
         }
 
@@ -261,16 +364,89 @@ impl<'a> Loader<'a> {
 
 #[cfg(test)]
 mod tests {
-    use crate::part::tests::*;
+    use crate::{
+        compression::tests::Bytes,
+        fs::{self, Read},
+        mem::MemoryRegion,
+        part::tests::*,
+    };
 
     use std::alloc;
 
+    // Counts how the file is read
+    struct Counted<'a> {
+        file: Bytes<'a>,
+        reads: usize,
+        loads: usize,
+    }
+
+    impl Read for Counted<'_> {
+        fn read(&mut self, data: &mut [u8]) -> Result<u32, fs::Error> {
+            self.reads += 1;
+            self.file.read(data)
+        }
+
+        fn seek(&mut self, position: u32) -> Result<(), fs::Error> {
+            self.file.seek(position)
+        }
+
+        fn get_size(&self) -> u32 {
+            self.file.get_size()
+        }
+
+        fn load_file(&mut self, mem: &mut MemoryRegion) -> Result<(), fs::Error> {
+            self.loads += 1;
+            self.file.load_file(mem)
+        }
+    }
+
+    #[test]
+    fn test_section_load_file() {
+        let data: Vec<u8> = (0..8192u32).map(|i| (i % 251) as u8).collect();
+        let mut file = Counted {
+            file: Bytes::new(&data),
+            reads: 0,
+            loads: 0,
+        };
+        let mut section = super::SectionFile {
+            file: &mut file,
+            offset: 1024,
+            size: 5000,
+            position: 0,
+        };
+
+        section.seek(512).unwrap();
+        let loaded = vec![0u8; 4000];
+        section
+            .load_file(&mut MemoryRegion::from_bytes(&loaded))
+            .unwrap();
+        assert_eq!(loaded, data[1536..5536]);
+
+        // Only what is left of the section can be loaded
+        let loaded = vec![0u8; 489];
+        assert_eq!(
+            section.load_file(&mut MemoryRegion::from_bytes(&loaded)),
+            Err(fs::Error::EndOfFile)
+        );
+        let loaded = vec![0u8; 488];
+        section
+            .load_file(&mut MemoryRegion::from_bytes(&loaded))
+            .unwrap();
+        assert_eq!(loaded, data[5536..6024]);
+
+        // Handed to the file as a whole
+        assert_eq!((file.loads, file.reads), (2, 0));
+    }
+
     // TODO: Add aarch64 specific loader test target
     #[cfg(target_arch = "x86_64")]
     #[test]
     fn test_loader() {
         let d = FakeDisk::new(&clear_disk_path());
-        let (start, end) = crate::part::find_efi_partition(&d).unwrap();
+        let p = crate::part::partitions(&d)
+            .and_then(|p| p.find_efi_partition())
+            .unwrap();
+        let (start, end) = (p.first_lba, p.last_lba);
 
         let mut f = crate::fat::Filesystem::new(&d, start, end);
         f.init().unwrap();
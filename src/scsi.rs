@@ -0,0 +1,445 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2026 The Rust Hypervisor Firmware Authors
+
+use core::cell::RefCell;
+
+use heapless::Vec;
+use log::{info, warn};
+
+use crate::{
+    block::{
+        is_supported_block_size, read_unaligned, write_unaligned, BlockDevice, Error as BlockError,
+        SectorBuf, SectorRead, SectorWrite,
+    },
+    virtio::{Buffer, Error as VirtioError, VirtioTransport, Virtqueue, QUEUE_SIZE},
+};
+
+// The control and event queues come before the first request queue
+const REQUEST_QUEUE: u16 = 2;
+
+const CDB_SIZE: usize = 32;
+const SENSE_SIZE: usize = 96;
+
+// Only the first LUNs of each target are probed
+const MAX_LUNS: u32 = 8;
+const MAX_TARGETS: u16 = 256;
+
+const VIRTIO_SCSI_S_OK: u8 = 0;
+
+const SCSI_STATUS_GOOD: u8 = 0x00;
+const SCSI_STATUS_CHECK_CONDITION: u8 = 0x02;
+const SCSI_SENSE_UNIT_ATTENTION: u8 = 0x06;
+
+const SCSI_TYPE_DISK: u8 = 0x00;
+
+// SCSI commands
+const INQUIRY: u8 = 0x12;
+const SYNCHRONIZE_CACHE_10: u8 = 0x35;
+const READ_16: u8 = 0x88;
+const WRITE_16: u8 = 0x8a;
+const SERVICE_ACTION_IN_16: u8 = 0x9e;
+const READ_CAPACITY_16: u8 = 0x10;
+
+#[repr(C, packed)]
+/// Header used for virtio-scsi requests
+struct RequestHeader {
+    lun: [u8; 8],
+    id: u64,
+    task_attr: u8,
+    prio: u8,
+    crn: u8,
+    cdb: [u8; CDB_SIZE],
+}
+
+#[repr(C, packed)]
+/// Footer used for virtio-scsi requests
+struct ResponseFooter {
+    sense_len: u32,
+    residual: u32,
+    status_qualifier: u16,
+    status: u8,
+    response: u8,
+    sense: [u8; SENSE_SIZE],
+}
+
+#[derive(Debug)]
+enum CommandError {
+    // Transport level failure, e.g. a target that does not exist
+    #[allow(dead_code)]
+    Response(u8),
+    // SCSI status with the sense key
+    #[allow(dead_code)]
+    Status(u8, u8),
+    Residual,
+}
+
+enum Data<'b> {
+    None,
+    In(&'b mut [u8]),
+    Out(&'b [u8]),
+}
+
+#[repr(C)]
+#[repr(align(64))]
+/// Device driver for virtio-scsi over any transport
+pub struct VirtioScsiController<'a> {
+    transport: &'a mut dyn VirtioTransport,
+    queue: RefCell<Virtqueue>,
+    cdb_size: usize,
+    sense_size: usize,
+    max_sectors: u32,
+    max_target: u16,
+    max_lun: u32,
+}
+
+/// A logical unit attached to a virtio-scsi controller
+pub struct ScsiDisk<'a, 'b> {
+    controller: &'b VirtioScsiController<'a>,
+    lun: [u8; 8],
+    capacity: u64,
+    block_size: usize,
+}
+
+impl<'a> VirtioScsiController<'a> {
+    pub fn new(transport: &'a mut dyn VirtioTransport) -> VirtioScsiController<'a> {
+        VirtioScsiController {
+            transport,
+            queue: RefCell::new(Virtqueue::default()),
+            cdb_size: CDB_SIZE,
+            sense_size: SENSE_SIZE,
+            max_sectors: 1,
+            max_target: 0,
+            max_lun: 0,
+        }
+    }
+
+    pub fn init(&mut self) -> Result<(), VirtioError> {
+        const VIRTIO_SUBSYSTEM_SCSI: u32 = 0x8;
+        const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+        const VIRTIO_STATUS_RESET: u32 = 0;
+        const VIRTIO_STATUS_ACKNOWLEDGE: u32 = 1;
+        const VIRTIO_STATUS_DRIVER: u32 = 2;
+        const VIRTIO_STATUS_FEATURES_OK: u32 = 8;
+        const VIRTIO_STATUS_DRIVER_OK: u32 = 4;
+        const VIRTIO_STATUS_FAILED: u32 = 128;
+
+        // Initialise the transport
+        self.transport.init(VIRTIO_SUBSYSTEM_SCSI)?;
+
+        // Reset device
+        self.transport.set_status(VIRTIO_STATUS_RESET);
+
+        // Acknowledge
+        self.transport.add_status(VIRTIO_STATUS_ACKNOWLEDGE);
+
+        // And advertise driver
+        self.transport.add_status(VIRTIO_STATUS_DRIVER);
+
+        // Request device features
+        let device_features = self.transport.get_features();
+
+        if device_features & VIRTIO_F_VERSION_1 != VIRTIO_F_VERSION_1 {
+            self.transport.add_status(VIRTIO_STATUS_FAILED);
+            return Err(VirtioError::LegacyOnly);
+        }
+
+        // Don't support any advanced features (hotplug, T10 PI) for now
+        self.transport.set_features(VIRTIO_F_VERSION_1);
+
+        self.transport.add_status(VIRTIO_STATUS_FEATURES_OK);
+        if self.transport.get_status() & VIRTIO_STATUS_FEATURES_OK != VIRTIO_STATUS_FEATURES_OK {
+            self.transport.add_status(VIRTIO_STATUS_FAILED);
+            return Err(VirtioError::FeatureNegotiationFailed);
+        }
+
+        // max_sectors: 0x08, sense_size: 0x14, cdb_size: 0x18, max_target: 0x1e, max_lun: 0x20
+        self.max_sectors = u32::max(self.transport.read_device_config(0x08), 1);
+        self.sense_size = self.transport.read_device_config(0x14) as usize;
+        self.cdb_size = self.transport.read_device_config(0x18) as usize;
+        self.max_target = (self.transport.read_device_config(0x1c) >> 16) as u16;
+        self.max_lun = self.transport.read_device_config(0x20);
+
+        if self.cdb_size < 16 || self.cdb_size > CDB_SIZE || self.sense_size > SENSE_SIZE {
+            self.transport.add_status(VIRTIO_STATUS_FAILED);
+            return Err(VirtioError::UnsupportedDevice);
+        }
+
+        // Program queues
+        if let Err(err) = self.queue.borrow().setup(self.transport, REQUEST_QUEUE) {
+            self.transport.add_status(VIRTIO_STATUS_FAILED);
+            return Err(err);
+        }
+
+        // Report driver ready
+        self.transport.add_status(VIRTIO_STATUS_DRIVER_OK);
+
+        Ok(())
+    }
+
+    fn command(&self, lun: &[u8; 8], cdb: &[u8], data: Data) -> Result<(), CommandError> {
+        let mut header = RequestHeader {
+            lun: *lun,
+            id: 0,
+            task_attr: 0,
+            prio: 0,
+            crn: 0,
+            cdb: [0; CDB_SIZE],
+        };
+        header.cdb[..cdb.len()].copy_from_slice(cdb);
+
+        let mut footer = ResponseFooter {
+            sense_len: 0,
+            residual: 0,
+            status_qualifier: 0,
+            status: 0,
+            response: 0,
+            sense: [0; SENSE_SIZE],
+        };
+
+        // The device expects the CDB and sense sizes from its configuration
+        let mut request = Buffer::readable(&header);
+        request.length -= (CDB_SIZE - self.cdb_size) as u32;
+        let mut response = Buffer::writable(&mut footer);
+        response.length -= (SENSE_SIZE - self.sense_size) as u32;
+
+        // Device readable buffers need to come first
+        let mut buffers: Vec<Buffer, QUEUE_SIZE> = Vec::new();
+        buffers.push(request).unwrap();
+        match data {
+            Data::None => buffers.push(response).unwrap(),
+            Data::Out(data) => {
+                buffers.push(Buffer::readable(data)).unwrap();
+                buffers.push(response).unwrap();
+            }
+            Data::In(data) => {
+                buffers.push(response).unwrap();
+                buffers.push(Buffer::writable(data)).unwrap();
+            }
+        }
+
+        self.queue
+            .borrow_mut()
+            .submit(self.transport, REQUEST_QUEUE, &buffers);
+
+        if footer.response != VIRTIO_SCSI_S_OK {
+            return Err(CommandError::Response(footer.response));
+        }
+        match footer.status {
+            SCSI_STATUS_GOOD => {}
+            SCSI_STATUS_CHECK_CONDITION => {
+                // Fixed (0x70/0x71) or descriptor (0x72/0x73) format sense data
+                let sense_key = match footer.sense[0] & 0x7f {
+                    0x72 | 0x73 => footer.sense[1] & 0xf,
+                    _ => footer.sense[2] & 0xf,
+                };
+                return Err(CommandError::Status(footer.status, sense_key));
+            }
+            status => return Err(CommandError::Status(status, 0)),
+        }
+        if footer.residual != 0 {
+            return Err(CommandError::Residual);
+        }
+        Ok(())
+    }
+
+    // Retry commands that fail with a unit attention condition, as reported
+    // for the first command after a reset.
+    fn command_with_retry(
+        &self,
+        lun: &[u8; 8],
+        cdb: &[u8],
+        mut data: Data,
+    ) -> Result<(), CommandError> {
+        let mut retries = 3;
+        loop {
+            let attempt = match &mut data {
+                Data::None => Data::None,
+                Data::In(data) => Data::In(data),
+                Data::Out(data) => Data::Out(data),
+            };
+            match self.command(lun, cdb, attempt) {
+                Err(CommandError::Status(
+                    SCSI_STATUS_CHECK_CONDITION,
+                    SCSI_SENSE_UNIT_ATTENTION,
+                )) if retries > 0 => retries -= 1,
+                result => return result,
+            }
+        }
+    }
+
+    fn lun_address(target: u16, lun: u32) -> [u8; 8] {
+        // Single level LUN structure with flat space addressing
+        let flat = (0x4000 | (lun & 0x3fff)) as u16;
+        let mut address = [0u8; 8];
+        address[0] = 1;
+        address[1] = target as u8;
+        address[2..4].copy_from_slice(&flat.to_be_bytes());
+        address
+    }
+
+    fn probe_lun<'b>(
+        &'b self,
+        target: u16,
+        lun: u32,
+    ) -> Result<Option<ScsiDisk<'a, 'b>>, CommandError> {
+        let address = Self::lun_address(target, lun);
+
+        let mut inquiry = [0u8; 36];
+        let cdb = [INQUIRY, 0, 0, 0, inquiry.len() as u8, 0];
+        self.command_with_retry(&address, &cdb, Data::In(&mut inquiry))?;
+
+        // Peripheral qualifier 0 (connected) and a direct access block device
+        if inquiry[0] != SCSI_TYPE_DISK {
+            return Ok(None);
+        }
+
+        let mut capacity = [0u8; 32];
+        let mut cdb = [0u8; 16];
+        cdb[0] = SERVICE_ACTION_IN_16;
+        cdb[1] = READ_CAPACITY_16;
+        cdb[10..14].copy_from_slice(&(capacity.len() as u32).to_be_bytes());
+        self.command_with_retry(&address, &cdb, Data::In(&mut capacity))?;
+
+        let last_lba = u64::from_be_bytes(capacity[0..8].try_into().unwrap());
+        let block_size = u32::from_be_bytes(capacity[8..12].try_into().unwrap());
+        if !is_supported_block_size(block_size as usize) {
+            warn!("Ignoring SCSI disk {target}:{lun} with {block_size} byte blocks");
+            return Ok(None);
+        }
+
+        info!(
+            "SCSI disk {target}:{lun} configured. Capacity: {} blocks of {block_size} bytes",
+            last_lba + 1
+        );
+        Ok(Some(ScsiDisk {
+            controller: self,
+            lun: address,
+            capacity: (last_lba + 1) * u64::from(block_size) / SectorBuf::len() as u64,
+            block_size: block_size as usize,
+        }))
+    }
+
+    /// Call `per_disk` for each disk attached to the controller until it
+    /// returns true
+    pub fn scan<'b, F>(&'b self, mut per_disk: F)
+    where
+        F: FnMut(ScsiDisk<'a, 'b>) -> bool,
+    {
+        for target in 0..=u16::min(self.max_target, MAX_TARGETS - 1) {
+            for lun in 0..=u32::min(self.max_lun, MAX_LUNS - 1) {
+                match self.probe_lun(target, lun) {
+                    Ok(Some(disk)) => {
+                        if per_disk(disk) {
+                            return;
+                        }
+                    }
+                    Ok(None) => {}
+                    // Nothing behind this target
+                    Err(_) if lun == 0 => break,
+                    Err(_) => {}
+                }
+            }
+        }
+    }
+}
+
+impl ScsiDisk<'_, '_> {
+    // Transfer whole logical blocks starting at the block holding `sector`
+    fn io(&self, opcode: u8, sector: u64, data: Data) -> Result<(), BlockError> {
+        let length = match &data {
+            Data::None => 0,
+            Data::In(data) => data.len(),
+            Data::Out(data) => data.len(),
+        };
+        if length == 0 || length % self.block_size != 0 {
+            return Err(BlockError::InvalidDataBufSize);
+        }
+
+        let lba = sector * SectorBuf::len() as u64 / self.block_size as u64;
+        let mut cdb = [0u8; 16];
+        cdb[0] = opcode;
+        cdb[2..10].copy_from_slice(&lba.to_be_bytes());
+        cdb[10..14].copy_from_slice(&((length / self.block_size) as u32).to_be_bytes());
+
+        self.controller
+            .command_with_retry(&self.lun, &cdb, data)
+            .map_err(|_| BlockError::BlockIO)
+    }
+
+    // Largest transfer the device accepts in a single command
+    fn max_transfer(&self) -> usize {
+        let max_transfer = self.controller.max_sectors as usize * SectorBuf::len();
+        usize::max(
+            max_transfer - max_transfer % self.block_size,
+            self.block_size,
+        )
+    }
+}
+
+impl SectorRead for ScsiDisk<'_, '_> {
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn read(&self, sector: u64, data: &mut [u8]) -> Result<(), BlockError> {
+        if data.len() != SectorBuf::len() {
+            return Err(BlockError::InvalidDataBufSize);
+        }
+        self.read_sectors(sector, data)
+    }
+
+    fn read_sectors(&self, sector: u64, data: &mut [u8]) -> Result<(), BlockError> {
+        let request_size = self.max_transfer();
+        read_unaligned(self.block_size, sector, data, |sector, data| {
+            for (i, chunk) in data.chunks_mut(request_size).enumerate() {
+                let offset = (i * request_size / SectorBuf::len()) as u64;
+                self.io(READ_16, sector + offset, Data::In(chunk))?;
+            }
+            Ok(())
+        })
+    }
+}
+
+impl SectorWrite for ScsiDisk<'_, '_> {
+    fn write(&self, sector: u64, data: &mut [u8]) -> Result<(), BlockError> {
+        write_unaligned(
+            self.block_size,
+            sector,
+            data,
+            |sector, data| self.io(READ_16, sector, Data::In(data)),
+            |sector, data| self.io(WRITE_16, sector, Data::Out(data)),
+        )
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        // Zero LBA and length covers the whole medium
+        let cdb = [SYNCHRONIZE_CACHE_10, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        self.controller
+            .command_with_retry(&self.lun, &cdb, Data::None)
+            .map_err(|_| BlockError::BlockIO)
+    }
+}
+
+impl BlockDevice for ScsiDisk<'_, '_> {
+    fn get_capacity(&self) -> u64 {
+        self.capacity
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::VirtioScsiController;
+
+    #[test]
+    fn test_lun_address() {
+        assert_eq!(
+            VirtioScsiController::lun_address(0, 0),
+            [1, 0, 0x40, 0, 0, 0, 0, 0]
+        );
+        assert_eq!(
+            VirtioScsiController::lun_address(3, 0x105),
+            [1, 3, 0x41, 0x05, 0, 0, 0, 0]
+        );
+    }
+}
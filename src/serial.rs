@@ -38,6 +38,14 @@ impl fmt::Write for Serial {
     }
 }
 
+/// Read a byte from the serial port if one has been received
+pub fn read_byte() -> Option<u8> {
+    #[cfg(target_arch = "x86_64")]
+    return PORT.borrow_mut().try_receive().ok();
+    #[cfg(not(target_arch = "x86_64"))]
+    return PORT.borrow_mut().try_receive();
+}
+
 #[macro_export]
 macro_rules! log {
     ($($arg:tt)*) => {{
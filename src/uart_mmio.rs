@@ -19,6 +19,16 @@ impl UartMmio {
         self.region.io_write_u8(0, byte)
     }
 
+    // The data ready bit of the line status register
+    const LSR_DR: u8 = 1;
+
+    pub fn try_receive(&mut self) -> Option<u8> {
+        if self.region.io_read_u8(5) & Self::LSR_DR == 0 {
+            return None;
+        }
+        Some(self.region.io_read_u8(0))
+    }
+
     pub fn init(&mut self) {}
 }
 
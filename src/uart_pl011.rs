@@ -21,6 +21,17 @@ impl Pl011 {
             core::ptr::write_volatile(self.base as *mut u8, data);
         }
     }
+
+    // The receive FIFO empty bit of the flag register
+    const FR_RXFE: u32 = 1 << 4;
+
+    pub fn try_receive(&mut self) -> Option<u8> {
+        let flags = unsafe { core::ptr::read_volatile((self.base + 0x18) as *const u32) };
+        if flags & Self::FR_RXFE != 0 {
+            return None;
+        }
+        Some(unsafe { core::ptr::read_volatile(self.base as *const u32) } as u8)
+    }
 }
 
 impl fmt::Write for Pl011 {
@@ -30,3 +30,160 @@ pub trait VirtioTransport {
     fn notify_queue(&self, queue: u16);
     fn read_device_config(&self, offset: u64) -> u32;
 }
+
+pub const QUEUE_SIZE: usize = 16;
+
+const VIRTQ_DESC_F_NEXT: u16 = 1;
+const VIRTQ_DESC_F_WRITE: u16 = 2;
+
+#[repr(C)]
+#[repr(align(16))]
+#[derive(Default)]
+/// A virtio qeueue entry descriptor
+struct Desc {
+    addr: u64,
+    length: u32,
+    flags: u16,
+    next: u16,
+}
+
+#[repr(C)]
+#[repr(align(2))]
+#[derive(Default)]
+/// The virtio available ring
+struct AvailRing {
+    flags: u16,
+    idx: u16,
+    ring: [u16; QUEUE_SIZE],
+}
+
+#[repr(C)]
+#[repr(align(4))]
+#[derive(Default)]
+/// The virtio used ring
+struct UsedRing {
+    flags: u16,
+    idx: u16,
+    ring: [UsedElem; QUEUE_SIZE],
+}
+
+#[repr(C)]
+#[derive(Default)]
+/// A single element in the used ring
+struct UsedElem {
+    id: u32,
+    len: u32,
+}
+
+/// A buffer that is part of a descriptor chain
+#[derive(Debug)]
+pub struct Buffer {
+    pub addr: u64,
+    pub length: u32,
+    pub device_writable: bool,
+}
+
+impl Buffer {
+    /// A buffer the device reads from
+    pub fn readable<T: ?Sized>(data: &T) -> Buffer {
+        Buffer {
+            addr: data as *const T as *const u8 as u64,
+            length: core::mem::size_of_val(data) as u32,
+            device_writable: false,
+        }
+    }
+
+    /// A buffer the device writes into
+    pub fn writable<T: ?Sized>(data: &mut T) -> Buffer {
+        Buffer {
+            addr: data as *mut T as *mut u8 as u64,
+            length: core::mem::size_of_val(data) as u32,
+            device_writable: true,
+        }
+    }
+}
+
+#[repr(C)]
+#[repr(align(64))]
+#[derive(Default)]
+/// A split virtqueue that is used synchronously, one request at a time
+pub struct Virtqueue {
+    descriptors: [Desc; QUEUE_SIZE],
+    avail: AvailRing,
+    used: UsedRing,
+    next_head: usize,
+}
+
+impl Virtqueue {
+    /// Program the queue into the transport. The queue must not move after this.
+    pub fn setup(&self, transport: &dyn VirtioTransport, queue: u16) -> Result<(), Error> {
+        transport.set_queue(queue);
+
+        let max_queue = transport.get_queue_max_size();
+
+        // Hardcoded queue size to QUEUE_SIZE at the moment
+        if max_queue < QUEUE_SIZE as u16 {
+            return Err(Error::QueueTooSmall);
+        }
+        transport.set_queue_size(QUEUE_SIZE as u16);
+
+        // Update all queue parts
+        let addr = self.descriptors.as_ptr() as u64;
+        transport.set_descriptors_address(addr);
+
+        let addr = (&self.avail as *const _) as u64;
+        transport.set_avail_ring(addr);
+
+        let addr = (&self.used as *const _) as u64;
+        transport.set_used_ring(addr);
+
+        // Confirm queue
+        transport.set_queue_enable();
+
+        Ok(())
+    }
+
+    /// Chain `buffers` together, hand them to the device and wait until it
+    /// has used them.
+    pub fn submit(&mut self, transport: &dyn VirtioTransport, queue: u16, buffers: &[Buffer]) {
+        assert!(!buffers.is_empty() && buffers.len() <= QUEUE_SIZE);
+
+        let head = self.next_head;
+        let mut next_desc = head;
+        for (i, buffer) in buffers.iter().enumerate() {
+            let d = &mut self.descriptors[next_desc];
+            next_desc = (next_desc + 1) % QUEUE_SIZE;
+            d.addr = buffer.addr;
+            d.length = buffer.length;
+            d.flags = if buffer.device_writable {
+                VIRTQ_DESC_F_WRITE
+            } else {
+                0
+            };
+            if i + 1 < buffers.len() {
+                d.flags |= VIRTQ_DESC_F_NEXT;
+                d.next = next_desc as u16;
+            } else {
+                d.next = 0;
+            }
+        }
+
+        // Update ring to point to head of chain. Fence. Then update idx
+        let avail_index = self.avail.idx;
+        self.avail.ring[(avail_index % QUEUE_SIZE as u16) as usize] = head as u16;
+        core::sync::atomic::fence(core::sync::atomic::Ordering::Acquire);
+
+        self.avail.idx = self.avail.idx.wrapping_add(1);
+
+        // Next free descriptor to use
+        self.next_head = next_desc;
+
+        // Notify queue has been updated
+        transport.notify_queue(queue);
+
+        // Check for the completion of the request
+        while unsafe { core::ptr::read_volatile(&self.used.idx) } != self.avail.idx {
+            core::sync::atomic::fence(core::sync::atomic::Ordering::Acquire);
+        }
+    }
+}